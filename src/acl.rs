@@ -0,0 +1,181 @@
+use std::collections::HashMap;
+
+use kafka_protocol::records::RecordSet;
+use uuid::Uuid;
+
+use crate::config::BrokerConfig;
+use crate::record::{extract_record_value, AccessControlEntryRecord, RecordValue};
+
+pub const ANONYMOUS_PRINCIPAL: &str = "User:ANONYMOUS";
+const WILDCARD_PRINCIPAL: &str = "User:*";
+const WILDCARD: &str = "*";
+
+// org.apache.kafka.common.resource.ResourceType
+pub const RESOURCE_TYPE_TOPIC: i8 = 2;
+pub const RESOURCE_TYPE_CLUSTER: i8 = 4;
+
+// org.apache.kafka.common.resource.PatternType
+const PATTERN_TYPE_LITERAL: i8 = 3;
+const PATTERN_TYPE_PREFIXED: i8 = 4;
+
+// org.apache.kafka.common.acl.AclPermissionType
+const PERMISSION_DENY: i8 = 2;
+const PERMISSION_ALLOW: i8 = 3;
+
+// org.apache.kafka.common.acl.AclOperation
+pub const OP_ALL: i8 = 2;
+pub const OP_READ: i8 = 3;
+pub const OP_WRITE: i8 = 4;
+pub const OP_CREATE: i8 = 5;
+pub const OP_DELETE: i8 = 6;
+pub const OP_ALTER: i8 = 7;
+pub const OP_DESCRIBE: i8 = 8;
+pub const OP_CLUSTER_ACTION: i8 = 9;
+pub const OP_DESCRIBE_CONFIGS: i8 = 10;
+pub const OP_ALTER_CONFIGS: i8 = 11;
+pub const OP_IDEMPOTENT_WRITE: i8 = 12;
+
+pub const TOPIC_OPERATIONS: &[i8] = &[OP_READ, OP_WRITE, OP_CREATE, OP_DELETE, OP_ALTER, OP_DESCRIBE, OP_DESCRIBE_CONFIGS, OP_ALTER_CONFIGS];
+pub const CLUSTER_OPERATIONS: &[i8] = &[OP_CREATE, OP_ALTER, OP_DESCRIBE, OP_CLUSTER_ACTION, OP_DESCRIBE_CONFIGS, OP_ALTER_CONFIGS, OP_IDEMPOTENT_WRITE];
+
+/// ACL bindings replayed from AccessControlEntryRecords in the metadata log.
+#[derive(Debug, Clone, Default)]
+pub struct AclState {
+    acls: HashMap<Uuid, AccessControlEntryRecord>,
+}
+
+impl AclState {
+    pub fn from_record_sets(record_sets: &[RecordSet]) -> AclState {
+        let mut acls = HashMap::new();
+        for record in record_sets.iter().flat_map(|record_set| record_set.records.iter()) {
+            if record.value.is_none() {
+                continue;
+            }
+            match extract_record_value(record) {
+                RecordValue::AccessControlEntryRecord(acl) => {
+                    acls.insert(acl.id, acl);
+                }
+                RecordValue::RemoveAccessControlEntryRecord(remove) => {
+                    acls.remove(&remove.id);
+                }
+                _ => {}
+            }
+        }
+        AclState { acls }
+    }
+
+    fn matching(&self, resource_type: i8, resource_name: &str) -> Vec<&AccessControlEntryRecord> {
+        self.acls.values()
+            .filter(|acl| acl.resource_type == resource_type)
+            .filter(|acl| match acl.pattern_type {
+                PATTERN_TYPE_LITERAL => acl.resource_name == resource_name || acl.resource_name == WILDCARD,
+                PATTERN_TYPE_PREFIXED => resource_name.starts_with(acl.resource_name.as_str()),
+                _ => false,
+            })
+            .collect()
+    }
+}
+
+fn matches_operation(acl_operation: i8, operation: i8, permission_type: i8) -> bool {
+    if acl_operation == OP_ALL || acl_operation == operation {
+        return true;
+    }
+    // allowing these operations implies the right to describe, see AclAuthorizer
+    permission_type == PERMISSION_ALLOW && match operation {
+        OP_DESCRIBE => matches!(acl_operation, OP_READ | OP_WRITE | OP_DELETE | OP_ALTER),
+        OP_DESCRIBE_CONFIGS => acl_operation == OP_ALTER_CONFIGS,
+        _ => false,
+    }
+}
+
+/// Decides operations for a principal following Kafka's StandardAuthorizer rules:
+/// DENY wins over ALLOW, and resources without ACLs fall back to
+/// `allow.everyone.if.no.acl.found`. Without `authorizer.class.name` everything is allowed.
+#[derive(Debug, Clone)]
+pub struct Authorizer {
+    enabled: bool,
+    allow_everyone_if_no_acl_found: bool,
+    acls: AclState,
+}
+
+impl Authorizer {
+    pub fn new(config: &BrokerConfig, acls: AclState) -> Authorizer {
+        Authorizer {
+            enabled: config.get("authorizer.class.name").map(|name| !name.is_empty()).unwrap_or(false),
+            allow_everyone_if_no_acl_found: config.get_or("allow.everyone.if.no.acl.found", false),
+            acls,
+        }
+    }
+
+    pub fn authorize(&self, principal: &str, host: &str, operation: i8, resource_type: i8, resource_name: &str) -> bool {
+        if !self.enabled {
+            return true;
+        }
+        let acls = self.acls.matching(resource_type, resource_name);
+        if acls.is_empty() {
+            return self.allow_everyone_if_no_acl_found;
+        }
+        let applies = |acl: &&AccessControlEntryRecord, permission_type: i8| {
+            acl.permission_type == permission_type
+                && (acl.principal == principal || acl.principal == WILDCARD_PRINCIPAL)
+                && (acl.host == host || acl.host == WILDCARD)
+                && matches_operation(acl.operation, operation, permission_type)
+        };
+        if acls.iter().any(|acl| applies(acl, PERMISSION_DENY)) {
+            return false;
+        }
+        acls.iter().any(|acl| applies(acl, PERMISSION_ALLOW))
+    }
+
+    /// Bit field of the allowed operations, as used by `*_authorized_operations` fields.
+    pub fn authorized_operations(&self, principal: &str, host: &str, resource_type: i8, resource_name: &str, operations: &[i8]) -> i32 {
+        operations.iter()
+            .filter(|operation| self.authorize(principal, host, **operation, resource_type, resource_name))
+            .fold(0, |bits, operation| bits | (1 << *operation))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use uuid::Uuid;
+
+    use crate::config::BrokerConfig;
+    use crate::record::AccessControlEntryRecord;
+
+    use super::*;
+
+    fn acl(id: u128, name: &str, pattern_type: i8, operation: i8, permission_type: i8) -> AccessControlEntryRecord {
+        AccessControlEntryRecord {
+            id: Uuid::from_u128(id),
+            resource_type: RESOURCE_TYPE_TOPIC,
+            resource_name: name.to_string(),
+            pattern_type,
+            principal: ANONYMOUS_PRINCIPAL.to_string(),
+            host: WILDCARD.to_string(),
+            operation,
+            permission_type,
+        }
+    }
+
+    #[test]
+    fn test_authorized_operations() {
+        let config = BrokerConfig::parse("authorizer.class.name=org.apache.kafka.metadata.authorizer.StandardAuthorizer");
+        let acls = AclState {
+            acls: HashMap::from([
+                (Uuid::from_u128(1), acl(1, "foo", PATTERN_TYPE_PREFIXED, OP_READ, PERMISSION_ALLOW)),
+                (Uuid::from_u128(2), acl(2, "foobar", PATTERN_TYPE_LITERAL, OP_ALL, PERMISSION_DENY)),
+            ]),
+        };
+        let authorizer = Authorizer::new(&config, acls);
+
+        let ops = authorizer.authorized_operations(ANONYMOUS_PRINCIPAL, "127.0.0.1", RESOURCE_TYPE_TOPIC, "foo", TOPIC_OPERATIONS);
+        assert_eq!((1 << OP_READ) | (1 << OP_DESCRIBE), ops);
+        assert!(!authorizer.authorize(ANONYMOUS_PRINCIPAL, "127.0.0.1", OP_READ, RESOURCE_TYPE_TOPIC, "foobar"));
+        assert!(!authorizer.authorize(ANONYMOUS_PRINCIPAL, "127.0.0.1", OP_READ, RESOURCE_TYPE_TOPIC, "bar"));
+
+        let authorizer = Authorizer::new(&BrokerConfig::default(), AclState::default());
+        assert!(authorizer.authorize(ANONYMOUS_PRINCIPAL, "127.0.0.1", OP_ALTER, RESOURCE_TYPE_TOPIC, "bar"));
+    }
+}
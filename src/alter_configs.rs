@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+
+use bytes::Bytes;
+use kafka_protocol::error::ResponseError;
+use kafka_protocol::messages::{AlterConfigsRequest, AlterConfigsResponse, IncrementalAlterConfigsRequest, IncrementalAlterConfigsResponse};
+use kafka_protocol::messages::alter_configs_response::AlterConfigsResourceResponse;
+use kafka_protocol::messages::incremental_alter_configs_response::AlterConfigsResourceResponse as IncrementalAlterConfigsResourceResponse;
+use kafka_protocol::protocol::StrBytes;
+use uuid::Uuid;
+
+use crate::acl::{Authorizer, ANONYMOUS_PRINCIPAL, OP_ALTER_CONFIGS, RESOURCE_TYPE_CLUSTER, RESOURCE_TYPE_TOPIC};
+use crate::broker::Broker;
+use crate::describe_configs::CLUSTER_RESOURCE_NAME;
+use crate::log_config::{validate_broker_config, validate_topic_config, CLEANUP_POLICY_CONFIG, CONFIG_RESOURCE_BROKER, CONFIG_RESOURCE_TOPIC};
+use crate::record::{encode_config_record, ConfigRecord};
+use crate::replica::node_id;
+
+// IncrementalAlterConfigs config_operation
+const OP_SET: i8 = 0;
+const OP_DELETE: i8 = 1;
+const OP_APPEND: i8 = 2;
+const OP_SUBTRACT: i8 = 3;
+
+type AlterError = (ResponseError, Option<String>);
+
+fn invalid_config(message: String) -> AlterError {
+    (ResponseError::InvalidConfig, Some(message))
+}
+
+fn is_list_config(name: &str) -> bool {
+    name == CLEANUP_POLICY_CONFIG || name == "log.cleanup.policy"
+}
+
+/// Checks that the resource exists and may be altered, returning its current
+/// dynamic configs.
+async fn current_configs(broker: &Broker, resource_type: i8, resource_name: &str, topics: &HashMap<String, (Uuid, Vec<i32>)>, authorizer: &Authorizer, client_host: &str) -> Result<HashMap<String, String>, AlterError> {
+    let configs = match resource_type {
+        CONFIG_RESOURCE_TOPIC => {
+            if !topics.contains_key(resource_name) {
+                return Err((ResponseError::UnknownTopicOrPartition, None));
+            }
+            if !authorizer.authorize(ANONYMOUS_PRINCIPAL, client_host, OP_ALTER_CONFIGS, RESOURCE_TYPE_TOPIC, resource_name) {
+                return Err((ResponseError::TopicAuthorizationFailed, None));
+            }
+            broker.topic_configs.lock().await.get(resource_name).cloned()
+        }
+        CONFIG_RESOURCE_BROKER => {
+            if !resource_name.is_empty() && resource_name != node_id(&broker.config).to_string() {
+                return Err((ResponseError::InvalidRequest, Some(format!("Unexpected broker id {}", resource_name))));
+            }
+            if !authorizer.authorize(ANONYMOUS_PRINCIPAL, client_host, OP_ALTER_CONFIGS, RESOURCE_TYPE_CLUSTER, CLUSTER_RESOURCE_NAME) {
+                return Err((ResponseError::ClusterAuthorizationFailed, None));
+            }
+            broker.broker_configs.lock().await.get(resource_name).cloned()
+        }
+        _ => return Err((ResponseError::InvalidRequest, Some(format!("Unsupported resource type {}", resource_type)))),
+    };
+    Ok(configs.unwrap_or_default())
+}
+
+/// The ConfigRecords that turn `current` into `updated`, a null value
+/// removing a config.
+fn config_records(resource_type: i8, resource_name: &str, current: &HashMap<String, String>, updated: &HashMap<String, String>) -> Vec<Bytes> {
+    let mut changes: Vec<(&String, Option<&String>)> = updated.iter()
+        .filter(|(name, value)| current.get(*name) != Some(*value))
+        .map(|(name, value)| (name, Some(value)))
+        .chain(current.keys()
+            .filter(|name| !updated.contains_key(*name))
+            .map(|name| (name, None)))
+        .collect();
+    changes.sort();
+    changes.into_iter()
+        .map(|(name, value)| encode_config_record(&ConfigRecord {
+            resource_type,
+            resource_name: resource_name.to_string(),
+            name: name.clone(),
+            value: value.cloned(),
+        }))
+        .collect()
+}
+
+/// Validates the new dynamic configs of a resource and, unless only
+/// validating, writes the changes to the metadata log and applies them.
+async fn alter(broker: &Broker, resource_type: i8, resource_name: &str, current: &HashMap<String, String>, updated: HashMap<String, String>, validate_only: bool) -> Result<(), AlterError> {
+    for (name, value) in updated.iter() {
+        let valid = match resource_type {
+            CONFIG_RESOURCE_TOPIC => validate_topic_config(name, value),
+            _ => validate_broker_config(name, value),
+        };
+        valid.map_err(invalid_config)?;
+    }
+    let records = config_records(resource_type, resource_name, current, &updated);
+    if validate_only || records.is_empty() {
+        return Ok(());
+    }
+    broker.append_metadata(records).await.map_err(|e| {
+        println!("Failed to write configs of {}: {}", resource_name, e);
+        (ResponseError::UnknownServerError, None)
+    })?;
+    broker.refresh_configs().await;
+    Ok(())
+}
+
+/// Replaces the dynamic configs of each resource with the given set.
+pub async fn handle_alter_configs(broker: &Broker, req: AlterConfigsRequest, topics: &HashMap<String, (Uuid, Vec<i32>)>, authorizer: &Authorizer, client_host: &str) -> AlterConfigsResponse {
+    broker.refresh_configs().await;
+    let mut responses = Vec::new();
+    for resource in req.resources.iter() {
+        let resource_name = resource.resource_name.as_str();
+        let result = match current_configs(broker, resource.resource_type, resource_name, topics, authorizer, client_host).await {
+            Ok(current) => {
+                let updated = resource.configs.iter()
+                    .filter_map(|config| config.value.as_ref().map(|value| (config.name.to_string(), value.to_string())))
+                    .collect();
+                alter(broker, resource.resource_type, resource_name, &current, updated, req.validate_only).await
+            }
+            Err(error) => Err(error),
+        };
+        let (error, message) = result.err().unzip();
+        responses.push(AlterConfigsResourceResponse::default()
+            .with_error_code(error.map(|error| error.code()).unwrap_or(0))
+            .with_error_message(message.flatten().map(StrBytes::from_string))
+            .with_resource_type(resource.resource_type)
+            .with_resource_name(resource.resource_name.clone()));
+    }
+    AlterConfigsResponse::default()
+        .with_responses(responses)
+}
+
+/// Sets, deletes, appends to or subtracts from single dynamic configs of each
+/// resource, leaving the others as they are (KIP-339).
+pub async fn handle_incremental_alter_configs(broker: &Broker, req: IncrementalAlterConfigsRequest, topics: &HashMap<String, (Uuid, Vec<i32>)>, authorizer: &Authorizer, client_host: &str) -> IncrementalAlterConfigsResponse {
+    broker.refresh_configs().await;
+    let mut responses = Vec::new();
+    for resource in req.resources.iter() {
+        let resource_name = resource.resource_name.as_str();
+        let result = match current_configs(broker, resource.resource_type, resource_name, topics, authorizer, client_host).await {
+            Ok(current) => {
+                // lists grow from the value in effect, which may not be dynamic
+                let effective = match resource.resource_type {
+                    CONFIG_RESOURCE_TOPIC => broker.log_config(resource_name).await.value(CLEANUP_POLICY_CONFIG),
+                    _ => broker.dynamic_config().await.get("log.cleanup.policy").map(|value| value.to_string()),
+                };
+                let mut updated = current.clone();
+                let mut result = Ok(());
+                for config in resource.configs.iter() {
+                    let name = config.name.to_string();
+                    let value = config.value.as_ref().map(|value| value.to_string());
+                    match (config.config_operation, value) {
+                        (OP_SET, Some(value)) => {
+                            updated.insert(name, value);
+                        }
+                        (OP_DELETE, _) => {
+                            updated.remove(&name);
+                        }
+                        (OP_APPEND | OP_SUBTRACT, Some(value)) if is_list_config(&name) => {
+                            let mut items: Vec<String> = updated.get(&name).cloned().or_else(|| effective.clone())
+                                .unwrap_or_default()
+                                .split(',')
+                                .map(|item| item.trim().to_string())
+                                .filter(|item| !item.is_empty())
+                                .collect();
+                            for item in value.split(',').map(|item| item.trim()) {
+                                if config.config_operation == OP_SUBTRACT {
+                                    items.retain(|existing| existing != item);
+                                } else if !items.iter().any(|existing| existing == item) {
+                                    items.push(item.to_string());
+                                }
+                            }
+                            updated.insert(name, items.join(","));
+                        }
+                        (OP_APPEND | OP_SUBTRACT, Some(_)) => {
+                            result = Err(invalid_config(format!("Config {} is not a list and cannot be appended to or subtracted from", name)));
+                            break;
+                        }
+                        (operation, _) => {
+                            result = Err(invalid_config(format!("Invalid operation {} for config {}", operation, name)));
+                            break;
+                        }
+                    }
+                }
+                match result {
+                    Ok(()) => alter(broker, resource.resource_type, resource_name, &current, updated, req.validate_only).await,
+                    Err(error) => Err(error),
+                }
+            }
+            Err(error) => Err(error),
+        };
+        let (error, message) = result.err().unzip();
+        responses.push(IncrementalAlterConfigsResourceResponse::default()
+            .with_error_code(error.map(|error| error.code()).unwrap_or(0))
+            .with_error_message(message.flatten().map(StrBytes::from_string))
+            .with_resource_type(resource.resource_type)
+            .with_resource_name(resource.resource_name.clone()));
+    }
+    IncrementalAlterConfigsResponse::default()
+        .with_responses(responses)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use bytes::Bytes;
+
+    use crate::log_config::CONFIG_RESOURCE_TOPIC;
+    use crate::record::{parse_record_value, RecordValue};
+
+    use super::config_records;
+
+    #[test]
+    fn test_config_records() {
+        let current = HashMap::from([
+            ("retention.ms".to_string(), "1000".to_string()),
+            ("cleanup.policy".to_string(), "delete".to_string()),
+        ]);
+        let updated = HashMap::from([
+            ("retention.ms".to_string(), "1000".to_string()),
+            ("segment.bytes".to_string(), "4096".to_string()),
+        ]);
+        let changes: Vec<_> = config_records(CONFIG_RESOURCE_TOPIC, "foo", &current, &updated).into_iter()
+            .map(|mut value: Bytes| match parse_record_value(&mut value) {
+                RecordValue::ConfigRecord(config) => (config.resource_name, config.name, config.value),
+                other => panic!("unexpected {:?}", other),
+            })
+            .collect();
+        assert_eq!(vec![
+            ("foo".to_string(), "cleanup.policy".to_string(), None),
+            ("foo".to_string(), "segment.bytes".to_string(), Some("4096".to_string())),
+        ], changes);
+    }
+}
@@ -0,0 +1,57 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+use kafka_protocol::error::ResponseError;
+use kafka_protocol::messages::alter_replica_log_dirs_response::{AlterReplicaLogDirPartitionResult, AlterReplicaLogDirTopicResult};
+use kafka_protocol::messages::{AlterReplicaLogDirsRequest, AlterReplicaLogDirsResponse, TopicName};
+use kafka_protocol::protocol::StrBytes;
+use uuid::Uuid;
+
+use crate::acl::{Authorizer, ANONYMOUS_PRINCIPAL, OP_ALTER, RESOURCE_TYPE_CLUSTER};
+use crate::broker::Broker;
+use crate::describe_configs::CLUSTER_RESOURCE_NAME;
+
+async fn alter_replica_log_dir(broker: &Broker, topic_name: &str, partition_idx: i32, log_dir: &Path, topics: &HashMap<String, (Uuid, Vec<i32>)>) -> Result<(), ResponseError> {
+    if !topics.get(topic_name).is_some_and(|(_, partition_ids)| partition_ids.contains(&partition_idx)) {
+        return Err(ResponseError::UnknownTopicOrPartition);
+    }
+    let online = match broker.log_dirs.lock().await.get(log_dir) {
+        Some(dir) => dir.online,
+        None => return Err(ResponseError::LogDirNotFound),
+    };
+    if !online {
+        return Err(ResponseError::KafkaStorageError);
+    }
+    broker.move_partition(topic_name, partition_idx, log_dir).await.map_err(|e| {
+        println!("Failed to move {}-{} to {}: {}", topic_name, partition_idx, log_dir.display(), e);
+        ResponseError::KafkaStorageError
+    })
+}
+
+/// Moves partitions between the log dirs of this broker.
+pub async fn handle_alter_replica_log_dirs(broker: &Broker, req: AlterReplicaLogDirsRequest, topics: &HashMap<String, (Uuid, Vec<i32>)>, authorizer: &Authorizer, client_host: &str) -> AlterReplicaLogDirsResponse {
+    let authorized = authorizer.authorize(ANONYMOUS_PRINCIPAL, client_host, OP_ALTER, RESOURCE_TYPE_CLUSTER, CLUSTER_RESOURCE_NAME);
+    let mut results: BTreeMap<String, Vec<AlterReplicaLogDirPartitionResult>> = BTreeMap::new();
+    for dir in req.dirs.iter() {
+        let log_dir = Path::new(dir.path.as_str());
+        for topic in dir.topics.iter() {
+            let topic_name = topic.name.0.as_str();
+            for partition_idx in topic.partitions.iter() {
+                let result = if authorized {
+                    alter_replica_log_dir(broker, topic_name, *partition_idx, log_dir, topics).await
+                } else {
+                    Err(ResponseError::ClusterAuthorizationFailed)
+                };
+                results.entry(topic_name.to_string()).or_default().push(AlterReplicaLogDirPartitionResult::default()
+                    .with_partition_index(*partition_idx)
+                    .with_error_code(result.err().map(|error| error.code()).unwrap_or(0)));
+            }
+        }
+    }
+    AlterReplicaLogDirsResponse::default()
+        .with_results(results.into_iter()
+            .map(|(topic_name, partitions)| AlterReplicaLogDirTopicResult::default()
+                .with_topic_name(TopicName(StrBytes::from_string(topic_name)))
+                .with_partitions(partitions))
+            .collect())
+}
@@ -0,0 +1,787 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::{Bytes, BytesMut};
+use kafka_protocol::records::RecordSet;
+use tokio::fs;
+use tokio::sync::{MappedMutexGuard, Mutex, MutexGuard};
+use tokio::time::{timeout_at, Instant};
+use uuid::Uuid;
+
+use crate::checkpoint::{checkpoint_path, read_checkpoint, write_checkpoint, LOG_START_OFFSET_CHECKPOINT, RECOVERY_POINT_OFFSET_CHECKPOINT, REPLICATION_OFFSET_CHECKPOINT};
+use crate::config::BrokerConfig;
+use crate::fetch_session::{FetchSessionCache, DEFAULT_MAX_CACHE_SLOTS, MAX_CACHE_SLOTS_CONFIG};
+use crate::delete_records::HIGH_WATERMARK_OFFSET;
+use crate::group::{self, GroupCoordinator};
+use crate::list_offsets::{EARLIEST_LOCAL_TIMESTAMP, EARLIEST_TIMESTAMP, LATEST_TIMESTAMP, MAX_TIMESTAMP};
+use crate::log::{copy_partition_dir, future_partition_dir, list_partition_dirs_in, log_dirs, partition_dir, FetchedData, PartitionLog, TopicPartition, CLEAN_SHUTDOWN_FILE};
+use crate::log_config::{broker_configs, topic_configs, LogConfig};
+use crate::log_dir::{directory_assignments, random_uuid, LogDirs};
+use crate::metadata::{metadata_log_dir, snapshot_file_name, MetadataCache, DEFAULT_MAX_BYTES_BETWEEN_SNAPSHOTS, MAX_BYTES_BETWEEN_SNAPSHOTS_CONFIG};
+use crate::purgatory::FetchPurgatory;
+use crate::raft::{QuorumConfig, Raft};
+use crate::record::{encode_partition_change_record, record_set_to_topic};
+use crate::replica::{node_id, partition_states, ReplicaManager};
+use crate::remote_log::{fetch_remote, remote_offset_for_timestamp, remote_storage_manager, RemoteStorageManager};
+use crate::segment_cache::{SegmentCache, DEFAULT_MAX_OPEN_FILES, MAX_OPEN_FILES_CONFIG};
+use crate::txn::{self, TransactionCoordinator};
+
+pub const NODE_ID: i32 = 1;
+pub const HOST: &str = "localhost";
+pub const PORT: i32 = 9092;
+
+pub fn now_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+/// State shared by every connection.
+#[derive(Debug, Default)]
+pub struct Broker {
+    pub config: BrokerConfig,
+    pub logs: Mutex<HashMap<TopicPartition, PartitionLog>>,
+    pub txn_coordinator: Mutex<TransactionCoordinator>,
+    pub group_coordinator: Mutex<GroupCoordinator>,
+    pub fetch_purgatory: FetchPurgatory,
+    pub fetch_sessions: Mutex<FetchSessionCache>,
+    // topic -> config overrides from the metadata log
+    pub topic_configs: Mutex<HashMap<String, HashMap<String, String>>>,
+    // "" or node id -> dynamic broker settings from the metadata log
+    pub broker_configs: Mutex<HashMap<String, HashMap<String, String>>>,
+    pub segment_cache: Arc<SegmentCache>,
+    pub log_dirs: Mutex<LogDirs>,
+    // set with remote.log.storage.system.enable
+    pub remote_storage: Option<Arc<dyn RemoteStorageManager>>,
+    pub metadata: Mutex<MetadataCache>,
+    // set with process.roles and controller.quorum.voters
+    pub raft: Option<Raft>,
+    // locked after `logs` when both are
+    pub replica_manager: Mutex<ReplicaManager>,
+}
+
+impl Broker {
+    pub fn new(config: BrokerConfig) -> Broker {
+        let max_slots = config.get_or(MAX_CACHE_SLOTS_CONFIG, DEFAULT_MAX_CACHE_SLOTS);
+        let max_open_files = config.get_or(MAX_OPEN_FILES_CONFIG, DEFAULT_MAX_OPEN_FILES);
+        Broker {
+            fetch_sessions: Mutex::new(FetchSessionCache::new(max_slots)),
+            segment_cache: Arc::new(SegmentCache::new(max_open_files)),
+            log_dirs: Mutex::new(LogDirs::new(log_dirs(&config))),
+            remote_storage: remote_storage_manager(&config),
+            metadata: Mutex::new(MetadataCache::new(metadata_log_dir(&config))),
+            raft: QuorumConfig::from_config(&config).map(|quorum| Raft::new(quorum, metadata_log_dir(&config))),
+            replica_manager: Mutex::new(ReplicaManager::new(node_id(&config))),
+            config,
+            ..Broker::default()
+        }
+    }
+
+    /// Joins the metadata quorum, opens every partition log, recovering them
+    /// if the last shutdown was not clean, takes on the partitions' leaders
+    /// and followers, then restores coordinator state from the internal
+    /// topics.
+    pub async fn load(&self) -> io::Result<()> {
+        if let Some(raft) = &self.raft {
+            raft.load(self).await?;
+        }
+        self.load_logs().await?;
+        self.reconcile_replicas().await?;
+        group::load(self).await?;
+        txn::load(self).await
+    }
+
+    /// Brings the leaders and followers in line with the partition states in
+    /// the metadata log. A new leader epoch starts at the log end offset of
+    /// its leader. A partition followed under a new leader finds where it
+    /// diverges from the leader's log through the epochs of its fetches, or
+    /// without any epochs is truncated to its high watermark, the last
+    /// offset it surely shares with the leader's log.
+    pub async fn reconcile_replicas(&self) -> io::Result<()> {
+        let states = partition_states(&self.cluster_metadata().await);
+        let (led, followed) = self.replica_manager.lock().await.reconcile(&states, now_ms());
+        for ((topic_name, partition_idx), leader_epoch) in led {
+            self.assign_leader_epoch(&topic_name, partition_idx, leader_epoch).await?;
+        }
+        for (topic_name, partition_idx) in followed {
+            if self.latest_epoch(&topic_name, partition_idx).await?.is_some() {
+                continue;
+            }
+            let (_, _, high_watermark) = self.log_offsets(&topic_name, partition_idx).await?;
+            self.truncate_log(&topic_name, partition_idx, high_watermark).await?;
+        }
+        Ok(())
+    }
+
+    /// Loads every online log dir. A dir that fails to load is taken
+    /// offline, leaving the others to serve.
+    async fn load_logs(&self) -> io::Result<()> {
+        let log_dirs = LogDirs::load(&self.config).await;
+        let online: Vec<PathBuf> = log_dirs.dirs().iter()
+            .filter(|dir| dir.online)
+            .map(|dir| dir.path.clone())
+            .collect();
+        *self.log_dirs.lock().await = log_dirs;
+        for log_dir in online {
+            if let Err(e) = self.load_log_dir(&log_dir).await {
+                self.take_offline(&log_dir, &e).await;
+            }
+        }
+        self.record_directories().await;
+        Ok(())
+    }
+
+    async fn load_log_dir(&self, log_dir: &Path) -> io::Result<()> {
+        let clean = match fs::remove_file(log_dir.join(CLEAN_SHUTDOWN_FILE)).await {
+            Ok(()) => true,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => false,
+            Err(e) => return Err(e),
+        };
+        let recovery_points = read_checkpoint(&checkpoint_path(log_dir, RECOVERY_POINT_OFFSET_CHECKPOINT)).await?;
+        let high_watermarks = read_checkpoint(&checkpoint_path(log_dir, REPLICATION_OFFSET_CHECKPOINT)).await?;
+        let log_start_offsets = read_checkpoint(&checkpoint_path(log_dir, LOG_START_OFFSET_CHECKPOINT)).await?;
+        for (topic_partition, dir, future) in list_partition_dirs_in(log_dir).await? {
+            if future {
+                // a move interrupted by the shutdown, the current replica is intact
+                println!("Removing unfinished copy {}", dir.display());
+                fs::remove_dir_all(&dir).await?;
+                continue;
+            }
+            if let Some(other) = self.log_dirs.lock().await.dir_of(&topic_partition) {
+                println!("Ignoring {}, {}-{} is already loaded from {}", dir.display(), topic_partition.0, topic_partition.1, other.path.display());
+                continue;
+            }
+            let config = self.log_config(&topic_partition.0).await;
+            let mut log = if clean {
+                PartitionLog::open(dir, config, self.segment_cache.clone()).await?
+            } else {
+                let recovery_point = recovery_points.get(&topic_partition).copied().unwrap_or(0);
+                let (log, truncated) = PartitionLog::recover(dir, config, self.segment_cache.clone(), recovery_point).await?;
+                if truncated > 0 {
+                    println!("Truncated {} bytes of {}-{}, log end offset is now {}", truncated, topic_partition.0, topic_partition.1, log.log_end_offset());
+                }
+                log
+            };
+            if let Some(offset) = log_start_offsets.get(&topic_partition) {
+                log.advance_log_start_offset(*offset).await?;
+            }
+            if let Some(high_watermark) = high_watermarks.get(&topic_partition) {
+                log.restore_high_watermark(*high_watermark);
+            }
+            self.logs.lock().await.insert(topic_partition.clone(), log);
+            self.log_dirs.lock().await.assign(topic_partition, log_dir);
+        }
+        Ok(())
+    }
+
+    /// Syncs the logs, checkpoints their offsets and marks the shutdown of
+    /// each online log dir as clean, so the next start skips recovery there.
+    pub async fn shutdown(&self) -> io::Result<()> {
+        let mut logs = self.logs.lock().await;
+        for log in logs.values_mut() {
+            if let Err(e) = log.flush().await {
+                let log_dir = log.dir().parent().unwrap_or(log.dir()).to_path_buf();
+                if self.log_dirs.lock().await.mark_offline(&log_dir) {
+                    println!("Marking log dir {} offline: {}", log_dir.display(), e);
+                }
+            }
+        }
+        self.write_offsets(&logs, RECOVERY_POINT_OFFSET_CHECKPOINT, PartitionLog::recovery_point).await?;
+        self.write_offsets(&logs, REPLICATION_OFFSET_CHECKPOINT, PartitionLog::high_watermark).await?;
+        self.write_offsets(&logs, LOG_START_OFFSET_CHECKPOINT, PartitionLog::log_start_offset).await?;
+        for log_dir in self.online_log_dirs().await {
+            fs::write(log_dir.join(CLEAN_SHUTDOWN_FILE), b"").await?;
+        }
+        Ok(())
+    }
+
+    /// Writes the recovery points and log start offsets of every log.
+    pub async fn checkpoint_log_offsets(&self) -> io::Result<()> {
+        let logs = self.logs.lock().await;
+        self.write_offsets(&logs, RECOVERY_POINT_OFFSET_CHECKPOINT, PartitionLog::recovery_point).await?;
+        self.write_offsets(&logs, LOG_START_OFFSET_CHECKPOINT, PartitionLog::log_start_offset).await
+    }
+
+    /// Writes the high watermarks of every log.
+    pub async fn checkpoint_high_watermarks(&self) -> io::Result<()> {
+        let logs = self.logs.lock().await;
+        self.write_offsets(&logs, REPLICATION_OFFSET_CHECKPOINT, PartitionLog::high_watermark).await
+    }
+
+    async fn write_offsets(&self, logs: &HashMap<TopicPartition, PartitionLog>, file_name: &str, offset: impl Fn(&PartitionLog) -> i64) -> io::Result<()> {
+        let offsets = logs.iter()
+            .map(|(topic_partition, log)| (topic_partition.clone(), offset(log)))
+            .collect();
+        self.write_checkpoints(file_name, &offsets).await
+    }
+
+    /// A checkpoint file merged over the online log dirs.
+    pub async fn read_checkpoints(&self, file_name: &str) -> io::Result<HashMap<TopicPartition, i64>> {
+        let mut offsets = HashMap::new();
+        for log_dir in self.online_log_dirs().await {
+            offsets.extend(read_checkpoint(&checkpoint_path(&log_dir, file_name)).await?);
+        }
+        Ok(offsets)
+    }
+
+    /// Writes a checkpoint file in every online log dir, each with the
+    /// offsets of the partitions placed there. A dir that cannot be written
+    /// is taken offline.
+    pub async fn write_checkpoints(&self, file_name: &str, offsets: &HashMap<TopicPartition, i64>) -> io::Result<()> {
+        let mut by_dir: HashMap<PathBuf, HashMap<TopicPartition, i64>> = self.online_log_dirs().await.into_iter()
+            .map(|log_dir| (log_dir, HashMap::new()))
+            .collect();
+        {
+            let log_dirs = self.log_dirs.lock().await;
+            for (topic_partition, offset) in offsets.iter() {
+                let dir_offsets = log_dirs.dir_of(topic_partition).and_then(|dir| by_dir.get_mut(&dir.path));
+                if let Some(dir_offsets) = dir_offsets {
+                    dir_offsets.insert(topic_partition.clone(), *offset);
+                }
+            }
+        }
+        let mut result = Ok(());
+        for (log_dir, offsets) in by_dir {
+            if let Err(e) = write_checkpoint(&checkpoint_path(&log_dir, file_name), &offsets).await {
+                if self.log_dirs.lock().await.mark_offline(&log_dir) {
+                    println!("Marking log dir {} offline: {}", log_dir.display(), e);
+                }
+                result = Err(e);
+            }
+        }
+        result
+    }
+
+    async fn online_log_dirs(&self) -> Vec<PathBuf> {
+        self.log_dirs.lock().await.dirs().iter()
+            .filter(|dir| dir.online)
+            .map(|dir| dir.path.clone())
+            .collect()
+    }
+
+    /// The log dir of a partition. A new partition goes to the online dir
+    /// holding the fewest partitions, and the placement is recorded in the
+    /// metadata log.
+    async fn log_dir_for(&self, topic_name: &str, partition_idx: i32) -> io::Result<PathBuf> {
+        let topic_partition = (topic_name.to_string(), partition_idx);
+        let log_dir = {
+            let mut log_dirs = self.log_dirs.lock().await;
+            if let Some(dir) = log_dirs.dir_of(&topic_partition) {
+                return if dir.online { Ok(dir.path.clone()) } else { Err(offline_error(&dir.path)) };
+            }
+            let log_dir = log_dirs.least_loaded()
+                .map(|dir| dir.path.clone())
+                .ok_or_else(|| io::Error::other("no online log dir"))?;
+            log_dirs.assign(topic_partition, &log_dir);
+            log_dir
+        };
+        self.record_directories().await;
+        Ok(log_dir)
+    }
+
+    /// Writes a PartitionChangeRecord for each placed partition whose log dir
+    /// differs from the `directories` in the metadata log.
+    async fn record_directories(&self) {
+        let record_sets = self.cluster_metadata().await;
+        let topics = record_set_to_topic(&record_sets);
+        let recorded = directory_assignments(&record_sets);
+        let mut changes: Vec<(TopicPartition, Uuid, Uuid)> = self.log_dirs.lock().await.assignments()
+            .filter(|(topic_partition, dir)| recorded.get(*topic_partition) != Some(&dir.id))
+            .filter_map(|(topic_partition, dir)| {
+                let (topic_id, _) = topics.get(&topic_partition.0)?;
+                Some((topic_partition.clone(), *topic_id, dir.id))
+            })
+            .collect();
+        if changes.is_empty() {
+            return;
+        }
+        changes.sort();
+        let values = changes.iter()
+            .map(|((_, partition_idx), topic_id, dir_id)| encode_partition_change_record(*partition_idx, *topic_id, &[*dir_id]))
+            .collect();
+        if let Err(e) = self.append_metadata(values).await {
+            println!("Failed to record the log dirs of {} partitions: {}", changes.len(), e);
+        }
+    }
+
+    /// Takes a log dir offline and closes its logs. Its partitions fail with
+    /// KAFKA_STORAGE_ERROR from then on, while the other dirs keep serving.
+    async fn take_offline(&self, log_dir: &Path, e: &io::Error) {
+        if self.log_dirs.lock().await.mark_offline(log_dir) {
+            println!("Marking log dir {} offline: {}", log_dir.display(), e);
+            self.logs.lock().await.retain(|_, log| !log.dir().starts_with(log_dir));
+        }
+    }
+
+    // an error reading or writing a log dir, rather than bad input
+    async fn check_log_dir<T>(&self, log_dir: &Path, result: io::Result<T>) -> io::Result<T> {
+        if let Err(e) = &result {
+            if !matches!(e.kind(), io::ErrorKind::InvalidData | io::ErrorKind::InvalidInput) {
+                self.take_offline(log_dir, e).await;
+            }
+        }
+        result
+    }
+
+    /// Runs `f` on a partition's log with the logs locked, opening the log
+    /// on first use and applying the topic's current config. An I/O error
+    /// takes the partition's log dir offline.
+    async fn with_log<'s, T, F, Fut>(&'s self, topic_name: &str, partition_idx: i32, f: F) -> io::Result<T>
+    where
+        F: FnOnce(MappedMutexGuard<'s, PartitionLog>) -> Fut,
+        Fut: Future<Output = io::Result<T>>,
+    {
+        let config = self.log_config(topic_name).await;
+        let log_dir = self.log_dir_for(topic_name, partition_idx).await?;
+        let result = async {
+            let mut logs = self.logs.lock().await;
+            let topic_partition = (topic_name.to_string(), partition_idx);
+            match logs.get_mut(&topic_partition) {
+                Some(log) => log.set_config(config),
+                None => {
+                    let log = PartitionLog::open(partition_dir(&log_dir, topic_name, partition_idx), config, self.segment_cache.clone()).await?;
+                    logs.insert(topic_partition.clone(), log);
+                }
+            }
+            f(MutexGuard::map(logs, |logs| logs.get_mut(&topic_partition).unwrap())).await
+        }.await;
+        self.check_log_dir(&log_dir, result).await
+    }
+
+    /// Moves a partition to another log dir (AlterReplicaLogDirs). The log
+    /// is copied into a future replica while it keeps serving, and copied
+    /// again so that the final copy, made with the logs locked, only has the
+    /// latest changes to catch up on. The open log is then pointed at the
+    /// copy.
+    pub async fn move_partition(&self, topic_name: &str, partition_idx: i32, log_dir: &Path) -> io::Result<()> {
+        let topic_partition = (topic_name.to_string(), partition_idx);
+        let source = {
+            let mut log_dirs = self.log_dirs.lock().await;
+            if !log_dirs.is_online(log_dir) {
+                return Err(offline_error(log_dir));
+            }
+            match log_dirs.dir_of(&topic_partition) {
+                Some(dir) if dir.path == log_dir => return Ok(()),
+                Some(dir) if !dir.online => return Err(offline_error(&dir.path)),
+                Some(dir) => dir.path.clone(),
+                None => {
+                    // nothing written yet, it is simply placed there
+                    log_dirs.assign(topic_partition, log_dir);
+                    drop(log_dirs);
+                    self.record_directories().await;
+                    return Ok(());
+                }
+            }
+        };
+        if !self.log_dirs.lock().await.start_move(&topic_partition) {
+            return Ok(());
+        }
+        let source_dir = partition_dir(&source, topic_name, partition_idx);
+        let future_dir = future_partition_dir(log_dir, topic_name, partition_idx, random_uuid());
+        let started = SystemTime::now();
+        let result: io::Result<()> = async {
+            copy_partition_dir(&source_dir, &future_dir, None).await?;
+            let caught_up = SystemTime::now();
+            copy_partition_dir(&source_dir, &future_dir, Some(started)).await?;
+            let mut logs = self.logs.lock().await;
+            if let Some(log) = logs.get_mut(&topic_partition) {
+                log.flush().await?;
+            }
+            copy_partition_dir(&source_dir, &future_dir, Some(caught_up)).await?;
+            let dir = partition_dir(log_dir, topic_name, partition_idx);
+            fs::rename(&future_dir, &dir).await?;
+            if let Some(log) = logs.get_mut(&topic_partition) {
+                if let Err(e) = log.relocate(dir.clone()) {
+                    fs::remove_dir_all(&dir).await?;
+                    return Err(e);
+                }
+            }
+            self.log_dirs.lock().await.assign(topic_partition.clone(), log_dir);
+            Ok(())
+        }.await;
+        self.log_dirs.lock().await.finish_move(&topic_partition);
+        if let Err(e) = result {
+            // the current replica is untouched
+            if let Err(e) = fs::remove_dir_all(&future_dir).await {
+                if e.kind() != io::ErrorKind::NotFound {
+                    println!("Failed to remove {}: {}", future_dir.display(), e);
+                }
+            }
+            return Err(e);
+        }
+        self.record_directories().await;
+        fs::remove_dir_all(&source_dir).await
+    }
+
+    /// The record batches of the cluster metadata log.
+    pub async fn cluster_metadata(&self) -> Arc<Vec<RecordSet>> {
+        self.metadata.lock().await.record_sets(&self.segment_cache)
+    }
+
+    /// Appends metadata record values to the cluster metadata log. In a
+    /// quorum only the leader writes, returning once the records commit.
+    pub async fn append_metadata(&self, values: Vec<Bytes>) -> io::Result<()> {
+        match &self.raft {
+            Some(raft) => raft.append(self, values).await,
+            None => self.metadata.lock().await.append(&self.segment_cache, values, now_ms()).map(|_| ()),
+        }
+    }
+
+    /// Snapshots the committed metadata once
+    /// `metadata.log.max.record.bytes.between.snapshots` have been committed
+    /// since the last snapshot, deleting the log it covers.
+    pub async fn snapshot_metadata(&self) -> io::Result<()> {
+        let max_bytes = self.config.get_or(MAX_BYTES_BETWEEN_SNAPSHOTS_CONFIG, DEFAULT_MAX_BYTES_BETWEEN_SNAPSHOTS);
+        let mut metadata = self.metadata.lock().await;
+        if metadata.bytes_since_snapshot(&self.segment_cache)? < max_bytes {
+            return Ok(());
+        }
+        if let Some((end_offset, epoch)) = metadata.write_snapshot(&self.segment_cache, now_ms())? {
+            println!("Wrote metadata snapshot {}", snapshot_file_name(end_offset, epoch));
+        }
+        Ok(())
+    }
+
+    /// Reloads the topic and dynamic broker configs from the metadata log and
+    /// applies them to the open logs.
+    pub async fn refresh_configs(&self) {
+        let record_sets = self.cluster_metadata().await;
+        *self.topic_configs.lock().await = topic_configs(&record_sets);
+        *self.broker_configs.lock().await = broker_configs(&record_sets);
+        let topic_names: Vec<String> = self.logs.lock().await.keys()
+            .map(|(topic_name, _)| topic_name.clone())
+            .collect();
+        for topic_name in topic_names {
+            let config = self.log_config(&topic_name).await;
+            for ((name, _), log) in self.logs.lock().await.iter_mut() {
+                if *name == topic_name {
+                    log.set_config(config.clone());
+                }
+            }
+        }
+    }
+
+    /// The static settings with the cluster-wide and then this broker's
+    /// dynamic settings on top.
+    pub async fn dynamic_config(&self) -> BrokerConfig {
+        let broker_configs = self.broker_configs.lock().await;
+        let mut config = self.config.clone();
+        for resource_name in [String::new(), node_id(&self.config).to_string()] {
+            if let Some(overrides) = broker_configs.get(&resource_name) {
+                config = config.with_overrides(overrides);
+            }
+        }
+        config
+    }
+
+    pub async fn log_config(&self, topic_name: &str) -> LogConfig {
+        let defaults = LogConfig::from_broker(&self.dynamic_config().await);
+        match self.topic_configs.lock().await.get(topic_name) {
+            Some(overrides) => defaults.with_overrides(overrides),
+            None => defaults,
+        }
+    }
+
+    /// Appends raw record batches to a partition, opening its log on first
+    /// use. They commit once the in-sync replicas have them, right away for
+    /// a partition without followers. Returns the base offset of the records
+    /// and the log start offset after the append.
+    pub async fn append(&self, topic_name: &str, partition_idx: i32, records: &[u8]) -> io::Result<(i64, i64)> {
+        let topic_partition = (topic_name.to_string(), partition_idx);
+        self.with_log(topic_name, partition_idx, |mut log| async move {
+            let leader_epoch = self.replica_manager.lock().await
+                .leader_epoch(&topic_partition)
+                .unwrap_or_else(|| log.leader_epochs().latest_epoch().unwrap_or(0));
+            let base_offset = log.append_as_leader(records, leader_epoch).await?;
+            let high_watermark = self.replica_manager.lock().await
+                .high_watermark(&topic_partition, log.log_end_offset())
+                .unwrap_or(log.log_end_offset());
+            log.advance_high_watermark(high_watermark);
+            // parked fetches wake up either way, followers read past the high watermark
+            self.fetch_purgatory.update(&topic_partition, log.high_watermark());
+            Ok((base_offset, log.log_start_offset()))
+        }).await
+    }
+
+    /// Appends record batches fetched from a partition's leader, then takes
+    /// on the leader's high watermark and log start offset as far as this
+    /// log has the records.
+    pub async fn append_as_follower(&self, topic_name: &str, partition_idx: i32, records: &[u8], high_watermark: i64, log_start_offset: i64) -> io::Result<()> {
+        self.with_log(topic_name, partition_idx, |mut log| async move {
+            log.append_as_follower(records).await?;
+            if log.advance_high_watermark(high_watermark) {
+                self.fetch_purgatory.update(&(topic_name.to_string(), partition_idx), log.high_watermark());
+            }
+            if log_start_offset <= log.high_watermark() {
+                log.advance_log_start_offset(log_start_offset).await?;
+            }
+            Ok(())
+        }).await
+    }
+
+    /// Removes the records of a partition from `offset` on.
+    pub async fn truncate_log(&self, topic_name: &str, partition_idx: i32, offset: i64) -> io::Result<()> {
+        self.with_log(topic_name, partition_idx, |mut log| async move {
+            log.truncate_to(offset).await
+        }).await
+    }
+
+    /// Empties a partition's log and starts it over at `offset`.
+    pub async fn truncate_fully_and_start_at(&self, topic_name: &str, partition_idx: i32, offset: i64) -> io::Result<()> {
+        self.with_log(topic_name, partition_idx, |mut log| async move {
+            log.truncate_fully_and_start_at(offset).await
+        }).await
+    }
+
+    /// Truncates a follower's log where it diverges from the leader's, given
+    /// the end offset of the leader's epoch from a Fetch diverging epoch.
+    /// Returns the offset truncated to.
+    pub async fn truncate_diverged(&self, topic_name: &str, partition_idx: i32, leader_epoch: i32, leader_end_offset: i64) -> io::Result<i64> {
+        self.with_log(topic_name, partition_idx, |mut log| async move {
+            let offset = log.leader_epochs().truncation_offset(leader_epoch, leader_end_offset, log.log_end_offset(), log.high_watermark());
+            log.truncate_to(offset).await?;
+            Ok(offset)
+        }).await
+    }
+
+    /// Starts a leader epoch of a partition at its log end offset.
+    pub async fn assign_leader_epoch(&self, topic_name: &str, partition_idx: i32, leader_epoch: i32) -> io::Result<()> {
+        self.with_log(topic_name, partition_idx, |mut log| async move {
+            log.assign_leader_epoch(leader_epoch).await
+        }).await
+    }
+
+    /// The latest leader epoch in a partition's log, None before any.
+    pub async fn latest_epoch(&self, topic_name: &str, partition_idx: i32) -> io::Result<Option<i32>> {
+        self.with_log(topic_name, partition_idx, |log| async move {
+            Ok(log.leader_epochs().latest_epoch())
+        }).await
+    }
+
+    /// The largest epoch up to `epoch` in a partition's log and the offset
+    /// where it ends.
+    pub async fn end_offset_for_epoch(&self, topic_name: &str, partition_idx: i32, epoch: i32) -> io::Result<(i32, i64)> {
+        self.with_log(topic_name, partition_idx, |log| async move {
+            Ok(log.end_offset_for_epoch(epoch))
+        }).await
+    }
+
+    /// The (log start offset, log end offset, high watermark) of a partition.
+    pub async fn log_offsets(&self, topic_name: &str, partition_idx: i32) -> io::Result<(i64, i64, i64)> {
+        self.with_log(topic_name, partition_idx, |log| async move {
+            Ok((log.log_start_offset(), log.log_end_offset(), log.high_watermark()))
+        }).await
+    }
+
+    /// Recomputes the high watermark of a partition this broker leads, as
+    /// after its ISR changed.
+    pub async fn update_high_watermark(&self, topic_partition: &TopicPartition) {
+        let mut logs = self.logs.lock().await;
+        let Some(log) = logs.get_mut(topic_partition) else { return };
+        let high_watermark = self.replica_manager.lock().await.high_watermark(topic_partition, log.log_end_offset());
+        if high_watermark.is_some_and(|high_watermark| log.advance_high_watermark(high_watermark)) {
+            self.fetch_purgatory.update(topic_partition, log.high_watermark());
+        }
+    }
+
+    /// Waits until the high watermark of a partition reaches `offset`, as a
+    /// Produce with acks=all does for its records to be replicated. Returns
+    /// false once `timeout_ms` has passed.
+    pub async fn await_high_watermark(&self, topic_name: &str, partition_idx: i32, offset: i64, timeout_ms: i32) -> bool {
+        let topic_partition = (topic_name.to_string(), partition_idx);
+        let deadline = Instant::now() + Duration::from_millis(timeout_ms.max(0) as u64);
+        loop {
+            // subscribed before the check, so an update in between is not missed
+            let mut watermark = self.fetch_purgatory.subscribe(&topic_partition);
+            let high_watermark = self.logs.lock().await.get(&topic_partition).map(|log| log.high_watermark());
+            if high_watermark.is_some_and(|high_watermark| high_watermark >= offset) {
+                return true;
+            }
+            if timeout_at(deadline, watermark.changed()).await.is_err() {
+                return false;
+            }
+        }
+    }
+
+    /// Reads a partition from `fetch_offset`, from remote storage when the
+    /// offset is no longer on local disk.
+    pub async fn read(&self, topic_name: &str, partition_idx: i32, fetch_offset: i64, max_bytes: i32, read_committed: bool) -> io::Result<FetchedData> {
+        let fetched = self.with_log(topic_name, partition_idx, |log| async move {
+            log.read(fetch_offset, max_bytes, read_committed).await
+        }).await?;
+        self.read_remote(topic_name, partition_idx, fetched, fetch_offset, max_bytes, read_committed).await
+    }
+
+    /// Reads a partition up to its log end offset for the follower
+    /// `replica_id`, recording that it has everything before `fetch_offset`
+    /// and moving the high watermark with it.
+    pub async fn read_for_follower(&self, topic_name: &str, partition_idx: i32, replica_id: i32, fetch_offset: i64, max_bytes: i32) -> io::Result<FetchedData> {
+        let topic_partition = (topic_name.to_string(), partition_idx);
+        let fetched = self.with_log(topic_name, partition_idx, |mut log| async move {
+            let mut fetched = log.read_for_follower(fetch_offset, max_bytes).await?;
+            let mut replica_manager = self.replica_manager.lock().await;
+            replica_manager.update_follower(&topic_partition, replica_id, fetch_offset, log.log_end_offset(), now_ms());
+            let high_watermark = replica_manager.high_watermark(&topic_partition, log.log_end_offset());
+            if high_watermark.is_some_and(|high_watermark| log.advance_high_watermark(high_watermark)) {
+                self.fetch_purgatory.update(&topic_partition, log.high_watermark());
+            }
+            fetched.high_watermark = log.high_watermark();
+            fetched.last_stable_offset = log.last_stable_offset();
+            Ok(fetched)
+        }).await?;
+        self.read_remote(topic_name, partition_idx, fetched, fetch_offset, max_bytes, false).await
+    }
+
+    // fills in the records of a read that are only in remote storage
+    async fn read_remote(&self, topic_name: &str, partition_idx: i32, mut fetched: FetchedData, fetch_offset: i64, max_bytes: i32, read_committed: bool) -> io::Result<FetchedData> {
+        if let Some(metadata) = fetched.remote_segment.clone() {
+            let remote_storage = self.remote_storage.clone().ok_or_else(|| io::Error::other("remote storage is disabled"))?;
+            let topic_partition = (topic_name.to_string(), partition_idx);
+            let (records, aborted_transactions) = tokio::task::spawn_blocking(move || {
+                fetch_remote(&*remote_storage, &topic_partition, &metadata, fetch_offset, max_bytes, read_committed)
+            }).await.map_err(io::Error::other)??;
+            fetched.records.memory = records;
+            fetched.aborted_transactions = aborted_transactions;
+        }
+        Ok(fetched)
+    }
+
+    pub async fn read_all(&self, topic_name: &str, partition_idx: i32) -> io::Result<BytesMut> {
+        self.with_log(topic_name, partition_idx, |log| async move {
+            log.read_all().await
+        }).await
+    }
+
+    /// Looks up an offset for ListOffsets, as (offset, timestamp). A
+    /// timestamp only in remote storage is searched for there.
+    pub async fn list_offset(&self, topic_name: &str, partition_idx: i32, timestamp: i64, read_committed: bool) -> io::Result<Option<(i64, i64)>> {
+        // the offset found locally, or the remote segment to search with the
+        // timestamp and the log start offset
+        let located = self.with_log(topic_name, partition_idx, |log| async move {
+            let timestamp = match timestamp {
+                EARLIEST_TIMESTAMP => return Ok((Some((log.log_start_offset(), -1)), None)),
+                EARLIEST_LOCAL_TIMESTAMP => return Ok((Some((log.local_log_start_offset(), -1)), None)),
+                LATEST_TIMESTAMP if read_committed => return Ok((Some((log.last_stable_offset(), -1)), None)),
+                LATEST_TIMESTAMP => return Ok((Some((log.high_watermark(), -1)), None)),
+                MAX_TIMESTAMP if log.max_timestamp() < 0 => return Ok((None, None)),
+                MAX_TIMESTAMP => log.max_timestamp(),
+                _ => timestamp,
+            };
+            if let Some(metadata) = log.remote_segment_for_timestamp(timestamp) {
+                return Ok((None, Some((metadata.clone(), timestamp, log.log_start_offset()))));
+            }
+            Ok((log.offset_for_timestamp(timestamp).await?, None))
+        }).await?;
+        match located {
+            (_, Some((metadata, timestamp, log_start_offset))) => {
+                let remote_storage = self.remote_storage.clone().ok_or_else(|| io::Error::other("remote storage is disabled"))?;
+                let topic_partition = (topic_name.to_string(), partition_idx);
+                tokio::task::spawn_blocking(move || {
+                    remote_offset_for_timestamp(&*remote_storage, &topic_partition, &metadata, timestamp, log_start_offset)
+                }).await.map_err(io::Error::other)?
+            }
+            (found, None) => Ok(found),
+        }
+    }
+
+    /// Applies retention to a partition with its current config, returning
+    /// the number of segments deleted.
+    pub async fn delete_old_segments(&self, topic_name: &str, partition_idx: i32, now: i64) -> io::Result<usize> {
+        self.with_log(topic_name, partition_idx, |mut log| async move {
+            log.delete_old_segments(now).await
+        }).await
+    }
+
+    /// Copies the closed segments of a partition below the last stable
+    /// offset to remote storage, returning the number copied.
+    pub async fn copy_to_remote(&self, topic_name: &str, partition_idx: i32) -> io::Result<usize> {
+        let Some(remote_storage) = self.remote_storage.clone() else { return Ok(0) };
+        let topic_partition = (topic_name.to_string(), partition_idx);
+        let segments = self.with_log(topic_name, partition_idx, |log| async move {
+            Ok(log.segments_to_copy())
+        }).await?;
+        let log_dir = self.log_dir_for(topic_name, partition_idx).await?;
+        let mut copied = 0;
+        // closed segments do not change, so they are copied without the logs locked
+        for (metadata, data) in segments {
+            let (remote, partition, segment) = (remote_storage.clone(), topic_partition.clone(), metadata.clone());
+            tokio::task::spawn_blocking(move || remote.copy_log_segment(&partition, &segment, &data))
+                .await
+                .map_err(io::Error::other)??;
+            let result: io::Result<bool> = async {
+                match self.logs.lock().await.get_mut(&topic_partition) {
+                    Some(log) => log.add_remote_segment(metadata.clone()).await,
+                    None => Ok(false),
+                }
+            }.await;
+            if !self.check_log_dir(&log_dir, result).await? {
+                let (remote, partition) = (remote_storage.clone(), topic_partition.clone());
+                tokio::task::spawn_blocking(move || remote.delete_log_segment(&partition, &metadata))
+                    .await
+                    .map_err(io::Error::other)??;
+                break;
+            }
+            copied += 1;
+        }
+        Ok(copied)
+    }
+
+    /// Applies retention to the remote segments of a partition, deleting
+    /// them from remote storage, and returns the number deleted.
+    pub async fn expire_remote_segments(&self, topic_name: &str, partition_idx: i32, now: i64) -> io::Result<usize> {
+        let Some(remote_storage) = self.remote_storage.clone() else { return Ok(0) };
+        let expired = self.with_log(topic_name, partition_idx, |mut log| async move {
+            log.expire_remote_segments(now).await
+        }).await?;
+        let count = expired.len();
+        let topic_partition = (topic_name.to_string(), partition_idx);
+        // no longer read once they are out of the metadata
+        tokio::task::spawn_blocking(move || {
+            expired.iter().try_for_each(|metadata| remote_storage.delete_log_segment(&topic_partition, metadata))
+        }).await.map_err(io::Error::other)??;
+        Ok(count)
+    }
+
+    /// Compacts a partition if its topic has cleanup.policy=compact, see
+    /// `PartitionLog::start_clean`. The cleaned segments are written with the
+    /// logs unlocked, which are locked again only to swap them in.
+    pub async fn clean(&self, topic_name: &str, partition_idx: i32, first_dirty_offset: i64, now: i64) -> io::Result<Option<i64>> {
+        let pass = self.with_log(topic_name, partition_idx, |log| async move {
+            Ok(if log.config().compact { log.start_clean(first_dirty_offset, now) } else { None })
+        }).await?;
+        let Some(pass) = pass else { return Ok(None) };
+        let log_dir = self.log_dir_for(topic_name, partition_idx).await?;
+        let cleaned = self.check_log_dir(&log_dir, pass.run().await).await?;
+        self.with_log(topic_name, partition_idx, |mut log| async move {
+            log.finish_clean(cleaned).await
+        }).await
+    }
+
+    /// Deletes the records before `offset`, or before the high watermark for
+    /// -1, and persists the new log start offset. Returns the new low
+    /// watermark, or None when the offset is out of range.
+    pub async fn delete_records(&self, topic_name: &str, partition_idx: i32, offset: i64) -> io::Result<Option<i64>> {
+        let log_start_offset = self.with_log(topic_name, partition_idx, |mut log| async move {
+            let offset = if offset == HIGH_WATERMARK_OFFSET { log.high_watermark() } else { offset };
+            if offset < 0 || offset > log.high_watermark() {
+                return Ok(None);
+            }
+            log.advance_log_start_offset(offset).await?;
+            Ok(Some(log.log_start_offset()))
+        }).await?;
+        if log_start_offset.is_some() {
+            let logs = self.logs.lock().await;
+            self.write_offsets(&logs, LOG_START_OFFSET_CHECKPOINT, PartitionLog::log_start_offset).await?;
+        }
+        Ok(log_start_offset)
+    }
+}
+
+fn offline_error(log_dir: &Path) -> io::Error {
+    io::Error::other(format!("log dir {} is offline", log_dir.display()))
+}
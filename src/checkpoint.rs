@@ -0,0 +1,99 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use tokio::fs;
+
+use crate::log::TopicPartition;
+
+const CHECKPOINT_VERSION: i32 = 0;
+
+pub const CLEANER_OFFSET_CHECKPOINT: &str = "cleaner-offset-checkpoint";
+pub const LOG_START_OFFSET_CHECKPOINT: &str = "log-start-offset-checkpoint";
+pub const RECOVERY_POINT_OFFSET_CHECKPOINT: &str = "recovery-point-offset-checkpoint";
+pub const REPLICATION_OFFSET_CHECKPOINT: &str = "replication-offset-checkpoint";
+
+/// A checkpoint file of a log dir, covering the partitions in that dir.
+pub fn checkpoint_path(log_dir: &Path, file_name: &str) -> PathBuf {
+    log_dir.join(file_name)
+}
+
+/// Parses Kafka's offset checkpoint format: a version line, an entry count
+/// and one `topic partition offset` line per entry.
+pub fn parse_checkpoint(text: &str) -> io::Result<HashMap<TopicPartition, i64>> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+    let mut lines = text.lines();
+    let version: i32 = lines.next()
+        .and_then(|line| line.trim().parse().ok())
+        .ok_or_else(|| invalid("missing checkpoint version"))?;
+    if version != CHECKPOINT_VERSION {
+        return Err(invalid(&format!("unsupported checkpoint version {}", version)));
+    }
+    let count: usize = lines.next()
+        .and_then(|line| line.trim().parse().ok())
+        .ok_or_else(|| invalid("missing checkpoint entry count"))?;
+    let mut offsets = HashMap::new();
+    for line in lines.take(count) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [topic_name, partition_idx, offset] = fields[..] else {
+            return Err(invalid(&format!("malformed checkpoint entry {:?}", line)));
+        };
+        let partition_idx = partition_idx.parse().map_err(|_| invalid(line))?;
+        let offset = offset.parse().map_err(|_| invalid(line))?;
+        offsets.insert((topic_name.to_string(), partition_idx), offset);
+    }
+    if offsets.len() != count {
+        return Err(invalid("checkpoint entry count mismatch"));
+    }
+    Ok(offsets)
+}
+
+pub fn format_checkpoint(offsets: &HashMap<TopicPartition, i64>) -> String {
+    let mut entries: Vec<_> = offsets.iter().collect();
+    entries.sort();
+    let mut text = format!("{}\n{}\n", CHECKPOINT_VERSION, entries.len());
+    for ((topic_name, partition_idx), offset) in entries {
+        text.push_str(&format!("{} {} {}\n", topic_name, partition_idx, offset));
+    }
+    text
+}
+
+/// Reads a checkpoint file; a missing file is an empty checkpoint.
+pub async fn read_checkpoint(path: &Path) -> io::Result<HashMap<TopicPartition, i64>> {
+    match fs::read_to_string(path).await {
+        Ok(text) => parse_checkpoint(&text),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(HashMap::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Replaces a checkpoint file through a temporary file, so a crash leaves
+/// either the old or the new checkpoint.
+pub async fn write_checkpoint(path: &Path, offsets: &HashMap<TopicPartition, i64>) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).await?;
+    }
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, format_checkpoint(offsets)).await?;
+    fs::rename(&tmp, path).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{format_checkpoint, parse_checkpoint};
+
+    #[test]
+    fn test_round_trip() {
+        let offsets = HashMap::from([
+            (("foo".to_string(), 1), 42),
+            (("foo".to_string(), 0), 7),
+        ]);
+        let text = format_checkpoint(&offsets);
+        assert_eq!("0\n2\nfoo 0 7\nfoo 1 42\n", text);
+        assert_eq!(offsets, parse_checkpoint(&text).unwrap());
+        assert!(parse_checkpoint("0\n2\nfoo 0 7\n").is_err());
+        assert!(parse_checkpoint("1\n0\n").is_err());
+    }
+}
@@ -0,0 +1,42 @@
+use std::io;
+use std::time::Duration;
+
+use bytes::{Bytes, BytesMut};
+use kafka_protocol::messages::{ApiKey, RequestHeader, ResponseHeader};
+use kafka_protocol::protocol::{Decodable, Encodable, HeaderVersion, StrBytes};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+fn invalid_data(e: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+/// Sends one request to another node over a fresh connection and decodes
+/// its response, failing with TimedOut after `timeout_ms`.
+pub async fn send_request<Req, Resp>(address: &str, client_id: &str, api_key: ApiKey, api_version: i16, req: &Req, timeout_ms: i64) -> io::Result<Resp>
+where
+    Req: Encodable + HeaderVersion,
+    Resp: Decodable + HeaderVersion,
+{
+    let header = RequestHeader::default()
+        .with_request_api_key(api_key as i16)
+        .with_request_api_version(api_version)
+        .with_correlation_id(0)
+        .with_client_id(Some(StrBytes::from_string(client_id.to_string())));
+    let mut buf = BytesMut::new();
+    header.encode(&mut buf, Req::header_version(api_version)).map_err(invalid_data)?;
+    req.encode(&mut buf, api_version).map_err(invalid_data)?;
+    let exchange = async {
+        let mut socket = TcpStream::connect(address).await?;
+        socket.write_i32(buf.len() as i32).await?;
+        socket.write_all(&buf).await?;
+        let size = socket.read_i32().await?;
+        let mut response = vec![0; size.max(0) as usize];
+        socket.read_exact(&mut response).await?;
+        let mut response = Bytes::from(response);
+        ResponseHeader::decode(&mut response, Resp::header_version(api_version)).map_err(invalid_data)?;
+        Resp::decode(&mut response, api_version).map_err(invalid_data)
+    };
+    tokio::time::timeout(Duration::from_millis(timeout_ms.max(0) as u64), exchange).await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, format!("{:?} request to {} timed out", api_key, address)))?
+}
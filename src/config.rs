@@ -0,0 +1,68 @@
+use std::collections::HashMap;
+use std::io;
+use std::str::FromStr;
+
+use tokio::fs;
+
+/// Broker settings from the `server.properties` file passed on the command line.
+#[derive(Debug, Clone, Default)]
+pub struct BrokerConfig {
+    props: HashMap<String, String>,
+}
+
+impl BrokerConfig {
+    pub async fn load(path: &str) -> io::Result<BrokerConfig> {
+        let text = fs::read_to_string(path).await?;
+        Ok(BrokerConfig::parse(&text))
+    }
+
+    pub fn parse(text: &str) -> BrokerConfig {
+        let props = text.lines()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty() && !line.starts_with('#') && !line.starts_with('!'))
+            .filter_map(|line| line.split_once('='))
+            .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+            .collect();
+        BrokerConfig { props }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.props.get(key).map(|value| value.as_str())
+    }
+
+    /// The settings with dynamic overrides from the metadata log on top.
+    pub fn with_overrides(&self, overrides: &HashMap<String, String>) -> BrokerConfig {
+        let mut props = self.props.clone();
+        props.extend(overrides.iter().map(|(key, value)| (key.clone(), value.clone())));
+        BrokerConfig { props }
+    }
+
+    pub fn props(&self) -> &HashMap<String, String> {
+        &self.props
+    }
+
+    pub fn get_or<T: FromStr>(&self, key: &str, default: T) -> T {
+        self.get(key)
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(default)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BrokerConfig;
+
+    #[test]
+    fn test_parse() {
+        let config = BrokerConfig::parse("
+            # comment
+            node.id=1
+            log.dirs = /tmp/kraft-combined-logs
+            max.incremental.fetch.session.cache.slots=abc
+        ");
+        assert_eq!(Some("1"), config.get("node.id"));
+        assert_eq!(Some("/tmp/kraft-combined-logs"), config.get("log.dirs"));
+        assert_eq!(1000, config.get_or("max.incremental.fetch.session.cache.slots", 1000));
+        assert_eq!(None, config.get("process.roles"));
+    }
+}
@@ -0,0 +1,45 @@
+use std::collections::HashMap;
+
+use kafka_protocol::error::ResponseError;
+use kafka_protocol::messages::delete_records_response::{DeleteRecordsPartitionResult, DeleteRecordsTopicResult};
+use kafka_protocol::messages::{DeleteRecordsRequest, DeleteRecordsResponse};
+use uuid::Uuid;
+
+use crate::broker::Broker;
+
+// DeleteRecordsPartition.offset meaning "up to the high watermark"
+pub const HIGH_WATERMARK_OFFSET: i64 = -1;
+
+pub async fn handle_delete_records(broker: &Broker, req: DeleteRecordsRequest, topics: &HashMap<String, (Uuid, Vec<i32>)>) -> DeleteRecordsResponse {
+    let mut responses = Vec::new();
+    for topic in req.topics.iter() {
+        let topic_name = topic.name.0.as_str();
+        let mut partitions = Vec::new();
+        for partition in topic.partitions.iter() {
+            let known = topics.get(topic_name)
+                .map(|(_, partition_ids)| partition_ids.contains(&partition.partition_index))
+                .unwrap_or(false);
+            let resp = DeleteRecordsPartitionResult::default()
+                .with_partition_index(partition.partition_index)
+                .with_low_watermark(-1);
+            let resp = if !known {
+                resp.with_error_code(ResponseError::UnknownTopicOrPartition.code())
+            } else {
+                match broker.delete_records(topic_name, partition.partition_index, partition.offset).await {
+                    Ok(Some(low_watermark)) => resp.with_low_watermark(low_watermark),
+                    Ok(None) => resp.with_error_code(ResponseError::OffsetOutOfRange.code()),
+                    Err(e) => {
+                        println!("Failed to delete records of {}-{}: {}", topic_name, partition.partition_index, e);
+                        resp.with_error_code(ResponseError::KafkaStorageError.code())
+                    }
+                }
+            };
+            partitions.push(resp);
+        }
+        responses.push(DeleteRecordsTopicResult::default()
+            .with_name(topic.name.clone())
+            .with_partitions(partitions));
+    }
+    DeleteRecordsResponse::default()
+        .with_topics(responses)
+}
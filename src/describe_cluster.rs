@@ -0,0 +1,235 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use kafka_protocol::messages::describe_cluster_response::DescribeClusterBroker;
+use kafka_protocol::messages::{BrokerId, DescribeClusterRequest, DescribeClusterResponse};
+use kafka_protocol::protocol::StrBytes;
+use kafka_protocol::records::RecordSet;
+use tokio::fs;
+
+use crate::acl::{Authorizer, ANONYMOUS_PRINCIPAL, CLUSTER_OPERATIONS, RESOURCE_TYPE_CLUSTER};
+use crate::broker::{HOST, PORT};
+use crate::config::BrokerConfig;
+use crate::describe_configs::CLUSTER_RESOURCE_NAME;
+use crate::log::log_dirs;
+use crate::raft::{parse_voters, QUORUM_VOTERS_CONFIG};
+use crate::record::{extract_record_value, BrokerEndpoint, RecordValue, RegisterBrokerRecord};
+use crate::replica::node_id;
+
+pub const META_PROPERTIES_FILE: &str = "meta.properties";
+
+// DescribeClusterRequest.endpoint_type
+const ENDPOINT_TYPE_BROKERS: i8 = 1;
+const ENDPOINT_TYPE_CONTROLLERS: i8 = 2;
+
+// cluster_authorized_operations when not requested
+const AUTHORIZED_OPERATIONS_OMITTED: i32 = i32::MIN;
+
+/// The brokers and controllers registered in the metadata log, by id.
+#[derive(Debug, Clone, Default)]
+pub struct ClusterImage {
+    pub brokers: BTreeMap<i32, RegisterBrokerRecord>,
+    pub fenced: BTreeSet<i32>,
+    pub controllers: BTreeMap<i32, RegisterBrokerRecord>,
+}
+
+impl ClusterImage {
+    pub fn from_record_sets(record_sets: &[RecordSet]) -> ClusterImage {
+        let mut image = ClusterImage::default();
+        for record in record_sets.iter().flat_map(|record_set| record_set.records.iter()) {
+            if record.value.is_none() {
+                continue;
+            }
+            match extract_record_value(record) {
+                RecordValue::RegisterBrokerRecord(broker) => {
+                    // brokers register fenced and are unfenced once caught up
+                    image.fenced.insert(broker.broker_id);
+                    image.brokers.insert(broker.broker_id, broker);
+                }
+                RecordValue::UnregisterBrokerRecord(broker_id) => {
+                    image.brokers.remove(&broker_id);
+                    image.fenced.remove(&broker_id);
+                }
+                RecordValue::FenceBrokerRecord(broker_id) => {
+                    image.fenced.insert(broker_id);
+                }
+                RecordValue::UnfenceBrokerRecord(broker_id) => {
+                    image.fenced.remove(&broker_id);
+                }
+                RecordValue::RegisterControllerRecord(controller) => {
+                    image.controllers.insert(controller.broker_id, controller);
+                }
+                _ => {}
+            }
+        }
+        image
+    }
+}
+
+/// The cluster id from `meta.properties` in the first log dir that has one.
+pub async fn cluster_id(config: &BrokerConfig) -> Option<String> {
+    for log_dir in log_dirs(config) {
+        let Ok(text) = fs::read_to_string(log_dir.join(META_PROPERTIES_FILE)).await else { continue };
+        if let Some(id) = BrokerConfig::parse(&text).get("cluster.id") {
+            return Some(id.to_string());
+        }
+    }
+    None
+}
+
+/// (name, host, port) of each listener in a `listeners` style list.
+pub fn parse_listeners(listeners: &str) -> Vec<(String, String, i32)> {
+    listeners.split(',')
+        .filter_map(|listener| {
+            let (name, address) = listener.trim().split_once("://")?;
+            let (host, port) = address.rsplit_once(':')?;
+            let host = if host.is_empty() { HOST } else { host };
+            Some((name.to_string(), host.to_string(), port.parse().ok()?))
+        })
+        .collect()
+}
+
+/// The listener clients reach this broker on: the first one that is not a
+/// controller listener.
+pub fn client_listener(config: &BrokerConfig) -> String {
+    let controller_listeners: Vec<&str> = config.get("controller.listener.names")
+        .map(|names| names.split(',').map(|name| name.trim()).collect())
+        .unwrap_or_default();
+    config.get("listeners")
+        .map(parse_listeners)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(name, _, _)| name)
+        .find(|name| !controller_listeners.contains(&name.as_str()))
+        .unwrap_or_else(|| "PLAINTEXT".to_string())
+}
+
+/// The host and port clients reach this broker on, as advertised for the
+/// client listener.
+pub fn advertised_endpoint(config: &BrokerConfig) -> (String, i32) {
+    let listener = client_listener(config);
+    config.get("advertised.listeners")
+        .or_else(|| config.get("listeners"))
+        .map(parse_listeners)
+        .unwrap_or_default()
+        .into_iter()
+        .find(|(name, _, _)| *name == listener)
+        .map(|(_, host, port)| (host, port))
+        .unwrap_or((HOST.to_string(), PORT))
+}
+
+fn describe_broker(registration: &RegisterBrokerRecord, listener: Option<&str>) -> Option<DescribeClusterBroker> {
+    let endpoint: &BrokerEndpoint = listener
+        .and_then(|listener| registration.endpoints.iter().find(|endpoint| endpoint.name == listener))
+        .or_else(|| registration.endpoints.first())?;
+    Some(DescribeClusterBroker::default()
+        .with_broker_id(BrokerId(registration.broker_id))
+        .with_host(StrBytes::from_string(endpoint.host.clone()))
+        .with_port(endpoint.port as i32)
+        .with_rack(registration.rack.clone().map(StrBytes::from_string)))
+}
+
+/// The controllers from `controller.quorum.voters` ("id@host:port,..."), for
+/// a cluster whose controllers have not registered.
+fn configured_controllers(config: &BrokerConfig) -> Vec<DescribeClusterBroker> {
+    parse_voters(config.get(QUORUM_VOTERS_CONFIG).unwrap_or_default())
+        .into_iter()
+        .map(|(id, (host, port))| DescribeClusterBroker::default()
+            .with_broker_id(BrokerId(id))
+            .with_host(StrBytes::from_string(host))
+            .with_port(port))
+        .collect()
+}
+
+/// Lists the unfenced brokers, or the controllers, from the registrations in
+/// the metadata log. A single node without registrations describes itself
+/// from its configuration.
+pub fn handle_describe_cluster(req: DescribeClusterRequest, config: &BrokerConfig, image: &ClusterImage, cluster_id: Option<String>, authorizer: &Authorizer, client_host: &str) -> DescribeClusterResponse {
+    let listener = client_listener(config);
+    let brokers: Vec<DescribeClusterBroker> = if req.endpoint_type == ENDPOINT_TYPE_CONTROLLERS {
+        let controllers: Vec<_> = image.controllers.values()
+            .filter_map(|controller| describe_broker(controller, None))
+            .collect();
+        if controllers.is_empty() { configured_controllers(config) } else { controllers }
+    } else if image.brokers.is_empty() {
+        let (host, port) = advertised_endpoint(config);
+        vec![DescribeClusterBroker::default()
+            .with_broker_id(BrokerId(node_id(config)))
+            .with_host(StrBytes::from_string(host))
+            .with_port(port)
+            .with_rack(config.get("broker.rack").map(|rack| StrBytes::from_string(rack.to_string())))]
+    } else {
+        image.brokers.values()
+            .filter(|broker| !image.fenced.contains(&broker.broker_id))
+            .filter_map(|broker| describe_broker(broker, Some(&listener)))
+            .collect()
+    };
+    // clients of the brokers cannot reach the controllers, so any live
+    // broker stands in for the controller
+    let controller_id = brokers.first().map(|broker| broker.broker_id.0).unwrap_or(-1);
+    let cluster_authorized_operations = if req.include_cluster_authorized_operations {
+        authorizer.authorized_operations(ANONYMOUS_PRINCIPAL, client_host, RESOURCE_TYPE_CLUSTER, CLUSTER_RESOURCE_NAME, CLUSTER_OPERATIONS)
+    } else {
+        AUTHORIZED_OPERATIONS_OMITTED
+    };
+    DescribeClusterResponse::default()
+        .with_endpoint_type(if req.endpoint_type == ENDPOINT_TYPE_CONTROLLERS { ENDPOINT_TYPE_CONTROLLERS } else { ENDPOINT_TYPE_BROKERS })
+        .with_cluster_id(StrBytes::from_string(cluster_id.unwrap_or_default()))
+        .with_controller_id(BrokerId(controller_id))
+        .with_brokers(brokers)
+        .with_cluster_authorized_operations(cluster_authorized_operations)
+}
+
+#[cfg(test)]
+mod tests {
+    use kafka_protocol::messages::DescribeClusterRequest;
+
+    use crate::acl::{AclState, Authorizer};
+    use crate::config::BrokerConfig;
+    use crate::record::{BrokerEndpoint, RegisterBrokerRecord};
+
+    use super::{handle_describe_cluster, ClusterImage};
+
+    fn registration(broker_id: i32, port: u16) -> RegisterBrokerRecord {
+        RegisterBrokerRecord {
+            broker_id,
+            broker_epoch: 1,
+            endpoints: vec![
+                BrokerEndpoint { name: "CONTROLLER".to_string(), host: "localhost".to_string(), port: port + 1, security_protocol: 0 },
+                BrokerEndpoint { name: "PLAINTEXT".to_string(), host: "localhost".to_string(), port, security_protocol: 0 },
+            ],
+            rack: Some("rack-a".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_describe_cluster() {
+        let config = BrokerConfig::parse("
+            listeners=PLAINTEXT://:9092,CONTROLLER://:9093
+            controller.listener.names=CONTROLLER
+            controller.quorum.voters=1@localhost:9093
+        ");
+        let authorizer = Authorizer::new(&config, AclState::default());
+        let mut image = ClusterImage::default();
+        image.brokers.insert(1, registration(1, 9092));
+        image.brokers.insert(2, registration(2, 9094));
+        image.fenced.insert(2);
+
+        let resp = handle_describe_cluster(DescribeClusterRequest::default(), &config, &image, Some("cluster".to_string()), &authorizer, "127.0.0.1");
+        assert_eq!("cluster", resp.cluster_id.as_str());
+        assert_eq!(vec![(1, 9092)], resp.brokers.iter().map(|broker| (broker.broker_id.0, broker.port)).collect::<Vec<_>>());
+        assert_eq!(1, resp.controller_id.0);
+        assert_eq!(i32::MIN, resp.cluster_authorized_operations);
+
+        let req = DescribeClusterRequest::default()
+            .with_endpoint_type(2)
+            .with_include_cluster_authorized_operations(true);
+        let resp = handle_describe_cluster(req, &config, &image, None, &authorizer, "127.0.0.1");
+        assert_eq!(vec![(1, 9093)], resp.brokers.iter().map(|broker| (broker.broker_id.0, broker.port)).collect::<Vec<_>>());
+        assert_ne!(0, resp.cluster_authorized_operations);
+
+        // a single node describes itself from its listeners
+        let resp = handle_describe_cluster(DescribeClusterRequest::default(), &config, &ClusterImage::default(), None, &authorizer, "127.0.0.1");
+        assert_eq!(vec![(1, 9092)], resp.brokers.iter().map(|broker| (broker.broker_id.0, broker.port)).collect::<Vec<_>>());
+        assert_eq!("localhost", resp.brokers[0].host.as_str());
+    }
+}
@@ -0,0 +1,173 @@
+use std::collections::{BTreeSet, HashMap};
+
+use kafka_protocol::error::ResponseError;
+use kafka_protocol::messages::describe_configs_response::{DescribeConfigsResourceResult, DescribeConfigsResult, DescribeConfigsSynonym};
+use kafka_protocol::messages::{DescribeConfigsRequest, DescribeConfigsResponse};
+use kafka_protocol::protocol::StrBytes;
+use uuid::Uuid;
+
+use crate::acl::{Authorizer, ANONYMOUS_PRINCIPAL, OP_DESCRIBE_CONFIGS, RESOURCE_TYPE_CLUSTER, RESOURCE_TYPE_TOPIC};
+use crate::broker::Broker;
+use crate::config::BrokerConfig;
+use crate::log_config::{dynamic_topic_config, LogConfig, CONFIG_RESOURCE_BROKER, CONFIG_RESOURCE_TOPIC, TOPIC_CONFIG_SYNONYMS};
+use crate::replica::node_id;
+
+pub const CLUSTER_RESOURCE_NAME: &str = "kafka-cluster";
+
+// org.apache.kafka.common.requests.DescribeConfigsResponse.ConfigSource
+const SOURCE_TOPIC_CONFIG: i8 = 1;
+const SOURCE_DYNAMIC_BROKER_CONFIG: i8 = 2;
+const SOURCE_DYNAMIC_DEFAULT_BROKER_CONFIG: i8 = 3;
+const SOURCE_STATIC_BROKER_CONFIG: i8 = 4;
+const SOURCE_DEFAULT_CONFIG: i8 = 5;
+
+/// The settings a config value can come from, most specific first.
+struct ConfigLayers<'a> {
+    topic: Option<&'a HashMap<String, String>>,
+    dynamic_broker: Option<&'a HashMap<String, String>>,
+    dynamic_default: Option<&'a HashMap<String, String>>,
+    static_broker: &'a BrokerConfig,
+}
+
+impl ConfigLayers<'_> {
+    // (name, value, source) of the broker settings called `name`
+    fn broker_synonyms(&self, name: &str) -> Vec<(String, String, i8)> {
+        [
+            (self.dynamic_broker.and_then(|configs| configs.get(name)).map(|value| value.as_str()), SOURCE_DYNAMIC_BROKER_CONFIG),
+            (self.dynamic_default.and_then(|configs| configs.get(name)).map(|value| value.as_str()), SOURCE_DYNAMIC_DEFAULT_BROKER_CONFIG),
+            (self.static_broker.get(name), SOURCE_STATIC_BROKER_CONFIG),
+        ].into_iter()
+            .filter_map(|(value, source)| value.map(|value| (name.to_string(), value.to_string(), source)))
+            .collect()
+    }
+
+    // (name, value, source) of everything a topic config falls back to
+    fn topic_synonyms(&self, name: &str, broker_names: &[&str]) -> Vec<(String, String, i8)> {
+        let mut synonyms = Vec::new();
+        if let Some(value) = self.topic.and_then(|configs| configs.get(name)) {
+            synonyms.push((name.to_string(), value.clone(), SOURCE_TOPIC_CONFIG));
+        }
+        for broker_name in broker_names {
+            synonyms.extend(self.broker_synonyms(broker_name));
+        }
+        if let Some(value) = LogConfig::default().value(name) {
+            synonyms.push((name.to_string(), value, SOURCE_DEFAULT_CONFIG));
+        }
+        synonyms
+    }
+}
+
+fn is_sensitive(name: &str) -> bool {
+    name.contains("password")
+}
+
+fn config_entry(name: &str, value: Option<String>, synonyms: Vec<(String, String, i8)>, read_only: bool, include_synonyms: bool) -> DescribeConfigsResourceResult {
+    let source = synonyms.first().map(|(_, _, source)| *source).unwrap_or(SOURCE_DEFAULT_CONFIG);
+    let sensitive = is_sensitive(name);
+    let synonyms = if include_synonyms {
+        synonyms.into_iter()
+            .map(|(name, value, source)| DescribeConfigsSynonym::default()
+                .with_value((!is_sensitive(&name)).then(|| StrBytes::from_string(value)))
+                .with_name(StrBytes::from_string(name))
+                .with_source(source))
+            .collect()
+    } else {
+        Vec::new()
+    };
+    DescribeConfigsResourceResult::default()
+        .with_name(StrBytes::from_string(name.to_string()))
+        .with_value(value.filter(|_| !sensitive).map(StrBytes::from_string))
+        .with_read_only(read_only)
+        .with_is_default(source == SOURCE_DEFAULT_CONFIG)
+        .with_config_source(source)
+        .with_is_sensitive(sensitive)
+        .with_synonyms(synonyms)
+}
+
+fn error_result(resource_type: i8, resource_name: &StrBytes, error: ResponseError, message: Option<String>) -> DescribeConfigsResult {
+    DescribeConfigsResult::default()
+        .with_error_code(error.code())
+        .with_error_message(message.map(StrBytes::from_string))
+        .with_resource_type(resource_type)
+        .with_resource_name(resource_name.clone())
+}
+
+/// Describes topic configs and the settings of this broker, or the
+/// cluster-wide dynamic defaults for an empty broker name, with where each
+/// value comes from.
+pub async fn handle_describe_configs(broker: &Broker, req: DescribeConfigsRequest, topics: &HashMap<String, (Uuid, Vec<i32>)>, authorizer: &Authorizer, client_host: &str) -> DescribeConfigsResponse {
+    let topic_configs = broker.topic_configs.lock().await.clone();
+    let broker_configs = broker.broker_configs.lock().await.clone();
+    let node_id = node_id(&broker.config).to_string();
+    let no_static_config = BrokerConfig::default();
+    let mut results = Vec::new();
+    for resource in req.resources.iter() {
+        let resource_name = resource.resource_name.as_str();
+        let requested = |name: &str| resource.configuration_keys.as_ref()
+            .map(|keys| keys.iter().any(|key| key.as_str() == name))
+            .unwrap_or(true);
+        let layers = ConfigLayers {
+            topic: topic_configs.get(resource_name),
+            dynamic_broker: broker_configs.get(&node_id),
+            dynamic_default: broker_configs.get(""),
+            static_broker: &broker.config,
+        };
+        let configs = match resource.resource_type {
+            CONFIG_RESOURCE_TOPIC => {
+                if !topics.contains_key(resource_name) {
+                    results.push(error_result(resource.resource_type, &resource.resource_name, ResponseError::UnknownTopicOrPartition, None));
+                    continue;
+                }
+                if !authorizer.authorize(ANONYMOUS_PRINCIPAL, client_host, OP_DESCRIBE_CONFIGS, RESOURCE_TYPE_TOPIC, resource_name) {
+                    results.push(error_result(resource.resource_type, &resource.resource_name, ResponseError::TopicAuthorizationFailed, None));
+                    continue;
+                }
+                let log_config = broker.log_config(resource_name).await;
+                TOPIC_CONFIG_SYNONYMS.iter()
+                    .filter(|(name, _)| requested(name))
+                    .map(|(name, broker_names)| config_entry(name, log_config.value(name), layers.topic_synonyms(name, broker_names), false, req.include_synonyms))
+                    .collect()
+            }
+            CONFIG_RESOURCE_BROKER => {
+                if !resource_name.is_empty() && resource_name != node_id {
+                    let message = format!("Unexpected broker id, expected {} or empty string, but received {}", node_id, resource_name);
+                    results.push(error_result(resource.resource_type, &resource.resource_name, ResponseError::InvalidRequest, Some(message)));
+                    continue;
+                }
+                if !authorizer.authorize(ANONYMOUS_PRINCIPAL, client_host, OP_DESCRIBE_CONFIGS, RESOURCE_TYPE_CLUSTER, CLUSTER_RESOURCE_NAME) {
+                    results.push(error_result(resource.resource_type, &resource.resource_name, ResponseError::ClusterAuthorizationFailed, None));
+                    continue;
+                }
+                // the cluster-wide defaults only list what was set dynamically
+                let layers = if resource_name.is_empty() {
+                    ConfigLayers { dynamic_broker: None, static_broker: &no_static_config, ..layers }
+                } else {
+                    layers
+                };
+                let names: BTreeSet<&str> = layers.static_broker.props().keys()
+                    .chain(layers.dynamic_default.into_iter().flat_map(|configs| configs.keys()))
+                    .chain(layers.dynamic_broker.into_iter().flat_map(|configs| configs.keys()))
+                    .map(|name| name.as_str())
+                    .collect();
+                names.into_iter()
+                    .filter(|name| requested(name))
+                    .map(|name| {
+                        let synonyms = layers.broker_synonyms(name);
+                        let value = synonyms.first().map(|(_, value, _)| value.clone());
+                        config_entry(name, value, synonyms, dynamic_topic_config(name).is_none(), req.include_synonyms)
+                    })
+                    .collect()
+            }
+            _ => {
+                results.push(error_result(resource.resource_type, &resource.resource_name, ResponseError::InvalidRequest, None));
+                continue;
+            }
+        };
+        results.push(DescribeConfigsResult::default()
+            .with_resource_type(resource.resource_type)
+            .with_resource_name(resource.resource_name.clone())
+            .with_configs(configs));
+    }
+    DescribeConfigsResponse::default()
+        .with_results(results)
+}
@@ -0,0 +1,119 @@
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use kafka_protocol::error::ResponseError;
+use kafka_protocol::messages::describe_log_dirs_response::{DescribeLogDirsPartition, DescribeLogDirsResult, DescribeLogDirsTopic};
+use kafka_protocol::messages::{DescribeLogDirsRequest, DescribeLogDirsResponse, TopicName};
+use kafka_protocol::protocol::StrBytes;
+
+use crate::acl::{Authorizer, ANONYMOUS_PRINCIPAL, OP_DESCRIBE, RESOURCE_TYPE_CLUSTER};
+use crate::broker::Broker;
+use crate::describe_configs::CLUSTER_RESOURCE_NAME;
+use crate::log::{list_partition_dirs_in, log_dirs, partition_size, read_log_end_offset};
+
+/// (total, usable) bytes of the file system holding `dir`.
+pub fn disk_space(dir: &Path) -> io::Result<(i64, i64)> {
+    let path = CString::new(dir.as_os_str().as_bytes())?;
+    // SAFETY: statvfs only writes to the struct, which is plain data
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: path is NUL terminated and outlives the call
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let block_size = stat.f_frsize as u64;
+    Ok(((stat.f_blocks as u64 * block_size) as i64, (stat.f_bavail as u64 * block_size) as i64))
+}
+
+/// Describes one log dir: its disk space and the size of every partition in
+/// it, filtered by `requested` when given.
+async fn describe_log_dir(broker: &Broker, dir: &Path, requested: &Option<Vec<(String, i32)>>) -> io::Result<DescribeLogDirsResult> {
+    let (total_bytes, usable_bytes) = disk_space(dir)?;
+    let mut topics: BTreeMap<String, Vec<DescribeLogDirsPartition>> = BTreeMap::new();
+    for ((topic_name, partition_idx), partition_dir, future) in list_partition_dirs_in(dir).await? {
+        if let Some(requested) = requested {
+            if !requested.iter().any(|(name, idx)| *name == topic_name && *idx == partition_idx) {
+                continue;
+            }
+        }
+        // a future replica lags behind the current one while it is copied
+        let offset_lag = if future {
+            let current = broker.logs.lock().await
+                .get(&(topic_name.clone(), partition_idx))
+                .map(|log| log.log_end_offset());
+            match current {
+                Some(log_end_offset) => (log_end_offset - read_log_end_offset(&partition_dir).await?).max(0),
+                None => 0,
+            }
+        } else {
+            0
+        };
+        topics.entry(topic_name).or_default().push(DescribeLogDirsPartition::default()
+            .with_partition_index(partition_idx)
+            .with_partition_size(partition_size(&partition_dir).await? as i64)
+            .with_offset_lag(offset_lag)
+            .with_is_future_key(future));
+    }
+    let topics = topics.into_iter()
+        .map(|(topic_name, partitions)| DescribeLogDirsTopic::default()
+            .with_name(TopicName(StrBytes::from_string(topic_name)))
+            .with_partitions(partitions))
+        .collect();
+    Ok(DescribeLogDirsResult::default()
+        .with_log_dir(StrBytes::from_string(dir.display().to_string()))
+        .with_topics(topics)
+        .with_total_bytes(total_bytes)
+        .with_usable_bytes(usable_bytes))
+}
+
+/// Reports each configured log dir with its disk usage and partitions. Dirs
+/// that are offline or cannot be read come back with KAFKA_STORAGE_ERROR.
+pub async fn handle_describe_log_dirs(broker: &Broker, req: DescribeLogDirsRequest, authorizer: &Authorizer, client_host: &str) -> DescribeLogDirsResponse {
+    if !authorizer.authorize(ANONYMOUS_PRINCIPAL, client_host, OP_DESCRIBE, RESOURCE_TYPE_CLUSTER, CLUSTER_RESOURCE_NAME) {
+        return DescribeLogDirsResponse::default()
+            .with_error_code(ResponseError::ClusterAuthorizationFailed.code());
+    }
+    // None describes every partition
+    let requested = req.topics.as_ref().map(|topics| topics.iter()
+        .flat_map(|topic| topic.partitions.iter().map(|partition_idx| (topic.topic.0.to_string(), *partition_idx)))
+        .collect());
+    let mut results = Vec::new();
+    for dir in log_dirs(&broker.config) {
+        let online = broker.log_dirs.lock().await.is_online(&dir);
+        let described = if online {
+            describe_log_dir(broker, &dir, &requested).await
+        } else {
+            Err(io::Error::other("log dir is offline"))
+        };
+        let result = match described {
+            Ok(result) => result,
+            Err(e) => {
+                println!("Failed to describe log dir {}: {}", dir.display(), e);
+                DescribeLogDirsResult::default()
+                    .with_error_code(ResponseError::KafkaStorageError.code())
+                    .with_log_dir(StrBytes::from_string(dir.display().to_string()))
+                    .with_total_bytes(-1)
+                    .with_usable_bytes(-1)
+            }
+        };
+        results.push(result);
+    }
+    DescribeLogDirsResponse::default()
+        .with_results(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::disk_space;
+
+    #[test]
+    fn test_disk_space() {
+        let (total_bytes, usable_bytes) = disk_space(&std::env::temp_dir()).unwrap();
+        assert!(total_bytes > 0 && usable_bytes <= total_bytes);
+        assert!(disk_space(Path::new("/nonexistent/log/dir")).is_err());
+    }
+}
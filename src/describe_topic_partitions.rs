@@ -0,0 +1,199 @@
+use std::collections::HashMap;
+
+use kafka_protocol::error::ResponseError;
+use kafka_protocol::messages::describe_topic_partitions_response::{Cursor, DescribeTopicPartitionsResponsePartition, DescribeTopicPartitionsResponseTopic};
+use kafka_protocol::messages::{DescribeTopicPartitionsRequest, DescribeTopicPartitionsResponse, TopicName};
+use kafka_protocol::protocol::StrBytes;
+use uuid::Uuid;
+
+use crate::acl::{Authorizer, ANONYMOUS_PRINCIPAL, OP_DESCRIBE, RESOURCE_TYPE_TOPIC, TOPIC_OPERATIONS};
+
+// max.request.partition.size.limit
+pub const MAX_RESPONSE_PARTITION_LIMIT: i32 = 2000;
+
+pub fn is_internal_topic(topic_name: &str) -> bool {
+    topic_name == "__consumer_offsets" || topic_name == "__transaction_state"
+}
+
+fn error_topic(topic_name: &str, error: ResponseError) -> DescribeTopicPartitionsResponseTopic {
+    DescribeTopicPartitionsResponseTopic::default()
+        .with_name(Some(TopicName(StrBytes::from_string(topic_name.to_string()))))
+        .with_error_code(error.code())
+        .with_topic_id(Uuid::nil())
+}
+
+/// Describes the requested topics, or every topic for an empty request, in
+/// name order, returning at most `response_partition_limit` partitions and a
+/// `next_cursor` to resume from (KIP-966).
+pub fn handle_describe_topic_partitions(req: DescribeTopicPartitionsRequest, topics: &HashMap<String, (Uuid, Vec<i32>)>, authorizer: &Authorizer, client_host: &str) -> DescribeTopicPartitionsResponse {
+    let fetch_all = req.topics.is_empty();
+    let mut names: Vec<String> = if fetch_all {
+        topics.keys().cloned().collect()
+    } else {
+        req.topics.iter()
+            .map(|tr| tr.name.0.to_string())
+            .collect()
+    };
+    names.sort();
+    names.dedup();
+
+    let (cursor_topic, cursor_partition) = match &req.cursor {
+        Some(cursor) => (Some(cursor.topic_name.0.to_string()), cursor.partition_index),
+        None => (None, 0),
+    };
+    if let Some(cursor_topic) = &cursor_topic {
+        if (!fetch_all && !names.contains(cursor_topic)) || cursor_partition < 0 {
+            let topics = names.iter()
+                .map(|name| error_topic(name, ResponseError::InvalidRequest))
+                .collect();
+            return DescribeTopicPartitionsResponse::default()
+                .with_topics(topics);
+        }
+    }
+
+    let mut remaining = if req.response_partition_limit <= 0 {
+        MAX_RESPONSE_PARTITION_LIMIT
+    } else {
+        req.response_partition_limit.min(MAX_RESPONSE_PARTITION_LIMIT)
+    } as usize;
+    let mut resp_topics = Vec::new();
+    let mut next_cursor = None;
+    let mut iter = names.iter()
+        .skip_while(|name| cursor_topic.as_ref().map(|c| *name < c).unwrap_or(false))
+        .peekable();
+    while let Some(name) = iter.next() {
+        let Some((topic_id, partition_ids)) = topics.get(name) else {
+            resp_topics.push(error_topic(name, ResponseError::UnknownTopicOrPartition));
+            continue;
+        };
+        if !authorizer.authorize(ANONYMOUS_PRINCIPAL, client_host, OP_DESCRIBE, RESOURCE_TYPE_TOPIC, name) {
+            if !fetch_all {
+                resp_topics.push(error_topic(name, ResponseError::TopicAuthorizationFailed));
+            }
+            continue;
+        }
+        let first_partition = if cursor_topic.as_ref() == Some(name) { cursor_partition } else { 0 };
+        let mut partition_ids: Vec<i32> = partition_ids.iter()
+            .copied()
+            .filter(|partition_id| *partition_id >= first_partition)
+            .collect();
+        partition_ids.sort();
+        // a cursor past the topic's last partition resumes at the next topic
+        if partition_ids.is_empty() && first_partition > 0 {
+            continue;
+        }
+
+        if partition_ids.len() > remaining {
+            next_cursor = Some(Cursor::default()
+                .with_topic_name(TopicName(StrBytes::from_string(name.clone())))
+                .with_partition_index(partition_ids[remaining]));
+            partition_ids.truncate(remaining);
+            remaining = 0;
+        } else {
+            remaining -= partition_ids.len();
+        }
+        let partitions = partition_ids.iter()
+            .map(|partition_id| DescribeTopicPartitionsResponsePartition::default()
+                .with_partition_index(*partition_id))
+            .collect();
+        resp_topics.push(DescribeTopicPartitionsResponseTopic::default()
+            .with_name(Some(TopicName(StrBytes::from_string(name.clone()))))
+            .with_topic_id(*topic_id)
+            .with_is_internal(is_internal_topic(name))
+            .with_partitions(partitions)
+            .with_topic_authorized_operations(
+                authorizer.authorized_operations(ANONYMOUS_PRINCIPAL, client_host, RESOURCE_TYPE_TOPIC, name, TOPIC_OPERATIONS)));
+
+        if remaining == 0 {
+            if next_cursor.is_none() {
+                next_cursor = iter.peek().map(|next| Cursor::default()
+                    .with_topic_name(TopicName(StrBytes::from_string(next.to_string())))
+                    .with_partition_index(0));
+            }
+            break;
+        }
+    }
+
+    DescribeTopicPartitionsResponse::default()
+        .with_topics(resp_topics)
+        .with_next_cursor(next_cursor)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use kafka_protocol::messages::describe_topic_partitions_request::{Cursor, TopicRequest};
+    use kafka_protocol::messages::{DescribeTopicPartitionsRequest, TopicName};
+    use kafka_protocol::protocol::StrBytes;
+    use uuid::Uuid;
+
+    use crate::acl::{AclState, Authorizer};
+    use crate::config::BrokerConfig;
+
+    use super::handle_describe_topic_partitions;
+
+    fn request(names: &[&str], limit: i32, cursor: Option<(&str, i32)>) -> DescribeTopicPartitionsRequest {
+        DescribeTopicPartitionsRequest::default()
+            .with_topics(names.iter()
+                .map(|name| TopicRequest::default().with_name(TopicName(StrBytes::from_string(name.to_string()))))
+                .collect())
+            .with_response_partition_limit(limit)
+            .with_cursor(cursor.map(|(name, idx)| Cursor::default()
+                .with_topic_name(TopicName(StrBytes::from_string(name.to_string())))
+                .with_partition_index(idx)))
+    }
+
+    #[test]
+    fn test_pagination() {
+        let topics = HashMap::from([
+            ("foo".to_string(), (Uuid::from_u128(1), vec![0, 1, 2])),
+            ("bar".to_string(), (Uuid::from_u128(2), vec![1, 0])),
+        ]);
+        let authorizer = Authorizer::new(&BrokerConfig::default(), AclState::default());
+
+        let resp = handle_describe_topic_partitions(request(&["foo", "bar"], 3, None), &topics, &authorizer, "127.0.0.1");
+        let described: Vec<(String, Vec<i32>)> = resp.topics.iter()
+            .map(|t| (t.name.as_ref().unwrap().0.to_string(), t.partitions.iter().map(|p| p.partition_index).collect()))
+            .collect();
+        assert_eq!(vec![("bar".to_string(), vec![0, 1]), ("foo".to_string(), vec![0])], described);
+        let cursor = resp.next_cursor.unwrap();
+        assert_eq!("foo", cursor.topic_name.0.as_str());
+        assert_eq!(1, cursor.partition_index);
+
+        let resp = handle_describe_topic_partitions(request(&["foo", "bar"], 2, Some(("foo", 1))), &topics, &authorizer, "127.0.0.1");
+        assert_eq!(1, resp.topics.len());
+        assert_eq!(vec![1, 2], resp.topics[0].partitions.iter().map(|p| p.partition_index).collect::<Vec<_>>());
+        assert!(resp.next_cursor.is_none());
+
+        let resp = handle_describe_topic_partitions(request(&["foo", "bar"], 2, None), &topics, &authorizer, "127.0.0.1");
+        let cursor = resp.next_cursor.unwrap();
+        assert_eq!("foo", cursor.topic_name.0.as_str());
+        assert_eq!(0, cursor.partition_index);
+
+        // a cursor past the last partition of its topic moves on to the next
+        let resp = handle_describe_topic_partitions(request(&["foo", "bar"], 2, Some(("bar", 2))), &topics, &authorizer, "127.0.0.1");
+        assert_eq!(1, resp.topics.len());
+        assert_eq!("foo", resp.topics[0].name.as_ref().unwrap().0.as_str());
+        assert_eq!(vec![0, 1], resp.topics[0].partitions.iter().map(|p| p.partition_index).collect::<Vec<_>>());
+        assert_eq!(2, resp.next_cursor.unwrap().partition_index);
+        let resp = handle_describe_topic_partitions(request(&["foo", "bar"], 2, Some(("foo", 3))), &topics, &authorizer, "127.0.0.1");
+        assert!(resp.topics.is_empty());
+        assert!(resp.next_cursor.is_none());
+    }
+
+    #[test]
+    fn test_describe_all_topics() {
+        let topics = HashMap::from([
+            ("foo".to_string(), (Uuid::from_u128(1), vec![0])),
+            ("__consumer_offsets".to_string(), (Uuid::from_u128(2), vec![0])),
+        ]);
+        let authorizer = Authorizer::new(&BrokerConfig::default(), AclState::default());
+        let resp = handle_describe_topic_partitions(request(&[], 10, None), &topics, &authorizer, "127.0.0.1");
+        let described: Vec<(String, bool)> = resp.topics.iter()
+            .map(|t| (t.name.as_ref().unwrap().0.to_string(), t.is_internal))
+            .collect();
+        assert_eq!(vec![("__consumer_offsets".to_string(), true), ("foo".to_string(), false)], described);
+        assert_ne!(i32::MIN, resp.topics[0].topic_authorized_operations);
+    }
+}
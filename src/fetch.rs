@@ -0,0 +1,274 @@
+use std::collections::HashMap;
+use std::io;
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures::future::select_all;
+use kafka_protocol::error::ResponseError;
+use kafka_protocol::messages::fetch_response::{AbortedTransaction, EpochEndOffset, FetchableTopicResponse, PartitionData};
+use kafka_protocol::messages::{FetchRequest, FetchResponse, ProducerId, TopicName};
+use kafka_protocol::protocol::StrBytes;
+use tokio::sync::watch;
+use tokio::time::{sleep_until, Instant};
+use uuid::Uuid;
+
+use crate::broker::Broker;
+use crate::fetch_session::{SessionContext, SessionPartition, INVALID_SESSION_ID};
+use crate::leader_epoch::UNDEFINED_EPOCH;
+use crate::log::{FetchedData, FileRecords, TopicPartition};
+use crate::message_format::{down_convert, MAGIC_V0, MAGIC_V1};
+use crate::send::placeholder;
+
+// FetchRequest.isolation_level
+const READ_COMMITTED: i8 = 1;
+const TOPIC_ID_MIN_VERSION: i16 = 13;
+// the replica id moved into replica_state
+const REPLICA_STATE_MIN_VERSION: i16 = 15;
+// records are compact bytes from v12 on
+pub const FLEXIBLE_MIN_VERSION: i16 = 12;
+
+// the message format a Fetch version reads, None for v2 record batches
+fn legacy_magic(api_version: i16) -> Option<i8> {
+    match api_version {
+        0..=1 => Some(MAGIC_V0),
+        2..=3 => Some(MAGIC_V1),
+        _ => None,
+    }
+}
+
+// Old consumers get the records converted in memory, so they cannot be
+// sent from the segment files.
+async fn down_convert_records(records: &FileRecords, magic: i8) -> io::Result<Bytes> {
+    Ok(down_convert(&records.read().await?, magic)?.freeze())
+}
+
+// The end of the fetcher's last fetched epoch in this log, with the log
+// start offset and high watermark, when the fetcher's log diverges from
+// this one there: this log has a later epoch, or ends that epoch before the
+// fetch offset. The fetcher truncates before it fetches again. An epoch
+// this log has no end for comes back as UNDEFINED_EPOCH.
+async fn diverging_epoch(broker: &Broker, topic_partition: &TopicPartition, partition: &SessionPartition) -> io::Result<Option<(EpochEndOffset, i64, i64)>> {
+    if partition.last_fetched_epoch < 0 {
+        return Ok(None);
+    }
+    let (topic_name, partition_idx) = topic_partition;
+    let (epoch, end_offset) = broker.end_offset_for_epoch(topic_name, *partition_idx, partition.last_fetched_epoch).await?;
+    if epoch == partition.last_fetched_epoch && end_offset >= partition.fetch_offset {
+        return Ok(None);
+    }
+    let (log_start_offset, _, high_watermark) = broker.log_offsets(topic_name, *partition_idx).await?;
+    let diverging_epoch = EpochEndOffset::default()
+        .with_epoch(epoch)
+        .with_end_offset(end_offset);
+    Ok(Some((diverging_epoch, log_start_offset, high_watermark)))
+}
+
+fn is_diverging(partition: &PartitionData) -> bool {
+    partition.diverging_epoch.epoch >= 0
+}
+
+async fn fetch_partition(broker: &Broker, topic_partition: &TopicPartition, partition: &SessionPartition, partition_ids: &[i32], replica_id: Option<i32>, read_committed: bool) -> (PartitionData, FileRecords) {
+    let (topic_name, partition_idx) = topic_partition;
+    let partition_data = PartitionData::default()
+        .with_partition_index(*partition_idx);
+    if !partition_ids.contains(partition_idx) {
+        return (partition_data.with_error_code(ResponseError::UnknownTopicOrPartition.code()), FileRecords::default());
+    }
+    if let Err(error) = broker.replica_manager.lock().await.check_fetch(topic_partition, replica_id, partition.current_leader_epoch) {
+        return (partition_data.with_error_code(error.code()), FileRecords::default());
+    }
+    match diverging_epoch(broker, topic_partition, partition).await {
+        Ok(None) => {}
+        Ok(Some((diverging_epoch, log_start_offset, high_watermark))) => {
+            let partition_data = partition_data
+                .with_high_watermark(high_watermark)
+                .with_log_start_offset(log_start_offset);
+            // no common epoch to truncate to, the fetch offset is out of range
+            if diverging_epoch.epoch == UNDEFINED_EPOCH {
+                return (partition_data.with_error_code(ResponseError::OffsetOutOfRange.code()), FileRecords::default());
+            }
+            return (partition_data.with_diverging_epoch(diverging_epoch), FileRecords::default());
+        }
+        Err(e) => {
+            println!("Failed to look up the epochs of {}-{}: {}", topic_name, partition_idx, e);
+            return (partition_data.with_error_code(ResponseError::KafkaStorageError.code()), FileRecords::default());
+        }
+    }
+    let read = match replica_id {
+        Some(replica_id) => broker.read_for_follower(topic_name, *partition_idx, replica_id, partition.fetch_offset, partition.max_bytes).await,
+        None => broker.read(topic_name, *partition_idx, partition.fetch_offset, partition.max_bytes, read_committed).await,
+    };
+    // a follower reads up to the log end offset, a consumer up to the high watermark
+    let max_offset = |fetched: &FetchedData| if replica_id.is_some() { fetched.log_end_offset } else { fetched.high_watermark };
+    match read {
+        Ok(fetched) if partition.fetch_offset < fetched.log_start_offset || partition.fetch_offset > max_offset(&fetched) => {
+            let partition_data = partition_data
+                .with_error_code(ResponseError::OffsetOutOfRange.code())
+                .with_high_watermark(fetched.high_watermark)
+                .with_last_stable_offset(fetched.last_stable_offset)
+                .with_log_start_offset(fetched.log_start_offset);
+            (partition_data, FileRecords::default())
+        }
+        Ok(fetched) => {
+            let aborted_transactions = fetched.aborted_transactions.iter()
+                .map(|txn| AbortedTransaction::default()
+                    .with_producer_id(ProducerId(txn.producer_id))
+                    .with_first_offset(txn.first_offset))
+                .collect();
+            let partition_data = partition_data
+                .with_high_watermark(fetched.high_watermark)
+                .with_last_stable_offset(fetched.last_stable_offset)
+                .with_log_start_offset(fetched.log_start_offset)
+                .with_aborted_transactions(if read_committed { Some(aborted_transactions) } else { None });
+            (partition_data, fetched.records)
+        }
+        Err(e) => {
+            println!("Failed to read {}-{}: {}", topic_name, partition_idx, e);
+            (partition_data.with_error_code(ResponseError::KafkaStorageError.code()), FileRecords::default())
+        }
+    }
+}
+
+fn is_satisfied(partitions: &[(TopicPartition, Uuid, PartitionData, FileRecords)], min_bytes: i32) -> bool {
+    let mut bytes = 0;
+    for (_, _, partition, records) in partitions.iter() {
+        if partition.error_code != 0 || is_diverging(partition) {
+            return true;
+        }
+        bytes += records.len();
+    }
+    bytes >= min_bytes.max(0) as u64
+}
+
+/// Serves a Fetch, parking it in the purgatory until `min_bytes` are
+/// available, `max_wait_ms` elapses or the connection is closed.
+///
+/// The records of each partition are left in the segment files: the response
+/// carries a placeholder for them, indexing into the returned FileRecords,
+/// which is swapped for the records as the response is written.
+pub async fn handle_fetch(broker: &Broker, req: FetchRequest, api_version: i16, topics: &HashMap<String, (Uuid, Vec<i32>)>, mut closed: watch::Receiver<bool>) -> (FetchResponse, Vec<FileRecords>) {
+    let topic_id_to_name: HashMap<Uuid, &String> = topics.iter()
+        .map(|kv| (kv.1.0, kv.0))
+        .collect();
+    // topics are identified by id from v13 on, by name before
+    let resolve = |topic_name: &TopicName, topic_id: &Uuid| -> Option<(String, Uuid)> {
+        if api_version >= TOPIC_ID_MIN_VERSION {
+            topic_id_to_name.get(topic_id).map(|name| (name.to_string(), *topic_id))
+        } else {
+            topics.get(topic_name.0.as_str()).map(|(id, _)| (topic_name.0.to_string(), *id))
+        }
+    };
+    let read_committed = req.isolation_level == READ_COMMITTED;
+    let replica_id = if api_version >= REPLICA_STATE_MIN_VERSION { req.replica_state.replica_id.0 } else { req.replica_id.0 };
+    // consumers send -1
+    let replica_id = Some(replica_id).filter(|replica_id| *replica_id >= 0);
+    let deadline = Instant::now() + Duration::from_millis(req.max_wait_ms.max(0) as u64);
+
+    let mut unknown_topics = Vec::new();
+    let mut requested = Vec::new();
+    for fetch_topic in req.topics.iter() {
+        match resolve(&fetch_topic.topic, &fetch_topic.topic_id) {
+            Some((topic_name, topic_id)) => {
+                for fp in fetch_topic.partitions.iter() {
+                    let partition = SessionPartition {
+                        current_leader_epoch: fp.current_leader_epoch,
+                        last_fetched_epoch: fp.last_fetched_epoch,
+                        ..SessionPartition::new(topic_id, fp.fetch_offset, fp.partition_max_bytes)
+                    };
+                    requested.push(((topic_name.clone(), fp.partition), partition));
+                }
+            }
+            None if api_version >= TOPIC_ID_MIN_VERSION => unknown_topics.push(FetchableTopicResponse::default()
+                .with_topic_id(fetch_topic.topic_id)
+                .with_partitions(vec![PartitionData::default()
+                    .with_error_code(ResponseError::UnknownTopicId.code())])),
+            None => unknown_topics.push(FetchableTopicResponse::default()
+                .with_topic(fetch_topic.topic.clone())
+                .with_partitions(fetch_topic.partitions.iter()
+                    .map(|fp| PartitionData::default()
+                        .with_partition_index(fp.partition)
+                        .with_error_code(ResponseError::UnknownTopicOrPartition.code()))
+                    .collect())),
+        }
+    }
+    let forgotten: Vec<TopicPartition> = req.forgotten_topics_data.iter()
+        .filter_map(|t| resolve(&t.topic, &t.topic_id).map(|(name, _)| (t, name)))
+        .flat_map(|(t, name)| t.partitions.iter().map(move |p| (name.clone(), *p)))
+        .collect();
+
+    let context = broker.fetch_sessions.lock().await
+        .new_context(req.session_id, req.session_epoch, requested, &forgotten, Instant::now());
+    let (session_id, incremental, partitions) = match context {
+        Ok(SessionContext::Sessionless(partitions)) => (INVALID_SESSION_ID, false, partitions),
+        Ok(SessionContext::Full { session_id, partitions }) => (session_id, false, partitions),
+        Ok(SessionContext::Incremental { session_id, partitions }) => (session_id, true, partitions),
+        Err(error) => {
+            let resp = FetchResponse::default()
+                .with_error_code(error.code())
+                .with_session_id(INVALID_SESSION_ID);
+            return (resp, Vec::new());
+        }
+    };
+
+    let mut fetched = loop {
+        let mut watermarks: Vec<watch::Receiver<i64>> = partitions.iter()
+            .map(|(tp, _)| broker.fetch_purgatory.subscribe(tp))
+            .collect();
+
+        let mut fetched = Vec::new();
+        for (topic_partition, partition) in partitions.iter() {
+            let partition_ids = topics.get(&topic_partition.0).map(|(_, ids)| ids.as_slice()).unwrap_or(&[]);
+            let (data, records) = fetch_partition(broker, topic_partition, partition, partition_ids, replica_id, read_committed).await;
+            fetched.push((topic_partition.clone(), partition.topic_id, data, records));
+        }
+
+        if watermarks.is_empty() || Instant::now() >= deadline || is_satisfied(&fetched, req.min_bytes) {
+            break fetched;
+        }
+        let appended = select_all(watermarks.iter_mut().map(|w| Box::pin(w.changed())));
+        tokio::select! {
+            _ = appended => {}
+            _ = sleep_until(deadline) => {}
+            _ = closed.changed() => break fetched,
+        }
+    };
+
+    if session_id != INVALID_SESSION_ID {
+        let mut sessions = broker.fetch_sessions.lock().await;
+        fetched.retain(|(topic_partition, _, data, records)| {
+            let changed = sessions.update(session_id, topic_partition, data.high_watermark, data.last_stable_offset, data.log_start_offset);
+            !incremental || changed || data.error_code != 0 || is_diverging(data) || !records.is_empty()
+        });
+    }
+
+    let mut resps: Vec<FetchableTopicResponse> = Vec::new();
+    let mut file_records = Vec::new();
+    for ((topic_name, _), topic_id, mut data, records) in fetched {
+        match legacy_magic(api_version) {
+            _ if data.error_code != 0 => {}
+            None => {
+                data.records = Some(placeholder(file_records.len()));
+                file_records.push(records);
+            }
+            Some(magic) => match down_convert_records(&records, magic).await {
+                Ok(converted) => data.records = Some(converted),
+                Err(e) => {
+                    println!("Failed to down-convert {}-{}: {}", topic_name, data.partition_index, e);
+                    data.error_code = ResponseError::KafkaStorageError.code();
+                }
+            },
+        }
+        match resps.last_mut() {
+            Some(resp) if resp.topic_id == topic_id && resp.topic.0.as_str() == topic_name => resp.partitions.push(data),
+            _ => resps.push(FetchableTopicResponse::default()
+                .with_topic(TopicName(StrBytes::from_string(topic_name)))
+                .with_topic_id(topic_id)
+                .with_partitions(vec![data])),
+        }
+    }
+    resps.extend(unknown_topics);
+    let resp = FetchResponse::default()
+        .with_session_id(session_id)
+        .with_responses(resps);
+    (resp, file_records)
+}
@@ -0,0 +1,240 @@
+use std::collections::{BTreeMap, HashMap};
+
+use kafka_protocol::error::ResponseError;
+use tokio::time::Instant;
+use uuid::Uuid;
+
+use crate::log::TopicPartition;
+
+pub const MAX_CACHE_SLOTS_CONFIG: &str = "max.incremental.fetch.session.cache.slots";
+pub const DEFAULT_MAX_CACHE_SLOTS: usize = 1000;
+
+pub const INVALID_SESSION_ID: i32 = 0;
+pub const INITIAL_EPOCH: i32 = 0;
+pub const FINAL_EPOCH: i32 = -1;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionPartition {
+    pub topic_id: Uuid,
+    pub fetch_offset: i64,
+    pub max_bytes: i32,
+    // -1 when the fetcher does not check the leader epoch
+    pub current_leader_epoch: i32,
+    // the epoch of the last batch the fetcher has, -1 to skip the divergence check
+    pub last_fetched_epoch: i32,
+    // offsets last sent to the client, so unchanged partitions can be left out
+    pub high_watermark: i64,
+    pub last_stable_offset: i64,
+    pub log_start_offset: i64,
+}
+
+impl SessionPartition {
+    pub fn new(topic_id: Uuid, fetch_offset: i64, max_bytes: i32) -> SessionPartition {
+        SessionPartition {
+            topic_id,
+            fetch_offset,
+            max_bytes,
+            current_leader_epoch: -1,
+            last_fetched_epoch: -1,
+            high_watermark: -1,
+            last_stable_offset: -1,
+            log_start_offset: -1,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct FetchSession {
+    epoch: i32,
+    partitions: BTreeMap<TopicPartition, SessionPartition>,
+    last_used: Instant,
+}
+
+/// How a Fetch request relates to the session cache (KIP-227).
+#[derive(Debug, PartialEq)]
+pub enum SessionContext {
+    /// Full fetch without a session.
+    Sessionless(Vec<(TopicPartition, SessionPartition)>),
+    /// Full fetch that (re)created a session; the id is 0 when no slot was free.
+    Full { session_id: i32, partitions: Vec<(TopicPartition, SessionPartition)> },
+    /// Fetch of every partition in the session; only changes go in the response.
+    Incremental { session_id: i32, partitions: Vec<(TopicPartition, SessionPartition)> },
+}
+
+#[derive(Debug)]
+pub struct FetchSessionCache {
+    max_slots: usize,
+    next_id: i32,
+    sessions: HashMap<i32, FetchSession>,
+}
+
+impl Default for FetchSessionCache {
+    fn default() -> Self {
+        FetchSessionCache::new(DEFAULT_MAX_CACHE_SLOTS)
+    }
+}
+
+fn next_epoch(epoch: i32) -> i32 {
+    if epoch == i32::MAX { 1 } else { epoch + 1 }
+}
+
+impl FetchSessionCache {
+    pub fn new(max_slots: usize) -> FetchSessionCache {
+        FetchSessionCache {
+            max_slots,
+            next_id: 1,
+            sessions: HashMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+
+    fn create(&mut self, partitions: &[(TopicPartition, SessionPartition)], now: Instant) -> i32 {
+        if self.max_slots == 0 {
+            return INVALID_SESSION_ID;
+        }
+        if self.sessions.len() >= self.max_slots {
+            let lru = self.sessions.iter()
+                .min_by_key(|(_, session)| session.last_used)
+                .map(|(id, _)| *id);
+            if let Some(id) = lru {
+                self.sessions.remove(&id);
+            }
+        }
+        while self.next_id == INVALID_SESSION_ID || self.sessions.contains_key(&self.next_id) {
+            self.next_id = self.next_id.checked_add(1).unwrap_or(1);
+        }
+        let session_id = self.next_id;
+        self.next_id = self.next_id.checked_add(1).unwrap_or(1);
+        self.sessions.insert(session_id, FetchSession {
+            epoch: next_epoch(INITIAL_EPOCH),
+            partitions: partitions.iter().cloned().collect(),
+            last_used: now,
+        });
+        session_id
+    }
+
+    pub fn new_context(&mut self, session_id: i32, epoch: i32, requested: Vec<(TopicPartition, SessionPartition)>, forgotten: &[TopicPartition], now: Instant) -> Result<SessionContext, ResponseError> {
+        if epoch == FINAL_EPOCH {
+            self.sessions.remove(&session_id);
+            return Ok(SessionContext::Sessionless(requested));
+        }
+        if epoch == INITIAL_EPOCH {
+            self.sessions.remove(&session_id);
+            let session_id = self.create(&requested, now);
+            return Ok(SessionContext::Full { session_id, partitions: requested });
+        }
+
+        let session = self.sessions.get_mut(&session_id)
+            .ok_or(ResponseError::FetchSessionIdNotFound)?;
+        if session.epoch != epoch {
+            return Err(ResponseError::InvalidFetchSessionEpoch);
+        }
+        for topic_partition in forgotten {
+            session.partitions.remove(topic_partition);
+        }
+        for (topic_partition, partition) in requested {
+            match session.partitions.get_mut(&topic_partition) {
+                Some(cached) => {
+                    cached.topic_id = partition.topic_id;
+                    cached.fetch_offset = partition.fetch_offset;
+                    cached.max_bytes = partition.max_bytes;
+                    cached.current_leader_epoch = partition.current_leader_epoch;
+                    cached.last_fetched_epoch = partition.last_fetched_epoch;
+                }
+                None => {
+                    session.partitions.insert(topic_partition, partition);
+                }
+            }
+        }
+        session.epoch = next_epoch(epoch);
+        session.last_used = now;
+        let partitions = session.partitions.iter()
+            .map(|(tp, p)| (tp.clone(), p.clone()))
+            .collect();
+        Ok(SessionContext::Incremental { session_id, partitions })
+    }
+
+    /// Records the offsets sent for a partition, returning whether they differ
+    /// from the previous response.
+    pub fn update(&mut self, session_id: i32, topic_partition: &TopicPartition, high_watermark: i64, last_stable_offset: i64, log_start_offset: i64) -> bool {
+        let Some(cached) = self.sessions.get_mut(&session_id)
+            .and_then(|session| session.partitions.get_mut(topic_partition)) else {
+            return true;
+        };
+        let changed = cached.high_watermark != high_watermark
+            || cached.last_stable_offset != last_stable_offset
+            || cached.log_start_offset != log_start_offset;
+        cached.high_watermark = high_watermark;
+        cached.last_stable_offset = last_stable_offset;
+        cached.log_start_offset = log_start_offset;
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use kafka_protocol::error::ResponseError;
+    use tokio::time::{Duration, Instant};
+    use uuid::Uuid;
+
+    use super::{FetchSessionCache, SessionContext, SessionPartition};
+
+    fn partition(name: &str, idx: i32, fetch_offset: i64) -> ((String, i32), SessionPartition) {
+        ((name.to_string(), idx), SessionPartition::new(Uuid::nil(), fetch_offset, 1024))
+    }
+
+    #[test]
+    fn test_incremental_session() {
+        let mut cache = FetchSessionCache::new(10);
+        let now = Instant::now();
+        let session_id = match cache.new_context(0, 0, vec![partition("foo", 0, 0), partition("foo", 1, 0)], &[], now).unwrap() {
+            SessionContext::Full { session_id, .. } => session_id,
+            other => panic!("unexpected context {:?}", other),
+        };
+        assert_ne!(0, session_id);
+
+        let error = cache.new_context(session_id, 2, vec![], &[], now).unwrap_err();
+        assert_eq!(ResponseError::InvalidFetchSessionEpoch.code(), error.code());
+        let error = cache.new_context(session_id + 1, 1, vec![], &[], now).unwrap_err();
+        assert_eq!(ResponseError::FetchSessionIdNotFound.code(), error.code());
+
+        let forgotten = vec![("foo".to_string(), 0)];
+        match cache.new_context(session_id, 1, vec![partition("foo", 1, 5), partition("bar", 0, 0)], &forgotten, now).unwrap() {
+            SessionContext::Incremental { partitions, .. } => {
+                let offsets: Vec<(String, i32, i64)> = partitions.iter()
+                    .map(|((name, idx), p)| (name.clone(), *idx, p.fetch_offset))
+                    .collect();
+                assert_eq!(vec![("bar".to_string(), 0, 0), ("foo".to_string(), 1, 5)], offsets);
+            }
+            other => panic!("unexpected context {:?}", other),
+        }
+
+        let tp = ("foo".to_string(), 1);
+        assert!(cache.update(session_id, &tp, 10, 10, 0));
+        assert!(!cache.update(session_id, &tp, 10, 10, 0));
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let mut cache = FetchSessionCache::new(2);
+        let now = Instant::now();
+        let mut ids = Vec::new();
+        for i in 0..3 {
+            match cache.new_context(0, 0, vec![partition("foo", 0, 0)], &[], now + Duration::from_secs(i)).unwrap() {
+                SessionContext::Full { session_id, .. } => ids.push(session_id),
+                other => panic!("unexpected context {:?}", other),
+            }
+        }
+        assert_eq!(2, cache.len());
+        let error = cache.new_context(ids[0], 1, vec![], &[], now).unwrap_err();
+        assert_eq!(ResponseError::FetchSessionIdNotFound.code(), error.code());
+        assert!(cache.new_context(ids[2], 1, vec![], &[], now).is_ok());
+    }
+}
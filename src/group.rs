@@ -0,0 +1,358 @@
+use std::collections::HashMap;
+use std::io;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use kafka_protocol::error::ResponseError;
+use kafka_protocol::messages::offset_fetch_response::{OffsetFetchResponseGroup, OffsetFetchResponsePartition, OffsetFetchResponsePartitions, OffsetFetchResponseTopic, OffsetFetchResponseTopics};
+use kafka_protocol::messages::{OffsetFetchRequest, OffsetFetchResponse, TopicName};
+use kafka_protocol::protocol::StrBytes;
+use kafka_protocol::records::{Compression, Record, RecordBatchDecoder, RecordBatchEncoder, RecordEncodeOptions, TimestampType};
+
+use crate::broker::Broker;
+use crate::log::CONTROL_TYPE_COMMIT;
+use crate::txn::get_string;
+
+// like __transaction_state, a single partition is enough for one broker
+pub const CONSUMER_OFFSETS_TOPIC: &str = "__consumer_offsets";
+pub const CONSUMER_OFFSETS_PARTITION: i32 = 0;
+// the version of OffsetFetch that asks for several groups at once
+const OFFSET_FETCH_GROUPS_MIN_VERSION: i16 = 8;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OffsetAndMetadata {
+    pub offset: i64,
+    pub leader_epoch: i32,
+    pub metadata: Option<String>,
+}
+
+// (group_id, topic, partition)
+pub type GroupTopicPartition = (String, String, i32);
+
+// [(topic, [(partition, offset, error code)])] for an OffsetFetch response
+type FetchedOffsets = Vec<(String, Vec<(i32, Option<OffsetAndMetadata>, i16)>)>;
+
+// __consumer_offsets offset commit key v1: version(i16) group(string) topic(string) partition(i32)
+pub fn encode_offset_commit_key(key: &GroupTopicPartition) -> Bytes {
+    let mut buf = BytesMut::new();
+    buf.put_i16(1);
+    for s in [&key.0, &key.1] {
+        buf.put_i16(s.len() as i16);
+        buf.put_slice(s.as_bytes());
+    }
+    buf.put_i32(key.2);
+    buf.freeze()
+}
+
+// __consumer_offsets offset commit value v3: version(i16) offset(i64)
+// leader_epoch(i32) metadata(string) commit_timestamp(i64)
+pub fn encode_offset_commit_value(offset: &OffsetAndMetadata, commit_timestamp: i64) -> Bytes {
+    let metadata = offset.metadata.as_deref().unwrap_or_default();
+    let mut buf = BytesMut::new();
+    buf.put_i16(3);
+    buf.put_i64(offset.offset);
+    buf.put_i32(offset.leader_epoch);
+    buf.put_i16(metadata.len() as i16);
+    buf.put_slice(metadata.as_bytes());
+    buf.put_i64(commit_timestamp);
+    buf.freeze()
+}
+
+/// Decodes an offset commit key, None for group metadata keys (version 2)
+/// and keys cut short.
+pub fn decode_offset_commit_key<B: Buf>(buf: &mut B) -> Option<GroupTopicPartition> {
+    if buf.try_get_i16().ok()? > 1 {
+        return None;
+    }
+    let group_id = get_string(buf)?;
+    let topic = get_string(buf)?;
+    Some((group_id, topic, buf.try_get_i32().ok()?))
+}
+
+/// Decodes an offset commit value written as version 3, None otherwise.
+pub fn decode_offset_commit_value<B: Buf>(buf: &mut B) -> Option<OffsetAndMetadata> {
+    if buf.try_get_i16().ok()? != 3 {
+        return None;
+    }
+    let offset = buf.try_get_i64().ok()?;
+    let leader_epoch = buf.try_get_i32().ok()?;
+    let metadata = get_string(buf)?;
+    buf.try_get_i64().ok()?; // commit timestamp
+    Some(OffsetAndMetadata { offset, leader_epoch, metadata: Some(metadata) })
+}
+
+/// A batch of offset commits written for a producer's transaction, taking
+/// effect once its COMMIT marker follows.
+pub fn txn_offset_commit_batch(producer_id: i64, producer_epoch: i16, offsets: &[(GroupTopicPartition, OffsetAndMetadata)], timestamp: i64) -> BytesMut {
+    let records: Vec<Record> = offsets.iter().enumerate()
+        .map(|(idx, (key, offset))| Record {
+            transactional: true,
+            control: false,
+            partition_leader_epoch: 0,
+            producer_id,
+            producer_epoch,
+            timestamp_type: TimestampType::Creation,
+            offset: idx as i64,
+            // no sequence; one less than the offset keeps the records in one batch
+            sequence: idx as i32 - 1,
+            timestamp,
+            key: Some(encode_offset_commit_key(key)),
+            value: Some(encode_offset_commit_value(offset, timestamp)),
+            headers: Default::default(),
+        })
+        .collect();
+    let mut buf = BytesMut::new();
+    let options = RecordEncodeOptions {
+        version: 2,
+        compression: Compression::None,
+    };
+    RecordBatchEncoder::encode(&mut buf, &records, &options).unwrap();
+    buf
+}
+
+/// Committed consumer group offsets, rebuilt from __consumer_offsets. Offsets
+/// committed inside a transaction are kept per producer id until the
+/// transaction coordinator completes it.
+#[derive(Debug, Default)]
+pub struct GroupCoordinator {
+    offsets: HashMap<GroupTopicPartition, OffsetAndMetadata>,
+    pending_txn_offsets: HashMap<i64, HashMap<GroupTopicPartition, OffsetAndMetadata>>,
+}
+
+impl GroupCoordinator {
+    pub fn committed_offset(&self, group_id: &str, topic: &str, partition: i32) -> Option<&OffsetAndMetadata> {
+        self.offsets.get(&(group_id.to_string(), topic.to_string(), partition))
+    }
+
+    pub fn has_pending_txn_offsets(&self, group_id: &str, topic: &str, partition: i32) -> bool {
+        let key = (group_id.to_string(), topic.to_string(), partition);
+        self.pending_txn_offsets.values().any(|pending| pending.contains_key(&key))
+    }
+
+    /// The (topic, partition) pairs a group has committed offsets for, in order.
+    pub fn committed_partitions(&self, group_id: &str) -> Vec<(String, i32)> {
+        let mut partitions: Vec<(String, i32)> = self.offsets.keys()
+            .filter(|key| key.0 == group_id)
+            .map(|key| (key.1.clone(), key.2))
+            .collect();
+        partitions.sort();
+        partitions
+    }
+
+    pub fn store_txn_offset(&mut self, producer_id: i64, key: GroupTopicPartition, offset: OffsetAndMetadata) {
+        self.pending_txn_offsets
+            .entry(producer_id)
+            .or_default()
+            .insert(key, offset);
+    }
+
+    /// Materializes (commit) or discards (abort) the producer's pending
+    /// offsets. A producer has one transaction at a time, so all of them
+    /// belong to the one being completed.
+    pub fn complete_txn(&mut self, producer_id: i64, commit: bool) {
+        if let Some(pending) = self.pending_txn_offsets.remove(&producer_id) {
+            if commit {
+                self.offsets.extend(pending);
+            }
+        }
+    }
+
+    /// Replays one __consumer_offsets record: a plain commit applies right
+    /// away, a transactional one waits for the marker that ends it.
+    pub fn load(&mut self, record: &Record) {
+        let Some(mut key) = record.key.clone() else { return };
+        if record.control {
+            // version(i16) type(i16)
+            if let (Ok(_), Ok(control_type)) = (key.try_get_i16(), key.try_get_i16()) {
+                self.complete_txn(record.producer_id, control_type == CONTROL_TYPE_COMMIT);
+            }
+            return;
+        }
+        let Some(key) = decode_offset_commit_key(&mut key) else { return };
+        let Some(mut value) = record.value.clone() else {
+            self.offsets.remove(&key);
+            return;
+        };
+        let Some(offset) = decode_offset_commit_value(&mut value) else { return };
+        if record.transactional {
+            self.store_txn_offset(record.producer_id, key, offset);
+        } else {
+            self.offsets.insert(key, offset);
+        }
+    }
+
+    /// The offset OffsetFetch returns for a partition. With `require_stable`
+    /// an offset still waiting on a transaction is refused.
+    fn fetch_offset(&self, group_id: &str, topic: &str, partition: i32, require_stable: bool) -> Result<Option<&OffsetAndMetadata>, ResponseError> {
+        if require_stable && self.has_pending_txn_offsets(group_id, topic, partition) {
+            return Err(ResponseError::UnstableOffsetCommit);
+        }
+        Ok(self.committed_offset(group_id, topic, partition))
+    }
+
+    /// The offsets of a group's partitions for OffsetFetch, every committed
+    /// partition when `topics` is None.
+    fn fetch_offsets(&self, group_id: &str, topics: Option<Vec<(String, Vec<i32>)>>, require_stable: bool) -> FetchedOffsets {
+        let topics = topics.unwrap_or_else(|| {
+            let mut topics: Vec<(String, Vec<i32>)> = Vec::new();
+            for (topic, partition) in self.committed_partitions(group_id) {
+                match topics.last_mut() {
+                    Some((name, partitions)) if *name == topic => partitions.push(partition),
+                    _ => topics.push((topic, vec![partition])),
+                }
+            }
+            topics
+        });
+        topics.into_iter()
+            .map(|(topic, partitions)| {
+                let partitions = partitions.into_iter()
+                    .map(|partition| match self.fetch_offset(group_id, &topic, partition, require_stable) {
+                        Ok(offset) => (partition, offset.cloned(), 0),
+                        Err(error) => (partition, None, error.code()),
+                    })
+                    .collect();
+                (topic, partitions)
+            })
+            .collect()
+    }
+}
+
+/// Rebuilds the committed offsets from __consumer_offsets, including those of
+/// transactions that have not ended yet.
+pub async fn load(broker: &Broker) -> io::Result<()> {
+    let mut buf = broker.read_all(CONSUMER_OFFSETS_TOPIC, CONSUMER_OFFSETS_PARTITION).await?;
+    let record_sets = RecordBatchDecoder::decode_all(&mut buf)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+    let mut coordinator = broker.group_coordinator.lock().await;
+    for record_set in record_sets.iter() {
+        for record in record_set.records.iter() {
+            coordinator.load(record);
+        }
+    }
+    Ok(())
+}
+
+// (offset, leader epoch, metadata) of a partition in an OffsetFetch response
+fn committed(offset: Option<OffsetAndMetadata>) -> (i64, i32, Option<StrBytes>) {
+    match offset {
+        Some(offset) => (offset.offset, offset.leader_epoch, offset.metadata.map(StrBytes::from_string)),
+        None => (-1, -1, Some(StrBytes::default())),
+    }
+}
+
+pub async fn handle_offset_fetch(broker: &Broker, req: OffsetFetchRequest, api_version: i16) -> OffsetFetchResponse {
+    let coordinator = broker.group_coordinator.lock().await;
+    if api_version < OFFSET_FETCH_GROUPS_MIN_VERSION {
+        let topics = req.topics.map(|topics| topics.into_iter()
+            .map(|t| (t.name.0.to_string(), t.partition_indexes))
+            .collect());
+        let topics = coordinator.fetch_offsets(req.group_id.0.as_str(), topics, req.require_stable).into_iter()
+            .map(|(topic, partitions)| OffsetFetchResponseTopic::default()
+                .with_name(TopicName(StrBytes::from_string(topic)))
+                .with_partitions(partitions.into_iter()
+                    .map(|(partition, offset, error_code)| {
+                        let (offset, leader_epoch, metadata) = committed(offset);
+                        OffsetFetchResponsePartition::default()
+                            .with_partition_index(partition)
+                            .with_committed_offset(offset)
+                            .with_committed_leader_epoch(leader_epoch)
+                            .with_metadata(metadata)
+                            .with_error_code(error_code)
+                    })
+                    .collect()))
+            .collect();
+        return OffsetFetchResponse::default()
+            .with_topics(topics);
+    }
+    let groups = req.groups.into_iter()
+        .map(|group| {
+            let topics = group.topics.map(|topics| topics.into_iter()
+                .map(|t| (t.name.0.to_string(), t.partition_indexes))
+                .collect());
+            let topics = coordinator.fetch_offsets(group.group_id.0.as_str(), topics, req.require_stable).into_iter()
+                .map(|(topic, partitions)| OffsetFetchResponseTopics::default()
+                    .with_name(TopicName(StrBytes::from_string(topic)))
+                    .with_partitions(partitions.into_iter()
+                        .map(|(partition, offset, error_code)| {
+                            let (offset, leader_epoch, metadata) = committed(offset);
+                            OffsetFetchResponsePartitions::default()
+                                .with_partition_index(partition)
+                                .with_committed_offset(offset)
+                                .with_committed_leader_epoch(leader_epoch)
+                                .with_metadata(metadata)
+                                .with_error_code(error_code)
+                        })
+                        .collect()))
+                .collect();
+            OffsetFetchResponseGroup::default()
+                .with_group_id(group.group_id)
+                .with_topics(topics)
+        })
+        .collect();
+    OffsetFetchResponse::default()
+        .with_groups(groups)
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::{Bytes, BytesMut};
+    use kafka_protocol::error::ResponseError;
+    use kafka_protocol::records::{Record, RecordBatchDecoder};
+
+    use crate::txn::control_batch;
+
+    use super::{decode_offset_commit_key, decode_offset_commit_value, encode_offset_commit_key, encode_offset_commit_value, txn_offset_commit_batch, GroupCoordinator, OffsetAndMetadata};
+
+    fn offset(offset: i64) -> OffsetAndMetadata {
+        OffsetAndMetadata { offset, leader_epoch: 2, metadata: Some("meta".to_string()) }
+    }
+
+    fn records(mut buf: BytesMut) -> Vec<Record> {
+        RecordBatchDecoder::decode_all(&mut buf).unwrap().into_iter()
+            .flat_map(|record_set| record_set.records)
+            .collect()
+    }
+
+    #[test]
+    fn test_offset_commit_round_trip() {
+        let key = ("group".to_string(), "foo".to_string(), 3);
+        assert_eq!(Some(key.clone()), decode_offset_commit_key(&mut encode_offset_commit_key(&key)));
+        let value = encode_offset_commit_value(&offset(42), 1_000);
+        assert_eq!(Some(offset(42)), decode_offset_commit_value(&mut value.clone()));
+        for len in 0..value.len() {
+            assert_eq!(None, decode_offset_commit_value(&mut value.slice(..len)));
+        }
+        // group metadata shares the topic under key version 2
+        assert_eq!(None, decode_offset_commit_key(&mut Bytes::from_static(&[0, 2, 0, 0])));
+    }
+
+    #[test]
+    fn test_replay_txn_offsets() {
+        let committed = ("group".to_string(), "foo".to_string(), 0);
+        let aborted = ("group".to_string(), "foo".to_string(), 1);
+        let mut log = records(txn_offset_commit_batch(7, 0, &[(committed.clone(), offset(10))], 0));
+        log.extend(records(txn_offset_commit_batch(8, 0, &[(aborted.clone(), offset(20))], 0)));
+        log.extend(records(control_batch(7, 0, true, 1)));
+
+        let mut coordinator = GroupCoordinator::default();
+        for record in log.iter() {
+            coordinator.load(record);
+        }
+        assert_eq!(Some(&offset(10)), coordinator.committed_offset("group", "foo", 0));
+        assert_eq!(None, coordinator.committed_offset("group", "foo", 1));
+        assert!(coordinator.has_pending_txn_offsets("group", "foo", 1));
+        assert_eq!(vec![("foo".to_string(), 0)], coordinator.committed_partitions("group"));
+
+        // an offset waiting on a transaction is only refused when asked to be stable
+        let unstable = ResponseError::UnstableOffsetCommit.code();
+        let fetched = coordinator.fetch_offsets("group", Some(vec![("foo".to_string(), vec![0, 1])]), true);
+        assert_eq!(vec![("foo".to_string(), vec![(0, Some(offset(10)), 0), (1, None, unstable)])], fetched);
+        let fetched = coordinator.fetch_offsets("group", Some(vec![("foo".to_string(), vec![1])]), false);
+        assert_eq!(vec![("foo".to_string(), vec![(1, None, 0)])], fetched);
+
+        for record in records(control_batch(8, 0, false, 2)).iter() {
+            coordinator.load(record);
+        }
+        assert!(!coordinator.has_pending_txn_offsets("group", "foo", 1));
+        assert_eq!(None, coordinator.committed_offset("group", "foo", 1));
+    }
+}
@@ -0,0 +1,214 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use tokio::fs;
+
+pub const LEADER_EPOCH_CHECKPOINT: &str = "leader-epoch-checkpoint";
+const LEADER_EPOCH_CHECKPOINT_VERSION: i32 = 0;
+
+// the epoch and end offset of a lookup that found nothing
+pub const UNDEFINED_EPOCH: i32 = -1;
+pub const UNDEFINED_EPOCH_OFFSET: i64 = -1;
+
+/// The first offset written under a leader epoch.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EpochEntry {
+    pub epoch: i32,
+    pub start_offset: i64,
+}
+
+/// A partition's leader epochs with their start offsets, both increasing,
+/// from which the end offset of any epoch follows.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LeaderEpochCache {
+    entries: Vec<EpochEntry>,
+}
+
+impl LeaderEpochCache {
+    pub fn entries(&self) -> &[EpochEntry] {
+        &self.entries
+    }
+
+    pub fn latest_epoch(&self) -> Option<i32> {
+        self.entries.last().map(|entry| entry.epoch)
+    }
+
+    /// Records that `epoch` starts at `start_offset`. Entries it contradicts,
+    /// of a later epoch or starting at or after it, are dropped. Returns
+    /// whether the cache changed.
+    pub fn assign(&mut self, epoch: i32, start_offset: i64) -> bool {
+        if epoch < 0 || start_offset < 0 {
+            return false;
+        }
+        if self.entries.last().is_some_and(|last| last.epoch == epoch && last.start_offset <= start_offset) {
+            return false;
+        }
+        self.entries.retain(|entry| entry.epoch < epoch && entry.start_offset < start_offset);
+        self.entries.push(EpochEntry { epoch, start_offset });
+        true
+    }
+
+    /// The largest epoch up to `requested_epoch` and the offset where it
+    /// ends, the start of the next epoch or `log_end_offset` for the latest.
+    /// An epoch before the first entry ends where the first entry starts.
+    /// Undefined for an unknown epoch past the latest one.
+    pub fn end_offset_for(&self, requested_epoch: i32, log_end_offset: i64) -> (i32, i64) {
+        if requested_epoch == UNDEFINED_EPOCH {
+            return (UNDEFINED_EPOCH, UNDEFINED_EPOCH_OFFSET);
+        }
+        if self.latest_epoch() == Some(requested_epoch) {
+            return (requested_epoch, log_end_offset);
+        }
+        let Some(higher) = self.entries.iter().find(|entry| entry.epoch > requested_epoch) else {
+            return (UNDEFINED_EPOCH, UNDEFINED_EPOCH_OFFSET);
+        };
+        match self.entries.iter().rev().find(|entry| entry.epoch <= requested_epoch) {
+            Some(floor) => (floor.epoch, higher.start_offset),
+            None => (requested_epoch, higher.start_offset),
+        }
+    }
+
+    /// Where a follower truncates its log given the end offset of the
+    /// leader's last epoch it shares, as a Fetch diverging epoch tells it.
+    /// Without a common epoch it falls back to its high watermark; an epoch
+    /// the leader knows but this log does not truncates to the end of the
+    /// largest epoch below it, and the next fetch checks again from there.
+    pub fn truncation_offset(&self, leader_epoch: i32, leader_end_offset: i64, log_end_offset: i64, high_watermark: i64) -> i64 {
+        if leader_end_offset == UNDEFINED_EPOCH_OFFSET {
+            return high_watermark.min(log_end_offset);
+        }
+        if leader_epoch == UNDEFINED_EPOCH {
+            return leader_end_offset.min(log_end_offset);
+        }
+        match self.end_offset_for(leader_epoch, log_end_offset) {
+            (UNDEFINED_EPOCH, _) => high_watermark.min(log_end_offset),
+            (epoch, end_offset) if epoch != leader_epoch => end_offset.min(log_end_offset),
+            (_, end_offset) => end_offset.min(leader_end_offset).min(log_end_offset),
+        }
+    }
+
+    /// Drops the epochs starting at or after `end_offset`, as the log is
+    /// truncated there. Returns whether the cache changed.
+    pub fn truncate_from_end(&mut self, end_offset: i64) -> bool {
+        let len = self.entries.len();
+        self.entries.retain(|entry| entry.start_offset < end_offset);
+        self.entries.len() != len
+    }
+
+    /// Drops the epochs that end before `start_offset`, the log start
+    /// offset having moved past them, and has the first one left start
+    /// there. Returns whether the cache changed.
+    pub fn truncate_from_start(&mut self, start_offset: i64) -> bool {
+        let covering = self.entries.iter().take_while(|entry| entry.start_offset <= start_offset).count();
+        if covering == 0 || (covering == 1 && self.entries[0].start_offset == start_offset) {
+            return false;
+        }
+        self.entries.drain(..covering - 1);
+        self.entries[0].start_offset = start_offset;
+        true
+    }
+
+    pub fn clear(&mut self) -> bool {
+        let changed = !self.entries.is_empty();
+        self.entries.clear();
+        changed
+    }
+}
+
+/// The leader epoch checkpoint of a partition, in its log dir.
+pub fn leader_epoch_checkpoint_path(dir: &Path) -> PathBuf {
+    dir.join(LEADER_EPOCH_CHECKPOINT)
+}
+
+/// Parses Kafka's leader epoch checkpoint format: a version line, an entry
+/// count and one `epoch start_offset` line per entry.
+pub fn parse_leader_epoch_checkpoint(text: &str) -> io::Result<LeaderEpochCache> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+    let mut lines = text.lines();
+    let version: i32 = lines.next()
+        .and_then(|line| line.trim().parse().ok())
+        .ok_or_else(|| invalid("missing leader epoch checkpoint version"))?;
+    if version != LEADER_EPOCH_CHECKPOINT_VERSION {
+        return Err(invalid(&format!("unsupported leader epoch checkpoint version {}", version)));
+    }
+    let count: usize = lines.next()
+        .and_then(|line| line.trim().parse().ok())
+        .ok_or_else(|| invalid("missing leader epoch checkpoint entry count"))?;
+    let mut cache = LeaderEpochCache::default();
+    for line in lines.take(count) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [epoch, start_offset] = fields[..] else {
+            return Err(invalid(&format!("malformed leader epoch checkpoint entry {:?}", line)));
+        };
+        let epoch = epoch.parse().map_err(|_| invalid(line))?;
+        let start_offset = start_offset.parse().map_err(|_| invalid(line))?;
+        cache.entries.push(EpochEntry { epoch, start_offset });
+    }
+    if cache.entries.len() != count {
+        return Err(invalid("leader epoch checkpoint entry count mismatch"));
+    }
+    Ok(cache)
+}
+
+pub fn format_leader_epoch_checkpoint(cache: &LeaderEpochCache) -> String {
+    let mut text = format!("{}\n{}\n", LEADER_EPOCH_CHECKPOINT_VERSION, cache.entries.len());
+    for entry in cache.entries.iter() {
+        text.push_str(&format!("{} {}\n", entry.epoch, entry.start_offset));
+    }
+    text
+}
+
+/// Reads a partition's leader epoch checkpoint; a missing file is an
+/// empty cache.
+pub async fn read_leader_epoch_checkpoint(dir: &Path) -> io::Result<LeaderEpochCache> {
+    match fs::read_to_string(leader_epoch_checkpoint_path(dir)).await {
+        Ok(text) => parse_leader_epoch_checkpoint(&text),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(LeaderEpochCache::default()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Replaces a partition's leader epoch checkpoint through a temporary file.
+pub async fn write_leader_epoch_checkpoint(dir: &Path, cache: &LeaderEpochCache) -> io::Result<()> {
+    let path = leader_epoch_checkpoint_path(dir);
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, format_leader_epoch_checkpoint(cache)).await?;
+    fs::rename(&tmp, &path).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_leader_epoch_checkpoint, parse_leader_epoch_checkpoint, LeaderEpochCache, UNDEFINED_EPOCH, UNDEFINED_EPOCH_OFFSET};
+
+    #[test]
+    fn test_leader_epoch_cache() {
+        let mut cache = LeaderEpochCache::default();
+        assert!(cache.assign(0, 0));
+        assert!(!cache.assign(0, 5));
+        assert!(cache.assign(2, 10));
+        assert!(cache.assign(4, 15));
+        assert_eq!((0, 10), cache.end_offset_for(0, 20));
+        assert_eq!((2, 15), cache.end_offset_for(3, 20));
+        assert_eq!((4, 20), cache.end_offset_for(4, 20));
+        assert_eq!((UNDEFINED_EPOCH, UNDEFINED_EPOCH_OFFSET), cache.end_offset_for(5, 20));
+
+        // the leader's epoch 2 ended at 12, so this log diverges from there
+        assert_eq!(12, cache.truncation_offset(2, 12, 20, 8));
+        // the leader has an epoch 3 this log lacks: back to the end of epoch 2
+        assert_eq!(15, cache.truncation_offset(3, 13, 20, 8));
+        assert_eq!(8, cache.truncation_offset(0, UNDEFINED_EPOCH_OFFSET, 20, 8));
+
+        let text = format_leader_epoch_checkpoint(&cache);
+        assert_eq!("0\n3\n0 0\n2 10\n4 15\n", text);
+        assert_eq!(cache, parse_leader_epoch_checkpoint(&text).unwrap());
+        assert!(parse_leader_epoch_checkpoint("0\n2\n0 0\n").is_err());
+
+        // a new leader starting back at 12 overrides what came after
+        assert!(cache.assign(5, 12));
+        assert_eq!(vec![(0, 0), (2, 10), (5, 12)], cache.entries().iter().map(|entry| (entry.epoch, entry.start_offset)).collect::<Vec<_>>());
+        assert!(cache.truncate_from_start(11));
+        assert_eq!(vec![(2, 11), (5, 12)], cache.entries().iter().map(|entry| (entry.epoch, entry.start_offset)).collect::<Vec<_>>());
+        assert!(cache.truncate_from_end(12));
+        assert_eq!(Some(2), cache.latest_epoch());
+    }
+}
@@ -1 +1,38 @@
-pub mod record;
\ No newline at end of file
+pub mod acl;
+pub mod alter_configs;
+pub mod alter_replica_log_dirs;
+pub mod broker;
+pub mod checkpoint;
+pub mod client;
+pub mod config;
+pub mod delete_records;
+pub mod describe_cluster;
+pub mod describe_configs;
+pub mod describe_log_dirs;
+pub mod describe_topic_partitions;
+pub mod fetch;
+pub mod fetch_session;
+pub mod group;
+pub mod leader_epoch;
+pub mod list_offsets;
+pub mod log;
+pub mod log_config;
+pub mod log_dir;
+pub mod log_manager;
+pub mod log_validator;
+pub mod message_format;
+pub mod metadata;
+pub mod offset_for_leader_epoch;
+pub mod offset_index;
+pub mod produce;
+pub mod purgatory;
+pub mod raft;
+pub mod record;
+pub mod remote_log;
+pub mod replica;
+pub mod segment_cache;
+pub mod send;
+#[cfg(test)]
+pub mod test_util;
+pub mod txn;
+pub mod txn_index;
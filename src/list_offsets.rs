@@ -0,0 +1,54 @@
+use std::collections::HashMap;
+
+use kafka_protocol::error::ResponseError;
+use kafka_protocol::messages::list_offsets_response::{ListOffsetsPartitionResponse, ListOffsetsTopicResponse};
+use kafka_protocol::messages::{ListOffsetsRequest, ListOffsetsResponse};
+use uuid::Uuid;
+
+use crate::broker::Broker;
+
+// special ListOffsetsPartition.timestamp values
+pub const LATEST_TIMESTAMP: i64 = -1;
+pub const EARLIEST_TIMESTAMP: i64 = -2;
+pub const MAX_TIMESTAMP: i64 = -3;
+pub const EARLIEST_LOCAL_TIMESTAMP: i64 = -4;
+
+// ListOffsetsRequest.isolation_level
+const READ_COMMITTED: i8 = 1;
+
+pub async fn handle_list_offsets(broker: &Broker, req: ListOffsetsRequest, topics: &HashMap<String, (Uuid, Vec<i32>)>) -> ListOffsetsResponse {
+    let read_committed = req.isolation_level == READ_COMMITTED;
+    let mut responses = Vec::new();
+    for topic in req.topics.iter() {
+        let topic_name = topic.name.0.as_str();
+        let mut partitions = Vec::new();
+        for partition in topic.partitions.iter() {
+            let known = topics.get(topic_name)
+                .map(|(_, partition_ids)| partition_ids.contains(&partition.partition_index))
+                .unwrap_or(false);
+            let resp = ListOffsetsPartitionResponse::default()
+                .with_partition_index(partition.partition_index)
+                .with_timestamp(-1)
+                .with_offset(-1);
+            let resp = if !known {
+                resp.with_error_code(ResponseError::UnknownTopicOrPartition.code())
+            } else {
+                match broker.list_offset(topic_name, partition.partition_index, partition.timestamp, read_committed).await {
+                    Ok(Some((offset, timestamp))) => resp.with_offset(offset)
+                        .with_timestamp(timestamp),
+                    Ok(None) => resp,
+                    Err(e) => {
+                        println!("Failed to list offsets of {}-{}: {}", topic_name, partition.partition_index, e);
+                        resp.with_error_code(ResponseError::KafkaStorageError.code())
+                    }
+                }
+            };
+            partitions.push(resp);
+        }
+        responses.push(ListOffsetsTopicResponse::default()
+            .with_name(topic.name.clone())
+            .with_partitions(partitions));
+    }
+    ListOffsetsResponse::default()
+        .with_topics(responses)
+}
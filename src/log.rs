@@ -0,0 +1,1992 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, BufReader, Read};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use kafka_protocol::records::{Compression, Record, RecordBatchDecoder, RecordBatchEncoder, RecordEncodeOptions, RecordSet};
+use tokio::fs::{self, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use uuid::Uuid;
+
+use crate::config::BrokerConfig;
+use crate::leader_epoch::{read_leader_epoch_checkpoint, write_leader_epoch_checkpoint, LeaderEpochCache};
+use crate::log_config::LogConfig;
+use crate::log_dir::random_uuid;
+use crate::offset_index::{offset_index_path, OffsetIndex};
+use crate::remote_log::{read_remote_log_metadata, write_remote_log_metadata, LogSegmentData, RemoteLogSegmentMetadata};
+use crate::segment_cache::SegmentCache;
+use crate::txn_index::{append_txn_index, read_txn_index, txn_index_path, write_txn_index, AbortedTxn};
+
+pub const LOG_DIR: &str = "/tmp/kraft-combined-logs";
+pub const LOG_DIRS_CONFIG: &str = "log.dirs";
+// a partition being copied to another log dir, see AlterReplicaLogDirs
+pub const FUTURE_DIR_SUFFIX: &str = "-future";
+
+// baseOffset(8) + batchLength(4) + partitionLeaderEpoch(4) + magic(1) + crc(4)
+// + attributes(2) + lastOffsetDelta(4) + baseTimestamp(8) + maxTimestamp(8)
+// + producerId(8) + producerEpoch(2) + baseSequence(4) + recordsCount(4)
+pub const BATCH_HEADER_SIZE: usize = 61;
+// baseOffset + batchLength, not counted in batchLength itself
+pub const LOG_OVERHEAD: usize = 12;
+// the crc covers the batch from the attributes on
+const CRC_COVERED_OFFSET: usize = 21;
+const CRC_OFFSET: usize = 17;
+const PARTITION_LEADER_EPOCH_OFFSET: usize = 12;
+const ATTRIBUTES_OFFSET: usize = 21;
+const MAX_TIMESTAMP_OFFSET: usize = 35;
+const MIN_BATCH_LENGTH: i32 = (BATCH_HEADER_SIZE - LOG_OVERHEAD) as i32;
+const CURRENT_MAGIC: i8 = 2;
+// bytes between entries of a segment's offset index, as index.interval.bytes
+const INDEX_INTERVAL_BYTES: u64 = 4096;
+
+// written on a clean shutdown, its absence on startup triggers recovery
+pub const CLEAN_SHUTDOWN_FILE: &str = ".kafka_cleanshutdown";
+// the KRaft metadata log is written by the controller, not through PartitionLog
+pub const METADATA_TOPIC: &str = "__cluster_metadata";
+
+// files making up a segment, removed together when it is deleted
+const SEGMENT_SUFFIXES: &[&str] = &["log", "index", "txnindex"];
+
+// (topic_name, partition_idx)
+pub type TopicPartition = (String, i32);
+
+const COMPRESSION_CODEC_MASK: i16 = 0x07;
+const TIMESTAMP_TYPE_FLAG: i16 = 0x08;
+const TRANSACTIONAL_FLAG: i16 = 0x10;
+const CONTROL_FLAG: i16 = 0x20;
+
+// control record types, see org.apache.kafka.common.record.ControlRecordType
+pub const CONTROL_TYPE_ABORT: i16 = 0;
+pub const CONTROL_TYPE_COMMIT: i16 = 1;
+
+/// The configured log directories, `log.dirs` taking precedence over `log.dir`.
+pub fn log_dirs(config: &BrokerConfig) -> Vec<PathBuf> {
+    config.get(LOG_DIRS_CONFIG)
+        .or_else(|| config.get("log.dir"))
+        .unwrap_or(LOG_DIR)
+        .split(',')
+        .map(|dir| dir.trim())
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .collect()
+}
+
+/// Parses a partition directory name, `<topic>-<partition>` or
+/// `<topic>-<partition>.<id>-future`, into the partition and whether it is a
+/// future replica.
+pub fn parse_partition_dir_name(name: &str) -> Option<(TopicPartition, bool)> {
+    let (name, future) = match name.strip_suffix(FUTURE_DIR_SUFFIX) {
+        Some(name) => (name.rsplit_once('.')?.0, true),
+        None => (name, false),
+    };
+    let (topic_name, partition_idx) = name.rsplit_once('-')?;
+    let partition_idx = partition_idx.parse::<i32>().ok()?;
+    if topic_name == METADATA_TOPIC {
+        return None;
+    }
+    Some(((topic_name.to_string(), partition_idx), future))
+}
+
+pub fn partition_dir(log_dir: &Path, topic_name: &str, partition_idx: i32) -> PathBuf {
+    log_dir.join(format!("{}-{}", topic_name, partition_idx))
+}
+
+/// The directory a partition is copied into before it replaces the current
+/// replica in another log dir.
+pub fn future_partition_dir(log_dir: &Path, topic_name: &str, partition_idx: i32, unique_id: Uuid) -> PathBuf {
+    log_dir.join(format!("{}-{}.{}{}", topic_name, partition_idx, unique_id.simple(), FUTURE_DIR_SUFFIX))
+}
+
+pub fn segment_file_name(base_offset: i64, suffix: &str) -> String {
+    format!("{:020}.{}", base_offset, suffix)
+}
+
+pub fn segment_path(dir: &Path, base_offset: i64) -> PathBuf {
+    dir.join(segment_file_name(base_offset, "log"))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatchHeader {
+    pub base_offset: i64,
+    pub batch_length: i32,
+    pub partition_leader_epoch: i32,
+    pub magic: i8,
+    pub crc: u32,
+    pub attributes: i16,
+    pub last_offset_delta: i32,
+    pub base_timestamp: i64,
+    pub max_timestamp: i64,
+    pub producer_id: i64,
+    pub producer_epoch: i16,
+    pub base_sequence: i32,
+    pub records_count: i32,
+}
+
+impl BatchHeader {
+    pub fn parse(mut buf: &[u8]) -> Option<BatchHeader> {
+        if buf.len() < BATCH_HEADER_SIZE {
+            return None;
+        }
+        Some(BatchHeader {
+            base_offset: buf.get_i64(),
+            batch_length: buf.get_i32(),
+            partition_leader_epoch: buf.get_i32(),
+            magic: buf.get_i8(),
+            crc: buf.get_u32(),
+            attributes: buf.get_i16(),
+            last_offset_delta: buf.get_i32(),
+            base_timestamp: buf.get_i64(),
+            max_timestamp: buf.get_i64(),
+            producer_id: buf.get_i64(),
+            producer_epoch: buf.get_i16(),
+            base_sequence: buf.get_i32(),
+            records_count: buf.get_i32(),
+        })
+    }
+
+    /// Size of the whole batch on disk, including offset and length prefix.
+    pub fn size(&self) -> usize {
+        LOG_OVERHEAD + self.batch_length as usize
+    }
+
+    pub fn last_offset(&self) -> i64 {
+        self.base_offset + self.last_offset_delta as i64
+    }
+
+    pub fn next_offset(&self) -> i64 {
+        self.last_offset() + 1
+    }
+
+    /// The codec of the records, None for an unknown one.
+    pub fn compression(&self) -> Option<Compression> {
+        match self.attributes & COMPRESSION_CODEC_MASK {
+            0 => Some(Compression::None),
+            1 => Some(Compression::Gzip),
+            2 => Some(Compression::Snappy),
+            3 => Some(Compression::Lz4),
+            4 => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+
+    pub fn is_transactional(&self) -> bool {
+        self.attributes & TRANSACTIONAL_FLAG != 0
+    }
+
+    pub fn is_control(&self) -> bool {
+        self.attributes & CONTROL_FLAG != 0
+    }
+}
+
+/// Marks a batch as LogAppendTime with `timestamp` as its max timestamp,
+/// updating the crc.
+pub fn set_log_append_time(batch: &mut [u8], timestamp: i64) {
+    let attributes = (&batch[ATTRIBUTES_OFFSET..]).get_i16() | TIMESTAMP_TYPE_FLAG;
+    (&mut batch[ATTRIBUTES_OFFSET..ATTRIBUTES_OFFSET + 2]).put_i16(attributes);
+    (&mut batch[MAX_TIMESTAMP_OFFSET..MAX_TIMESTAMP_OFFSET + 8]).put_i64(timestamp);
+    let crc = crc32c::crc32c(&batch[CRC_COVERED_OFFSET..]);
+    (&mut batch[CRC_OFFSET..CRC_OFFSET + 4]).put_u32(crc);
+}
+
+/// Writes the header of a batch without its records, as compaction keeps the
+/// last batch of a producer so its epoch and sequence outlive the records.
+fn put_empty_batch(buf: &mut BytesMut, header: &BatchHeader) {
+    let start = buf.len();
+    buf.put_i64(header.base_offset);
+    buf.put_i32(MIN_BATCH_LENGTH);
+    buf.put_i32(header.partition_leader_epoch);
+    buf.put_i8(CURRENT_MAGIC);
+    buf.put_u32(0); // crc, set below
+    buf.put_i16(header.attributes & !COMPRESSION_CODEC_MASK);
+    buf.put_i32(header.last_offset_delta);
+    buf.put_i64(header.base_timestamp);
+    buf.put_i64(header.max_timestamp);
+    buf.put_i64(header.producer_id);
+    buf.put_i16(header.producer_epoch);
+    buf.put_i32(header.base_sequence);
+    buf.put_i32(0); // records count
+    let crc = crc32c::crc32c(&buf[start + CRC_COVERED_OFFSET..]);
+    (&mut buf[start + CRC_OFFSET..start + CRC_OFFSET + 4]).put_u32(crc);
+}
+
+/// Walks the complete batches in `buf`, yielding each batch's position and header.
+/// A trailing partial batch is ignored.
+pub fn batches(buf: &[u8]) -> Vec<(usize, BatchHeader)> {
+    let mut ret = Vec::new();
+    let mut pos = 0;
+    while let Some(header) = BatchHeader::parse(&buf[pos..]) {
+        if header.batch_length < 0 || pos + header.size() > buf.len() {
+            break;
+        }
+        let size = header.size();
+        ret.push((pos, header));
+        pos += size;
+    }
+    ret
+}
+
+/// Checks a batch's magic, lengths and CRC-32C.
+pub fn validate_batch(header: &BatchHeader, batch: &[u8]) -> Result<(), String> {
+    if header.magic != CURRENT_MAGIC {
+        return Err(format!("unsupported magic {}", header.magic));
+    }
+    if header.batch_length < MIN_BATCH_LENGTH {
+        return Err(format!("batch length {} below the header size", header.batch_length));
+    }
+    if header.last_offset_delta < 0 || header.records_count < 0 {
+        return Err(format!("invalid record count {}", header.records_count));
+    }
+    let crc = crc32c::crc32c(&batch[CRC_COVERED_OFFSET..]);
+    if crc != header.crc {
+        return Err(format!("crc {:#010x} does not match the computed {:#010x}", header.crc, crc));
+    }
+    Ok(())
+}
+
+/// The partition directories under a log dir, current and future replicas,
+/// except the metadata log's.
+pub async fn list_partition_dirs_in(log_dir: &Path) -> io::Result<Vec<(TopicPartition, PathBuf, bool)>> {
+    let mut dirs = Vec::new();
+    let mut entries = match fs::read_dir(log_dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(dirs),
+        Err(e) => return Err(e),
+    };
+    while let Some(entry) = entries.next_entry().await? {
+        if !entry.file_type().await?.is_dir() {
+            continue;
+        }
+        let file_name = entry.file_name();
+        let Some((topic_partition, future)) = parse_partition_dir_name(&file_name.to_string_lossy()) else { continue };
+        dirs.push((topic_partition, entry.path(), future));
+    }
+    dirs.sort();
+    Ok(dirs)
+}
+
+async fn remove_segment_files(dir: &Path, base_offset: i64) -> io::Result<()> {
+    for suffix in SEGMENT_SUFFIXES {
+        match fs::remove_file(dir.join(segment_file_name(base_offset, suffix))).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+async fn list_segments(dir: &Path) -> io::Result<Vec<i64>> {
+    let mut segments = Vec::new();
+    let mut entries = fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let file_name = entry.file_name();
+        let file_name = file_name.to_string_lossy();
+        if let Some(base_offset) = file_name.strip_suffix(".log") {
+            if let Ok(base_offset) = base_offset.parse::<i64>() {
+                segments.push(base_offset);
+            }
+        }
+    }
+    segments.sort();
+    Ok(segments)
+}
+
+/// Total bytes of the segment files in a partition directory.
+pub async fn partition_size(dir: &Path) -> io::Result<u64> {
+    let mut size = 0;
+    for base_offset in list_segments(dir).await? {
+        size += fs::metadata(segment_path(dir, base_offset)).await?.len();
+    }
+    Ok(size)
+}
+
+/// Copies the files of a partition directory into `dst`, only those modified
+/// since `since` when given, and removes the files `src` no longer has.
+/// Indexes are written through a memory map, which need not touch their
+/// modification time, so they are always copied. The files of a cleaner
+/// pass in progress are left out, it is dropped once the log moved.
+pub async fn copy_partition_dir(src: &Path, dst: &Path, since: Option<SystemTime>) -> io::Result<()> {
+    fs::create_dir_all(dst).await?;
+    let mut entries = fs::read_dir(src).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let metadata = entry.metadata().await?;
+        if !metadata.is_file() {
+            continue;
+        }
+        let path = entry.path();
+        if path.extension().is_some_and(|ext| ext == "cleaned") {
+            continue;
+        }
+        let is_index = path.extension().is_some_and(|ext| ext == "index" || ext == "txnindex");
+        let modified = match since {
+            Some(since) => metadata.modified()? >= since,
+            None => true,
+        };
+        if is_index || modified {
+            fs::copy(&path, dst.join(entry.file_name())).await?;
+        }
+    }
+    let mut entries = fs::read_dir(dst).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        if !fs::try_exists(src.join(entry.file_name())).await? {
+            fs::remove_file(entry.path()).await?;
+        }
+    }
+    Ok(())
+}
+
+/// The offset after the last complete batch of a partition directory, read
+/// from the batch headers of its last segment without opening the log.
+pub async fn read_log_end_offset(dir: &Path) -> io::Result<i64> {
+    let Some(base_offset) = list_segments(dir).await?.last().copied() else { return Ok(0) };
+    let file = std::fs::File::open(segment_path(dir, base_offset))?;
+    let len = file.metadata()?.len();
+    let mut header = [0; BATCH_HEADER_SIZE];
+    let (mut position, mut log_end_offset) = (0, base_offset);
+    while position + BATCH_HEADER_SIZE as u64 <= len {
+        file.read_exact_at(&mut header, position)?;
+        let Some(batch) = BatchHeader::parse(&header) else { break };
+        if batch.batch_length < MIN_BATCH_LENGTH || position + batch.size() as u64 > len {
+            break;
+        }
+        log_end_offset = batch.next_offset();
+        position += batch.size() as u64;
+    }
+    Ok(log_end_offset)
+}
+
+fn read_varint(buf: &mut &[u8]) -> Option<i64> {
+    let mut value: u64 = 0;
+    for shift in (0..64).step_by(7) {
+        if buf.is_empty() {
+            return None;
+        }
+        let byte = buf.get_u8();
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Some(((value >> 1) as i64) ^ -((value & 1) as i64));
+        }
+    }
+    None
+}
+
+/// Reads the type of the first control record in a control batch:
+/// 0 for ABORT, 1 for COMMIT.
+pub fn control_type(batch: &[u8]) -> Option<i16> {
+    let mut buf = batch.get(BATCH_HEADER_SIZE..)?;
+    read_varint(&mut buf)?; // length
+    buf.get_i8(); // attributes
+    read_varint(&mut buf)?; // timestamp delta
+    read_varint(&mut buf)?; // offset delta
+    let key_length = read_varint(&mut buf)?;
+    if key_length < 4 || buf.len() < 4 {
+        return None;
+    }
+    buf.get_i16(); // version
+    Some(buf.get_i16())
+}
+
+/// A byte range of a segment file. The file is held open, so the range stays
+/// readable after the segment is deleted or replaced by the cleaner.
+#[derive(Debug, Clone)]
+pub struct FileRegion {
+    pub file: Arc<std::fs::File>,
+    pub position: u64,
+    pub length: u64,
+}
+
+impl FileRegion {
+    pub async fn read(&self) -> io::Result<Vec<u8>> {
+        let region = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut bytes = vec![0; region.length as usize];
+            region.file.read_exact_at(&mut bytes, region.position)?;
+            Ok::<_, io::Error>(bytes)
+        }).await.map_err(io::Error::other)?
+    }
+}
+
+/// Fetched record batches, left in the segment files so they can be sent to
+/// the socket without copying them through the broker.
+#[derive(Debug, Clone, Default)]
+pub struct FileRecords {
+    // records already in memory, as those fetched from remote storage,
+    // which go before the regions
+    pub memory: Bytes,
+    pub regions: Vec<FileRegion>,
+}
+
+impl FileRecords {
+    pub fn len(&self) -> u64 {
+        self.memory.len() as u64 + self.regions.iter().map(|region| region.length).sum::<u64>()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Copies the records into memory.
+    pub async fn read(&self) -> io::Result<BytesMut> {
+        let mut buf = BytesMut::from(&self.memory[..]);
+        for region in self.regions.iter() {
+            buf.extend_from_slice(&region.read().await?);
+        }
+        Ok(buf)
+    }
+}
+
+/// Records read from a partition along with its offsets at the time of the read.
+#[derive(Debug, Default)]
+pub struct FetchedData {
+    pub records: FileRecords,
+    pub high_watermark: i64,
+    pub last_stable_offset: i64,
+    pub log_start_offset: i64,
+    pub log_end_offset: i64,
+    pub aborted_transactions: Vec<AbortedTxn>,
+    // set instead of the records when the fetch offset is only in remote
+    // storage, in this segment
+    pub remote_segment: Option<RemoteLogSegmentMetadata>,
+}
+
+/// A segment and what rolling, retention and reads need to know about it.
+#[derive(Debug)]
+struct Segment {
+    base_offset: i64,
+    size: u64,
+    // max timestamp of the first batch, segment.ms counts from it
+    first_timestamp: Option<i64>,
+    max_timestamp: i64,
+    // an entry per INDEX_INTERVAL_BYTES
+    index: OffsetIndex,
+}
+
+impl Segment {
+    fn new(dir: &Path, base_offset: i64) -> io::Result<Segment> {
+        Ok(Segment::with_index(base_offset, OffsetIndex::create(offset_index_path(dir, base_offset), base_offset)?))
+    }
+
+    fn with_index(base_offset: i64, index: OffsetIndex) -> Segment {
+        Segment {
+            base_offset,
+            size: 0,
+            first_timestamp: None,
+            max_timestamp: -1,
+            index,
+        }
+    }
+
+    fn track(&mut self, header: &BatchHeader) {
+        let position = self.size;
+        if self.index.last_position().map(|indexed| position - indexed >= INDEX_INTERVAL_BYTES).unwrap_or(true) {
+            self.index.append(header.base_offset, position);
+        }
+        self.size += header.size() as u64;
+        self.first_timestamp.get_or_insert(header.max_timestamp);
+        self.max_timestamp = self.max_timestamp.max(header.max_timestamp);
+    }
+}
+
+// Position and header of the first batch in a segment ending at or after
+// `offset`, starting from the closest index entry. A batch running past the
+// end of the segment fails with InvalidData.
+fn locate_batch(file: &std::fs::File, segment: &Segment, offset: i64) -> io::Result<Option<(u64, BatchHeader)>> {
+    let mut position = segment.index.lookup(offset);
+    let mut buf = [0; BATCH_HEADER_SIZE];
+    while position < segment.size {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("invalid batch at position {} of segment {}", position, segment.base_offset));
+        if position + BATCH_HEADER_SIZE as u64 > segment.size {
+            return Err(invalid());
+        }
+        file.read_exact_at(&mut buf, position)?;
+        let header = BatchHeader::parse(&buf).ok_or_else(invalid)?;
+        if header.batch_length < MIN_BATCH_LENGTH || position + header.size() as u64 > segment.size {
+            return Err(invalid());
+        }
+        if header.last_offset() >= offset {
+            return Ok(Some((position, header)));
+        }
+        position += header.size() as u64;
+    }
+    Ok(None)
+}
+
+// The headers of the complete batches in a segment file, read without their
+// records. Control batches come whole, the producer state needs their type.
+fn read_batch_headers(path: &Path) -> io::Result<Vec<(BatchHeader, Vec<u8>)>> {
+    let file = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let len = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+    let mut buf = [0; BATCH_HEADER_SIZE];
+    let mut headers = Vec::new();
+    let mut position = 0;
+    while position + BATCH_HEADER_SIZE as u64 <= len {
+        reader.read_exact(&mut buf)?;
+        let Some(header) = BatchHeader::parse(&buf) else { break };
+        if header.batch_length < MIN_BATCH_LENGTH || position + header.size() as u64 > len {
+            break;
+        }
+        let mut batch = Vec::new();
+        if header.is_control() {
+            batch.resize(header.size(), 0);
+            batch[..BATCH_HEADER_SIZE].copy_from_slice(&buf);
+            reader.read_exact(&mut batch[BATCH_HEADER_SIZE..])?;
+        } else {
+            reader.seek_relative((header.size() - BATCH_HEADER_SIZE) as i64)?;
+        }
+        position += header.size() as u64;
+        headers.push((header, batch));
+    }
+    Ok(headers)
+}
+
+/// Decodes a single batch, decompressing its records.
+pub fn decode_batch(batch: &[u8]) -> io::Result<RecordSet> {
+    let mut buf = Bytes::copy_from_slice(batch);
+    RecordBatchDecoder::decode(&mut buf)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+}
+
+// first record in a batch whose (offset, timestamp) matches
+fn find_record(batch: &[u8], matches: impl Fn(i64, i64) -> bool) -> io::Result<Option<(i64, i64)>> {
+    let record_set = decode_batch(batch)?;
+    Ok(record_set.records.iter()
+        .find(|record| matches(record.offset, record.timestamp))
+        .map(|record| (record.offset, record.timestamp)))
+}
+
+#[derive(Debug)]
+pub struct PartitionLog {
+    dir: PathBuf,
+    config: LogConfig,
+    // open segment files, shared with the other logs
+    cache: Arc<SegmentCache>,
+    segments: Vec<Segment>,
+    // may sit inside the first segment after DeleteRecords
+    log_start_offset: i64,
+    log_end_offset: i64,
+    high_watermark: i64,
+    // offsets below it are synced to disk and skipped by recovery
+    recovery_point: i64,
+    // producer id -> first offset of its ongoing transaction
+    ongoing_txns: BTreeMap<i64, i64>,
+    // producer id -> base offset of its last data batch
+    last_batches: BTreeMap<i64, i64>,
+    aborted_txns: Vec<AbortedTxn>,
+    // the active segment opened for appends, until it rolls
+    writer: Option<fs::File>,
+    // segments copied to remote storage, oldest first; the log start offset
+    // sits in the first one while they reach the local segments
+    remote_segments: Vec<RemoteLogSegmentMetadata>,
+    // kept in the leader-epoch-checkpoint file
+    leader_epochs: LeaderEpochCache,
+}
+
+impl PartitionLog {
+    pub async fn open(dir: PathBuf, config: LogConfig, cache: Arc<SegmentCache>) -> io::Result<PartitionLog> {
+        Ok(PartitionLog::load(dir, config, cache, None).await?.0)
+    }
+
+    /// Opens a log after an unclean shutdown: validates the batches from the
+    /// segment holding `recovery_point` on, truncates the log at the first
+    /// corrupt or partial batch and rebuilds the transaction indexes of the
+    /// recovered segments. Returns the log and the number of bytes truncated.
+    pub async fn recover(dir: PathBuf, config: LogConfig, cache: Arc<SegmentCache>, recovery_point: i64) -> io::Result<(PartitionLog, u64)> {
+        PartitionLog::load(dir, config, cache, Some(recovery_point)).await
+    }
+
+    async fn load(dir: PathBuf, config: LogConfig, cache: Arc<SegmentCache>, recovery_point: Option<i64>) -> io::Result<(PartitionLog, u64)> {
+        fs::create_dir_all(&dir).await?;
+        let mut base_offsets = list_segments(&dir).await?;
+        if base_offsets.is_empty() {
+            base_offsets.push(0);
+        }
+        let recover_from = match recovery_point {
+            Some(recovery_point) => base_offsets.iter()
+                .copied()
+                .take_while(|base_offset| *base_offset <= recovery_point)
+                .last()
+                .unwrap_or(base_offsets[0]),
+            None => i64::MAX,
+        };
+        let mut log = PartitionLog {
+            log_start_offset: base_offsets[0],
+            log_end_offset: base_offsets[0],
+            high_watermark: base_offsets[0],
+            recovery_point: base_offsets[0],
+            dir,
+            config,
+            cache,
+            segments: Vec::new(),
+            ongoing_txns: BTreeMap::new(),
+            last_batches: BTreeMap::new(),
+            aborted_txns: Vec::new(),
+            writer: None,
+            remote_segments: Vec::new(),
+            leader_epochs: LeaderEpochCache::default(),
+        };
+        log.remote_segments = read_remote_log_metadata(&log.dir).await?;
+        let checkpointed = read_leader_epoch_checkpoint(&log.dir).await?;
+        log.leader_epochs = checkpointed.clone();
+        if log.remote_segments.last().is_some_and(|last| last.end_offset + 1 >= log.log_start_offset) {
+            log.log_start_offset = log.log_start_offset.min(log.remote_segments[0].start_offset);
+        }
+        let mut truncated = 0;
+        let active = *base_offsets.last().unwrap();
+        for base_offset in base_offsets {
+            let path = segment_path(&log.dir, base_offset);
+            if truncated > 0 {
+                // nothing after a corrupt batch can be trusted
+                truncated += fs::metadata(&path).await.map(|metadata| metadata.len()).unwrap_or(0);
+                remove_segment_files(&log.dir, base_offset).await?;
+                log.cache.evict(&path);
+                continue;
+            }
+            // only the active segment's index stays writable
+            if let Some(previous) = log.segments.last_mut() {
+                previous.index.close()?;
+            }
+            log.log_end_offset = log.log_end_offset.max(base_offset);
+            if base_offset < recover_from {
+                // trusted as written, so only the batch headers are read
+                let headers = read_batch_headers(&path)?;
+                let size = headers.iter().map(|(header, _)| header.size() as u64).sum();
+                // the active segment's preallocated index was never trimmed
+                let index = if base_offset == active {
+                    None
+                } else {
+                    OffsetIndex::open(offset_index_path(&log.dir, base_offset), base_offset, size)?
+                };
+                let mut segment = match index {
+                    Some(index) => Segment::with_index(base_offset, index),
+                    None => Segment::new(&log.dir, base_offset)?,
+                };
+                log.aborted_txns.extend(read_txn_index(&txn_index_path(&log.dir, base_offset)).await?);
+                for (header, batch) in headers {
+                    log.track_producer(&header, &batch);
+                    log.log_end_offset = header.next_offset();
+                    segment.track(&header);
+                }
+                log.segments.push(segment);
+            } else {
+                let bytes = log.read_segment(base_offset).await?;
+                let mut segment = Segment::new(&log.dir, base_offset)?;
+                // the epochs of the recovered batches are assigned again
+                log.leader_epochs.truncate_from_end(base_offset);
+                let mut aborted = Vec::new();
+                let mut valid_len = 0;
+                for (pos, header) in batches(&bytes) {
+                    let batch = &bytes[pos..pos + header.size()];
+                    let valid = if header.base_offset < log.log_end_offset {
+                        Err(format!("offset {} below the log end offset {}", header.base_offset, log.log_end_offset))
+                    } else {
+                        validate_batch(&header, batch)
+                    };
+                    if let Err(reason) = valid {
+                        println!("Invalid batch at position {} of {}: {}", pos, path.display(), reason);
+                        break;
+                    }
+                    aborted.extend(log.track_producer(&header, batch));
+                    if log.leader_epochs.latest_epoch().map_or(true, |latest| header.partition_leader_epoch > latest) {
+                        log.leader_epochs.assign(header.partition_leader_epoch, header.base_offset);
+                    }
+                    log.log_end_offset = header.next_offset();
+                    segment.track(&header);
+                    valid_len = pos + header.size();
+                }
+                if valid_len < bytes.len() {
+                    let file = OpenOptions::new().write(true).open(&path).await?;
+                    file.set_len(valid_len as u64).await?;
+                    file.sync_all().await?;
+                    truncated += (bytes.len() - valid_len) as u64;
+                }
+                write_txn_index(&txn_index_path(&log.dir, base_offset), &aborted).await?;
+                log.aborted_txns.extend(aborted);
+                log.segments.push(segment);
+            }
+        }
+        log.high_watermark = log.log_end_offset;
+        log.recovery_point = log.log_end_offset;
+        log.leader_epochs.truncate_from_end(log.log_end_offset);
+        if log.leader_epochs != checkpointed {
+            write_leader_epoch_checkpoint(&log.dir, &log.leader_epochs).await?;
+        }
+        Ok((log, truncated))
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn config(&self) -> &LogConfig {
+        &self.config
+    }
+
+    pub fn set_config(&mut self, config: LogConfig) {
+        self.config = config;
+    }
+
+    pub fn log_end_offset(&self) -> i64 {
+        self.log_end_offset
+    }
+
+    pub fn log_start_offset(&self) -> i64 {
+        self.log_start_offset
+    }
+
+    /// The first offset still on local disk, above the log start offset
+    /// when older segments are only in remote storage.
+    pub fn local_log_start_offset(&self) -> i64 {
+        self.log_start_offset.max(self.segments[0].base_offset)
+    }
+
+    pub fn remote_segments(&self) -> &[RemoteLogSegmentMetadata] {
+        &self.remote_segments
+    }
+
+    pub fn high_watermark(&self) -> i64 {
+        self.high_watermark
+    }
+
+    /// Restores the high watermark from the replication offset checkpoint.
+    pub fn restore_high_watermark(&mut self, high_watermark: i64) {
+        self.high_watermark = high_watermark.clamp(self.log_start_offset, self.log_end_offset);
+    }
+
+    pub fn recovery_point(&self) -> i64 {
+        self.recovery_point
+    }
+
+    pub fn leader_epochs(&self) -> &LeaderEpochCache {
+        &self.leader_epochs
+    }
+
+    /// The largest epoch up to `epoch` this log has and the offset where it
+    /// ends, as OffsetForLeaderEpoch answers.
+    pub fn end_offset_for_epoch(&self, epoch: i32) -> (i32, i64) {
+        self.leader_epochs.end_offset_for(epoch, self.log_end_offset)
+    }
+
+    /// Starts `leader_epoch` at the log end offset, as a new leader does
+    /// before it appends.
+    pub async fn assign_leader_epoch(&mut self, leader_epoch: i32) -> io::Result<()> {
+        if self.leader_epochs.assign(leader_epoch, self.log_end_offset) {
+            write_leader_epoch_checkpoint(&self.dir, &self.leader_epochs).await?;
+        }
+        Ok(())
+    }
+
+    /// Total size of the segments in bytes.
+    pub fn size(&self) -> u64 {
+        self.segments.iter().map(|segment| segment.size).sum()
+    }
+
+    /// The first offset of the oldest ongoing transaction, or the high
+    /// watermark when no transaction is open.
+    pub fn last_stable_offset(&self) -> i64 {
+        self.ongoing_txns.values()
+            .min()
+            .copied()
+            .unwrap_or(self.high_watermark())
+            .min(self.high_watermark())
+    }
+
+    // Updates the ongoing transactions for an appended batch, returning the
+    // aborted transaction it completes, if any.
+    fn track_producer(&mut self, header: &BatchHeader, batch: &[u8]) -> Option<AbortedTxn> {
+        if header.producer_id >= 0 && !header.is_control() {
+            self.last_batches.insert(header.producer_id, header.base_offset);
+        }
+        if !header.is_transactional() {
+            return None;
+        }
+        if !header.is_control() {
+            self.ongoing_txns.entry(header.producer_id).or_insert(header.base_offset);
+            return None;
+        }
+        let first_offset = self.ongoing_txns.remove(&header.producer_id)?;
+        if control_type(batch) == Some(CONTROL_TYPE_ABORT) {
+            Some(AbortedTxn {
+                producer_id: header.producer_id,
+                first_offset,
+                last_offset: header.last_offset(),
+                last_stable_offset: self.ongoing_txns.values().min().copied().unwrap_or(header.next_offset()),
+            })
+        } else {
+            None
+        }
+    }
+
+    async fn read_segment(&self, base_offset: i64) -> io::Result<Vec<u8>> {
+        match fs::read(segment_path(&self.dir, base_offset)).await {
+            Ok(bytes) => Ok(bytes),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub async fn read_all(&self) -> io::Result<BytesMut> {
+        let mut buf = BytesMut::new();
+        for segment in self.segments.iter() {
+            buf.extend_from_slice(&self.read_segment(segment.base_offset).await?);
+        }
+        Ok(buf)
+    }
+
+    /// Locates the batches containing offsets from `fetch_offset` up to the
+    /// high watermark, or up to the last stable offset for read_committed.
+    /// As in Kafka, a fetch is served from a single segment and returns up to
+    /// `max_bytes`, but always the whole first batch so consumers make progress.
+    /// Offsets below the local log start offset are left to the caller to
+    /// fetch from remote storage, see `FetchedData::remote_segment`.
+    pub async fn read(&self, fetch_offset: i64, max_bytes: i32, read_committed: bool) -> io::Result<FetchedData> {
+        let upper_bound = if read_committed { self.last_stable_offset() } else { self.high_watermark() };
+        self.read_upto(fetch_offset, max_bytes, upper_bound, read_committed).await
+    }
+
+    /// Reads as `read` does but up to the log end offset, for a follower
+    /// copying the records that are not committed yet.
+    pub async fn read_for_follower(&self, fetch_offset: i64, max_bytes: i32) -> io::Result<FetchedData> {
+        self.read_upto(fetch_offset, max_bytes, self.log_end_offset, false).await
+    }
+
+    async fn read_upto(&self, fetch_offset: i64, max_bytes: i32, upper_bound: i64, read_committed: bool) -> io::Result<FetchedData> {
+        if fetch_offset >= self.log_start_offset && fetch_offset < self.local_log_start_offset() {
+            if let Some(segment) = self.remote_segments.iter().find(|segment| segment.end_offset >= fetch_offset) {
+                return Ok(FetchedData {
+                    high_watermark: self.high_watermark(),
+                    last_stable_offset: self.last_stable_offset(),
+                    log_start_offset: self.log_start_offset(),
+                    log_end_offset: self.log_end_offset,
+                    remote_segment: Some(segment.clone()),
+                    ..FetchedData::default()
+                });
+            }
+        }
+        let mut records = FileRecords::default();
+        let first = self.segments.partition_point(|segment| segment.base_offset <= fetch_offset).saturating_sub(1);
+        for segment in self.segments[first..].iter() {
+            if segment.base_offset >= upper_bound {
+                break;
+            }
+            let file = match self.cache.open(&segment_path(&self.dir, segment.base_offset)) {
+                Ok(file) => file,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            // the cleaner may have emptied the rest of this segment
+            let Some((start, first_batch)) = locate_batch(&file, segment, fetch_offset)? else { continue };
+            if first_batch.base_offset >= upper_bound {
+                break;
+            }
+            let end = match locate_batch(&file, segment, upper_bound)? {
+                Some((position, header)) if header.base_offset >= upper_bound => position,
+                Some((position, header)) => position + header.size() as u64,
+                None => segment.size,
+            };
+            let length = (end - start).min((max_bytes.max(0) as u64).max(first_batch.size() as u64));
+            records.regions.push(FileRegion {
+                file,
+                position: start,
+                length,
+            });
+            break;
+        }
+        let aborted_transactions = if read_committed {
+            self.aborted_txns.iter()
+                .filter(|txn| txn.last_offset >= fetch_offset && txn.first_offset < upper_bound)
+                .cloned()
+                .collect()
+        } else {
+            Vec::new()
+        };
+        Ok(FetchedData {
+            records,
+            high_watermark: self.high_watermark(),
+            last_stable_offset: self.last_stable_offset(),
+            log_start_offset: self.log_start_offset(),
+            log_end_offset: self.log_end_offset,
+            aborted_transactions,
+            remote_segment: None,
+        })
+    }
+
+    /// Finds the first record with a timestamp at or after `timestamp`, as
+    /// (offset, timestamp).
+    pub async fn offset_for_timestamp(&self, timestamp: i64) -> io::Result<Option<(i64, i64)>> {
+        for segment in self.segments.iter().filter(|segment| segment.max_timestamp >= timestamp) {
+            let bytes = self.read_segment(segment.base_offset).await?;
+            for (pos, header) in batches(&bytes) {
+                if header.is_control() || header.max_timestamp < timestamp || header.last_offset() < self.log_start_offset {
+                    continue;
+                }
+                let found = find_record(&bytes[pos..pos + header.size()], |offset, t| offset >= self.log_start_offset && t >= timestamp)?;
+                if found.is_some() {
+                    return Ok(found);
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// The first record carrying the largest timestamp in the local
+    /// segments, as (offset, timestamp).
+    pub async fn max_timestamp_offset(&self) -> io::Result<Option<(i64, i64)>> {
+        let max_timestamp = self.segments.iter().map(|segment| segment.max_timestamp).max().unwrap_or(-1);
+        if max_timestamp < 0 {
+            return Ok(None);
+        }
+        self.offset_for_timestamp(max_timestamp).await
+    }
+
+    /// The largest timestamp in the log, remote segments included.
+    pub fn max_timestamp(&self) -> i64 {
+        self.segments.iter().map(|segment| segment.max_timestamp)
+            .chain(self.remote_segments.iter().map(|segment| segment.max_timestamp))
+            .max()
+            .unwrap_or(-1)
+    }
+
+    /// The oldest remote segment above the log start offset holding a record
+    /// at or after `timestamp`, where a timestamp lookup starts.
+    pub fn remote_segment_for_timestamp(&self, timestamp: i64) -> Option<&RemoteLogSegmentMetadata> {
+        self.remote_segments.iter()
+            .find(|segment| segment.end_offset >= self.log_start_offset && segment.max_timestamp >= timestamp)
+    }
+
+    fn active_segment(&self) -> PathBuf {
+        segment_path(&self.dir, self.segments.last().unwrap().base_offset)
+    }
+
+    async fn sync_active_segment(&self) -> io::Result<()> {
+        if let Some(writer) = &self.writer {
+            return writer.sync_all().await;
+        }
+        match OpenOptions::new().append(true).open(self.active_segment()).await {
+            Ok(file) => file.sync_all().await,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Syncs the active segment to disk, moving the recovery point to the
+    /// log end offset.
+    pub async fn flush(&mut self) -> io::Result<()> {
+        self.sync_active_segment().await?;
+        self.recovery_point = self.log_end_offset;
+        Ok(())
+    }
+
+    /// Points the log at a copy of its directory, byte for byte the same,
+    /// as when the log moves to another log dir. Nothing is reloaded: the
+    /// indexes are mapped from the copy and the old files evicted.
+    pub fn relocate(&mut self, dir: PathBuf) -> io::Result<()> {
+        let indexes = self.segments.iter()
+            .map(|segment| segment.index.reopen_at(offset_index_path(&dir, segment.base_offset)))
+            .collect::<io::Result<Vec<OffsetIndex>>>()?;
+        for (segment, index) in self.segments.iter_mut().zip(indexes) {
+            self.cache.evict(&segment_path(&self.dir, segment.base_offset));
+            segment.index = index;
+        }
+        self.writer = None;
+        self.dir = dir;
+        Ok(())
+    }
+
+    fn should_roll(&self, size: u64, max_timestamp: i64) -> bool {
+        let active = self.segments.last().unwrap();
+        if active.size == 0 {
+            return false;
+        }
+        active.size + size > self.config.segment_bytes
+            || active.first_timestamp.map(|first| max_timestamp - first > self.config.segment_ms).unwrap_or(false)
+    }
+
+    /// Starts a new active segment at the log end offset, syncing the old one.
+    async fn roll(&mut self) -> io::Result<()> {
+        self.flush().await?;
+        self.writer = None;
+        self.segments.last_mut().unwrap().index.close()?;
+        let segment = Segment::new(&self.dir, self.log_end_offset)?;
+        // created right away so the log end offset survives a restart
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(segment_path(&self.dir, segment.base_offset))
+            .await?;
+        self.segments.push(segment);
+        Ok(())
+    }
+
+    /// Appends the record batches in `records`, assigning offsets from the
+    /// current log end offset, and commits them right away as the only
+    /// replica. Returns the base offset of the first batch.
+    pub async fn append(&mut self, records: &[u8]) -> io::Result<i64> {
+        let leader_epoch = self.leader_epochs.latest_epoch().unwrap_or(0);
+        let base_offset = self.append_as_leader(records, leader_epoch).await?;
+        self.high_watermark = self.log_end_offset;
+        Ok(base_offset)
+    }
+
+    /// Appends the record batches in `records` as the leader of a replicated
+    /// partition, assigning offsets from the current log end offset and
+    /// stamping the batches with `leader_epoch`. The high watermark stays
+    /// where it is until the followers have the records, see
+    /// `advance_high_watermark`.
+    pub async fn append_as_leader(&mut self, records: &[u8], leader_epoch: i32) -> io::Result<i64> {
+        let mut buf = BytesMut::from(records);
+        let base_offset = self.log_end_offset;
+        let mut next_offset = base_offset;
+        let mut headers = batches(&buf);
+        if headers.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "no complete record batch"));
+        }
+        for (pos, header) in headers.iter_mut() {
+            // the crc starts at the attributes, so the base offset and leader epoch can be rewritten in place
+            (&mut buf[*pos..*pos + 8]).put_i64(next_offset);
+            (&mut buf[*pos + PARTITION_LEADER_EPOCH_OFFSET..*pos + PARTITION_LEADER_EPOCH_OFFSET + 4]).put_i32(leader_epoch);
+            header.base_offset = next_offset;
+            header.partition_leader_epoch = leader_epoch;
+            next_offset = header.next_offset();
+        }
+        let len = headers.last().map(|(pos, header)| pos + header.size()).unwrap();
+        buf.truncate(len);
+        self.write_batches(&buf, &headers).await?;
+        Ok(base_offset)
+    }
+
+    /// Appends record batches fetched from the leader, keeping their
+    /// offsets. Batches the log already has are skipped, and one that does
+    /// not follow on from the log end offset fails with InvalidData. Returns
+    /// the number of batches appended.
+    pub async fn append_as_follower(&mut self, records: &[u8]) -> io::Result<usize> {
+        // a fetch may end in a partial batch, which batches() leaves out
+        let headers: Vec<(usize, BatchHeader)> = batches(records).into_iter()
+            .filter(|(_, header)| header.last_offset() >= self.log_end_offset)
+            .collect();
+        let Some((start, _)) = headers.first() else { return Ok(0) };
+        let start = *start;
+        let mut next_offset = self.log_end_offset;
+        for (pos, header) in headers.iter() {
+            if header.base_offset != next_offset {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("batch at offset {} does not follow offset {}", header.base_offset, next_offset)));
+            }
+            validate_batch(header, &records[*pos..*pos + header.size()])
+                .map_err(|reason| io::Error::new(io::ErrorKind::InvalidData, reason))?;
+            next_offset = header.next_offset();
+        }
+        let (last, header) = headers.last().unwrap();
+        let buf = &records[start..last + header.size()];
+        let headers: Vec<(usize, BatchHeader)> = headers.iter()
+            .map(|(pos, header)| (pos - start, header.clone()))
+            .collect();
+        self.write_batches(buf, &headers).await?;
+        Ok(headers.len())
+    }
+
+    // writes batches with their offsets assigned to the active segment,
+    // rolling it first if they do not fit
+    async fn write_batches(&mut self, buf: &[u8], headers: &[(usize, BatchHeader)]) -> io::Result<()> {
+        let max_timestamp = headers.iter().map(|(_, header)| header.max_timestamp).max().unwrap();
+        if self.should_roll(buf.len() as u64, max_timestamp) {
+            self.roll().await?;
+        }
+        if self.writer.is_none() {
+            self.writer = Some(OpenOptions::new().create(true).append(true).open(self.active_segment()).await?);
+        }
+        let writer = self.writer.as_mut().unwrap();
+        if let Err(e) = async { writer.write_all(buf).await?; writer.flush().await }.await {
+            // reopened on the next append, whatever this one left behind
+            self.writer = None;
+            return Err(e);
+        }
+        self.log_end_offset = headers.last().unwrap().1.next_offset();
+
+        let mut aborted = Vec::new();
+        for (pos, header) in headers.iter() {
+            self.segments.last_mut().unwrap().track(header);
+            if let Some(txn) = self.track_producer(header, &buf[*pos..*pos + header.size()]) {
+                aborted.push(txn);
+            }
+        }
+        let active = self.segments.last().unwrap().base_offset;
+        append_txn_index(&txn_index_path(&self.dir, active), &aborted).await?;
+        self.aborted_txns.extend(aborted);
+
+        let mut assigned = false;
+        for (_, header) in headers.iter() {
+            assigned |= self.leader_epochs.assign(header.partition_leader_epoch, header.base_offset);
+        }
+        if assigned {
+            write_leader_epoch_checkpoint(&self.dir, &self.leader_epochs).await?;
+        }
+        Ok(())
+    }
+
+    /// Moves the high watermark forward to `offset`, up to the log end
+    /// offset. Returns whether it moved.
+    pub fn advance_high_watermark(&mut self, offset: i64) -> bool {
+        let offset = offset.min(self.log_end_offset);
+        if offset <= self.high_watermark {
+            return false;
+        }
+        self.high_watermark = offset;
+        true
+    }
+
+    /// Removes the records from `offset` on, as a follower does with what it
+    /// has beyond its leader's log. The log is cut at the start of the batch
+    /// holding `offset`, and the active segment is recovered from there.
+    pub async fn truncate_to(&mut self, offset: i64) -> io::Result<()> {
+        if offset >= self.log_end_offset {
+            return Ok(());
+        }
+        if offset <= self.segments[0].base_offset {
+            return self.truncate_fully_and_start_at(offset.max(self.log_start_offset)).await;
+        }
+        let keep = self.segments.partition_point(|segment| segment.base_offset <= offset);
+        for segment in self.segments[keep..].iter() {
+            remove_segment_files(&self.dir, segment.base_offset).await?;
+            self.cache.evict(&segment_path(&self.dir, segment.base_offset));
+        }
+        let segment = &self.segments[keep - 1];
+        let path = segment_path(&self.dir, segment.base_offset);
+        let position = match self.cache.open(&path) {
+            Ok(file) => locate_batch(&file, segment, offset)?.map(|(position, _)| position),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        if let Some(position) = position {
+            let file = OpenOptions::new().write(true).open(&path).await?;
+            file.set_len(position).await?;
+            file.sync_all().await?;
+        }
+        self.cache.evict(&path);
+        // rebuilds the producer state and the transaction index of the cut segment
+        let (log_start_offset, high_watermark, recovery_point) = (self.log_start_offset, self.high_watermark, self.recovery_point);
+        let base_offset = segment.base_offset;
+        *self = PartitionLog::load(self.dir.clone(), self.config.clone(), self.cache.clone(), Some(base_offset)).await?.0;
+        self.log_start_offset = log_start_offset.min(self.log_end_offset);
+        self.high_watermark = high_watermark.min(self.log_end_offset);
+        self.recovery_point = recovery_point.min(self.log_end_offset);
+        Ok(())
+    }
+
+    /// Deletes every local segment and starts the log over, empty, at
+    /// `offset`, as a follower does when its leader no longer has the
+    /// offsets it would fetch next.
+    pub async fn truncate_fully_and_start_at(&mut self, offset: i64) -> io::Result<()> {
+        for segment in self.segments.iter() {
+            remove_segment_files(&self.dir, segment.base_offset).await?;
+            self.cache.evict(&segment_path(&self.dir, segment.base_offset));
+        }
+        write_leader_epoch_checkpoint(&self.dir, &LeaderEpochCache::default()).await?;
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(segment_path(&self.dir, offset))
+            .await?;
+        *self = PartitionLog::open(self.dir.clone(), self.config.clone(), self.cache.clone()).await?;
+        self.log_start_offset = offset;
+        self.high_watermark = offset;
+        self.recovery_point = offset;
+        Ok(())
+    }
+
+    /// Deletes the oldest segments whose records are all past `retention.ms`,
+    /// or that push the log beyond `retention.bytes`, advancing the log start
+    /// offset. With remote storage the local retention applies instead and
+    /// only segments already copied go, the log start offset staying in
+    /// remote storage. Returns the number of segments deleted.
+    pub async fn delete_old_segments(&mut self, now: i64) -> io::Result<usize> {
+        if !self.config.delete {
+            return Ok(0);
+        }
+        let (retention_ms, retention_bytes) = if self.config.remote_storage_enable {
+            self.config.local_retention()
+        } else {
+            (self.config.retention_ms, self.config.retention_bytes)
+        };
+        let mut count = self.segments.iter()
+            .take_while(|segment| retention_ms >= 0 && segment.size > 0 && now - segment.max_timestamp > retention_ms)
+            .count();
+        if retention_bytes >= 0 {
+            let mut excess = self.size() as i64 - retention_bytes;
+            // the active segment only goes by time
+            let mut by_size = 0;
+            for segment in self.segments[..self.segments.len() - 1].iter() {
+                if excess < segment.size as i64 {
+                    break;
+                }
+                excess -= segment.size as i64;
+                by_size += 1;
+            }
+            count = count.max(by_size);
+        }
+        if self.config.remote_storage_enable {
+            count = count.min(self.copied_segments());
+        }
+        if count == 0 {
+            return Ok(0);
+        }
+        // the log always keeps an active segment, so an expired one is rolled first
+        if count == self.segments.len() {
+            self.roll().await?;
+        }
+        self.delete_segments(count).await?;
+        Ok(count)
+    }
+
+    /// Moves the log start offset forward to `offset`, as DeleteRecords does,
+    /// deleting the segments entirely below it.
+    pub async fn advance_log_start_offset(&mut self, offset: i64) -> io::Result<()> {
+        if offset <= self.log_start_offset {
+            return Ok(());
+        }
+        self.log_start_offset = offset.min(self.log_end_offset);
+        let count = (0..self.segments.len() - 1)
+            .take_while(|idx| self.segments[idx + 1].base_offset <= self.log_start_offset)
+            .count();
+        self.delete_segments(count).await
+    }
+
+    // removes the oldest `count` segments, never the active one
+    async fn delete_segments(&mut self, count: usize) -> io::Result<()> {
+        let deleted: Vec<Segment> = self.segments.drain(..count).collect();
+        for segment in deleted.iter() {
+            remove_segment_files(&self.dir, segment.base_offset).await?;
+            self.cache.evict(&segment_path(&self.dir, segment.base_offset));
+        }
+        // the offsets before the local segments may still be in remote storage
+        let local_start = self.segments[0].base_offset;
+        if !self.remote_segments.last().is_some_and(|last| last.end_offset + 1 >= local_start) {
+            self.log_start_offset = self.log_start_offset.max(local_start);
+        }
+        let log_start_offset = self.log_start_offset;
+        self.aborted_txns.retain(|txn| txn.last_offset >= log_start_offset);
+        if self.leader_epochs.truncate_from_start(log_start_offset) {
+            write_leader_epoch_checkpoint(&self.dir, &self.leader_epochs).await?;
+        }
+        Ok(())
+    }
+
+    // the number of oldest local segments that are entirely in remote storage
+    fn copied_segments(&self) -> usize {
+        let Some(copied_end) = self.remote_segments.last().map(|segment| segment.end_offset) else { return 0 };
+        (0..self.segments.len() - 1)
+            .take_while(|idx| self.segments[idx + 1].base_offset - 1 <= copied_end)
+            .count()
+    }
+
+    /// The closed segments below the last stable offset that are not in
+    /// remote storage yet, oldest first, each with a new segment id.
+    pub fn segments_to_copy(&self) -> Vec<(RemoteLogSegmentMetadata, LogSegmentData)> {
+        let copied_end = self.remote_segments.last().map(|segment| segment.end_offset).unwrap_or(-1);
+        let last_stable_offset = self.last_stable_offset();
+        (0..self.segments.len() - 1)
+            .filter(|idx| self.segments[*idx].base_offset > copied_end && self.segments[*idx].size > 0)
+            .take_while(|idx| self.segments[idx + 1].base_offset <= last_stable_offset)
+            .map(|idx| {
+                let segment = &self.segments[idx];
+                let metadata = RemoteLogSegmentMetadata {
+                    segment_id: random_uuid(),
+                    start_offset: segment.base_offset,
+                    end_offset: self.segments[idx + 1].base_offset - 1,
+                    max_timestamp: segment.max_timestamp,
+                    size: segment.size,
+                };
+                let data = LogSegmentData {
+                    log: segment_path(&self.dir, segment.base_offset),
+                    offset_index: offset_index_path(&self.dir, segment.base_offset),
+                    txn_index: txn_index_path(&self.dir, segment.base_offset),
+                };
+                (metadata, data)
+            })
+            .collect()
+    }
+
+    /// Records a segment copied to remote storage. Returns false when it is
+    /// no longer the next one to copy, as when retention deleted it while it
+    /// was being copied.
+    pub async fn add_remote_segment(&mut self, metadata: RemoteLogSegmentMetadata) -> io::Result<bool> {
+        let copied_end = self.remote_segments.last().map(|segment| segment.end_offset).unwrap_or(-1);
+        if metadata.start_offset <= copied_end || !self.segments.iter().any(|segment| segment.base_offset == metadata.start_offset) {
+            return Ok(false);
+        }
+        self.remote_segments.push(metadata);
+        write_remote_log_metadata(&self.dir, &self.remote_segments).await?;
+        Ok(true)
+    }
+
+    /// Drops the remote segments below the log start offset and the oldest
+    /// ones past `retention.ms` or beyond `retention.bytes`, which counts the
+    /// remote segments and the local ones not copied yet, advancing the log
+    /// start offset. Returns the dropped segments for the caller to delete
+    /// from remote storage.
+    pub async fn expire_remote_segments(&mut self, now: i64) -> io::Result<Vec<RemoteLogSegmentMetadata>> {
+        let delete = self.config.delete;
+        let (retention_ms, retention_bytes) = (self.config.retention_ms, self.config.retention_bytes);
+        let mut count = self.remote_segments.iter()
+            .take_while(|segment| segment.end_offset < self.log_start_offset
+                || (delete && retention_ms >= 0 && now - segment.max_timestamp > retention_ms))
+            .count();
+        if delete && retention_bytes >= 0 {
+            let local_only: u64 = self.segments[self.copied_segments()..].iter().map(|segment| segment.size).sum();
+            let remote: u64 = self.remote_segments.iter().map(|segment| segment.size).sum();
+            let mut excess = (local_only + remote) as i64 - retention_bytes;
+            let mut by_size = 0;
+            for segment in self.remote_segments.iter() {
+                if excess < segment.size as i64 {
+                    break;
+                }
+                excess -= segment.size as i64;
+                by_size += 1;
+            }
+            count = count.max(by_size);
+        }
+        if count == 0 {
+            return Ok(Vec::new());
+        }
+        let expired: Vec<RemoteLogSegmentMetadata> = self.remote_segments.drain(..count).collect();
+        write_remote_log_metadata(&self.dir, &self.remote_segments).await?;
+        // along with any local copies of the expired segments
+        self.advance_log_start_offset(expired.last().unwrap().end_offset + 1).await?;
+        Ok(expired)
+    }
+
+    /// Starts a compaction pass over the closed segments below the last
+    /// stable offset, which keeps only the latest record per key. Records of
+    /// aborted transactions are dropped, tombstones and abort markers once
+    /// they are older than `delete.retention.ms`; commit markers are kept so
+    /// the producer state rebuilt on open stays right. `first_dirty_offset`
+    /// is where the previous pass stopped. None when the log is below
+    /// `min.cleanable.dirty.ratio`. The pass works on a snapshot of the
+    /// segments, so it runs without the log, see `CleanerPass::run` and
+    /// `finish_clean`.
+    pub fn start_clean(&self, first_dirty_offset: i64, now: i64) -> Option<CleanerPass> {
+        let last_stable_offset = self.last_stable_offset();
+        let cleanable = (0..self.segments.len() - 1)
+            .take_while(|idx| self.segments[idx + 1].base_offset <= last_stable_offset)
+            .count();
+        let is_dirty = |idx: usize| self.segments[idx + 1].base_offset > first_dirty_offset;
+        let dirty_bytes: u64 = (0..cleanable).filter(|idx| is_dirty(*idx)).map(|idx| self.segments[idx].size).sum();
+        let total_bytes: u64 = self.segments[..cleanable].iter().map(|segment| segment.size).sum();
+        if dirty_bytes == 0 || (dirty_bytes as f64) < self.config.min_cleanable_dirty_ratio * total_bytes as f64 {
+            return None;
+        }
+        Some(CleanerPass {
+            dir: self.dir.clone(),
+            segments: self.segments[..cleanable].iter()
+                .enumerate()
+                .map(|(idx, segment)| (segment.base_offset, segment.size, is_dirty(idx)))
+                .collect(),
+            next_dirty_offset: self.segments[cleanable].base_offset,
+            aborted_txns: self.aborted_txns.clone(),
+            last_batches: self.last_batches.clone(),
+            delete_horizon: now - self.config.delete_retention_ms,
+        })
+    }
+
+    /// Swaps the segments a cleaner pass wrote in for the ones it cleaned,
+    /// evicting their open files. A pass over segments that changed since it
+    /// started, truncated or moved to another log dir, is dropped. Returns
+    /// where the next pass starts, or None when the pass was dropped.
+    pub async fn finish_clean(&mut self, cleaned: CleanedSegments) -> io::Result<Option<i64>> {
+        // the oldest segments may have gone to retention in the meantime
+        let local_start = self.segments[0].base_offset;
+        let unchanged = cleaned.dir == self.dir
+            && self.segments.iter().any(|segment| segment.base_offset == cleaned.next_dirty_offset)
+            && cleaned.segments.iter().all(|(original_size, segment, _)| {
+                segment.base_offset < local_start || self.segments.iter().any(|kept| kept.base_offset == segment.base_offset && kept.size == *original_size)
+            });
+        if !unchanged {
+            cleaned.discard().await;
+            return Ok(None);
+        }
+        for (_, mut segment, aborted) in cleaned.segments {
+            let Some(idx) = self.segments.iter().position(|kept| kept.base_offset == segment.base_offset) else {
+                remove_cleaned_files(&cleaned.dir, segment.base_offset).await;
+                continue;
+            };
+            // swapped in with renames so a crash leaves the old or the new
+            // segment, without the old index, which is then rebuilt
+            let path = segment_path(&self.dir, segment.base_offset);
+            match fs::remove_file(offset_index_path(&self.dir, segment.base_offset)).await {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+            fs::rename(cleaned_path(&self.dir, segment.base_offset), &path).await?;
+            segment.index.rename(offset_index_path(&self.dir, segment.base_offset))?;
+            fs::rename(cleaned_txn_index_path(&self.dir, segment.base_offset), txn_index_path(&self.dir, segment.base_offset)).await?;
+            self.cache.evict(&path);
+            // forgets the aborted transactions whose markers the pass dropped
+            let end = self.segments.get(idx + 1).map_or(self.log_end_offset, |next| next.base_offset);
+            self.aborted_txns.retain(|txn| txn.last_offset < segment.base_offset || txn.last_offset >= end || aborted.contains(txn));
+            self.segments[idx] = segment;
+        }
+        Ok(Some(cleaned.next_dirty_offset))
+    }
+
+    /// Runs a whole compaction pass, see `start_clean`.
+    pub async fn clean(&mut self, first_dirty_offset: i64, now: i64) -> io::Result<Option<i64>> {
+        let Some(pass) = self.start_clean(first_dirty_offset, now) else { return Ok(None) };
+        let cleaned = pass.run().await?;
+        self.finish_clean(cleaned).await
+    }
+}
+
+fn cleaned_path(dir: &Path, base_offset: i64) -> PathBuf {
+    dir.join(segment_file_name(base_offset, "cleaned"))
+}
+
+fn cleaned_index_path(dir: &Path, base_offset: i64) -> PathBuf {
+    dir.join(segment_file_name(base_offset, "index.cleaned"))
+}
+
+fn cleaned_txn_index_path(dir: &Path, base_offset: i64) -> PathBuf {
+    dir.join(segment_file_name(base_offset, "txnindex.cleaned"))
+}
+
+async fn remove_cleaned_files(dir: &Path, base_offset: i64) {
+    for path in [cleaned_path(dir, base_offset), cleaned_index_path(dir, base_offset), cleaned_txn_index_path(dir, base_offset)] {
+        if let Err(e) = fs::remove_file(&path).await {
+            if e.kind() != io::ErrorKind::NotFound {
+                println!("Failed to remove {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+/// A compaction pass over a snapshot of a log's closed segments, see
+/// `PartitionLog::start_clean`.
+#[derive(Debug)]
+pub struct CleanerPass {
+    dir: PathBuf,
+    // (base offset, size, dirty) of the segments to clean
+    segments: Vec<(i64, u64, bool)>,
+    next_dirty_offset: i64,
+    aborted_txns: Vec<AbortedTxn>,
+    // producer id -> base offset of its last data batch, kept even when emptied
+    last_batches: BTreeMap<i64, i64>,
+    delete_horizon: i64,
+}
+
+/// The segments a cleaner pass wrote next to the ones they replace, each
+/// with the size of the segment it cleaned and the aborted transactions
+/// whose markers it kept.
+#[derive(Debug)]
+pub struct CleanedSegments {
+    dir: PathBuf,
+    segments: Vec<(u64, Segment, Vec<AbortedTxn>)>,
+    next_dirty_offset: i64,
+}
+
+impl CleanedSegments {
+    async fn discard(self) {
+        for (_, segment, _) in self.segments {
+            remove_cleaned_files(&self.dir, segment.base_offset).await;
+        }
+    }
+}
+
+impl CleanerPass {
+    fn is_aborted(&self, producer_id: i64, offset: i64) -> bool {
+        self.aborted_txns.iter()
+            .any(|txn| txn.producer_id == producer_id && txn.first_offset <= offset && offset <= txn.last_offset)
+    }
+
+    // a closed segment, None once retention or a truncation removed it
+    async fn read_segment(&self, base_offset: i64) -> io::Result<Option<Vec<u8>>> {
+        match fs::read(segment_path(&self.dir, base_offset)).await {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Writes the cleaned segments and their indexes next to the segments
+    /// of the snapshot, for `PartitionLog::finish_clean` to swap in.
+    pub async fn run(self) -> io::Result<CleanedSegments> {
+        let mut cleaned = CleanedSegments {
+            dir: self.dir.clone(),
+            segments: Vec::new(),
+            next_dirty_offset: self.next_dirty_offset,
+        };
+        match self.write_segments(&mut cleaned).await {
+            Ok(()) => Ok(cleaned),
+            Err(e) => {
+                cleaned.discard().await;
+                Err(e)
+            }
+        }
+    }
+
+    async fn write_segments(&self, cleaned: &mut CleanedSegments) -> io::Result<()> {
+        // key -> offset of its latest record in the dirty section
+        let mut latest: HashMap<Bytes, i64> = HashMap::new();
+        for (base_offset, _, _) in self.segments.iter().filter(|(_, _, dirty)| *dirty) {
+            let Some(bytes) = self.read_segment(*base_offset).await? else { continue };
+            for (pos, header) in batches(&bytes) {
+                if header.is_control() {
+                    continue;
+                }
+                for record in decode_batch(&bytes[pos..pos + header.size()])?.records {
+                    if let Some(key) = record.key {
+                        if !self.is_aborted(header.producer_id, record.offset) {
+                            latest.insert(key, record.offset);
+                        }
+                    }
+                }
+            }
+        }
+
+        for (base_offset, size, _) in self.segments.iter() {
+            let Some(bytes) = self.read_segment(*base_offset).await? else { continue };
+            let mut buf = BytesMut::new();
+            // the aborted transactions whose markers stay, for the segment's txn index
+            let mut aborted = Vec::new();
+            for (pos, header) in batches(&bytes) {
+                let batch = &bytes[pos..pos + header.size()];
+                if header.is_control() {
+                    if control_type(batch) != Some(CONTROL_TYPE_ABORT) {
+                        buf.extend_from_slice(batch);
+                        continue;
+                    }
+                    // an emptied last batch of the producer still opens the transaction
+                    let ends_last_batch = self.last_batches.get(&header.producer_id)
+                        .is_some_and(|last| *last < header.base_offset && self.is_aborted(header.producer_id, *last));
+                    if header.max_timestamp >= self.delete_horizon || ends_last_batch {
+                        buf.extend_from_slice(batch);
+                        aborted.extend(self.aborted_txns.iter().filter(|txn| txn.last_offset == header.last_offset()).cloned());
+                    }
+                    continue;
+                }
+                let records = decode_batch(batch)?.records;
+                let kept: Vec<Record> = records.iter()
+                    .filter(|record| {
+                        if self.is_aborted(header.producer_id, record.offset) {
+                            return false;
+                        }
+                        let Some(key) = &record.key else { return true };
+                        if latest.get(key).map(|offset| record.offset < *offset).unwrap_or(false) {
+                            return false;
+                        }
+                        record.value.is_some() || record.timestamp >= self.delete_horizon
+                    })
+                    .cloned()
+                    .collect();
+                if kept.len() == records.len() {
+                    buf.extend_from_slice(batch);
+                } else if kept.is_empty() {
+                    // the producer's last batch keeps its header, as Kafka's cleaner does
+                    if self.last_batches.get(&header.producer_id) == Some(&header.base_offset) {
+                        put_empty_batch(&mut buf, &header);
+                    }
+                } else {
+                    // the batch keeps the codec it was written with
+                    let options = RecordEncodeOptions {
+                        version: 2,
+                        compression: header.compression().unwrap_or(Compression::None),
+                    };
+                    RecordBatchEncoder::encode(&mut buf, &kept, &options)
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+                }
+            }
+            let mut segment = Segment::with_index(*base_offset, OffsetIndex::create(cleaned_index_path(&self.dir, *base_offset), *base_offset)?);
+            for (_, header) in batches(&buf) {
+                segment.track(&header);
+            }
+            segment.index.close()?;
+            // registered first so a failure below removes its files too
+            let mut txn_index = BytesMut::new();
+            for txn in aborted.iter() {
+                txn.encode(&mut txn_index);
+            }
+            cleaned.segments.push((*size, segment, aborted));
+            for (path, bytes) in [(cleaned_path(&self.dir, *base_offset), buf), (cleaned_txn_index_path(&self.dir, *base_offset), txn_index)] {
+                let mut file = fs::File::create(path).await?;
+                file.write_all(&bytes).await?;
+                file.sync_all().await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use bytes::{Bytes, BytesMut};
+    use kafka_protocol::records::{Compression, Record, RecordBatchDecoder, RecordBatchEncoder, RecordEncodeOptions};
+
+    use crate::config::BrokerConfig;
+    use crate::log_config::LogConfig;
+    use crate::test_util::{batch, record, temp_path};
+    use crate::txn::control_batch;
+    use crate::txn_index::{read_txn_index, txn_index_path};
+
+    use super::{batches, log_dirs, parse_partition_dir_name, PartitionLog};
+
+    fn txn_batch(producer_id: i64, sequence: i32, key: &'static str, timestamp: i64) -> BytesMut {
+        let record = Record {
+            transactional: true,
+            producer_id,
+            producer_epoch: 0,
+            sequence,
+            ..record(0, timestamp, Some(Bytes::from_static(key.as_bytes())), Some(Bytes::from_static(b"value")))
+        };
+        let mut buf = BytesMut::new();
+        let options = RecordEncodeOptions {
+            version: 2,
+            compression: Compression::None,
+        };
+        RecordBatchEncoder::encode(&mut buf, &[record], &options).unwrap();
+        buf
+    }
+
+    #[tokio::test]
+    async fn test_retention() {
+        let dir = temp_path("retention");
+        // one batch per segment
+        let config = LogConfig { segment_bytes: 1, retention_ms: -1, ..LogConfig::default() };
+        let mut log = PartitionLog::open(dir.clone(), config.clone(), Arc::default()).await.unwrap();
+        for timestamp in [1000, 2000, 3000, 4000] {
+            log.append(&batch(None, Some("value"), timestamp)).await.unwrap();
+        }
+        assert_eq!(Some((2, 3000)), log.offset_for_timestamp(2500).await.unwrap());
+        assert_eq!(Some((3, 4000)), log.max_timestamp_offset().await.unwrap());
+
+        let segment_size = log.size() as i64 / 4;
+        log.set_config(LogConfig { retention_bytes: 2 * segment_size, ..config.clone() });
+        assert_eq!(2, log.delete_old_segments(5000).await.unwrap());
+        assert_eq!(2, log.log_start_offset());
+
+        log.set_config(LogConfig { retention_ms: 1500, ..config });
+        assert_eq!(1, log.delete_old_segments(5000).await.unwrap());
+        assert_eq!(3, log.log_start_offset());
+
+        let log = PartitionLog::open(dir.clone(), LogConfig::default(), Arc::default()).await.unwrap();
+        assert_eq!(3, log.log_start_offset());
+        assert_eq!(4, log.log_end_offset());
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_read() {
+        let dir = temp_path("read");
+        let mut log = PartitionLog::open(dir.clone(), LogConfig::default(), Arc::default()).await.unwrap();
+        let batches: Vec<BytesMut> = [1000, 2000, 3000].into_iter()
+            .map(|timestamp| batch(None, Some("value"), timestamp))
+            .collect();
+        for batch in batches.iter() {
+            log.append(batch).await.unwrap();
+        }
+        let all = log.read_all().await.unwrap();
+        let first_size = batches[0].len();
+
+        let fetched = log.read(1, i32::MAX, false).await.unwrap();
+        assert_eq!(&all[first_size..], &fetched.records.read().await.unwrap()[..]);
+
+        // max_bytes below the first batch still returns it whole
+        let fetched = log.read(0, 1, false).await.unwrap();
+        assert_eq!(&all[..first_size], &fetched.records.read().await.unwrap()[..]);
+
+        let fetched = log.read(3, i32::MAX, false).await.unwrap();
+        assert!(fetched.records.is_empty());
+
+        // a batch length running past the segment is corrupt, not a panic
+        let file = std::fs::OpenOptions::new().write(true).open(dir.join("00000000000000000000.log")).unwrap();
+        std::os::unix::fs::FileExt::write_all_at(&file, &i32::MAX.to_be_bytes(), first_size as u64 + 8).unwrap();
+        let error = log.read(2, i32::MAX, false).await.unwrap_err();
+        assert_eq!(std::io::ErrorKind::InvalidData, error.kind());
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_follower_append_and_truncate() {
+        let leader_dir = temp_path("leader");
+        let follower_dir = temp_path("follower");
+        let mut leader = PartitionLog::open(leader_dir.clone(), LogConfig::default(), Arc::default()).await.unwrap();
+        for (timestamp, leader_epoch) in [(1000, 0), (2000, 0), (3000, 2)] {
+            leader.append_as_leader(&batch(None, Some("value"), timestamp), leader_epoch).await.unwrap();
+        }
+        assert_eq!(0, leader.high_watermark());
+        assert_eq!((0, 2), leader.end_offset_for_epoch(1));
+        assert_eq!((2, 3), leader.end_offset_for_epoch(2));
+        let fetched = leader.read_for_follower(0, i32::MAX).await.unwrap();
+        assert_eq!(3, fetched.log_end_offset);
+        let records = fetched.records.read().await.unwrap();
+
+        let mut follower = PartitionLog::open(follower_dir.clone(), LogConfig::default(), Arc::default()).await.unwrap();
+        assert_eq!(3, follower.append_as_follower(&records).await.unwrap());
+        // fetched again, nothing is new
+        assert_eq!(0, follower.append_as_follower(&records).await.unwrap());
+        assert_eq!(3, follower.log_end_offset());
+        assert_eq!(leader.leader_epochs(), follower.leader_epochs());
+        assert!(follower.advance_high_watermark(2));
+        assert!(!follower.advance_high_watermark(1));
+
+        follower.truncate_to(1).await.unwrap();
+        assert_eq!((1, 1), (follower.log_end_offset(), follower.high_watermark()));
+        assert_eq!(Some(0), follower.leader_epochs().latest_epoch());
+        assert_eq!(2, follower.append_as_follower(&records).await.unwrap());
+        // the epochs survive a reopen through the checkpoint
+        let follower_epochs = follower.leader_epochs().clone();
+        let mut follower = PartitionLog::open(follower_dir.clone(), LogConfig::default(), Arc::default()).await.unwrap();
+        assert_eq!(&follower_epochs, follower.leader_epochs());
+        assert_eq!(&records[..], &follower.read_all().await.unwrap()[..]);
+
+        // a gap is refused
+        let gap = leader.read_for_follower(2, i32::MAX).await.unwrap().records.read().await.unwrap();
+        follower.truncate_fully_and_start_at(1).await.unwrap();
+        assert!(follower.append_as_follower(&gap).await.is_err());
+        assert_eq!((1, 1), (follower.log_start_offset(), follower.log_end_offset()));
+        assert_eq!(None, follower.leader_epochs().latest_epoch());
+        tokio::fs::remove_dir_all(&leader_dir).await.unwrap();
+        tokio::fs::remove_dir_all(&follower_dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_truncate_diverged() {
+        let leader_dir = temp_path("diverged-leader");
+        let follower_dir = temp_path("diverged-follower");
+        let mut leader = PartitionLog::open(leader_dir.clone(), LogConfig::default(), Arc::default()).await.unwrap();
+        let mut follower = PartitionLog::open(follower_dir.clone(), LogConfig::default(), Arc::default()).await.unwrap();
+        for log in [&mut leader, &mut follower] {
+            for timestamp in [1000, 2000] {
+                log.append_as_leader(&batch(None, Some("value"), timestamp), 0).await.unwrap();
+            }
+        }
+        // the follower led epoch 1 without replicating it, the leader took
+        // over in epoch 2 from offset 2
+        for timestamp in [3000, 4000] {
+            follower.append_as_leader(&batch(None, Some("lost"), timestamp), 1).await.unwrap();
+        }
+        leader.append_as_leader(&batch(None, Some("value"), 5000), 2).await.unwrap();
+        assert_eq!((4, 3), (follower.log_end_offset(), leader.log_end_offset()));
+
+        // the fetch from 4 in epoch 1 diverges where the leader's epoch 0 ends
+        let (epoch, end_offset) = leader.end_offset_for_epoch(1);
+        assert_eq!((0, 2), (epoch, end_offset));
+        let offset = follower.leader_epochs().truncation_offset(epoch, end_offset, follower.log_end_offset(), follower.high_watermark());
+        assert_eq!(2, offset);
+        follower.truncate_to(offset).await.unwrap();
+        assert_eq!(Some(0), follower.leader_epochs().latest_epoch());
+
+        let fetched = leader.read_for_follower(offset, i32::MAX).await.unwrap();
+        assert_eq!(1, follower.append_as_follower(&fetched.records.read().await.unwrap()).await.unwrap());
+        assert_eq!(leader.leader_epochs(), follower.leader_epochs());
+        assert_eq!(&leader.read_all().await.unwrap()[..], &follower.read_all().await.unwrap()[..]);
+        tokio::fs::remove_dir_all(&leader_dir).await.unwrap();
+        tokio::fs::remove_dir_all(&follower_dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_compaction() {
+        let dir = temp_path("compaction");
+        let config = LogConfig { segment_bytes: 1, retention_ms: -1, compact: true, delete_retention_ms: 1000, ..LogConfig::default() };
+        let mut log = PartitionLog::open(dir.clone(), config, Arc::default()).await.unwrap();
+        log.append(&batch(Some("a"), Some("1"), 1000)).await.unwrap();
+        log.append(&batch(Some("b"), Some("1"), 1000)).await.unwrap();
+        log.append(&batch(Some("a"), Some("2"), 1000)).await.unwrap();
+        log.append(&batch(Some("b"), None, 5000)).await.unwrap();
+        log.append(&batch(Some("c"), Some("1"), 5000)).await.unwrap();
+
+        // the active segment holding offset 4 is left alone
+        assert_eq!(Some(4), log.clean(0, 5500).await.unwrap());
+        let mut records = log.read_all().await.unwrap();
+        let offsets: Vec<i64> = RecordBatchDecoder::decode_all(&mut records).unwrap().iter()
+            .flat_map(|record_set| record_set.records.iter().map(|record| record.offset))
+            .collect();
+        assert_eq!(vec![2, 3, 4], offsets);
+        assert_eq!(None, log.clean(4, 5500).await.unwrap());
+
+        // the tombstone goes once it is older than delete.retention.ms
+        log.append(&batch(Some("d"), Some("1"), 7000)).await.unwrap();
+        assert_eq!(Some(5), log.clean(0, 7000).await.unwrap());
+        let mut records = log.read_all().await.unwrap();
+        let offsets: Vec<i64> = RecordBatchDecoder::decode_all(&mut records).unwrap().iter()
+            .flat_map(|record_set| record_set.records.iter().map(|record| record.offset))
+            .collect();
+        assert_eq!(vec![2, 4, 5], offsets);
+        assert_eq!(6, log.log_end_offset());
+        // the cleaned segments came with their indexes
+        let fetched = log.read(4, i32::MAX, false).await.unwrap();
+        assert_eq!(Some(4), RecordBatchDecoder::decode(&mut fetched.records.read().await.unwrap().freeze()).unwrap().records.first().map(|record| record.offset));
+
+        // segments retention removed while a pass ran stay removed
+        log.append(&batch(Some("d"), Some("2"), 8000)).await.unwrap();
+        log.append(&batch(Some("e"), Some("1"), 8000)).await.unwrap();
+        let cleaned = log.start_clean(4, 8000).unwrap().run().await.unwrap();
+        log.set_config(LogConfig { delete: true, retention_bytes: 0, ..log.config().clone() });
+        assert_eq!(7, log.delete_old_segments(8000).await.unwrap());
+        assert_eq!(Some(7), log.finish_clean(cleaned).await.unwrap());
+        assert_eq!(7, log.log_start_offset());
+        assert!(!std::fs::read_dir(&dir).unwrap().any(|entry| entry.unwrap().path().extension().is_some_and(|ext| ext == "cleaned")));
+
+        // a pass over segments truncated while it ran is dropped, files and all
+        log.append(&batch(Some("d"), Some("2"), 8000)).await.unwrap();
+        log.append(&batch(Some("e"), Some("1"), 8000)).await.unwrap();
+        let cleaned = log.start_clean(7, 8000).unwrap().run().await.unwrap();
+        log.truncate_to(8).await.unwrap();
+        assert_eq!(None, log.finish_clean(cleaned).await.unwrap());
+        assert!(!std::fs::read_dir(&dir).unwrap().any(|entry| entry.unwrap().path().extension().is_some_and(|ext| ext == "cleaned")));
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_compaction_of_transactions() {
+        let dir = temp_path("compaction-txns");
+        let config = LogConfig { segment_bytes: 1, retention_ms: -1, compact: true, delete_retention_ms: 1000, ..LogConfig::default() };
+        let mut log = PartitionLog::open(dir.clone(), config.clone(), Arc::default()).await.unwrap();
+        // producer 7 aborts and then commits, producer 8 ends on an abort
+        log.append(&txn_batch(7, 0, "a", 1000)).await.unwrap();
+        log.append(&control_batch(7, 0, false, 1000)).await.unwrap();
+        log.append(&txn_batch(7, 1, "b", 1000)).await.unwrap();
+        log.append(&control_batch(7, 0, true, 1000)).await.unwrap();
+        log.append(&txn_batch(8, 5, "c", 1000)).await.unwrap();
+        log.append(&control_batch(8, 0, false, 1000)).await.unwrap();
+        log.append(&batch(Some("d"), Some("1"), 1000)).await.unwrap();
+
+        assert_eq!(Some(6), log.clean(0, 5000).await.unwrap());
+        let all = log.read_all().await.unwrap();
+        let headers: Vec<(i64, i64, i32, i32)> = batches(&all).into_iter()
+            .map(|(_, header)| (header.base_offset, header.producer_id, header.base_sequence, header.records_count))
+            .collect();
+        // the aborted last batch of producer 8 keeps its header, and with it its marker
+        assert_eq!(vec![(2, 7, 1, 1), (3, 7, -1, 1), (4, 8, 5, 0), (5, 8, -1, 1), (6, -1, -1, 1)], headers);
+
+        // the dropped marker leaves the txn indexes and the aborted transactions
+        assert!(read_txn_index(&txn_index_path(&dir, 1)).await.unwrap().is_empty());
+        assert_eq!(vec![(8, 4, 5)], log.aborted_txns.iter().map(|txn| (txn.producer_id, txn.first_offset, txn.last_offset)).collect::<Vec<_>>());
+        assert_eq!(log.aborted_txns, read_txn_index(&txn_index_path(&dir, 5)).await.unwrap());
+
+        let reopened = PartitionLog::open(dir.clone(), config, Arc::default()).await.unwrap();
+        assert_eq!(log.aborted_txns, reopened.aborted_txns);
+        assert_eq!(7, reopened.last_stable_offset());
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_relocate() {
+        let dir = temp_path("relocate");
+        let copy = temp_path("relocate-copy");
+        let config = LogConfig { segment_bytes: 200, ..LogConfig::default() };
+        let mut log = PartitionLog::open(dir.clone(), config.clone(), Arc::default()).await.unwrap();
+        for timestamp in [1000, 2000, 3000] {
+            log.append(&batch(None, Some("value"), timestamp)).await.unwrap();
+        }
+        log.flush().await.unwrap();
+        super::copy_partition_dir(&dir, &copy, None).await.unwrap();
+        log.relocate(copy.clone()).unwrap();
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+
+        // appends and reads go to the copy from then on
+        log.append(&batch(None, Some("value"), 4000)).await.unwrap();
+        let fetched = log.read(1, i32::MAX, false).await.unwrap();
+        assert!(!fetched.records.is_empty());
+        let reopened = PartitionLog::open(copy.clone(), config, Arc::default()).await.unwrap();
+        assert_eq!(4, reopened.log_end_offset());
+        assert_eq!(log.read_all().await.unwrap(), reopened.read_all().await.unwrap());
+        tokio::fs::remove_dir_all(&copy).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_advance_log_start_offset() {
+        let dir = temp_path("delete-records");
+        let config = LogConfig { segment_bytes: 1, ..LogConfig::default() };
+        let mut log = PartitionLog::open(dir.clone(), config, Arc::default()).await.unwrap();
+        for timestamp in [1000, 2000, 3000] {
+            log.append(&batch(None, Some("value"), timestamp)).await.unwrap();
+        }
+        log.advance_log_start_offset(1).await.unwrap();
+        assert_eq!(1, log.log_start_offset());
+        assert_eq!(2, log.segments.len());
+        assert_eq!(Some((1, 2000)), log.offset_for_timestamp(0).await.unwrap());
+
+        // the active segment stays even when every record is deleted
+        log.advance_log_start_offset(3).await.unwrap();
+        assert_eq!(3, log.log_start_offset());
+        assert_eq!(1, log.segments.len());
+        assert_eq!(None, log.offset_for_timestamp(0).await.unwrap());
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_recover() {
+        let dir = temp_path("recover");
+        let mut log = PartitionLog::open(dir.clone(), LogConfig::default(), Arc::default()).await.unwrap();
+        log.append(&batch(None, Some("first"), 1000)).await.unwrap();
+        log.append(&batch(None, Some("second"), 2000)).await.unwrap();
+
+        let path = super::segment_path(&dir, 0);
+        let mut bytes = tokio::fs::read(&path).await.unwrap();
+        let len = bytes.len();
+        bytes[len - 1] ^= 0xff;
+        bytes.extend_from_slice(&[0, 0, 0]);
+        tokio::fs::write(&path, &bytes).await.unwrap();
+
+        let (log, truncated) = PartitionLog::recover(dir.clone(), LogConfig::default(), Arc::default(), 0).await.unwrap();
+        assert_eq!((len + 3 - log.size() as usize) as u64, truncated);
+        assert_eq!(1, log.log_end_offset());
+        assert_eq!(log.size(), tokio::fs::metadata(&path).await.unwrap().len());
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_recover_from_recovery_point() {
+        let dir = temp_path("recovery-point");
+        let config = LogConfig { segment_bytes: 1, ..LogConfig::default() };
+        let mut log = PartitionLog::open(dir.clone(), config.clone(), Arc::default()).await.unwrap();
+        for timestamp in [1000, 2000, 3000] {
+            log.append(&batch(None, Some("value"), timestamp)).await.unwrap();
+        }
+        // rolling synced the closed segments
+        assert_eq!(2, log.recovery_point());
+
+        // closed segments keep their index files, which a rebuild would trim
+        // back to the one entry of a single batch
+        let index_path = crate::offset_index::offset_index_path(&dir, 1);
+        tokio::fs::write(&index_path, [0; 16]).await.unwrap();
+        drop(log);
+
+        // corruption below the recovery point is not looked at
+        let path = super::segment_path(&dir, 0);
+        let mut bytes = tokio::fs::read(&path).await.unwrap();
+        let len = bytes.len();
+        bytes[len - 1] ^= 0xff;
+        tokio::fs::write(&path, &bytes).await.unwrap();
+        let (log, truncated) = PartitionLog::recover(dir.clone(), config.clone(), Arc::default(), 2).await.unwrap();
+        assert_eq!(0, truncated);
+        assert_eq!(3, log.log_end_offset());
+        assert_eq!(16, tokio::fs::metadata(&index_path).await.unwrap().len());
+
+        // from offset 0 every segment is validated, and those after the corrupt batch go
+        let (log, truncated) = PartitionLog::recover(dir.clone(), config, Arc::default(), 0).await.unwrap();
+        assert_eq!(len as u64 * 3, truncated);
+        assert_eq!(0, log.log_end_offset());
+        assert_eq!(1, log.segments.len());
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[test]
+    fn test_partition_dirs() {
+        assert_eq!(Some((("foo.bar".to_string(), 3), false)), parse_partition_dir_name("foo.bar-3"));
+        assert_eq!(Some((("foo-bar".to_string(), 0), true)), parse_partition_dir_name("foo-bar-0.5f3c9e2a-future"));
+        assert_eq!(None, parse_partition_dir_name("__cluster_metadata-0"));
+        assert_eq!(None, parse_partition_dir_name("meta.properties"));
+
+        let config = BrokerConfig::parse("log.dirs=/data/a, /data/b");
+        assert_eq!(vec![std::path::PathBuf::from("/data/a"), std::path::PathBuf::from("/data/b")], log_dirs(&config));
+        assert_eq!(vec![std::path::PathBuf::from(super::LOG_DIR)], log_dirs(&BrokerConfig::default()));
+    }
+}
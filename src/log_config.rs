@@ -0,0 +1,421 @@
+use std::collections::HashMap;
+
+use kafka_protocol::records::{Compression, RecordSet, TimestampType};
+
+use crate::config::BrokerConfig;
+use crate::record::{extract_record_value, RecordValue};
+
+pub const SEGMENT_BYTES_CONFIG: &str = "segment.bytes";
+pub const SEGMENT_MS_CONFIG: &str = "segment.ms";
+pub const RETENTION_MS_CONFIG: &str = "retention.ms";
+pub const RETENTION_BYTES_CONFIG: &str = "retention.bytes";
+pub const CLEANUP_POLICY_CONFIG: &str = "cleanup.policy";
+pub const DELETE_RETENTION_MS_CONFIG: &str = "delete.retention.ms";
+pub const MIN_CLEANABLE_DIRTY_RATIO_CONFIG: &str = "min.cleanable.dirty.ratio";
+pub const COMPRESSION_TYPE_CONFIG: &str = "compression.type";
+pub const MAX_MESSAGE_BYTES_CONFIG: &str = "max.message.bytes";
+pub const MESSAGE_TIMESTAMP_TYPE_CONFIG: &str = "message.timestamp.type";
+pub const MESSAGE_TIMESTAMP_BEFORE_MAX_MS_CONFIG: &str = "message.timestamp.before.max.ms";
+pub const MESSAGE_TIMESTAMP_AFTER_MAX_MS_CONFIG: &str = "message.timestamp.after.max.ms";
+pub const REMOTE_STORAGE_ENABLE_CONFIG: &str = "remote.storage.enable";
+pub const LOCAL_RETENTION_MS_CONFIG: &str = "local.retention.ms";
+pub const LOCAL_RETENTION_BYTES_CONFIG: &str = "local.retention.bytes";
+
+// org.apache.kafka.common.config.ConfigResource.Type
+pub const CONFIG_RESOURCE_TOPIC: i8 = 2;
+pub const CONFIG_RESOURCE_BROKER: i8 = 4;
+
+/// Topic configs and the broker settings they fall back to, in the order the
+/// broker settings are looked up.
+pub const TOPIC_CONFIG_SYNONYMS: &[(&str, &[&str])] = &[
+    (SEGMENT_BYTES_CONFIG, &["log.segment.bytes"]),
+    (SEGMENT_MS_CONFIG, &["log.roll.ms", "log.roll.hours"]),
+    (RETENTION_MS_CONFIG, &["log.retention.ms", "log.retention.minutes", "log.retention.hours"]),
+    (RETENTION_BYTES_CONFIG, &["log.retention.bytes"]),
+    (CLEANUP_POLICY_CONFIG, &["log.cleanup.policy"]),
+    (DELETE_RETENTION_MS_CONFIG, &["log.cleaner.delete.retention.ms"]),
+    (MIN_CLEANABLE_DIRTY_RATIO_CONFIG, &["log.cleaner.min.cleanable.ratio"]),
+    (COMPRESSION_TYPE_CONFIG, &["compression.type"]),
+    (MAX_MESSAGE_BYTES_CONFIG, &["message.max.bytes"]),
+    (MESSAGE_TIMESTAMP_TYPE_CONFIG, &["log.message.timestamp.type"]),
+    (MESSAGE_TIMESTAMP_BEFORE_MAX_MS_CONFIG, &["log.message.timestamp.before.max.ms"]),
+    (MESSAGE_TIMESTAMP_AFTER_MAX_MS_CONFIG, &["log.message.timestamp.after.max.ms"]),
+    // set per topic only
+    (REMOTE_STORAGE_ENABLE_CONFIG, &[]),
+    (LOCAL_RETENTION_MS_CONFIG, &["log.local.retention.ms"]),
+    (LOCAL_RETENTION_BYTES_CONFIG, &["log.local.retention.bytes"]),
+];
+
+// local.retention.ms and local.retention.bytes falling back to the total retention
+const RETENTION_DEFAULT: i64 = -2;
+
+const HOUR_MS: i64 = 60 * 60 * 1000;
+
+/// compression.type: keep the codec the producer used, or store every batch
+/// with the given one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompressionType {
+    Producer,
+    Codec(Compression),
+}
+
+impl CompressionType {
+    pub fn parse(value: &str) -> Option<CompressionType> {
+        let compression_type = match value.trim() {
+            "producer" => CompressionType::Producer,
+            "uncompressed" => CompressionType::Codec(Compression::None),
+            "gzip" => CompressionType::Codec(Compression::Gzip),
+            "snappy" => CompressionType::Codec(Compression::Snappy),
+            "lz4" => CompressionType::Codec(Compression::Lz4),
+            "zstd" => CompressionType::Codec(Compression::Zstd),
+            _ => return None,
+        };
+        Some(compression_type)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            CompressionType::Producer => "producer",
+            CompressionType::Codec(Compression::None) => "uncompressed",
+            CompressionType::Codec(Compression::Gzip) => "gzip",
+            CompressionType::Codec(Compression::Snappy) => "snappy",
+            CompressionType::Codec(Compression::Lz4) => "lz4",
+            CompressionType::Codec(Compression::Zstd) => "zstd",
+        }
+    }
+}
+
+/// Per-partition log settings: the broker `log.*` defaults with the topic's
+/// overrides from the metadata log applied on top.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogConfig {
+    pub segment_bytes: u64,
+    pub segment_ms: i64,
+    // -1 keeps data forever
+    pub retention_ms: i64,
+    pub retention_bytes: i64,
+    // cleanup.policy, which may list both "delete" and "compact"
+    pub delete: bool,
+    pub compact: bool,
+    // how long tombstones stay readable after compaction
+    pub delete_retention_ms: i64,
+    pub min_cleanable_dirty_ratio: f64,
+    pub compression_type: CompressionType,
+    // largest record batch accepted from a producer
+    pub max_message_bytes: i32,
+    // LogAppendTime replaces the producer's timestamps with the broker's
+    pub message_timestamp_type: TimestampType,
+    // how far CreateTime timestamps may lie in the past or the future
+    pub message_timestamp_before_max_ms: i64,
+    pub message_timestamp_after_max_ms: i64,
+    // segments are copied to remote storage and kept locally only for the
+    // local retention, -2 meaning the same as retention.ms/retention.bytes
+    pub remote_storage_enable: bool,
+    pub local_retention_ms: i64,
+    pub local_retention_bytes: i64,
+}
+
+fn parse_timestamp_type(value: &str) -> Option<TimestampType> {
+    match value.trim() {
+        "CreateTime" => Some(TimestampType::Creation),
+        "LogAppendTime" => Some(TimestampType::LogAppend),
+        _ => None,
+    }
+}
+
+fn timestamp_type_name(timestamp_type: TimestampType) -> &'static str {
+    match timestamp_type {
+        TimestampType::Creation => "CreateTime",
+        TimestampType::LogAppend => "LogAppendTime",
+    }
+}
+
+// (delete, compact)
+fn parse_cleanup_policy(policy: &str) -> (bool, bool) {
+    let policies: Vec<&str> = policy.split(',').map(|p| p.trim()).collect();
+    (policies.contains(&"delete"), policies.contains(&"compact"))
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            segment_bytes: 1024 * 1024 * 1024,
+            segment_ms: 168 * HOUR_MS,
+            retention_ms: 168 * HOUR_MS,
+            retention_bytes: -1,
+            delete: true,
+            compact: false,
+            delete_retention_ms: 24 * HOUR_MS,
+            min_cleanable_dirty_ratio: 0.5,
+            compression_type: CompressionType::Producer,
+            max_message_bytes: 1024 * 1024 + 12,
+            message_timestamp_type: TimestampType::Creation,
+            message_timestamp_before_max_ms: i64::MAX,
+            message_timestamp_after_max_ms: i64::MAX,
+            remote_storage_enable: false,
+            local_retention_ms: RETENTION_DEFAULT,
+            local_retention_bytes: RETENTION_DEFAULT,
+        }
+    }
+}
+
+impl LogConfig {
+    pub fn from_broker(config: &BrokerConfig) -> LogConfig {
+        let defaults = LogConfig::default();
+        // the finer grained setting wins, as in KafkaConfig
+        let segment_ms = config.get("log.roll.ms")
+            .and_then(|ms| ms.parse().ok())
+            .unwrap_or_else(|| config.get_or("log.roll.hours", defaults.segment_ms / HOUR_MS) * HOUR_MS);
+        let retention_ms = config.get("log.retention.ms")
+            .and_then(|ms| ms.parse().ok())
+            .or_else(|| config.get("log.retention.minutes")
+                .and_then(|minutes| minutes.parse::<i64>().ok())
+                .map(|minutes| minutes * 60 * 1000))
+            .unwrap_or_else(|| config.get_or("log.retention.hours", defaults.retention_ms / HOUR_MS) * HOUR_MS);
+        let (delete, compact) = config.get("log.cleanup.policy")
+            .map(parse_cleanup_policy)
+            .unwrap_or((defaults.delete, defaults.compact));
+        LogConfig {
+            segment_bytes: config.get_or("log.segment.bytes", defaults.segment_bytes),
+            segment_ms,
+            retention_ms,
+            retention_bytes: config.get_or("log.retention.bytes", defaults.retention_bytes),
+            delete,
+            compact,
+            delete_retention_ms: config.get_or("log.cleaner.delete.retention.ms", defaults.delete_retention_ms),
+            min_cleanable_dirty_ratio: config.get_or("log.cleaner.min.cleanable.ratio", defaults.min_cleanable_dirty_ratio),
+            // a broker setting without the log. prefix
+            compression_type: config.get(COMPRESSION_TYPE_CONFIG)
+                .and_then(CompressionType::parse)
+                .unwrap_or(defaults.compression_type),
+            max_message_bytes: config.get_or("message.max.bytes", defaults.max_message_bytes),
+            message_timestamp_type: config.get("log.message.timestamp.type")
+                .and_then(parse_timestamp_type)
+                .unwrap_or(defaults.message_timestamp_type),
+            message_timestamp_before_max_ms: config.get_or("log.message.timestamp.before.max.ms", defaults.message_timestamp_before_max_ms),
+            message_timestamp_after_max_ms: config.get_or("log.message.timestamp.after.max.ms", defaults.message_timestamp_after_max_ms),
+            // only ever set per topic
+            remote_storage_enable: defaults.remote_storage_enable,
+            local_retention_ms: config.get_or("log.local.retention.ms", defaults.local_retention_ms),
+            local_retention_bytes: config.get_or("log.local.retention.bytes", defaults.local_retention_bytes),
+        }
+    }
+
+    pub fn with_overrides(&self, overrides: &HashMap<String, String>) -> LogConfig {
+        let get_or = |key: &str, default| overrides.get(key)
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(default);
+        let (delete, compact) = overrides.get(CLEANUP_POLICY_CONFIG)
+            .map(|policy| parse_cleanup_policy(policy))
+            .unwrap_or((self.delete, self.compact));
+        LogConfig {
+            segment_bytes: overrides.get(SEGMENT_BYTES_CONFIG)
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(self.segment_bytes),
+            segment_ms: get_or(SEGMENT_MS_CONFIG, self.segment_ms),
+            retention_ms: get_or(RETENTION_MS_CONFIG, self.retention_ms),
+            retention_bytes: get_or(RETENTION_BYTES_CONFIG, self.retention_bytes),
+            delete,
+            compact,
+            delete_retention_ms: get_or(DELETE_RETENTION_MS_CONFIG, self.delete_retention_ms),
+            min_cleanable_dirty_ratio: overrides.get(MIN_CLEANABLE_DIRTY_RATIO_CONFIG)
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(self.min_cleanable_dirty_ratio),
+            compression_type: overrides.get(COMPRESSION_TYPE_CONFIG)
+                .and_then(|value| CompressionType::parse(value))
+                .unwrap_or(self.compression_type),
+            max_message_bytes: overrides.get(MAX_MESSAGE_BYTES_CONFIG)
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(self.max_message_bytes),
+            message_timestamp_type: overrides.get(MESSAGE_TIMESTAMP_TYPE_CONFIG)
+                .and_then(|value| parse_timestamp_type(value))
+                .unwrap_or(self.message_timestamp_type),
+            message_timestamp_before_max_ms: get_or(MESSAGE_TIMESTAMP_BEFORE_MAX_MS_CONFIG, self.message_timestamp_before_max_ms),
+            message_timestamp_after_max_ms: get_or(MESSAGE_TIMESTAMP_AFTER_MAX_MS_CONFIG, self.message_timestamp_after_max_ms),
+            remote_storage_enable: overrides.get(REMOTE_STORAGE_ENABLE_CONFIG)
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(self.remote_storage_enable),
+            local_retention_ms: get_or(LOCAL_RETENTION_MS_CONFIG, self.local_retention_ms),
+            local_retention_bytes: get_or(LOCAL_RETENTION_BYTES_CONFIG, self.local_retention_bytes),
+        }
+    }
+
+    /// How long and up to what size segments stay on local disk once they
+    /// are in remote storage, as (ms, bytes).
+    pub fn local_retention(&self) -> (i64, i64) {
+        let resolve = |local, total| if local == RETENTION_DEFAULT { total } else { local };
+        (resolve(self.local_retention_ms, self.retention_ms), resolve(self.local_retention_bytes, self.retention_bytes))
+    }
+
+    /// The value of a topic config as DescribeConfigs reports it.
+    pub fn value(&self, name: &str) -> Option<String> {
+        let value = match name {
+            SEGMENT_BYTES_CONFIG => self.segment_bytes.to_string(),
+            SEGMENT_MS_CONFIG => self.segment_ms.to_string(),
+            RETENTION_MS_CONFIG => self.retention_ms.to_string(),
+            RETENTION_BYTES_CONFIG => self.retention_bytes.to_string(),
+            CLEANUP_POLICY_CONFIG => match (self.delete, self.compact) {
+                (true, true) => "compact,delete".to_string(),
+                (false, true) => "compact".to_string(),
+                // an empty policy keeps data like delete without limits
+                (true, false) => "delete".to_string(),
+                (false, false) => String::new(),
+            },
+            DELETE_RETENTION_MS_CONFIG => self.delete_retention_ms.to_string(),
+            MIN_CLEANABLE_DIRTY_RATIO_CONFIG => self.min_cleanable_dirty_ratio.to_string(),
+            COMPRESSION_TYPE_CONFIG => self.compression_type.name().to_string(),
+            MAX_MESSAGE_BYTES_CONFIG => self.max_message_bytes.to_string(),
+            MESSAGE_TIMESTAMP_TYPE_CONFIG => timestamp_type_name(self.message_timestamp_type).to_string(),
+            MESSAGE_TIMESTAMP_BEFORE_MAX_MS_CONFIG => self.message_timestamp_before_max_ms.to_string(),
+            MESSAGE_TIMESTAMP_AFTER_MAX_MS_CONFIG => self.message_timestamp_after_max_ms.to_string(),
+            REMOTE_STORAGE_ENABLE_CONFIG => self.remote_storage_enable.to_string(),
+            LOCAL_RETENTION_MS_CONFIG => self.local_retention_ms.to_string(),
+            LOCAL_RETENTION_BYTES_CONFIG => self.local_retention_bytes.to_string(),
+            _ => return None,
+        };
+        Some(value)
+    }
+}
+
+/// Checks a topic config before it is written to the metadata log.
+pub fn validate_topic_config(name: &str, value: &str) -> Result<(), String> {
+    let valid = match name {
+        SEGMENT_BYTES_CONFIG => value.parse::<u64>().is_ok(),
+        SEGMENT_MS_CONFIG | RETENTION_MS_CONFIG | RETENTION_BYTES_CONFIG | DELETE_RETENTION_MS_CONFIG
+            | MESSAGE_TIMESTAMP_BEFORE_MAX_MS_CONFIG | MESSAGE_TIMESTAMP_AFTER_MAX_MS_CONFIG => value.parse::<i64>().is_ok(),
+        LOCAL_RETENTION_MS_CONFIG | LOCAL_RETENTION_BYTES_CONFIG => value.parse::<i64>().is_ok_and(|value| value >= RETENTION_DEFAULT),
+        REMOTE_STORAGE_ENABLE_CONFIG => value.parse::<bool>().is_ok(),
+        CLEANUP_POLICY_CONFIG => value.split(',').all(|policy| matches!(policy.trim(), "delete" | "compact")),
+        MIN_CLEANABLE_DIRTY_RATIO_CONFIG => value.parse::<f64>().map(|ratio| (0.0..=1.0).contains(&ratio)).unwrap_or(false),
+        COMPRESSION_TYPE_CONFIG => CompressionType::parse(value).is_some(),
+        MAX_MESSAGE_BYTES_CONFIG => value.parse::<i32>().is_ok(),
+        MESSAGE_TIMESTAMP_TYPE_CONFIG => parse_timestamp_type(value).is_some(),
+        _ => return Err(format!("Unknown topic config name: {}", name)),
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(format!("Invalid value {} for configuration {}", value, name))
+    }
+}
+
+/// Checks a broker setting that can be changed at runtime, which are the
+/// defaults of the topic configs.
+pub fn validate_broker_config(name: &str, value: &str) -> Result<(), String> {
+    match dynamic_topic_config(name) {
+        Some(topic_config) => validate_topic_config(topic_config, value),
+        None => Err(format!("Cannot update {} dynamically", name)),
+    }
+}
+
+/// The topic config a dynamic broker setting is the default of, None for
+/// settings that are read-only at runtime.
+pub fn dynamic_topic_config(name: &str) -> Option<&'static str> {
+    TOPIC_CONFIG_SYNONYMS.iter()
+        .find(|(_, synonyms)| synonyms.contains(&name))
+        .map(|(topic_config, _)| *topic_config)
+}
+
+/// Replays the topic ConfigRecords in the metadata log into topic -> overrides.
+pub fn topic_configs(record_sets: &[RecordSet]) -> HashMap<String, HashMap<String, String>> {
+    config_overrides(record_sets, CONFIG_RESOURCE_TOPIC)
+}
+
+/// Replays the broker ConfigRecords in the metadata log into resource name ->
+/// overrides, where "" holds the cluster-wide defaults and a node id the
+/// settings of that broker.
+pub fn broker_configs(record_sets: &[RecordSet]) -> HashMap<String, HashMap<String, String>> {
+    config_overrides(record_sets, CONFIG_RESOURCE_BROKER)
+}
+
+// a record with a null value removes the override
+fn config_overrides(record_sets: &[RecordSet], resource_type: i8) -> HashMap<String, HashMap<String, String>> {
+    let mut configs: HashMap<String, HashMap<String, String>> = HashMap::new();
+    for record in record_sets.iter().flat_map(|record_set| record_set.records.iter()) {
+        if record.value.is_none() {
+            continue;
+        }
+        let RecordValue::ConfigRecord(config) = extract_record_value(record) else { continue };
+        if config.resource_type != resource_type {
+            continue;
+        }
+        let overrides = configs.entry(config.resource_name).or_default();
+        match config.value {
+            Some(value) => {
+                overrides.insert(config.name, value);
+            }
+            None => {
+                overrides.remove(&config.name);
+            }
+        }
+    }
+    configs
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use kafka_protocol::records::Compression;
+
+    use crate::config::BrokerConfig;
+
+    use super::{validate_broker_config, validate_topic_config, CompressionType, LogConfig, CLEANUP_POLICY_CONFIG, COMPRESSION_TYPE_CONFIG, LOCAL_RETENTION_MS_CONFIG, RETENTION_BYTES_CONFIG, RETENTION_MS_CONFIG};
+
+    #[test]
+    fn test_overrides() {
+        let config = LogConfig::from_broker(&BrokerConfig::parse("
+            log.retention.hours=1
+            log.retention.minutes=2
+            log.segment.bytes=1024
+            compression.type=gzip
+        "));
+        assert_eq!(2 * 60 * 1000, config.retention_ms);
+        assert_eq!(1024, config.segment_bytes);
+        assert_eq!(-1, config.retention_bytes);
+        assert_eq!(CompressionType::Codec(Compression::Gzip), config.compression_type);
+
+        let overrides = HashMap::from([
+            (RETENTION_MS_CONFIG.to_string(), "1000".to_string()),
+            (RETENTION_BYTES_CONFIG.to_string(), "abc".to_string()),
+        ]);
+        let config = config.with_overrides(&overrides);
+        assert_eq!(1000, config.retention_ms);
+        assert_eq!(-1, config.retention_bytes);
+        assert_eq!(1024, config.segment_bytes);
+        assert!(config.delete && !config.compact);
+
+        let overrides = HashMap::from([
+            (CLEANUP_POLICY_CONFIG.to_string(), "compact, delete".to_string()),
+            (COMPRESSION_TYPE_CONFIG.to_string(), "producer".to_string()),
+        ]);
+        let config = config.with_overrides(&overrides);
+        assert!(config.delete && config.compact);
+        assert_eq!(CompressionType::Producer, config.compression_type);
+    }
+
+    #[test]
+    fn test_values() {
+        let overrides = HashMap::from([
+            (CLEANUP_POLICY_CONFIG.to_string(), "delete,compact".to_string()),
+            (COMPRESSION_TYPE_CONFIG.to_string(), "lz4".to_string()),
+        ]);
+        let config = LogConfig::default().with_overrides(&overrides);
+        assert_eq!(Some("compact,delete".to_string()), config.value(CLEANUP_POLICY_CONFIG));
+        assert_eq!(Some("lz4".to_string()), config.value(COMPRESSION_TYPE_CONFIG));
+        assert_eq!(Some("604800000".to_string()), config.value(RETENTION_MS_CONFIG));
+        assert_eq!(None, config.value("unknown"));
+        // local retention follows the total retention unless set
+        assert_eq!((604800000, -1), config.local_retention());
+        let config = config.with_overrides(&HashMap::from([(LOCAL_RETENTION_MS_CONFIG.to_string(), "1000".to_string())]));
+        assert_eq!((1000, -1), config.local_retention());
+
+        assert!(validate_topic_config(RETENTION_MS_CONFIG, "-1").is_ok());
+        assert!(validate_topic_config(RETENTION_MS_CONFIG, "forever").is_err());
+        assert!(validate_topic_config(CLEANUP_POLICY_CONFIG, "compact,archive").is_err());
+        assert!(validate_topic_config(LOCAL_RETENTION_MS_CONFIG, "-3").is_err());
+        assert!(validate_topic_config("unknown", "1").is_err());
+        assert!(validate_broker_config("log.retention.hours", "24").is_ok());
+        assert!(validate_broker_config("node.id", "2").is_err());
+    }
+}
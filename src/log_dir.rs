@@ -0,0 +1,283 @@
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet};
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use kafka_protocol::records::RecordSet;
+use tokio::fs;
+use uuid::Uuid;
+
+use crate::broker::NODE_ID;
+use crate::config::BrokerConfig;
+use crate::describe_cluster::META_PROPERTIES_FILE;
+use crate::log::{log_dirs, TopicPartition};
+use crate::record::{extract_record_value, RecordValue};
+
+const DIRECTORY_ID_PROPERTY: &str = "directory.id";
+const BASE64_URL_ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+/// A random (version 4) UUID. Every RandomState gets fresh keys, which is
+/// random enough for directory ids.
+pub fn random_uuid() -> Uuid {
+    let mut bytes = [0; 16];
+    for (idx, chunk) in bytes.chunks_mut(8).enumerate() {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0));
+        hasher.write_usize(idx);
+        chunk.copy_from_slice(&hasher.finish().to_be_bytes());
+    }
+    uuid::Builder::from_random_bytes(bytes).into_uuid()
+}
+
+/// Kafka's string form of a UUID: URL-safe base64 without padding.
+pub fn uuid_to_base64(id: Uuid) -> String {
+    let mut text = String::new();
+    let (mut acc, mut bits) = (0u32, 0);
+    for byte in id.as_bytes() {
+        acc = ((acc << 8) | *byte as u32) & 0xffff;
+        bits += 8;
+        while bits >= 6 {
+            bits -= 6;
+            text.push(BASE64_URL_ALPHABET[(acc >> bits) as usize & 0x3f] as char);
+        }
+    }
+    if bits > 0 {
+        text.push(BASE64_URL_ALPHABET[(acc << (6 - bits)) as usize & 0x3f] as char);
+    }
+    text
+}
+
+pub fn uuid_from_base64(text: &str) -> Option<Uuid> {
+    let mut bytes = Vec::with_capacity(16);
+    let (mut acc, mut bits) = (0u32, 0);
+    for c in text.trim_end_matches('=').bytes() {
+        let digit = BASE64_URL_ALPHABET.iter().position(|digit| *digit == c)? as u32;
+        acc = ((acc << 6) | digit) & 0xffff;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((acc >> bits) as u8);
+        }
+    }
+    Uuid::from_slice(&bytes).ok()
+}
+
+/// Reads the id of a log dir from its meta.properties, generating and
+/// storing one the first time the dir is used.
+async fn load_directory_id(path: &Path) -> io::Result<Uuid> {
+    fs::create_dir_all(path).await?;
+    let meta_path = path.join(META_PROPERTIES_FILE);
+    let mut text = match fs::read_to_string(&meta_path).await {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => format!("version=1\nnode.id={}\n", NODE_ID),
+        Err(e) => return Err(e),
+    };
+    if let Some(id) = BrokerConfig::parse(&text).get(DIRECTORY_ID_PROPERTY) {
+        return uuid_from_base64(id)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("invalid {} {}", DIRECTORY_ID_PROPERTY, id)));
+    }
+    let id = random_uuid();
+    if !text.is_empty() && !text.ends_with('\n') {
+        text.push('\n');
+    }
+    text.push_str(&format!("{}={}\n", DIRECTORY_ID_PROPERTY, uuid_to_base64(id)));
+    let tmp = meta_path.with_extension("tmp");
+    fs::write(&tmp, text).await?;
+    fs::rename(&tmp, &meta_path).await?;
+    Ok(id)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogDir {
+    pub path: PathBuf,
+    pub id: Uuid,
+    // false once an I/O error took it offline
+    pub online: bool,
+}
+
+/// The configured log dirs and the partitions placed on each.
+#[derive(Debug, Default)]
+pub struct LogDirs {
+    dirs: Vec<LogDir>,
+    assignments: HashMap<TopicPartition, PathBuf>,
+    // partitions being copied to another dir
+    moving: HashSet<TopicPartition>,
+}
+
+impl LogDirs {
+    /// The dirs at `paths`, online and with ids not read yet.
+    pub fn new(paths: Vec<PathBuf>) -> LogDirs {
+        let dirs = paths.into_iter()
+            .map(|path| LogDir { path, id: Uuid::nil(), online: true })
+            .collect();
+        LogDirs { dirs, ..LogDirs::default() }
+    }
+
+    /// Creates the configured dirs and reads their ids. A dir that cannot be
+    /// used starts offline rather than failing the broker.
+    pub async fn load(config: &BrokerConfig) -> LogDirs {
+        let mut dirs = Vec::new();
+        for path in log_dirs(config) {
+            let (id, online) = match load_directory_id(&path).await {
+                Ok(id) => (id, true),
+                Err(e) => {
+                    println!("Failed to load log dir {}: {}", path.display(), e);
+                    (Uuid::nil(), false)
+                }
+            };
+            dirs.push(LogDir { path, id, online });
+        }
+        LogDirs { dirs, ..LogDirs::default() }
+    }
+
+    pub fn dirs(&self) -> &[LogDir] {
+        &self.dirs
+    }
+
+    pub fn get(&self, path: &Path) -> Option<&LogDir> {
+        self.dirs.iter().find(|dir| dir.path == path)
+    }
+
+    pub fn is_online(&self, path: &Path) -> bool {
+        self.get(path).map(|dir| dir.online).unwrap_or(false)
+    }
+
+    /// The dir a partition was placed on.
+    pub fn dir_of(&self, topic_partition: &TopicPartition) -> Option<&LogDir> {
+        self.assignments.get(topic_partition).and_then(|path| self.get(path))
+    }
+
+    /// Each placed partition with its dir.
+    pub fn assignments(&self) -> impl Iterator<Item = (&TopicPartition, &LogDir)> {
+        self.assignments.iter()
+            .filter_map(|(topic_partition, path)| Some((topic_partition, self.get(path)?)))
+    }
+
+    pub fn assign(&mut self, topic_partition: TopicPartition, path: &Path) {
+        self.assignments.insert(topic_partition, path.to_path_buf());
+    }
+
+    pub fn partition_count(&self, path: &Path) -> usize {
+        self.assignments.values().filter(|assigned| *assigned == path).count()
+    }
+
+    /// The online dir holding the fewest partitions, the first configured
+    /// one on a tie.
+    pub fn least_loaded(&self) -> Option<&LogDir> {
+        self.dirs.iter()
+            .filter(|dir| dir.online)
+            .min_by_key(|dir| self.partition_count(&dir.path))
+    }
+
+    /// Takes a dir offline, returning whether it was online.
+    pub fn mark_offline(&mut self, path: &Path) -> bool {
+        match self.dirs.iter_mut().find(|dir| dir.path == path) {
+            Some(dir) if dir.online => {
+                dir.online = false;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Claims a partition for a move, false if it is already moving.
+    pub fn start_move(&mut self, topic_partition: &TopicPartition) -> bool {
+        self.moving.insert(topic_partition.clone())
+    }
+
+    pub fn finish_move(&mut self, topic_partition: &TopicPartition) {
+        self.moving.remove(topic_partition);
+    }
+}
+
+/// This broker's log dir of each partition as recorded in the metadata log,
+/// from the `directories` of PartitionRecords and PartitionChangeRecords.
+pub fn directory_assignments(record_sets: &[RecordSet]) -> HashMap<TopicPartition, Uuid> {
+    let mut topic_names = HashMap::new();
+    let mut replicas: HashMap<TopicPartition, Vec<i32>> = HashMap::new();
+    let mut assignments = HashMap::new();
+    for record in record_sets.iter().flat_map(|record_set| record_set.records.iter()) {
+        if record.value.is_none() {
+            continue;
+        }
+        let (topic_id, partition_id, partition_replicas, directories) = match extract_record_value(record) {
+            RecordValue::TopicRecord(topic) => {
+                topic_names.insert(topic.topic_id, topic.name);
+                continue;
+            }
+            RecordValue::PartitionRecord(partition) => (partition.topic_id, partition.partition_id, Some(partition.replicas), Some(partition.directories)),
+            RecordValue::PartitionChangeRecord(change) => (change.topic_id, change.partition_id, change.replicas, change.directories),
+            _ => continue,
+        };
+        let Some(topic_name) = topic_names.get(&topic_id) else { continue };
+        let topic_partition = (topic_name.clone(), partition_id);
+        if let Some(partition_replicas) = partition_replicas {
+            replicas.insert(topic_partition.clone(), partition_replicas);
+        }
+        let Some(directories) = directories else { continue };
+        // the directories line up with the replicas
+        let directory = replicas.get(&topic_partition)
+            .and_then(|replicas| replicas.iter().position(|replica| *replica == NODE_ID))
+            .and_then(|idx| directories.get(idx));
+        match directory {
+            Some(directory) => assignments.insert(topic_partition, *directory),
+            None => assignments.remove(&topic_partition),
+        };
+    }
+    assignments
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use uuid::Uuid;
+
+    use crate::record::{encode_partition_change_record, parse_record_value, RecordValue};
+
+    use super::{random_uuid, uuid_from_base64, uuid_to_base64, LogDirs};
+
+    #[test]
+    fn test_directory_ids() {
+        let id = Uuid::from_bytes([0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]);
+        assert_eq!("AAECAwQFBgcICQoLDA0ODw", uuid_to_base64(id));
+        assert_eq!(Some(id), uuid_from_base64("AAECAwQFBgcICQoLDA0ODw"));
+        let id = random_uuid();
+        assert_eq!(Some(id), uuid_from_base64(&uuid_to_base64(id)));
+        assert_ne!(id, random_uuid());
+        assert_eq!(None, uuid_from_base64("not base64!"));
+
+        let mut value = encode_partition_change_record(3, Uuid::from_u128(7), &[id]);
+        match parse_record_value(&mut value) {
+            RecordValue::PartitionChangeRecord(change) => {
+                assert_eq!((3, Uuid::from_u128(7)), (change.partition_id, change.topic_id));
+                assert_eq!(None, change.replicas);
+                assert_eq!(Some(vec![id]), change.directories);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_placement() {
+        let mut log_dirs = LogDirs::new(vec![PathBuf::from("/data/a"), PathBuf::from("/data/b")]);
+        assert_eq!(Path::new("/data/a"), log_dirs.least_loaded().unwrap().path);
+        log_dirs.assign(("foo".to_string(), 0), Path::new("/data/a"));
+        assert_eq!(Path::new("/data/b"), log_dirs.least_loaded().unwrap().path);
+        log_dirs.assign(("foo".to_string(), 1), Path::new("/data/b"));
+        assert_eq!(Path::new("/data/a"), log_dirs.least_loaded().unwrap().path);
+
+        assert!(log_dirs.mark_offline(Path::new("/data/a")));
+        assert!(!log_dirs.mark_offline(Path::new("/data/a")));
+        assert!(!log_dirs.is_online(Path::new("/data/a")));
+        assert_eq!(Path::new("/data/b"), log_dirs.least_loaded().unwrap().path);
+        assert!(!log_dirs.dir_of(&("foo".to_string(), 0)).unwrap().online);
+
+        assert!(log_dirs.start_move(&("foo".to_string(), 1)));
+        assert!(!log_dirs.start_move(&("foo".to_string(), 1)));
+        log_dirs.finish_move(&("foo".to_string(), 1));
+        assert!(log_dirs.start_move(&("foo".to_string(), 1)));
+    }
+}
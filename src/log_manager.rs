@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::broker::{now_ms, Broker};
+use crate::checkpoint::CLEANER_OFFSET_CHECKPOINT;
+use crate::record::record_set_to_topic;
+use crate::remote_log::{manage_remote_logs, DEFAULT_REMOTE_LOG_MANAGER_TASK_INTERVAL_MS, REMOTE_LOG_MANAGER_TASK_INTERVAL_MS_CONFIG};
+
+pub const RETENTION_CHECK_INTERVAL_MS_CONFIG: &str = "log.retention.check.interval.ms";
+pub const DEFAULT_RETENTION_CHECK_INTERVAL_MS: u64 = 5 * 60 * 1000;
+pub const CLEANER_ENABLE_CONFIG: &str = "log.cleaner.enable";
+pub const CLEANER_BACKOFF_MS_CONFIG: &str = "log.cleaner.backoff.ms";
+pub const DEFAULT_CLEANER_BACKOFF_MS: u64 = 15 * 1000;
+pub const FLUSH_OFFSET_CHECKPOINT_INTERVAL_MS_CONFIG: &str = "log.flush.offset.checkpoint.interval.ms";
+pub const DEFAULT_FLUSH_OFFSET_CHECKPOINT_INTERVAL_MS: u64 = 60 * 1000;
+pub const HIGH_WATERMARK_CHECKPOINT_INTERVAL_MS_CONFIG: &str = "replica.high.watermark.checkpoint.interval.ms";
+pub const DEFAULT_HIGH_WATERMARK_CHECKPOINT_INTERVAL_MS: u64 = 5 * 1000;
+// how often the metadata log is checked for enough new records to snapshot
+const METADATA_SNAPSHOT_CHECK_INTERVAL_MS: u64 = 1000;
+
+fn schedule<F, Fut>(broker: &Arc<Broker>, interval_ms: u64, task: F)
+where
+    F: Fn(Arc<Broker>) -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send,
+{
+    let broker = broker.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_millis(interval_ms));
+        loop {
+            interval.tick().await;
+            task(broker.clone()).await;
+        }
+    });
+}
+
+/// Starts the background retention, cleaner, checkpoint, metadata snapshot
+/// and remote log manager tasks.
+pub fn start(broker: &Arc<Broker>) {
+    let config = &broker.config;
+    schedule(broker, config.get_or(RETENTION_CHECK_INTERVAL_MS_CONFIG, DEFAULT_RETENTION_CHECK_INTERVAL_MS), |broker| async move {
+        cleanup_logs(&broker).await;
+    });
+    if config.get_or(CLEANER_ENABLE_CONFIG, true) {
+        schedule(broker, config.get_or(CLEANER_BACKOFF_MS_CONFIG, DEFAULT_CLEANER_BACKOFF_MS), |broker| async move {
+            clean_logs(&broker).await;
+        });
+    }
+    if broker.remote_storage.is_some() {
+        schedule(broker, config.get_or(REMOTE_LOG_MANAGER_TASK_INTERVAL_MS_CONFIG, DEFAULT_REMOTE_LOG_MANAGER_TASK_INTERVAL_MS), |broker| async move {
+            manage_remote_logs(&broker).await;
+        });
+    }
+    schedule(broker, config.get_or(FLUSH_OFFSET_CHECKPOINT_INTERVAL_MS_CONFIG, DEFAULT_FLUSH_OFFSET_CHECKPOINT_INTERVAL_MS), |broker| async move {
+        if let Err(e) = broker.checkpoint_log_offsets().await {
+            println!("Failed to checkpoint log offsets: {}", e);
+        }
+    });
+    schedule(broker, config.get_or(HIGH_WATERMARK_CHECKPOINT_INTERVAL_MS_CONFIG, DEFAULT_HIGH_WATERMARK_CHECKPOINT_INTERVAL_MS), |broker| async move {
+        if let Err(e) = broker.checkpoint_high_watermarks().await {
+            println!("Failed to checkpoint high watermarks: {}", e);
+        }
+    });
+    schedule(broker, METADATA_SNAPSHOT_CHECK_INTERVAL_MS, |broker| async move {
+        if let Err(e) = broker.snapshot_metadata().await {
+            println!("Failed to snapshot the metadata log: {}", e);
+        }
+    });
+}
+
+/// Refreshes the config overrides from the metadata log and applies
+/// retention to every partition.
+pub async fn cleanup_logs(broker: &Broker) {
+    broker.refresh_configs().await;
+    let record_sets = broker.cluster_metadata().await;
+    for (topic_name, (_, partition_ids)) in record_set_to_topic(&record_sets) {
+        for partition_idx in partition_ids {
+            match broker.delete_old_segments(&topic_name, partition_idx, now_ms()).await {
+                Ok(0) => {}
+                Ok(deleted) => println!("Deleted {} segments of {}-{}", deleted, topic_name, partition_idx),
+                Err(e) => println!("Failed to apply retention to {}-{}: {}", topic_name, partition_idx, e),
+            }
+        }
+    }
+}
+
+/// Compacts the partitions of compacted topics, resuming each from the offset
+/// recorded in the `cleaner-offset-checkpoint` of its log dir.
+pub async fn clean_logs(broker: &Broker) {
+    let mut checkpoints = broker.read_checkpoints(CLEANER_OFFSET_CHECKPOINT).await.unwrap_or_else(|e| {
+        println!("Failed to read {}: {}", CLEANER_OFFSET_CHECKPOINT, e);
+        HashMap::new()
+    });
+    let mut cleaned = false;
+    let record_sets = broker.cluster_metadata().await;
+    for (topic_name, (_, partition_ids)) in record_set_to_topic(&record_sets) {
+        for partition_idx in partition_ids {
+            let topic_partition = (topic_name.clone(), partition_idx);
+            let first_dirty_offset = checkpoints.get(&topic_partition).copied().unwrap_or(0);
+            match broker.clean(&topic_name, partition_idx, first_dirty_offset, now_ms()).await {
+                Ok(Some(offset)) => {
+                    checkpoints.insert(topic_partition, offset);
+                    cleaned = true;
+                }
+                Ok(None) => {}
+                Err(e) => println!("Failed to clean {}-{}: {}", topic_name, partition_idx, e),
+            }
+        }
+    }
+    if cleaned {
+        if let Err(e) = broker.write_checkpoints(CLEANER_OFFSET_CHECKPOINT, &checkpoints).await {
+            println!("Failed to write {}: {}", CLEANER_OFFSET_CHECKPOINT, e);
+        }
+    }
+}
@@ -0,0 +1,215 @@
+use bytes::BytesMut;
+use kafka_protocol::error::ResponseError;
+use kafka_protocol::records::{Compression, RecordBatchEncoder, RecordEncodeOptions, TimestampType};
+
+use crate::log::{batches, decode_batch, set_log_append_time, validate_batch};
+use crate::log_config::{CompressionType, LogConfig};
+
+// zstd batches need Produce v7
+const ZSTD_MIN_PRODUCE_VERSION: i16 = 7;
+
+/// Why the records of a partition were rejected. `record_errors` points at
+/// the offending records by their index in the request, for the
+/// ProduceResponse's record_errors.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValidationError {
+    pub error: ResponseError,
+    pub message: Option<String>,
+    pub record_errors: Vec<(i32, String)>,
+}
+
+impl From<ResponseError> for ValidationError {
+    fn from(error: ResponseError) -> Self {
+        ValidationError {
+            error,
+            message: None,
+            record_errors: Vec::new(),
+        }
+    }
+}
+
+/// Checks the batches of a Produce against the topic's config and converts
+/// them to its compression.type and message.timestamp.type. Every batch is
+/// decoded, which also decompresses and so checks its records; batches
+/// already in the right codec, and control batches, keep their bytes.
+pub fn validate_records(records: &[u8], config: &LogConfig, api_version: i16, now: i64) -> Result<BytesMut, ValidationError> {
+    let headers = batches(records);
+    if headers.is_empty() {
+        return Err(ResponseError::CorruptMessage.into());
+    }
+    let mut validated = BytesMut::with_capacity(records.len());
+    let mut record_index = 0;
+    for (pos, header) in headers {
+        let batch = &records[pos..pos + header.size()];
+        if header.size() > config.max_message_bytes.max(0) as usize {
+            return Err(ValidationError {
+                message: Some(format!("record batch of {} bytes is larger than max.message.bytes {}", header.size(), config.max_message_bytes)),
+                ..ResponseError::MessageTooLarge.into()
+            });
+        }
+        if let Err(reason) = validate_batch(&header, batch) {
+            println!("Rejected record batch: {}", reason);
+            return Err(ResponseError::CorruptMessage.into());
+        }
+        let source = header.compression().ok_or(ResponseError::UnsupportedCompressionType)?;
+        if source == Compression::Zstd && api_version < ZSTD_MIN_PRODUCE_VERSION {
+            return Err(ResponseError::UnsupportedCompressionType.into());
+        }
+        let record_set = decode_batch(batch).map_err(|e| {
+            println!("Rejected record batch: {}", e);
+            ResponseError::CorruptMessage
+        })?;
+
+        // the first kind of error found is the one reported for the batch
+        let mut error = None;
+        let mut record_errors = Vec::new();
+        let mut reject = |index: i32, kind: ResponseError, reason: String| {
+            error.get_or_insert(kind);
+            record_errors.push((index, reason));
+        };
+        if header.records_count as usize != record_set.records.len() || header.last_offset_delta != header.records_count - 1 {
+            reject(record_index, ResponseError::InvalidRecord, format!("batch of {} records declares {} with last offset delta {}",
+                record_set.records.len(), header.records_count, header.last_offset_delta));
+        }
+        let earliest = now.saturating_sub(config.message_timestamp_before_max_ms);
+        let latest = now.saturating_add(config.message_timestamp_after_max_ms);
+        for (idx, record) in record_set.records.iter().enumerate() {
+            let index = record_index + idx as i32;
+            if record.offset != header.base_offset + idx as i64 {
+                reject(index, ResponseError::InvalidRecord, format!("record offset {} is not consecutive, expected {}", record.offset, header.base_offset + idx as i64));
+            }
+            if header.is_control() {
+                continue;
+            }
+            if config.compact && record.key.is_none() {
+                reject(index, ResponseError::InvalidRecord, "compacted topic cannot accept a record without a key".to_string());
+            }
+            if config.message_timestamp_type == TimestampType::Creation && (record.timestamp < earliest || record.timestamp > latest) {
+                reject(index, ResponseError::InvalidTimestamp, format!("timestamp {} of the record at offset {} is out of range [{}, {}]", record.timestamp, record.offset, earliest, latest));
+            }
+        }
+        if let Some(error) = error {
+            return Err(ValidationError {
+                error,
+                message: Some("One or more records have been rejected".to_string()),
+                record_errors,
+            });
+        }
+        record_index += record_set.records.len() as i32;
+
+        let target = match config.compression_type {
+            CompressionType::Producer => source,
+            CompressionType::Codec(compression) => compression,
+        };
+        let start = validated.len();
+        if target == source || header.is_control() {
+            validated.extend_from_slice(batch);
+        } else {
+            let options = RecordEncodeOptions {
+                version: 2,
+                compression: target,
+            };
+            RecordBatchEncoder::encode(&mut validated, &record_set.records, &options)
+                .map_err(|_| ResponseError::CorruptMessage)?;
+        }
+        if config.message_timestamp_type == TimestampType::LogAppend {
+            set_log_append_time(&mut validated[start..], now);
+        }
+    }
+    Ok(validated)
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::{Bytes, BytesMut};
+    use kafka_protocol::error::ResponseError;
+    use kafka_protocol::records::{Compression, Record, RecordBatchDecoder, TimestampType};
+
+    use crate::log::{validate_batch, BatchHeader};
+    use crate::log_config::{CompressionType, LogConfig};
+    use crate::test_util::{self, encode};
+
+    use super::validate_records;
+
+    const NOW: i64 = 10_000;
+
+    fn config(compression_type: CompressionType) -> LogConfig {
+        LogConfig { compression_type, ..LogConfig::default() }
+    }
+
+    fn record(offset: i64) -> Record {
+        test_util::record(offset, 1000 + offset, Some(Bytes::from(format!("key-{}", offset))), Some(Bytes::from("value ".repeat(100))))
+    }
+
+    fn batch(compression: Compression) -> BytesMut {
+        encode(&(0..3).map(record).collect::<Vec<_>>(), compression)
+    }
+
+    fn keys_and_values(mut buf: Bytes) -> Vec<(Option<Bytes>, Option<Bytes>)> {
+        RecordBatchDecoder::decode_all(&mut buf).unwrap().into_iter()
+            .flat_map(|record_set| record_set.records)
+            .map(|record| (record.key, record.value))
+            .collect()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let uncompressed = batch(Compression::None);
+        let expected = keys_and_values(uncompressed.clone().freeze());
+        for compression in [Compression::Gzip, Compression::Snappy, Compression::Lz4, Compression::Zstd] {
+            // recompressed to the topic's codec
+            let compressed = validate_records(&uncompressed, &config(CompressionType::Codec(compression)), 11, NOW).unwrap();
+            assert_eq!(Some(compression), BatchHeader::parse(&compressed).unwrap().compression());
+            assert!(compressed.len() < uncompressed.len());
+            assert_eq!(expected, keys_and_values(compressed.clone().freeze()));
+
+            // the producer's codec is kept as is
+            assert_eq!(compressed, validate_records(&compressed, &config(CompressionType::Producer), 11, NOW).unwrap());
+            assert_eq!(compressed, validate_records(&compressed, &config(CompressionType::Codec(compression)), 11, NOW).unwrap());
+
+            let decompressed = validate_records(&compressed, &config(CompressionType::Codec(Compression::None)), 11, NOW).unwrap();
+            assert_eq!(Some(Compression::None), BatchHeader::parse(&decompressed).unwrap().compression());
+            assert_eq!(expected, keys_and_values(decompressed.freeze()));
+        }
+    }
+
+    #[test]
+    fn test_invalid() {
+        let zstd = batch(Compression::Zstd);
+        assert_eq!(Some(ResponseError::UnsupportedCompressionType), validate_records(&zstd, &config(CompressionType::Producer), 6, NOW).err().map(|e| e.error));
+
+        let mut corrupt = batch(Compression::Gzip);
+        let last = corrupt.len() - 1;
+        corrupt[last] ^= 0xff;
+        assert_eq!(Some(ResponseError::CorruptMessage), validate_records(&corrupt, &config(CompressionType::Producer), 11, NOW).err().map(|e| e.error));
+        assert_eq!(Some(ResponseError::CorruptMessage), validate_records(&[], &config(CompressionType::Producer), 11, NOW).err().map(|e| e.error));
+    }
+
+    #[test]
+    fn test_record_errors() {
+        let uncompressed = batch(Compression::None);
+        let too_small = LogConfig { max_message_bytes: 100, ..LogConfig::default() };
+        assert_eq!(Some(ResponseError::MessageTooLarge), validate_records(&uncompressed, &too_small, 11, NOW).err().map(|e| e.error));
+
+        // the second record has no key and the third an old timestamp
+        let mut records: Vec<Record> = (0..3).map(record).collect();
+        records[1].key = None;
+        records[2].timestamp = 10;
+        let invalid = encode(&records, Compression::Gzip);
+        let compacted = LogConfig { compact: true, message_timestamp_before_max_ms: 9500, ..LogConfig::default() };
+        let error = validate_records(&invalid, &compacted, 11, NOW).unwrap_err();
+        assert_eq!(ResponseError::InvalidRecord, error.error);
+        assert_eq!(vec![1, 2], error.record_errors.iter().map(|(index, _)| *index).collect::<Vec<_>>());
+        assert!(validate_records(&uncompressed, &compacted, 11, NOW).is_ok());
+
+        // LogAppendTime overwrites the timestamps and keeps the crc valid
+        let log_append_time = LogConfig { message_timestamp_type: TimestampType::LogAppend, ..LogConfig::default() };
+        let stamped = validate_records(&invalid, &log_append_time, 11, NOW).unwrap();
+        let header = BatchHeader::parse(&stamped).unwrap();
+        assert_eq!(NOW, header.max_timestamp);
+        assert!(validate_batch(&header, &stamped).is_ok());
+        let mut buf = stamped.freeze();
+        let record_set = RecordBatchDecoder::decode_all(&mut buf).unwrap();
+        assert!(record_set[0].records.iter().all(|record| record.timestamp_type == TimestampType::LogAppend));
+    }
+}
@@ -1,51 +1,183 @@
-
-use std::collections::HashMap;
+use std::env;
+use std::process;
+use std::sync::Arc;
+use std::time::Duration;
 
 use bytes::{Buf, BufMut, BytesMut};
+use codecrafters_kafka::acl::{AclState, Authorizer};
+use codecrafters_kafka::alter_configs::{handle_alter_configs, handle_incremental_alter_configs};
+use codecrafters_kafka::alter_replica_log_dirs::handle_alter_replica_log_dirs;
+use codecrafters_kafka::broker::{Broker, HOST};
+use codecrafters_kafka::config::BrokerConfig;
+use codecrafters_kafka::delete_records::handle_delete_records;
+use codecrafters_kafka::describe_cluster::{advertised_endpoint, cluster_id, handle_describe_cluster, parse_listeners, ClusterImage};
+use codecrafters_kafka::describe_configs::handle_describe_configs;
+use codecrafters_kafka::describe_log_dirs::handle_describe_log_dirs;
+use codecrafters_kafka::describe_topic_partitions::handle_describe_topic_partitions;
+use codecrafters_kafka::fetch::{handle_fetch, FLEXIBLE_MIN_VERSION};
+use codecrafters_kafka::group;
+use codecrafters_kafka::list_offsets::handle_list_offsets;
+use codecrafters_kafka::log_manager;
+use codecrafters_kafka::offset_for_leader_epoch::handle_offset_for_leader_epoch;
+use codecrafters_kafka::produce::handle_produce;
+use codecrafters_kafka::raft;
 use codecrafters_kafka::record::record_set_to_topic;
-use futures::future::join_all;
+use codecrafters_kafka::replica;
+use codecrafters_kafka::send::{ResponseBuf, ResponseSend};
+use codecrafters_kafka::txn;
 use kafka_protocol::messages::api_versions_request::ApiVersionsRequest;
 use kafka_protocol::messages::api_versions_response::{ApiVersion, ApiVersionsResponse};
-use kafka_protocol::messages::describe_topic_partitions_response::{DescribeTopicPartitionsResponsePartition, DescribeTopicPartitionsResponseTopic};
-use kafka_protocol::messages::fetch_response::{FetchableTopicResponse, PartitionData};
-use kafka_protocol::messages::{ApiKey, DescribeTopicPartitionsRequest, DescribeTopicPartitionsResponse, FetchRequest, FetchResponse, RequestHeader, RequestKind, ResponseHeader, ResponseKind};
+use kafka_protocol::messages::find_coordinator_response::Coordinator;
+use kafka_protocol::messages::{AddOffsetsToTxnRequest, AddOffsetsToTxnResponse, AddPartitionsToTxnRequest, AddPartitionsToTxnResponse, AlterConfigsRequest, AlterConfigsResponse, AlterPartitionRequest, AlterPartitionResponse, AlterReplicaLogDirsRequest, AlterReplicaLogDirsResponse, ApiKey, BeginQuorumEpochRequest, BeginQuorumEpochResponse, BrokerId, DeleteRecordsRequest, DeleteRecordsResponse, DescribeClusterRequest, DescribeClusterResponse, DescribeConfigsRequest, DescribeConfigsResponse, DescribeLogDirsRequest, DescribeLogDirsResponse, DescribeQuorumRequest, DescribeQuorumResponse, DescribeTopicPartitionsRequest, DescribeTopicPartitionsResponse, EndQuorumEpochRequest, EndQuorumEpochResponse, EndTxnRequest, EndTxnResponse, FetchRequest, FetchResponse, FetchSnapshotRequest, FetchSnapshotResponse, FindCoordinatorRequest, FindCoordinatorResponse, IncrementalAlterConfigsRequest, IncrementalAlterConfigsResponse, InitProducerIdRequest, InitProducerIdResponse, ListOffsetsRequest, ListOffsetsResponse, OffsetFetchRequest, OffsetFetchResponse, OffsetForLeaderEpochRequest, OffsetForLeaderEpochResponse, ProduceRequest, ProduceResponse, RequestHeader, RequestKind, ResponseHeader, ResponseKind, TxnOffsetCommitRequest, TxnOffsetCommitResponse, VoteRequest, VoteResponse};
 use kafka_protocol::error::ResponseError;
-use kafka_protocol::protocol::buf::ByteBuf;
-use kafka_protocol::protocol::{Decodable, Encodable, HeaderVersion};
-use kafka_protocol::records::{RecordBatchDecoder, RecordSet};
-use tokio::fs;
-
+use kafka_protocol::protocol::buf::{ByteBuf, ByteBufMut};
+use kafka_protocol::protocol::{Decodable, Encodable, HeaderVersion, StrBytes};
+use futures::future::join_all;
 use tokio::net::TcpListener;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use uuid::Uuid;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, watch};
+
+const SUPPORTED_APIS: &[(ApiKey, i16, i16)] = &[
+    (ApiKey::Produce, 0, 11),
+    (ApiKey::Fetch, 0, 16),
+    (ApiKey::ListOffsets, 1, 8),
+    (ApiKey::OffsetForLeaderEpoch, 0, 4),
+    (ApiKey::FindCoordinator, 0, 4),
+    (ApiKey::ApiVersions, 0, 4),
+    (ApiKey::DeleteRecords, 0, 2),
+    (ApiKey::InitProducerId, 0, 4),
+    (ApiKey::AddPartitionsToTxn, 0, 4),
+    (ApiKey::AddOffsetsToTxn, 0, 4),
+    (ApiKey::EndTxn, 0, 4),
+    (ApiKey::TxnOffsetCommit, 0, 4),
+    (ApiKey::OffsetFetch, 1, 9),
+    (ApiKey::DescribeConfigs, 0, 4),
+    (ApiKey::AlterConfigs, 0, 2),
+    (ApiKey::IncrementalAlterConfigs, 0, 1),
+    (ApiKey::DescribeCluster, 0, 1),
+    (ApiKey::AlterReplicaLogDirs, 0, 2),
+    (ApiKey::DescribeLogDirs, 0, 4),
+    (ApiKey::DescribeTopicPartitions, 0, 4),
+    (ApiKey::Vote, 0, 0),
+    (ApiKey::BeginQuorumEpoch, 0, 0),
+    (ApiKey::EndQuorumEpoch, 0, 0),
+    (ApiKey::DescribeQuorum, 0, 1),
+    (ApiKey::AlterPartition, 0, 1),
+    (ApiKey::FetchSnapshot, 0, 0),
+];
 
+struct Connection {
+    client_host: String,
+    // flips when the client goes away, to cut parked requests short
+    closed: watch::Receiver<bool>,
+}
 
 #[tokio::main]
 async fn main() {
-    let listener = TcpListener::bind("127.0.0.1:9092").await.unwrap();
+    let config = match env::args().nth(1) {
+        Some(path) => BrokerConfig::load(&path).await.unwrap_or_else(|e| {
+            println!("Failed to read {}: {}", path, e);
+            BrokerConfig::default()
+        }),
+        None => BrokerConfig::default(),
+    };
+    let broker = Arc::new(Broker::new(config));
+    if let Err(e) = broker.load().await {
+        println!("Failed to load coordinator state: {}", e);
+    }
+
+    let txn_broker = broker.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            txn::abort_expired(&txn_broker).await;
+        }
+    });
 
+    log_manager::start(&broker);
+    raft::start(&broker);
+    replica::start(&broker);
+
+    let shutdown_broker = broker.clone();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        if let Err(e) = shutdown_broker.shutdown().await {
+            println!("Failed to shut down cleanly: {}", e);
+        }
+        process::exit(0);
+    });
+
+    let mut listeners = Vec::new();
+    for address in listener_addresses(&broker.config) {
+        listeners.push(TcpListener::bind(&address).await.unwrap());
+    }
+    join_all(listeners.into_iter().map(|listener| serve(broker.clone(), listener))).await;
+}
+
+// every configured listener, or 127.0.0.1:9092 without any
+fn listener_addresses(config: &BrokerConfig) -> Vec<String> {
+    let addresses: Vec<String> = config.get("listeners")
+        .map(parse_listeners)
+        .unwrap_or_default()
+        .into_iter()
+        // "localhost" could resolve to ::1 alone, which IPv4 clients miss
+        .map(|(_, host, port)| format!("{}:{}", if host == HOST { "127.0.0.1" } else { host.as_str() }, port))
+        .collect();
+    if addresses.is_empty() {
+        vec!["127.0.0.1:9092".to_string()]
+    } else {
+        addresses
+    }
+}
+
+async fn serve(broker: Arc<Broker>, listener: TcpListener) {
     loop {
         match listener.accept().await {
-            Ok((mut socket, _addr)) => {
+            Ok((socket, addr)) => {
                 println!("Accepted new connection");
+                let broker = broker.clone();
                 tokio::spawn(async move {
-                    let (mut rd, mut wr) = socket.split(); // Split for concurrent I/O
-                    let mut buffer = [0; 1024];
-                    loop {
-                        match rd.read(&mut buffer).await {
-                            Ok(n) if n > 0 => {
-                                let mut buf = BytesMut::from(&buffer[4..n]);
-                                let response = handle(&mut buf).await;
-                                let response_bytes: BytesMut = response.into();
-                                if let Err(e) = wr.write_all(&response_bytes).await {
-                                    println!("Failed to write to socket: {}", e);
+                    let (mut rd, mut wr) = socket.into_split(); // Split for concurrent I/O
+                    // reading runs separately so parked requests notice the client going away
+                    let (closed_tx, closed_rx) = watch::channel(false);
+                    let (request_tx, mut request_rx) = mpsc::channel::<BytesMut>(16);
+                    tokio::spawn(async move {
+                        loop {
+                            let message_size = match rd.read_i32().await {
+                                Ok(n) => n as usize,
+                                Err(_) => {
+                                    println!("Connection closed by client");
+                                    break;
                                 }
+                            };
+                            let mut request = vec![0; message_size];
+                            if let Err(e) = rd.read_exact(&mut request).await {
+                                println!("Failed to read from socket: {}", e);
+                                break;
+                            }
+                            if request_tx.send(BytesMut::from(&request[..])).await.is_err() {
+                                break;
                             }
-                            Ok(_) => println!("Connection closed by client"),
-                            Err(e) => println!("Failed to read from socket: {}", e),
+                        }
+                        let _ = closed_tx.send(true);
+                    });
+                    let connection = Connection {
+                        client_host: addr.ip().to_string(),
+                        closed: closed_rx,
+                    };
+                    while let Some(mut buf) = request_rx.recv().await {
+                        let response = handle(&broker, &mut buf, &connection).await;
+                        if response.is_empty() {
+                            continue;
+                        }
+                        if let Err(e) = response.write_to(&mut wr).await {
+                            println!("Failed to write to socket: {}", e);
+                            break;
                         }
                     }
-                    let _ = socket.shutdown().await;
+                    let _ = wr.shutdown().await;
                 });
             }
             Err(e) => {
@@ -55,9 +187,18 @@ async fn main() {
     }
 }
 
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).unwrap();
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+}
+
+// whether SUPPORTED_APIS lists the key with the version
 fn check_version(api_key: ApiKey, api_version: i16) -> bool {
-    let api_version_range = api_key.valid_versions();
-    api_version_range.min <= api_version && api_version_range.max >= api_version
+    SUPPORTED_APIS.iter()
+        .any(|(supported, min_version, max_version)| *supported == api_key && *min_version <= api_version && api_version <= *max_version)
 }
 
 fn default_response_header(correlation_id: i32) -> ResponseHeader {
@@ -68,160 +209,281 @@ fn default_response_header(correlation_id: i32) -> ResponseHeader {
 fn build_response(header: ResponseHeader,
     header_version: i16,
     body: ResponseKind,
-    body_version: i16) -> BytesMut {
-    let mut res_buf = BytesMut::new();
-    let mut header_buf = BytesMut::new();
-    let mut body_buf = BytesMut::new();
-
-    header.encode(&mut header_buf, header_version).unwrap();
-    body.encode(&mut body_buf, body_version).unwrap();
+    body_version: i16) -> ResponseBuf {
+    let mut res_buf = ResponseBuf::default();
+    res_buf.put_i32(0); // size, known once encoded
 
-    let message_size = header_buf.len() + body_buf.len();
-    res_buf.put_i32(message_size as i32);
+    header.encode(&mut res_buf, header_version).unwrap();
+    match body {
+        // encoded in place, so the records placeholders are noted as they are written
+        ResponseKind::Fetch(body) => body.encode(&mut res_buf, body_version).unwrap(),
+        body => {
+            let mut body_buf = BytesMut::new();
+            body.encode(&mut body_buf, body_version).unwrap();
+            res_buf.put_slice(&body_buf);
+        }
+    }
 
-    res_buf.extend_from_slice(&header_buf);
-    res_buf.extend_from_slice(&body_buf);
+    let message_size = res_buf.offset() - 4;
+    res_buf.range(0..4).copy_from_slice(&(message_size as i32).to_be_bytes());
     res_buf
 }
 
 fn response_with_error(correlation_id: i32, error: ResponseError) -> BytesMut {
     let mut res_buf = BytesMut::with_capacity(10);
-    res_buf.put_i32(4);
+    res_buf.put_i32(6); // correlation id and error code
     res_buf.put_i32(correlation_id);
     res_buf.put_i16(error.code());
-    return res_buf;
+    res_buf
 }
 
-async fn handle(buf: &mut BytesMut) -> BytesMut {
+async fn handle(broker: &Broker, buf: &mut BytesMut, connection: &Connection) -> ResponseSend {
+    if buf.len() < 8 {
+        println!("Request of {} bytes is shorter than its header", buf.len());
+        return ResponseSend::default();
+    }
     let api_key = buf.peek_bytes(0..2).get_i16();
     let api_version = buf.peek_bytes(2..4).get_i16();
-    let request_header_version = ApiKey::try_from(api_key).unwrap().request_header_version(api_version);
-    
-    let request_header = RequestHeader::decode(buf, request_header_version).unwrap();
-    let api_key = ApiKey::try_from(api_key).unwrap();
-
-    if !check_version(api_key, api_version) {
-        return response_with_error(request_header.correlation_id, ResponseError::UnsupportedVersion);
-    }
+    // every header version starts with the key, the version and the correlation id
+    let correlation_id = buf.peek_bytes(4..8).get_i32();
+    let api_key = match ApiKey::try_from(api_key) {
+        Ok(api_key) if check_version(api_key, api_version) => api_key,
+        _ => return response_with_error(correlation_id, ResponseError::UnsupportedVersion).into(),
+    };
+    let request_header = match RequestHeader::decode(buf, api_key.request_header_version(api_version)) {
+        Ok(request_header) => request_header,
+        Err(e) => {
+            println!("Failed to decode the {:?} request header: {}", api_key, e);
+            return response_with_error(correlation_id, ResponseError::InvalidRequest).into();
+        }
+    };
     let req = match api_key {
-        ApiKey::ApiVersions => RequestKind::ApiVersions(ApiVersionsRequest::decode(buf, request_header.request_api_version).unwrap()),
-        ApiKey::DescribeTopicPartitions => RequestKind::DescribeTopicPartitions(DescribeTopicPartitionsRequest::decode(buf, request_header.request_api_version).unwrap()),
-        ApiKey::Fetch => RequestKind::Fetch(FetchRequest::decode(buf, request_header.request_api_version).unwrap()),
-        _ => panic!("Unsupported API key: {:?}", api_key),
+        ApiKey::ApiVersions => ApiVersionsRequest::decode(buf, api_version).map(RequestKind::ApiVersions),
+        ApiKey::DescribeTopicPartitions => DescribeTopicPartitionsRequest::decode(buf, api_version).map(RequestKind::DescribeTopicPartitions),
+        ApiKey::Fetch => FetchRequest::decode(buf, api_version).map(RequestKind::Fetch),
+        ApiKey::ListOffsets => ListOffsetsRequest::decode(buf, api_version).map(RequestKind::ListOffsets),
+        ApiKey::OffsetForLeaderEpoch => OffsetForLeaderEpochRequest::decode(buf, api_version).map(RequestKind::OffsetForLeaderEpoch),
+        ApiKey::DeleteRecords => DeleteRecordsRequest::decode(buf, api_version).map(RequestKind::DeleteRecords),
+        ApiKey::Produce => ProduceRequest::decode(buf, api_version).map(RequestKind::Produce),
+        ApiKey::FindCoordinator => FindCoordinatorRequest::decode(buf, api_version).map(RequestKind::FindCoordinator),
+        ApiKey::InitProducerId => InitProducerIdRequest::decode(buf, api_version).map(RequestKind::InitProducerId),
+        ApiKey::AddPartitionsToTxn => AddPartitionsToTxnRequest::decode(buf, api_version).map(RequestKind::AddPartitionsToTxn),
+        ApiKey::AddOffsetsToTxn => AddOffsetsToTxnRequest::decode(buf, api_version).map(RequestKind::AddOffsetsToTxn),
+        ApiKey::EndTxn => EndTxnRequest::decode(buf, api_version).map(RequestKind::EndTxn),
+        ApiKey::TxnOffsetCommit => TxnOffsetCommitRequest::decode(buf, api_version).map(RequestKind::TxnOffsetCommit),
+        ApiKey::OffsetFetch => OffsetFetchRequest::decode(buf, api_version).map(RequestKind::OffsetFetch),
+        ApiKey::DescribeConfigs => DescribeConfigsRequest::decode(buf, api_version).map(RequestKind::DescribeConfigs),
+        ApiKey::AlterConfigs => AlterConfigsRequest::decode(buf, api_version).map(RequestKind::AlterConfigs),
+        ApiKey::IncrementalAlterConfigs => IncrementalAlterConfigsRequest::decode(buf, api_version).map(RequestKind::IncrementalAlterConfigs),
+        ApiKey::DescribeCluster => DescribeClusterRequest::decode(buf, api_version).map(RequestKind::DescribeCluster),
+        ApiKey::AlterReplicaLogDirs => AlterReplicaLogDirsRequest::decode(buf, api_version).map(RequestKind::AlterReplicaLogDirs),
+        ApiKey::DescribeLogDirs => DescribeLogDirsRequest::decode(buf, api_version).map(RequestKind::DescribeLogDirs),
+        ApiKey::AlterPartition => AlterPartitionRequest::decode(buf, api_version).map(RequestKind::AlterPartition),
+        ApiKey::Vote => VoteRequest::decode(buf, api_version).map(RequestKind::Vote),
+        ApiKey::BeginQuorumEpoch => BeginQuorumEpochRequest::decode(buf, api_version).map(RequestKind::BeginQuorumEpoch),
+        ApiKey::EndQuorumEpoch => EndQuorumEpochRequest::decode(buf, api_version).map(RequestKind::EndQuorumEpoch),
+        ApiKey::DescribeQuorum => DescribeQuorumRequest::decode(buf, api_version).map(RequestKind::DescribeQuorum),
+        ApiKey::FetchSnapshot => FetchSnapshotRequest::decode(buf, api_version).map(RequestKind::FetchSnapshot),
+        _ => return response_with_error(correlation_id, ResponseError::UnsupportedVersion).into(),
+    };
+    let req = match req {
+        Ok(req) => req,
+        Err(e) => {
+            println!("Failed to decode the {:?} request: {}", api_key, e);
+            return response_with_error(correlation_id, ResponseError::InvalidRequest).into();
+        }
     };
+    // record data of a Fetch, written to the socket straight from the segment
+    // files, and whether its length prefixes are compact
+    let mut file_records = None;
     let (response, header_version) = match req {
         RequestKind::ApiVersions(_req) => {
             let resp = ApiVersionsResponse::default()
-                .with_api_keys(vec![
-                    ApiVersion::default()
-                        .with_api_key(ApiKey::ApiVersions as i16)
-                        .with_min_version(0)
-                        .with_max_version(4),
-                    ApiVersion::default()
-                        .with_api_key(ApiKey::DescribeTopicPartitions as i16)
-                        .with_min_version(0)
-                        .with_max_version(4),
-                    ApiVersion::default()
-                        .with_api_key(ApiKey::Fetch as i16)
-                        .with_min_version(0)
-                        .with_max_version(16),
-                ]);
+                .with_api_keys(SUPPORTED_APIS.iter()
+                    .map(|(api_key, min_version, max_version)| ApiVersion::default()
+                        .with_api_key(*api_key as i16)
+                        .with_min_version(*min_version)
+                        .with_max_version(*max_version))
+                    .collect());
             (ResponseKind::ApiVersions(resp), ApiVersionsResponse::header_version(api_version))
         }
-        RequestKind::DescribeTopicPartitions(_req) => {
-            let record_sets = parse_cluster_metadata().await;
+        RequestKind::DescribeTopicPartitions(req) => {
+            let record_sets = broker.cluster_metadata().await;
             let topic_to_partition_ids = record_set_to_topic(&record_sets);
+            let authorizer = Authorizer::new(&broker.config, AclState::from_record_sets(&record_sets));
+            let resp = handle_describe_topic_partitions(req, &topic_to_partition_ids, &authorizer, &connection.client_host);
 
-            let topics = _req.topics
-                .iter()
-                .map(|tr| {
-                    let topic_name = tr.name.clone();
-                    let name = topic_name.0.as_str();
-                    if let Some(tuple) = topic_to_partition_ids.get(name) {
-                        let partitions = tuple.1.iter()
-                            .map(|partition_id| DescribeTopicPartitionsResponsePartition::default()
-                                .with_partition_index(*partition_id))
-                            .collect();
-                        DescribeTopicPartitionsResponseTopic::default()
-                            .with_name(Some(topic_name))
-                            .with_topic_id(tuple.0)
-                            .with_partitions(partitions)
-                    } else {
-                        DescribeTopicPartitionsResponseTopic::default()
-                            .with_name(Some(topic_name))
-                            .with_error_code(ResponseError::UnknownTopicOrPartition.code())
-                            .with_topic_id(Uuid::parse_str("00000000-0000-0000-0000-000000000000").unwrap())
-                    }
-                })
-                .collect();
-
-
-            let resp = DescribeTopicPartitionsResponse::default()
-                .with_topics(topics);
-            
             (ResponseKind::DescribeTopicPartitions(resp), DescribeTopicPartitionsResponse::header_version(api_version))
         }
-        RequestKind::Fetch(_req) => {
-            let record_sets = parse_cluster_metadata().await;
+        RequestKind::Fetch(req) if raft::is_metadata_fetch(broker, &req) => {
+            let resp = raft::handle_fetch(broker, req, api_version).await;
+            (ResponseKind::Fetch(resp), FetchResponse::header_version(api_version))
+        }
+        RequestKind::Fetch(req) => {
+            let record_sets = broker.cluster_metadata().await;
             let topic_to_partition_ids = record_set_to_topic(&record_sets);
-            let topic_id_to_partition_ids: HashMap<Uuid, (&String, Vec<i32>)> = topic_to_partition_ids.iter().map(|kv| (kv.1.0, (kv.0, kv.1.1.clone()))).collect();
-            
-            let resp = if _req.topics.is_empty() {
-                FetchResponse::default()
-            } else {
-                let mut resps = Vec::new();
-                for fetch_topic in _req.topics {
-                    let topic_id = fetch_topic.topic_id;
-                    let paritions_data = if topic_id_to_partition_ids.contains_key(&topic_id) {
-                        let topic_name = topic_id_to_partition_ids[&topic_id].0;
-                        let partition_data_future = fetch_topic.partitions.iter().map(async |fp| {
-                            let topic_data = read_topic_data(topic_name, fp.partition).await;
-                            let topic_data= topic_data.freeze();
-                            let partition_data = PartitionData::default()
-                                .with_partition_index(fp.partition)
-                                .with_records(Some(topic_data));
-                            partition_data
-                        });
-                        join_all(partition_data_future).await
-                    } else {
-                        vec![PartitionData::default()
-                            .with_error_code(ResponseError::UnknownTopicId.code())]
-                    };
-                    let resp = FetchableTopicResponse::default()
-                        .with_topic_id(topic_id)
-                        .with_partitions(paritions_data);
-                    resps.push(resp);
-                }
-                FetchResponse::default()
-                    .with_responses(resps)
-            };
+            let (resp, records) = handle_fetch(broker, req, api_version, &topic_to_partition_ids, connection.closed.clone()).await;
+            file_records = Some((api_version >= FLEXIBLE_MIN_VERSION, records));
 
             (ResponseKind::Fetch(resp), FetchResponse::header_version(api_version))
         }
-        _ => panic!()
-    };
-    build_response(default_response_header(request_header.correlation_id), header_version, response, api_version)
-}
+        RequestKind::ListOffsets(req) => {
+            let record_sets = broker.cluster_metadata().await;
+            let topics = record_set_to_topic(&record_sets);
+            let resp = handle_list_offsets(broker, req, &topics).await;
 
-async fn read_metadata() -> BytesMut {
-    let filename = "/tmp/kraft-combined-logs/__cluster_metadata-0/00000000000000000000.log";
-    read_file(filename).await
-}
+            (ResponseKind::ListOffsets(resp), ListOffsetsResponse::header_version(api_version))
+        }
+        RequestKind::OffsetForLeaderEpoch(req) => {
+            let record_sets = broker.cluster_metadata().await;
+            let topics = record_set_to_topic(&record_sets);
+            let resp = handle_offset_for_leader_epoch(broker, req, &topics).await;
 
-async fn read_topic_data(topic_name: &str, partition_idx: i32) -> BytesMut {
-    let filename = format!("/tmp/kraft-combined-logs/{}-{}/00000000000000000000.log", topic_name, partition_idx);
-    read_file(filename.as_str()).await
-}
+            (ResponseKind::OffsetForLeaderEpoch(resp), OffsetForLeaderEpochResponse::header_version(api_version))
+        }
+        RequestKind::DeleteRecords(req) => {
+            let record_sets = broker.cluster_metadata().await;
+            let topics = record_set_to_topic(&record_sets);
+            let resp = handle_delete_records(broker, req, &topics).await;
 
-async fn read_file(filename: &str) -> BytesMut {
-    let bytes = fs::read(filename).await.unwrap();
-    println!("{:02x?}", bytes);
-    let mut buf = BytesMut::new();
-    buf.extend_from_slice(&bytes);
-    buf
+            (ResponseKind::DeleteRecords(resp), DeleteRecordsResponse::header_version(api_version))
+        }
+        RequestKind::Produce(req) => {
+            let acks = req.acks;
+            let record_sets = broker.cluster_metadata().await;
+            let topics = record_set_to_topic(&record_sets);
+            let resp = handle_produce(broker, req, api_version, &topics).await;
+            if acks == 0 {
+                return ResponseSend::default();
+            }
+            (ResponseKind::Produce(resp), ProduceResponse::header_version(api_version))
+        }
+        RequestKind::FindCoordinator(req) => {
+            // this broker coordinates every group and transaction
+            let node_id = BrokerId(replica::node_id(&broker.config));
+            let (host, port) = advertised_endpoint(&broker.config);
+            let host = StrBytes::from_string(host);
+            let resp = if api_version >= 4 {
+                let coordinators = req.coordinator_keys
+                    .iter()
+                    .map(|key| Coordinator::default()
+                        .with_key(key.clone())
+                        .with_node_id(node_id)
+                        .with_host(host.clone())
+                        .with_port(port))
+                    .collect();
+                FindCoordinatorResponse::default()
+                    .with_coordinators(coordinators)
+            } else {
+                FindCoordinatorResponse::default()
+                    .with_node_id(node_id)
+                    .with_host(host)
+                    .with_port(port)
+            };
+            (ResponseKind::FindCoordinator(resp), FindCoordinatorResponse::header_version(api_version))
+        }
+        RequestKind::InitProducerId(req) => {
+            let resp = txn::handle_init_producer_id(broker, req).await;
+            (ResponseKind::InitProducerId(resp), InitProducerIdResponse::header_version(api_version))
+        }
+        RequestKind::AddPartitionsToTxn(req) => {
+            let resp = txn::handle_add_partitions_to_txn(broker, req, api_version).await;
+            (ResponseKind::AddPartitionsToTxn(resp), AddPartitionsToTxnResponse::header_version(api_version))
+        }
+        RequestKind::AddOffsetsToTxn(req) => {
+            let resp = txn::handle_add_offsets_to_txn(broker, req).await;
+            (ResponseKind::AddOffsetsToTxn(resp), AddOffsetsToTxnResponse::header_version(api_version))
+        }
+        RequestKind::EndTxn(req) => {
+            let resp = txn::handle_end_txn(broker, req).await;
+            (ResponseKind::EndTxn(resp), EndTxnResponse::header_version(api_version))
+        }
+        RequestKind::TxnOffsetCommit(req) => {
+            let resp = txn::handle_txn_offset_commit(broker, req).await;
+            (ResponseKind::TxnOffsetCommit(resp), TxnOffsetCommitResponse::header_version(api_version))
+        }
+        RequestKind::OffsetFetch(req) => {
+            let resp = group::handle_offset_fetch(broker, req, api_version).await;
+            (ResponseKind::OffsetFetch(resp), OffsetFetchResponse::header_version(api_version))
+        }
+        RequestKind::DescribeConfigs(req) => {
+            let record_sets = broker.cluster_metadata().await;
+            let topics = record_set_to_topic(&record_sets);
+            let authorizer = Authorizer::new(&broker.config, AclState::from_record_sets(&record_sets));
+            let resp = handle_describe_configs(broker, req, &topics, &authorizer, &connection.client_host).await;
+            (ResponseKind::DescribeConfigs(resp), DescribeConfigsResponse::header_version(api_version))
+        }
+        RequestKind::AlterConfigs(req) => {
+            let record_sets = broker.cluster_metadata().await;
+            let topics = record_set_to_topic(&record_sets);
+            let authorizer = Authorizer::new(&broker.config, AclState::from_record_sets(&record_sets));
+            let resp = handle_alter_configs(broker, req, &topics, &authorizer, &connection.client_host).await;
+            (ResponseKind::AlterConfigs(resp), AlterConfigsResponse::header_version(api_version))
+        }
+        RequestKind::IncrementalAlterConfigs(req) => {
+            let record_sets = broker.cluster_metadata().await;
+            let topics = record_set_to_topic(&record_sets);
+            let authorizer = Authorizer::new(&broker.config, AclState::from_record_sets(&record_sets));
+            let resp = handle_incremental_alter_configs(broker, req, &topics, &authorizer, &connection.client_host).await;
+            (ResponseKind::IncrementalAlterConfigs(resp), IncrementalAlterConfigsResponse::header_version(api_version))
+        }
+        RequestKind::DescribeCluster(req) => {
+            let record_sets = broker.cluster_metadata().await;
+            let image = ClusterImage::from_record_sets(&record_sets);
+            let authorizer = Authorizer::new(&broker.config, AclState::from_record_sets(&record_sets));
+            let resp = handle_describe_cluster(req, &broker.config, &image, cluster_id(&broker.config).await, &authorizer, &connection.client_host);
+            (ResponseKind::DescribeCluster(resp), DescribeClusterResponse::header_version(api_version))
+        }
+        RequestKind::AlterReplicaLogDirs(req) => {
+            let record_sets = broker.cluster_metadata().await;
+            let topics = record_set_to_topic(&record_sets);
+            let authorizer = Authorizer::new(&broker.config, AclState::from_record_sets(&record_sets));
+            let resp = handle_alter_replica_log_dirs(broker, req, &topics, &authorizer, &connection.client_host).await;
+            (ResponseKind::AlterReplicaLogDirs(resp), AlterReplicaLogDirsResponse::header_version(api_version))
+        }
+        RequestKind::DescribeLogDirs(req) => {
+            let record_sets = broker.cluster_metadata().await;
+            let authorizer = Authorizer::new(&broker.config, AclState::from_record_sets(&record_sets));
+            let resp = handle_describe_log_dirs(broker, req, &authorizer, &connection.client_host).await;
+            (ResponseKind::DescribeLogDirs(resp), DescribeLogDirsResponse::header_version(api_version))
+        }
+        RequestKind::AlterPartition(req) => {
+            let resp = replica::handle_alter_partition(broker, req, api_version).await;
+            (ResponseKind::AlterPartition(resp), AlterPartitionResponse::header_version(api_version))
+        }
+        RequestKind::Vote(req) => {
+            let resp = raft::handle_vote(broker, req).await;
+            (ResponseKind::Vote(resp), VoteResponse::header_version(api_version))
+        }
+        RequestKind::BeginQuorumEpoch(req) => {
+            let resp = raft::handle_begin_quorum_epoch(broker, req).await;
+            (ResponseKind::BeginQuorumEpoch(resp), BeginQuorumEpochResponse::header_version(api_version))
+        }
+        RequestKind::EndQuorumEpoch(req) => {
+            let resp = raft::handle_end_quorum_epoch(broker, req).await;
+            (ResponseKind::EndQuorumEpoch(resp), EndQuorumEpochResponse::header_version(api_version))
+        }
+        RequestKind::DescribeQuorum(req) => {
+            let record_sets = broker.cluster_metadata().await;
+            let authorizer = Authorizer::new(&broker.config, AclState::from_record_sets(&record_sets));
+            let resp = raft::handle_describe_quorum(broker, req, api_version, &authorizer, &connection.client_host).await;
+            (ResponseKind::DescribeQuorum(resp), DescribeQuorumResponse::header_version(api_version))
+        }
+        RequestKind::FetchSnapshot(req) => {
+            let resp = raft::handle_fetch_snapshot(broker, req).await;
+            (ResponseKind::FetchSnapshot(resp), FetchSnapshotResponse::header_version(api_version))
+        }
+        _ => return response_with_error(correlation_id, ResponseError::UnsupportedVersion).into(),
+    };
+    let header = default_response_header(request_header.correlation_id);
+    let response = build_response(header.clone(), header_version, response, api_version);
+    let Some((flexible, file_records)) = file_records else {
+        return response.into_inner().into();
+    };
+    ResponseSend::with_file_records(response, flexible, file_records).unwrap_or_else(|e| {
+        println!("Failed to splice the fetched records into the response: {}", e);
+        let resp = FetchResponse::default()
+            .with_error_code(ResponseError::UnknownServerError.code());
+        build_response(header, header_version, ResponseKind::Fetch(resp), api_version).into_inner().into()
+    })
 }
-
-async fn parse_cluster_metadata() -> Vec<RecordSet> {
-    let mut buf = read_metadata().await;
-    RecordBatchDecoder::decode_all(&mut buf).unwrap()
-}
\ No newline at end of file
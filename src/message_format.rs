@@ -0,0 +1,349 @@
+use std::io::{self, Read, Write};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use kafka_protocol::records::{Compression, Record, RecordBatchEncoder, RecordEncodeOptions, TimestampType};
+
+use crate::log::{batches, decode_batch};
+
+pub const MAGIC_V0: i8 = 0;
+pub const MAGIC_V1: i8 = 1;
+
+// offset(8) + messageSize(4)
+const LEGACY_LOG_OVERHEAD: usize = 12;
+// crc(4) + magic(1) + attributes(1) + key length(4) + value length(4)
+const MIN_MESSAGE_SIZE_V0: usize = 14;
+const CODEC_MASK: i8 = 0x07;
+// v1 only
+const TIMESTAMP_TYPE_FLAG: i8 = 0x08;
+const NO_TIMESTAMP: i64 = -1;
+
+const XERIAL_HEADER: &[u8; 8] = b"\x82SNAPPY\x00";
+const XERIAL_BLOCK_SIZE: usize = 32 * 1024;
+
+fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+fn codec_id(compression: Compression) -> i8 {
+    match compression {
+        Compression::None => 0,
+        Compression::Gzip => 1,
+        Compression::Snappy => 2,
+        Compression::Lz4 => 3,
+        Compression::Zstd => 4,
+    }
+}
+
+// the codec a v2 batch keeps when converted to `magic`: zstd only exists from
+// v2 on, and v0 framed lz4 with a broken header checksum
+fn legacy_codec(compression: Compression, magic: i8) -> Compression {
+    match compression {
+        Compression::Zstd => Compression::None,
+        Compression::Lz4 if magic == MAGIC_V0 => Compression::None,
+        compression => compression,
+    }
+}
+
+fn compress(compression: Compression, data: &[u8]) -> io::Result<Vec<u8>> {
+    match compression {
+        Compression::None => Ok(data.to_vec()),
+        Compression::Gzip => {
+            let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(data)?;
+            encoder.finish()
+        }
+        // the xerial framing of the Java client's SnappyOutputStream
+        Compression::Snappy => {
+            let mut buf = Vec::new();
+            buf.put_slice(XERIAL_HEADER);
+            buf.put_i32(1); // version
+            buf.put_i32(1); // minimum compatible version
+            let mut encoder = snap::raw::Encoder::new();
+            for block in data.chunks(XERIAL_BLOCK_SIZE) {
+                let compressed = encoder.compress_vec(block).map_err(|e| invalid(e.to_string()))?;
+                buf.put_i32(compressed.len() as i32);
+                buf.put_slice(&compressed);
+            }
+            Ok(buf)
+        }
+        Compression::Lz4 => {
+            let mut encoder = lz4::EncoderBuilder::new()
+                .block_mode(lz4::BlockMode::Independent)
+                .checksum(lz4::ContentChecksum::NoChecksum)
+                .build(Vec::new())?;
+            encoder.write_all(data)?;
+            let (buf, result) = encoder.finish();
+            result.map(|_| buf)
+        }
+        Compression::Zstd => Err(invalid("zstd needs magic v2")),
+    }
+}
+
+fn decompress(compression: Compression, data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    match compression {
+        Compression::None => buf.extend_from_slice(data),
+        Compression::Gzip => {
+            flate2::read::GzDecoder::new(data).read_to_end(&mut buf)?;
+        }
+        Compression::Snappy => {
+            let mut decoder = snap::raw::Decoder::new();
+            let Some(mut blocks) = data.strip_prefix(&XERIAL_HEADER[..]) else {
+                return decoder.decompress_vec(data).map_err(|e| invalid(e.to_string()));
+            };
+            if blocks.len() < 8 {
+                return Err(invalid("truncated xerial snappy header"));
+            }
+            blocks.advance(8); // versions
+            while blocks.len() >= 4 {
+                let len = blocks.get_i32() as usize;
+                if len > blocks.len() {
+                    return Err(invalid("truncated xerial snappy block"));
+                }
+                buf.extend_from_slice(&decoder.decompress_vec(&blocks[..len]).map_err(|e| invalid(e.to_string()))?);
+                blocks.advance(len);
+            }
+        }
+        Compression::Lz4 => {
+            lz4::Decoder::new(data)?.read_to_end(&mut buf)?;
+        }
+        Compression::Zstd => return Err(invalid("zstd needs magic v2")),
+    }
+    Ok(buf)
+}
+
+fn put_nullable_bytes(buf: &mut BytesMut, bytes: &Option<Bytes>) {
+    match bytes {
+        Some(bytes) => {
+            buf.put_i32(bytes.len() as i32);
+            buf.put_slice(bytes);
+        }
+        None => buf.put_i32(-1),
+    }
+}
+
+fn get_nullable_bytes(buf: &mut Bytes) -> io::Result<Option<Bytes>> {
+    if buf.remaining() < 4 {
+        return Err(invalid("truncated message"));
+    }
+    let len = buf.get_i32();
+    if len < 0 {
+        return Ok(None);
+    }
+    if len as usize > buf.remaining() {
+        return Err(invalid("truncated message"));
+    }
+    Ok(Some(buf.split_to(len as usize)))
+}
+
+// Appends a single message with its log overhead. The crc covers the message
+// from the magic on.
+fn put_message(buf: &mut BytesMut, offset: i64, magic: i8, attributes: i8, timestamp: i64, key: &Option<Bytes>, value: &Option<Bytes>) {
+    buf.put_i64(offset);
+    let size_pos = buf.len();
+    buf.put_i32(0);
+    let crc_pos = buf.len();
+    buf.put_u32(0);
+    buf.put_i8(magic);
+    buf.put_i8(attributes);
+    if magic == MAGIC_V1 {
+        buf.put_i64(timestamp);
+    }
+    put_nullable_bytes(buf, key);
+    put_nullable_bytes(buf, value);
+    let crc = crc32fast::hash(&buf[crc_pos + 4..]);
+    (&mut buf[crc_pos..crc_pos + 4]).put_u32(crc);
+    let size = buf.len() - crc_pos;
+    (&mut buf[size_pos..size_pos + 4]).put_i32(size as i32);
+}
+
+/// Converts v2 record batches to a v0 or v1 message set for Fetch versions
+/// that predate record batches. Timestamps are kept in v1, and compressed
+/// batches become wrapper messages with the same codec where `magic` has it.
+/// Control batches have no legacy form and are dropped.
+pub fn down_convert(records: &[u8], magic: i8) -> io::Result<BytesMut> {
+    let mut buf = BytesMut::new();
+    for (pos, header) in batches(records) {
+        if header.is_control() {
+            continue;
+        }
+        let decoded = decode_batch(&records[pos..pos + header.size()])?.records;
+        let Some(last) = decoded.last() else { continue };
+        let timestamp_flag = if magic == MAGIC_V1 && last.timestamp_type == TimestampType::LogAppend { TIMESTAMP_TYPE_FLAG } else { 0 };
+        let codec = legacy_codec(header.compression().unwrap_or(Compression::None), magic);
+        if codec == Compression::None {
+            for record in decoded.iter() {
+                put_message(&mut buf, record.offset, magic, timestamp_flag, record.timestamp, &record.key, &record.value);
+            }
+            continue;
+        }
+        // v1 inner offsets are relative, the wrapper carries the last absolute one
+        let first_offset = decoded[0].offset;
+        let mut inner = BytesMut::new();
+        for record in decoded.iter() {
+            let offset = if magic == MAGIC_V1 { record.offset - first_offset } else { record.offset };
+            put_message(&mut inner, offset, magic, timestamp_flag, record.timestamp, &record.key, &record.value);
+        }
+        let max_timestamp = decoded.iter().map(|record| record.timestamp).max().unwrap_or(NO_TIMESTAMP);
+        let wrapper_value = Some(Bytes::from(compress(codec, &inner)?));
+        put_message(&mut buf, last.offset, magic, codec_id(codec) | timestamp_flag, max_timestamp, &None, &wrapper_value);
+    }
+    Ok(buf)
+}
+
+// (offset, magic, attributes, timestamp, key, value) of each complete message
+type LegacyMessage = (i64, i8, i8, i64, Option<Bytes>, Option<Bytes>);
+
+fn parse_messages(mut buf: Bytes) -> io::Result<Vec<LegacyMessage>> {
+    let mut messages = Vec::new();
+    while buf.remaining() >= LEGACY_LOG_OVERHEAD {
+        let offset = buf.get_i64();
+        let size = buf.get_i32();
+        if size < 0 || size as usize > buf.remaining() {
+            // a partial trailing message, as fetched
+            break;
+        }
+        let mut message = buf.split_to(size as usize);
+        if message.remaining() < MIN_MESSAGE_SIZE_V0 {
+            return Err(invalid("message below the minimum size"));
+        }
+        let crc = message.get_u32();
+        if crc != crc32fast::hash(&message) {
+            return Err(invalid(format!("crc mismatch of the message at offset {}", offset)));
+        }
+        let magic = message.get_i8();
+        let attributes = message.get_i8();
+        let timestamp = match magic {
+            MAGIC_V0 => NO_TIMESTAMP,
+            MAGIC_V1 if message.remaining() >= 8 => message.get_i64(),
+            _ => return Err(invalid(format!("unsupported magic {}", magic))),
+        };
+        let key = get_nullable_bytes(&mut message)?;
+        let value = get_nullable_bytes(&mut message)?;
+        messages.push((offset, magic, attributes, timestamp, key, value));
+    }
+    Ok(messages)
+}
+
+fn legacy_compression(attributes: i8) -> io::Result<Compression> {
+    match attributes & CODEC_MASK {
+        0 => Ok(Compression::None),
+        1 => Ok(Compression::Gzip),
+        2 => Ok(Compression::Snappy),
+        3 => Ok(Compression::Lz4),
+        codec => Err(invalid(format!("unsupported codec {} for a legacy message", codec))),
+    }
+}
+
+/// Converts a v0 or v1 message set from an old Produce into a single v2
+/// record batch, compressed with the codec of its first compressed message.
+pub fn up_convert(message_set: &[u8]) -> io::Result<BytesMut> {
+    let mut records = Vec::new();
+    let mut compression = Compression::None;
+    for (_, magic, attributes, timestamp, key, value) in parse_messages(Bytes::copy_from_slice(message_set))? {
+        let codec = legacy_compression(attributes)?;
+        let timestamp_type = if magic == MAGIC_V1 && attributes & TIMESTAMP_TYPE_FLAG != 0 { TimestampType::LogAppend } else { TimestampType::Creation };
+        let messages = if codec == Compression::None {
+            vec![(0, magic, attributes, timestamp, key, value)]
+        } else {
+            if compression == Compression::None {
+                compression = codec;
+            }
+            let value = value.ok_or_else(|| invalid("compressed message without a value"))?;
+            parse_messages(Bytes::from(decompress(codec, &value)?))?
+        };
+        for (_, _, _, timestamp, key, value) in messages {
+            records.push(Record {
+                transactional: false,
+                control: false,
+                partition_leader_epoch: 0,
+                producer_id: -1,
+                producer_epoch: -1,
+                timestamp_type,
+                offset: records.len() as i64,
+                // keeps the records in one batch with a base sequence of -1
+                sequence: records.len() as i32 - 1,
+                timestamp,
+                key,
+                value,
+                headers: Default::default(),
+            });
+        }
+    }
+    if records.is_empty() {
+        return Err(invalid("no complete message"));
+    }
+    let mut buf = BytesMut::new();
+    let options = RecordEncodeOptions {
+        version: 2,
+        compression,
+    };
+    RecordBatchEncoder::encode(&mut buf, &records, &options).map_err(|e| invalid(e.to_string()))?;
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::{Bytes, BytesMut};
+    use kafka_protocol::records::{Compression, Record, RecordBatchDecoder};
+
+    use crate::test_util::{encode, record};
+
+    use super::{down_convert, parse_messages, up_convert, MAGIC_V0, MAGIC_V1};
+
+    fn batch(base_offset: i64, compression: Compression) -> BytesMut {
+        let records: Vec<Record> = (0..3).map(|delta| {
+            let value = if delta == 1 { None } else { Some(Bytes::from("value ".repeat(50))) };
+            record(base_offset + delta, 1000 + delta, Some(Bytes::from(format!("key-{}", delta))), value)
+        }).collect();
+        encode(&records, compression)
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let codecs = [Compression::None, Compression::Gzip, Compression::Snappy, Compression::Lz4, Compression::Zstd];
+        for magic in [MAGIC_V0, MAGIC_V1] {
+            for compression in codecs {
+                let mut records = batch(5, compression);
+                records.extend_from_slice(&batch(8, compression));
+                let expected: Vec<(i64, i64, Option<Bytes>, Option<Bytes>)> = RecordBatchDecoder::decode_all(&mut records.clone().freeze()).unwrap().into_iter()
+                    .flat_map(|record_set| record_set.records)
+                    .map(|record| (record.offset, if magic == MAGIC_V1 { record.timestamp } else { -1 }, record.key, record.value))
+                    .collect();
+
+                let message_set = down_convert(&records, magic).unwrap();
+                let mut converted = Vec::new();
+                for (offset, message_magic, attributes, timestamp, key, value) in parse_messages(message_set.clone().freeze()).unwrap() {
+                    assert_eq!(magic, message_magic);
+                    let codec = super::legacy_compression(attributes).unwrap();
+                    if codec == Compression::None {
+                        converted.push((offset, timestamp, key, value));
+                        continue;
+                    }
+                    let inner = parse_messages(Bytes::from(super::decompress(codec, &value.unwrap()).unwrap())).unwrap();
+                    let first_offset = if magic == MAGIC_V1 { offset - inner.last().unwrap().0 } else { 0 };
+                    for (inner_offset, _, _, timestamp, key, value) in inner {
+                        converted.push((first_offset + inner_offset, timestamp, key, value));
+                    }
+                }
+                assert_eq!(expected, converted, "magic {} {:?}", magic, compression);
+
+                let mut upconverted = up_convert(&message_set).unwrap().freeze();
+                let records: Vec<Record> = RecordBatchDecoder::decode_all(&mut upconverted).unwrap().into_iter()
+                    .flat_map(|record_set| record_set.records)
+                    .collect();
+                assert_eq!(6, records.len());
+                assert_eq!(expected.iter().map(|e| (e.1, e.2.clone(), e.3.clone())).collect::<Vec<_>>(),
+                    records.into_iter().map(|r| (r.timestamp, r.key, r.value)).collect::<Vec<_>>());
+            }
+        }
+    }
+
+    #[test]
+    fn test_corrupt_message() {
+        let mut message_set = down_convert(&batch(0, Compression::None), MAGIC_V1).unwrap();
+        let last = message_set.len() - 1;
+        message_set[last] ^= 0xff;
+        assert!(up_convert(&message_set).is_err());
+    }
+}
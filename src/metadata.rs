@@ -0,0 +1,607 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use bytes::{Bytes, BytesMut};
+use kafka_protocol::records::{Compression, Record, RecordBatchEncoder, RecordEncodeOptions, RecordSet, TimestampType};
+
+use crate::config::BrokerConfig;
+use crate::log::{batches, decode_batch, log_dirs, partition_dir, segment_path, BatchHeader, LOG_DIR, METADATA_TOPIC};
+use crate::record::{encode_leader_change_message, encode_snapshot_footer, encode_snapshot_header, parse_record_value, RecordValue};
+use crate::segment_cache::SegmentCache;
+
+pub const METADATA_LOG_DIR_CONFIG: &str = "metadata.log.dir";
+pub const MAX_BYTES_BETWEEN_SNAPSHOTS_CONFIG: &str = "metadata.log.max.record.bytes.between.snapshots";
+pub const DEFAULT_MAX_BYTES_BETWEEN_SNAPSHOTS: u64 = 20 * 1024 * 1024;
+pub const SNAPSHOT_SUFFIX: &str = "checkpoint";
+// a snapshot is written under this suffix and renamed once complete
+const PARTIAL_SUFFIX: &str = "part";
+const SNAPSHOT_BATCH_RECORDS: usize = 1000;
+
+/// The directory of the `__cluster_metadata-0` log: under `metadata.log.dir`,
+/// or else the first log dir.
+pub fn metadata_log_dir(config: &BrokerConfig) -> PathBuf {
+    let log_dir = config.get(METADATA_LOG_DIR_CONFIG)
+        .map(PathBuf::from)
+        .or_else(|| log_dirs(config).into_iter().next())
+        .unwrap_or_else(|| PathBuf::from(LOG_DIR));
+    partition_dir(&log_dir, METADATA_TOPIC, 0)
+}
+
+/// The name of a metadata snapshot, `<end offset>-<epoch>.checkpoint`.
+pub fn snapshot_file_name(end_offset: i64, epoch: i32) -> String {
+    format!("{:020}-{:010}.{}", end_offset, epoch, SNAPSHOT_SUFFIX)
+}
+
+/// The (end offset, epoch) of a snapshot file name.
+pub fn parse_snapshot_file_name(file_name: &str) -> Option<(i64, i32)> {
+    let (end_offset, epoch) = file_name.strip_suffix(SNAPSHOT_SUFFIX)?.strip_suffix('.')?.split_once('-')?;
+    Some((end_offset.parse().ok()?, epoch.parse().ok()?))
+}
+
+/// The metadata record values a snapshot keeps: every record in order, less
+/// the ACLs since removed, the configs since changed or deleted and all but
+/// the last producer id block.
+pub fn snapshot_values(record_sets: &[RecordSet]) -> Vec<Bytes> {
+    let values: Vec<Bytes> = record_sets.iter()
+        .flat_map(|record_set| record_set.records.iter())
+        .filter_map(|record| record.value.clone())
+        .collect();
+    let parsed: Vec<RecordValue> = values.iter().map(|value| parse_record_value(&mut value.clone())).collect();
+    let mut removed_acls = HashSet::new();
+    let mut last_configs = HashMap::new();
+    let mut last_producer_ids = None;
+    for (idx, value) in parsed.iter().enumerate() {
+        match value {
+            RecordValue::RemoveAccessControlEntryRecord(record) => {
+                removed_acls.insert(record.id);
+            }
+            RecordValue::ConfigRecord(record) => {
+                last_configs.insert((record.resource_type, record.resource_name.clone(), record.name.clone()), idx);
+            }
+            RecordValue::ProducerIdsRecord(_) => last_producer_ids = Some(idx),
+            _ => {}
+        }
+    }
+    values.into_iter().zip(parsed.iter()).enumerate()
+        .filter(|(idx, (_, parsed))| match parsed {
+            RecordValue::AccessControlEntryRecord(record) => !removed_acls.contains(&record.id),
+            RecordValue::RemoveAccessControlEntryRecord(_) => false,
+            RecordValue::ConfigRecord(record) => {
+                let key = (record.resource_type, record.resource_name.clone(), record.name.clone());
+                record.value.is_some() && last_configs.get(&key) == Some(idx)
+            }
+            RecordValue::ProducerIdsRecord(_) => last_producer_ids == Some(*idx),
+            _ => true,
+        })
+        .map(|(_, (value, _))| value)
+        .collect()
+}
+
+/// The cluster metadata log, read again only when the file has changed size
+/// since the last request. The log starts where the latest snapshot ends,
+/// and the snapshot's records come before its own. In a Raft quorum only the
+/// batches below the high watermark are committed and decoded.
+#[derive(Debug)]
+pub struct MetadataCache {
+    dir: PathBuf,
+    // whether the snapshot and the first offset of the log have been found
+    opened: bool,
+    log_start_offset: i64,
+    // (end offset, epoch) of the latest snapshot
+    snapshot: Option<(i64, i32)>,
+    snapshot_bytes: Bytes,
+    snapshot_batches: Vec<(usize, BatchHeader)>,
+    len: u64,
+    bytes: Bytes,
+    batches: Vec<(usize, BatchHeader)>,
+    // None outside a Raft quorum, where every batch counts
+    high_watermark: Option<i64>,
+    // (len, high watermark) the record sets were decoded at
+    decoded: Option<(u64, Option<i64>)>,
+    record_sets: Arc<Vec<RecordSet>>,
+}
+
+impl Default for MetadataCache {
+    fn default() -> Self {
+        MetadataCache::new(metadata_log_dir(&BrokerConfig::default()))
+    }
+}
+
+impl MetadataCache {
+    pub fn new(dir: PathBuf) -> MetadataCache {
+        MetadataCache {
+            dir,
+            opened: false,
+            log_start_offset: 0,
+            snapshot: None,
+            snapshot_bytes: Bytes::new(),
+            snapshot_batches: Vec::new(),
+            len: 0,
+            bytes: Bytes::new(),
+            batches: Vec::new(),
+            high_watermark: None,
+            decoded: None,
+            record_sets: Arc::default(),
+        }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn log_path(&self) -> PathBuf {
+        segment_path(&self.dir, self.log_start_offset)
+    }
+
+    // loads the latest snapshot and finds the log segment, dropping any an
+    // interrupted snapshot left behind
+    fn open(&mut self, segments: &SegmentCache) -> io::Result<()> {
+        if self.opened {
+            return Ok(());
+        }
+        let mut snapshots = Vec::new();
+        let mut log_segments = Vec::new();
+        match fs::read_dir(&self.dir) {
+            Ok(entries) => {
+                for entry in entries {
+                    let file_name = entry?.file_name();
+                    let file_name = file_name.to_string_lossy();
+                    if let Some(snapshot) = parse_snapshot_file_name(&file_name) {
+                        snapshots.push(snapshot);
+                    } else if let Some(base_offset) = file_name.strip_suffix(".log").and_then(|base_offset| base_offset.parse::<i64>().ok()) {
+                        log_segments.push(base_offset);
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        self.snapshot = snapshots.into_iter().max();
+        if let Some((end_offset, epoch)) = self.snapshot {
+            self.snapshot_bytes = Bytes::from(fs::read(self.dir.join(snapshot_file_name(end_offset, epoch)))?);
+            self.snapshot_batches = batches(&self.snapshot_bytes);
+        }
+        log_segments.sort();
+        self.log_start_offset = match log_segments.pop() {
+            Some(base_offset) => base_offset,
+            None => self.snapshot.map(|(end_offset, _)| end_offset).unwrap_or(0),
+        };
+        for base_offset in log_segments {
+            let path = segment_path(&self.dir, base_offset);
+            segments.evict(&path);
+            fs::remove_file(path)?;
+        }
+        self.opened = true;
+        self.invalidate();
+        Ok(())
+    }
+
+    // a missing log is an empty one
+    fn reload(&mut self, segments: &SegmentCache) -> io::Result<()> {
+        self.open(segments)?;
+        let file = match segments.open(&self.log_path()) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                self.invalidate();
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        let len = file.metadata()?.len();
+        if len != self.len {
+            let mut bytes = vec![0; len as usize];
+            file.read_exact_at(&mut bytes, 0)?;
+            self.bytes = Bytes::from(bytes);
+            self.batches = batches(&self.bytes);
+            self.len = len;
+        }
+        Ok(())
+    }
+
+    // forgets the log after a write that may keep its size
+    fn invalidate(&mut self) {
+        self.len = 0;
+        self.bytes = Bytes::new();
+        self.batches.clear();
+        self.decoded = None;
+    }
+
+    pub fn record_sets(&mut self, segments: &SegmentCache) -> Arc<Vec<RecordSet>> {
+        if let Err(e) = self.reload(segments) {
+            println!("Failed to read the metadata log: {}", e);
+            return self.record_sets.clone();
+        }
+        let key = (self.len, self.high_watermark);
+        if self.decoded != Some(key) {
+            // control batches carry quorum changes, not metadata records
+            let snapshot_end_offset = self.snapshot.map(|(end_offset, _)| end_offset).unwrap_or(0);
+            let snapshot_batches = self.snapshot_batches.iter().map(|(position, header)| (&self.snapshot_bytes, *position, header));
+            let log_batches = self.batches.iter()
+                .filter(|(_, header)| header.base_offset >= snapshot_end_offset)
+                .filter(|(_, header)| self.high_watermark.map_or(true, |high_watermark| header.last_offset() < high_watermark))
+                .map(|(position, header)| (&self.bytes, *position, header));
+            let record_sets = snapshot_batches.chain(log_batches)
+                .filter(|(_, _, header)| !header.is_control())
+                .filter_map(|(bytes, position, header)| match decode_batch(&bytes[position..position + header.size()]) {
+                    Ok(record_set) => Some(record_set),
+                    Err(e) => {
+                        println!("Skipping metadata batch at offset {}: {}", header.base_offset, e);
+                        None
+                    }
+                })
+                .collect();
+            self.record_sets = Arc::new(record_sets);
+            self.decoded = Some(key);
+        }
+        self.record_sets.clone()
+    }
+
+    pub fn high_watermark(&self) -> Option<i64> {
+        self.high_watermark
+    }
+
+    pub fn set_high_watermark(&mut self, high_watermark: i64) {
+        self.high_watermark = Some(high_watermark);
+    }
+
+    pub fn log_start_offset(&mut self, segments: &SegmentCache) -> io::Result<i64> {
+        self.reload(segments)?;
+        Ok(self.log_start_offset)
+    }
+
+    pub fn log_end_offset(&mut self, segments: &SegmentCache) -> io::Result<i64> {
+        self.reload(segments)?;
+        Ok(self.batches.last().map(|(_, header)| header.next_offset()).unwrap_or(self.log_start_offset))
+    }
+
+    /// The (end offset, epoch) of the latest snapshot.
+    pub fn snapshot_id(&mut self, segments: &SegmentCache) -> io::Result<Option<(i64, i32)>> {
+        self.reload(segments)?;
+        Ok(self.snapshot)
+    }
+
+    /// The leader epoch of the last batch, or of the snapshot when the log is
+    /// empty, 0 for neither.
+    pub fn last_epoch(&mut self, segments: &SegmentCache) -> io::Result<i32> {
+        self.reload(segments)?;
+        Ok(self.batches.last().map(|(_, header)| header.partition_leader_epoch)
+            .or(self.snapshot.map(|(_, epoch)| epoch))
+            .unwrap_or(0))
+    }
+
+    /// The largest epoch in the log not above `epoch` and the offset it ends
+    /// at, the start of the next epoch or the log end. An epoch older than the
+    /// whole log is -1, ending at the log start, or at -1 when it is older
+    /// than the snapshot too.
+    pub fn end_offset_for_epoch(&mut self, segments: &SegmentCache, epoch: i32) -> io::Result<(i32, i64)> {
+        self.reload(segments)?;
+        let mut found = -1;
+        if let Some((_, snapshot_epoch)) = self.snapshot {
+            if snapshot_epoch > epoch {
+                return Ok((-1, -1));
+            }
+            found = snapshot_epoch;
+        }
+        for (_, header) in self.batches.iter() {
+            if header.partition_leader_epoch > epoch {
+                return Ok((found, header.base_offset));
+            }
+            found = header.partition_leader_epoch;
+        }
+        Ok((found, self.batches.last().map(|(_, header)| header.next_offset()).unwrap_or(self.log_start_offset)))
+    }
+
+    /// The batches from the one holding `offset` on, at most `max_bytes` of
+    /// them but always the first.
+    pub fn read(&mut self, segments: &SegmentCache, offset: i64, max_bytes: usize) -> io::Result<Bytes> {
+        self.reload(segments)?;
+        let Some(first) = self.batches.iter().position(|(_, header)| header.last_offset() >= offset) else {
+            return Ok(Bytes::new());
+        };
+        let start = self.batches[first].0;
+        let mut end = start + self.batches[first].1.size();
+        for (position, header) in self.batches[first + 1..].iter() {
+            if position + header.size() - start > max_bytes {
+                break;
+            }
+            end = position + header.size();
+        }
+        Ok(self.bytes.slice(start..end))
+    }
+
+    /// Drops the batches from `offset` on, where a follower's log diverged
+    /// from the leader's.
+    pub fn truncate(&mut self, segments: &SegmentCache, offset: i64) -> io::Result<()> {
+        self.reload(segments)?;
+        let Some((position, _)) = self.batches.iter().find(|(_, header)| header.last_offset() >= offset) else {
+            return Ok(());
+        };
+        let file = OpenOptions::new().write(true).open(self.log_path())?;
+        file.set_len(*position as u64)?;
+        file.sync_data()?;
+        self.invalidate();
+        Ok(())
+    }
+
+    /// Appends batches fetched from the leader, skipping any the log already
+    /// has, and returns the new log end offset.
+    pub fn append_batches(&mut self, segments: &SegmentCache, records: &[u8]) -> io::Result<i64> {
+        let mut log_end_offset = self.log_end_offset(segments)?;
+        let mut buf = BytesMut::new();
+        for (position, header) in batches(records) {
+            if header.last_offset() < log_end_offset {
+                continue;
+            }
+            if header.base_offset != log_end_offset {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("batch at offset {} does not follow log end offset {}", header.base_offset, log_end_offset)));
+            }
+            buf.extend_from_slice(&records[position..position + header.size()]);
+            log_end_offset = header.next_offset();
+        }
+        if !buf.is_empty() {
+            self.write(&buf)?;
+        }
+        Ok(log_end_offset)
+    }
+
+    /// Appends metadata record values as one batch after the last record,
+    /// in the leader epoch of the last batch.
+    pub fn append(&mut self, segments: &SegmentCache, values: Vec<Bytes>, now: i64) -> io::Result<i64> {
+        let leader_epoch = self.last_epoch(segments)?;
+        self.append_in_epoch(segments, values, leader_epoch, now)
+    }
+
+    /// Appends metadata record values as one batch written by the leader of
+    /// `leader_epoch`, returning the offset of the last one.
+    pub fn append_in_epoch(&mut self, segments: &SegmentCache, values: Vec<Bytes>, leader_epoch: i32, now: i64) -> io::Result<i64> {
+        let records = values.into_iter().map(|value| (None, value)).collect();
+        self.write_batch(segments, records, leader_epoch, false, now)
+    }
+
+    /// Appends the LeaderChange control batch that opens a leader's epoch.
+    pub fn append_leader_change(&mut self, segments: &SegmentCache, leader_epoch: i32, leader_id: i32, voters: &[i32], granting_voters: &[i32], now: i64) -> io::Result<i64> {
+        let (key, value) = encode_leader_change_message(leader_id, voters, granting_voters);
+        self.write_batch(segments, vec![(Some(key), value)], leader_epoch, true, now)
+    }
+
+    fn write_batch(&mut self, segments: &SegmentCache, values: Vec<(Option<Bytes>, Bytes)>, leader_epoch: i32, control: bool, now: i64) -> io::Result<i64> {
+        let next_offset = self.log_end_offset(segments)?;
+        let count = values.len() as i64;
+        let mut buf = BytesMut::new();
+        encode_batch(&mut buf, values, next_offset, leader_epoch, control, now)?;
+        self.write(&buf)?;
+        Ok(next_offset + count - 1)
+    }
+
+    /// Bytes of committed batches past the latest snapshot.
+    pub fn bytes_since_snapshot(&mut self, segments: &SegmentCache) -> io::Result<u64> {
+        self.reload(segments)?;
+        let snapshot_end_offset = self.snapshot.map(|(end_offset, _)| end_offset).unwrap_or(0);
+        Ok(self.batches.iter()
+            .filter(|(_, header)| header.base_offset >= snapshot_end_offset)
+            .filter(|(_, header)| self.high_watermark.map_or(true, |high_watermark| header.last_offset() < high_watermark))
+            .map(|(_, header)| header.size() as u64)
+            .sum())
+    }
+
+    /// Writes a snapshot of the committed records as of the last committed
+    /// batch, then drops the older snapshots and the log the new one covers.
+    /// Returns its (end offset, epoch), or None with nothing new to cover.
+    pub fn write_snapshot(&mut self, segments: &SegmentCache, now: i64) -> io::Result<Option<(i64, i32)>> {
+        let record_sets = self.record_sets(segments);
+        let snapshot_end_offset = self.snapshot.map(|(end_offset, _)| end_offset).unwrap_or(0);
+        let Some((_, last)) = self.batches.iter()
+            .rfind(|(_, header)| self.high_watermark.map_or(true, |high_watermark| header.last_offset() < high_watermark)) else { return Ok(None) };
+        let (end_offset, epoch) = (last.next_offset(), last.partition_leader_epoch);
+        if end_offset <= snapshot_end_offset {
+            return Ok(None);
+        }
+        let mut buf = BytesMut::new();
+        let (key, value) = encode_snapshot_header(last.max_timestamp);
+        encode_batch(&mut buf, vec![(Some(key), value)], 0, epoch, true, now)?;
+        let mut offset = 1;
+        for chunk in snapshot_values(&record_sets).chunks(SNAPSHOT_BATCH_RECORDS) {
+            encode_batch(&mut buf, chunk.iter().map(|value| (None, value.clone())).collect(), offset, epoch, false, now)?;
+            offset += chunk.len() as i64;
+        }
+        let (key, value) = encode_snapshot_footer();
+        encode_batch(&mut buf, vec![(Some(key), value)], offset, epoch, true, now)?;
+        let path = self.dir.join(snapshot_file_name(end_offset, epoch));
+        write_file(&path, &buf)?;
+        self.roll(segments, end_offset)?;
+        Ok(Some((end_offset, epoch)))
+    }
+
+    /// Replaces the log with a snapshot fetched from the leader into the
+    /// partition dir, which the log continues from.
+    pub fn install_snapshot(&mut self, segments: &SegmentCache, end_offset: i64, epoch: i32, bytes: &[u8]) -> io::Result<()> {
+        write_file(&self.dir.join(snapshot_file_name(end_offset, epoch)), bytes)?;
+        self.roll(segments, end_offset)?;
+        if self.high_watermark.is_some_and(|high_watermark| high_watermark < end_offset) {
+            self.high_watermark = Some(end_offset);
+        }
+        Ok(())
+    }
+
+    // starts the log at `start_offset`, keeping the batches from there on,
+    // and deletes the segment and the snapshots before it
+    fn roll(&mut self, segments: &SegmentCache, start_offset: i64) -> io::Result<()> {
+        self.reload(segments)?;
+        let position = self.batches.iter()
+            .find(|(_, header)| header.base_offset >= start_offset)
+            .map(|(position, _)| *position)
+            .unwrap_or(self.bytes.len());
+        let old_path = self.log_path();
+        let new_path = segment_path(&self.dir, start_offset);
+        if new_path != old_path {
+            write_file(&new_path, &self.bytes[position..])?;
+            segments.evict(&old_path);
+            match fs::remove_file(&old_path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+            if parse_snapshot_file_name(&file_name).is_some_and(|(end_offset, _)| end_offset < start_offset) {
+                fs::remove_file(&path)?;
+            }
+        }
+        self.opened = false;
+        self.invalidate();
+        self.open(segments)
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let mut file = OpenOptions::new().create(true).append(true).open(self.log_path())?;
+        file.write_all(bytes)?;
+        file.sync_data()
+    }
+}
+
+fn encode_batch(buf: &mut BytesMut, values: Vec<(Option<Bytes>, Bytes)>, base_offset: i64, leader_epoch: i32, control: bool, now: i64) -> io::Result<()> {
+    let records: Vec<Record> = values.into_iter().enumerate().map(|(idx, (key, value))| Record {
+        transactional: false,
+        control,
+        partition_leader_epoch: leader_epoch,
+        producer_id: -1,
+        producer_epoch: -1,
+        timestamp_type: TimestampType::Creation,
+        offset: base_offset + idx as i64,
+        // the encoder starts a new batch wherever offset - sequence changes,
+        // so the sequence counts up from the batch's base sequence of -1
+        sequence: idx as i32 - 1,
+        timestamp: now,
+        key,
+        value: Some(value),
+        headers: Default::default(),
+    }).collect();
+    let options = RecordEncodeOptions {
+        version: 2,
+        compression: Compression::None,
+    };
+    RecordBatchEncoder::encode(buf, &records, &options)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+}
+
+// writes a whole file under a temporary name first, so a crash never leaves
+// it half written
+fn write_file(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp = PathBuf::from(format!("{}.{}", path.display(), PARTIAL_SUFFIX));
+    let mut file = fs::File::create(&tmp)?;
+    file.write_all(bytes)?;
+    file.sync_data()?;
+    fs::rename(&tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use crate::record::{encode_config_record, encode_producer_ids_record, extract_record_value, ConfigRecord, ProducerIdsRecord, RecordValue};
+    use crate::segment_cache::SegmentCache;
+    use crate::test_util::temp_path;
+
+    use super::{parse_snapshot_file_name, snapshot_file_name, MetadataCache};
+
+    #[test]
+    fn test_epochs_and_high_watermark() {
+        let dir = temp_path("metadata");
+        let segments = SegmentCache::default();
+        let mut cache = MetadataCache::new(dir.clone());
+        assert_eq!(0, cache.log_end_offset(&segments).unwrap());
+
+        assert_eq!(0, cache.append_leader_change(&segments, 1, 1, &[1], &[1], 0).unwrap());
+        assert_eq!(2, cache.append_in_epoch(&segments, vec![Bytes::from_static(b"a"), Bytes::from_static(b"b")], 1, 0).unwrap());
+        assert_eq!(3, cache.append_leader_change(&segments, 3, 1, &[1], &[1], 0).unwrap());
+        assert_eq!(4, cache.append(&segments, vec![Bytes::from_static(b"c")], 0).unwrap());
+        assert_eq!(3, cache.last_epoch(&segments).unwrap());
+        assert_eq!((1, 3), cache.end_offset_for_epoch(&segments, 2).unwrap());
+        assert_eq!((3, 5), cache.end_offset_for_epoch(&segments, 3).unwrap());
+
+        // the control batches never decode as metadata
+        assert_eq!(2, cache.record_sets(&segments).len());
+        cache.set_high_watermark(3);
+        assert_eq!(1, cache.record_sets(&segments).len());
+
+        cache.truncate(&segments, 3).unwrap();
+        assert_eq!(3, cache.log_end_offset(&segments).unwrap());
+        assert_eq!((1, 3), cache.end_offset_for_epoch(&segments, 3).unwrap());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_skip_undecodable_batch() {
+        let dir = temp_path("metadata-corrupt");
+        let segments = SegmentCache::default();
+        let mut cache = MetadataCache::new(dir.clone());
+        cache.append(&segments, vec![Bytes::from_static(b"a")], 0).unwrap();
+        cache.append(&segments, vec![Bytes::from_static(b"b")], 0).unwrap();
+        assert_eq!(2, cache.record_sets(&segments).len());
+
+        // the last byte of the first batch belongs to its record
+        let path = std::fs::read_dir(&dir).unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| path.extension().is_some_and(|extension| extension == "log"))
+            .unwrap();
+        let mut bytes = std::fs::read(&path).unwrap();
+        let first_batch_size = 12 + i32::from_be_bytes(bytes[8..12].try_into().unwrap()) as usize;
+        bytes[first_batch_size - 1] ^= 0xff;
+        std::fs::write(&path, &bytes).unwrap();
+
+        let mut reopened = MetadataCache::new(dir.clone());
+        assert_eq!(1, reopened.record_sets(&segments).len());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    fn config(name: &str, value: Option<&str>) -> Bytes {
+        encode_config_record(&ConfigRecord {
+            resource_type: 2,
+            resource_name: "foo".to_string(),
+            name: name.to_string(),
+            value: value.map(str::to_string),
+        })
+    }
+
+    fn producer_ids(next_producer_id: i64) -> Bytes {
+        encode_producer_ids_record(&ProducerIdsRecord { broker_id: 1, broker_epoch: -1, next_producer_id })
+    }
+
+    #[test]
+    fn test_snapshot() {
+        assert_eq!(Some((7, 2)), parse_snapshot_file_name(&snapshot_file_name(7, 2)));
+        let dir = temp_path("metadata-snapshot");
+        let segments = SegmentCache::default();
+        let mut cache = MetadataCache::new(dir.clone());
+        cache.append_in_epoch(&segments, vec![config("retention.ms", Some("1")), config("cleanup.policy", Some("compact")), producer_ids(1000)], 1, 0).unwrap();
+        cache.append_in_epoch(&segments, vec![config("retention.ms", Some("2")), config("cleanup.policy", None), producer_ids(2000)], 1, 0).unwrap();
+        cache.append_in_epoch(&segments, vec![config("segment.ms", Some("3"))], 2, 0).unwrap();
+        cache.set_high_watermark(6);
+
+        // only the changed retention.ms and the last producer id block
+        // survive of the committed records
+        assert_eq!(Some((6, 1)), cache.write_snapshot(&segments, 0).unwrap());
+        assert!(dir.join(snapshot_file_name(6, 1)).exists());
+        assert_eq!(None, cache.write_snapshot(&segments, 0).unwrap());
+        let record_sets = cache.record_sets(&segments);
+        let records: Vec<_> = record_sets.iter().flat_map(|record_set| record_set.records.iter()).collect();
+        assert_eq!(2, records.len());
+        assert!(matches!(extract_record_value(records[1]), RecordValue::ProducerIdsRecord(ProducerIdsRecord { next_producer_id: 2000, .. })));
+
+        let mut reopened = MetadataCache::new(dir.clone());
+        assert_eq!(6, reopened.log_start_offset(&segments).unwrap());
+        assert_eq!(7, reopened.log_end_offset(&segments).unwrap());
+        assert_eq!((-1, -1), reopened.end_offset_for_epoch(&segments, 0).unwrap());
+        assert_eq!(3, reopened.record_sets(&segments).iter().map(|record_set| record_set.records.len()).sum::<usize>());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
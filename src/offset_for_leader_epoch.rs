@@ -0,0 +1,54 @@
+use std::collections::HashMap;
+
+use kafka_protocol::error::ResponseError;
+use kafka_protocol::messages::offset_for_leader_epoch_response::{EpochEndOffset, OffsetForLeaderTopicResult};
+use kafka_protocol::messages::{OffsetForLeaderEpochRequest, OffsetForLeaderEpochResponse};
+use uuid::Uuid;
+
+use crate::broker::Broker;
+use crate::leader_epoch::{UNDEFINED_EPOCH, UNDEFINED_EPOCH_OFFSET};
+
+/// Serves OffsetForLeaderEpoch: the largest epoch up to the requested one
+/// in each partition's log and the offset where it ends, from which a
+/// follower or consumer finds where its log diverges from the leader's.
+pub async fn handle_offset_for_leader_epoch(broker: &Broker, req: OffsetForLeaderEpochRequest, topics: &HashMap<String, (Uuid, Vec<i32>)>) -> OffsetForLeaderEpochResponse {
+    // consumers send -1, or -2 before v3
+    let replica_id = Some(req.replica_id.0).filter(|replica_id| *replica_id >= 0);
+    let mut responses = Vec::new();
+    for topic in req.topics.iter() {
+        let topic_name = topic.topic.0.as_str();
+        let mut partitions = Vec::new();
+        for partition in topic.partitions.iter() {
+            let topic_partition = (topic_name.to_string(), partition.partition);
+            let known = topics.get(topic_name)
+                .map(|(_, partition_ids)| partition_ids.contains(&partition.partition))
+                .unwrap_or(false);
+            let resp = EpochEndOffset::default()
+                .with_partition(partition.partition)
+                .with_leader_epoch(UNDEFINED_EPOCH)
+                .with_end_offset(UNDEFINED_EPOCH_OFFSET);
+            if !known {
+                partitions.push(resp.with_error_code(ResponseError::UnknownTopicOrPartition.code()));
+                continue;
+            }
+            if let Err(error) = broker.replica_manager.lock().await.check_fetch(&topic_partition, replica_id, partition.current_leader_epoch) {
+                partitions.push(resp.with_error_code(error.code()));
+                continue;
+            }
+            let resp = match broker.end_offset_for_epoch(topic_name, partition.partition, partition.leader_epoch).await {
+                Ok((leader_epoch, end_offset)) => resp.with_leader_epoch(leader_epoch)
+                    .with_end_offset(end_offset),
+                Err(e) => {
+                    println!("Failed to look up the epochs of {}-{}: {}", topic_name, partition.partition, e);
+                    resp.with_error_code(ResponseError::KafkaStorageError.code())
+                }
+            };
+            partitions.push(resp);
+        }
+        responses.push(OffsetForLeaderTopicResult::default()
+            .with_topic(topic.topic.clone())
+            .with_partitions(partitions));
+    }
+    OffsetForLeaderEpochResponse::default()
+        .with_topics(responses)
+}
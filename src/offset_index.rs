@@ -0,0 +1,227 @@
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use bytes::{Buf, BufMut};
+use memmap2::{Mmap, MmapMut};
+
+use crate::log::segment_file_name;
+
+// relativeOffset(4) + position(4)
+const ENTRY_SIZE: usize = 8;
+// preallocated size of the active segment's index, as segment.index.bytes
+const MAX_INDEX_SIZE: u64 = 10 * 1024 * 1024;
+
+pub fn offset_index_path(dir: &Path, base_offset: i64) -> PathBuf {
+    dir.join(segment_file_name(base_offset, "index"))
+}
+
+/// Looks up the position of `offset` in the entries of an index file read
+/// whole, as an index fetched from remote storage.
+pub fn lookup_position(entries: &[u8], base_offset: i64, offset: i64) -> u64 {
+    let entry = |idx: usize| {
+        let mut buf = &entries[idx * ENTRY_SIZE..(idx + 1) * ENTRY_SIZE];
+        (base_offset + buf.get_u32() as i64, buf.get_u32() as u64)
+    };
+    let (mut low, mut high) = (0, entries.len() / ENTRY_SIZE);
+    while low < high {
+        let mid = (low + high) / 2;
+        if entry(mid).0 <= offset {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+    low.checked_sub(1).map(|idx| entry(idx).1).unwrap_or(0)
+}
+
+#[derive(Debug)]
+enum Mapping {
+    // the active segment's index, preallocated and appended to in place
+    Writable(MmapMut),
+    ReadOnly(Mmap),
+    // a closed index without entries, which cannot be mapped
+    Empty,
+}
+
+/// A segment's sparse offset index in Kafka's `.index` format, memory-mapped.
+/// Each entry is the offset of a batch relative to the segment base and its
+/// position in the segment file. A closed segment's index is trimmed to its
+/// entries and reused when the log is opened; the active one is rebuilt.
+#[derive(Debug)]
+pub struct OffsetIndex {
+    path: PathBuf,
+    base_offset: i64,
+    mapping: Mapping,
+    entries: usize,
+}
+
+impl OffsetIndex {
+    /// Creates an empty index for an active segment, replacing any old file.
+    pub fn create(path: PathBuf, base_offset: i64) -> io::Result<OffsetIndex> {
+        match fs::remove_file(&path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path)?;
+        file.set_len(MAX_INDEX_SIZE)?;
+        // SAFETY: the file was just created and is only written through this mapping
+        let mmap = unsafe { MmapMut::map_mut(&file)? };
+        Ok(OffsetIndex {
+            path,
+            base_offset,
+            mapping: Mapping::Writable(mmap),
+            entries: 0,
+        })
+    }
+
+    /// Maps the index a closed segment was left with read-only. None when
+    /// there is no usable one: missing, never trimmed as after a crash
+    /// during a roll, or pointing past the `segment_size` bytes of its
+    /// segment.
+    pub fn open(path: PathBuf, base_offset: i64, segment_size: u64) -> io::Result<Option<OffsetIndex>> {
+        let len = match fs::metadata(&path) {
+            Ok(metadata) => metadata.len(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        if len % ENTRY_SIZE as u64 != 0 || len >= MAX_INDEX_SIZE {
+            return Ok(None);
+        }
+        let mapping = if len == 0 {
+            Mapping::Empty
+        } else {
+            // SAFETY: the index of a closed segment is not modified again
+            Mapping::ReadOnly(unsafe { Mmap::map(&File::open(&path)?)? })
+        };
+        let index = OffsetIndex {
+            path,
+            base_offset,
+            mapping,
+            entries: len as usize / ENTRY_SIZE,
+        };
+        if index.last_position().is_some_and(|position| position >= segment_size) {
+            return Ok(None);
+        }
+        Ok(Some(index))
+    }
+
+    fn bytes(&self) -> &[u8] {
+        match &self.mapping {
+            Mapping::Writable(mmap) => &mmap[..],
+            Mapping::ReadOnly(mmap) => &mmap[..],
+            Mapping::Empty => &[],
+        }
+    }
+
+    // (offset, position)
+    fn entry(&self, idx: usize) -> (i64, u64) {
+        let mut buf = &self.bytes()[idx * ENTRY_SIZE..(idx + 1) * ENTRY_SIZE];
+        (self.base_offset + buf.get_u32() as i64, buf.get_u32() as u64)
+    }
+
+    /// Adds an entry for the batch at `position`. A full index stops growing;
+    /// lookups past its last entry then scan further through the segment.
+    pub fn append(&mut self, offset: i64, position: u64) {
+        let Mapping::Writable(mmap) = &mut self.mapping else { return };
+        let start = self.entries * ENTRY_SIZE;
+        if start + ENTRY_SIZE > mmap.len() {
+            return;
+        }
+        let mut buf = &mut mmap[start..start + ENTRY_SIZE];
+        buf.put_u32((offset - self.base_offset) as u32);
+        buf.put_u32(position as u32);
+        self.entries += 1;
+    }
+
+    /// Position of the last entry at or before `offset`, the start of the
+    /// segment when there is none.
+    pub fn lookup(&self, offset: i64) -> u64 {
+        lookup_position(&self.bytes()[..self.entries * ENTRY_SIZE], self.base_offset, offset)
+    }
+
+    pub fn last_position(&self) -> Option<u64> {
+        self.entries.checked_sub(1).map(|idx| self.entry(idx).1)
+    }
+
+    /// Maps a copy of the index at `path` as this one is mapped, as when its
+    /// log moves to another log dir.
+    pub fn reopen_at(&self, path: PathBuf) -> io::Result<OffsetIndex> {
+        let mapping = match &self.mapping {
+            Mapping::Writable(_) => {
+                let file = OpenOptions::new().read(true).write(true).open(&path)?;
+                file.set_len(MAX_INDEX_SIZE)?;
+                // SAFETY: the copy is only written through this mapping from now on
+                Mapping::Writable(unsafe { MmapMut::map_mut(&file)? })
+            }
+            // SAFETY: the index of a closed segment is not modified again
+            Mapping::ReadOnly(_) => Mapping::ReadOnly(unsafe { Mmap::map(&File::open(&path)?)? }),
+            Mapping::Empty => Mapping::Empty,
+        };
+        Ok(OffsetIndex {
+            path,
+            base_offset: self.base_offset,
+            mapping,
+            entries: self.entries,
+        })
+    }
+
+    /// Moves the index file to `path`, as a cleaned segment's is swapped in.
+    pub fn rename(&mut self, path: PathBuf) -> io::Result<()> {
+        fs::rename(&self.path, &path)?;
+        self.path = path;
+        Ok(())
+    }
+
+    /// Trims the index to its entries once its segment is rolled and maps it
+    /// read-only.
+    pub fn close(&mut self) -> io::Result<()> {
+        let Mapping::Writable(mmap) = &self.mapping else { return Ok(()) };
+        mmap.flush()?;
+        self.mapping = Mapping::Empty;
+        let file = OpenOptions::new().read(true).write(true).open(&self.path)?;
+        file.set_len((self.entries * ENTRY_SIZE) as u64)?;
+        if self.entries > 0 {
+            // SAFETY: the index of a closed segment is not modified again
+            self.mapping = Mapping::ReadOnly(unsafe { Mmap::map(&File::open(&self.path)?)? });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::temp_path;
+
+    use super::OffsetIndex;
+
+    #[test]
+    fn test_lookup() {
+        let path = temp_path("offset-index");
+        let mut index = OffsetIndex::create(path.clone(), 100).unwrap();
+        assert_eq!(0, index.lookup(150));
+        index.append(100, 0);
+        index.append(110, 4096);
+        index.append(130, 8192);
+        assert_eq!(0, index.lookup(109));
+        assert_eq!(4096, index.lookup(110));
+        assert_eq!(8192, index.lookup(200));
+        assert_eq!(Some(8192), index.last_position());
+
+        index.close().unwrap();
+        assert_eq!(24, std::fs::metadata(&path).unwrap().len());
+        assert_eq!(4096, index.lookup(129));
+        // closed indexes take no more entries
+        index.append(140, 12288);
+        assert_eq!(8192, index.lookup(140));
+
+        let reopened = OffsetIndex::open(path.clone(), 100, 10000).unwrap().unwrap();
+        assert_eq!(4096, reopened.lookup(129));
+        assert_eq!(Some(8192), reopened.last_position());
+        // an entry past the end of the segment means the index is stale
+        assert!(OffsetIndex::open(path.clone(), 100, 8192).unwrap().is_none());
+        std::fs::remove_file(&path).unwrap();
+        assert!(OffsetIndex::open(path.clone(), 100, 8192).unwrap().is_none());
+    }
+}
@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+use std::io;
+
+use kafka_protocol::error::ResponseError;
+use kafka_protocol::messages::produce_response::{BatchIndexAndErrorMessage, PartitionProduceResponse, TopicProduceResponse};
+use kafka_protocol::messages::{ProduceRequest, ProduceResponse};
+use kafka_protocol::protocol::StrBytes;
+use kafka_protocol::records::TimestampType;
+use uuid::Uuid;
+
+use crate::broker::{now_ms, Broker};
+use crate::log::batches;
+use crate::log_validator::{validate_records, ValidationError};
+use crate::message_format::up_convert;
+
+// Produce v3 and later carry v2 record batches, older versions message sets
+const RECORD_BATCH_MIN_VERSION: i16 = 3;
+// acks waiting for every in-sync replica
+const ACKS_ALL: i16 = -1;
+
+// the offset after the batches once appended at `base_offset`
+fn next_offset(base_offset: i64, records: &[u8]) -> i64 {
+    base_offset + batches(records).iter().map(|(_, header)| header.last_offset_delta as i64 + 1).sum::<i64>()
+}
+
+pub async fn handle_produce(broker: &Broker, req: ProduceRequest, api_version: i16, topics: &HashMap<String, (Uuid, Vec<i32>)>) -> ProduceResponse {
+    let mut responses = Vec::new();
+    for topic_data in req.topic_data.iter() {
+        let topic_name = topic_data.name.0.as_str();
+        let mut partition_responses = Vec::new();
+        for partition_data in topic_data.partition_data.iter() {
+            let known = topics.get(topic_name)
+                .map(|(_, partition_ids)| partition_ids.contains(&partition_data.index))
+                .unwrap_or(false);
+            let resp = PartitionProduceResponse::default()
+                .with_index(partition_data.index);
+            let topic_partition = (topic_name.to_string(), partition_data.index);
+            let resp = if !known {
+                resp.with_error_code(ResponseError::UnknownTopicOrPartition.code())
+                    .with_base_offset(-1)
+            } else if broker.replica_manager.lock().await.is_follower(&topic_partition) {
+                resp.with_error_code(ResponseError::NotLeaderOrFollower.code())
+                    .with_base_offset(-1)
+            } else {
+                let records = partition_data.records.as_deref().unwrap_or_default();
+                let config = broker.log_config(topic_name).await;
+                let now = now_ms();
+                let validated = if api_version < RECORD_BATCH_MIN_VERSION {
+                    up_convert(records)
+                        .map_err(|e| {
+                            println!("Failed to up-convert records for {}-{}: {}", topic_name, partition_data.index, e);
+                            ValidationError::from(ResponseError::CorruptMessage)
+                        })
+                        .and_then(|records| validate_records(&records, &config, api_version, now))
+                } else {
+                    validate_records(records, &config, api_version, now)
+                };
+                // -1 unless the broker stamped the batches
+                let log_append_time = match config.message_timestamp_type {
+                    TimestampType::LogAppend => now,
+                    TimestampType::Creation => -1,
+                };
+                match validated {
+                    Ok(records) => match broker.append(topic_name, partition_data.index, &records).await {
+                        Ok((base_offset, log_start_offset)) => {
+                            // acks=all answers once the in-sync replicas have the records
+                            let replicated = req.acks != ACKS_ALL
+                                || broker.await_high_watermark(topic_name, partition_data.index, next_offset(base_offset, &records), req.timeout_ms).await;
+                            if replicated {
+                                resp.with_base_offset(base_offset)
+                                    .with_log_append_time_ms(log_append_time)
+                                    .with_log_start_offset(log_start_offset)
+                            } else {
+                                resp.with_error_code(ResponseError::RequestTimedOut.code())
+                                    .with_base_offset(-1)
+                            }
+                        }
+                        Err(e) => {
+                            println!("Failed to append to {}-{}: {}", topic_name, partition_data.index, e);
+                            let error = match e.kind() {
+                                io::ErrorKind::InvalidData => ResponseError::CorruptMessage,
+                                _ => ResponseError::KafkaStorageError,
+                            };
+                            resp.with_error_code(error.code())
+                                .with_base_offset(-1)
+                        }
+                    },
+                    Err(ValidationError { error, message, record_errors }) => resp.with_error_code(error.code())
+                        .with_error_message(message.map(StrBytes::from_string))
+                        .with_record_errors(record_errors.into_iter()
+                            .map(|(batch_index, reason)| BatchIndexAndErrorMessage::default()
+                                .with_batch_index(batch_index)
+                                .with_batch_index_error_message(Some(StrBytes::from_string(reason))))
+                            .collect())
+                        .with_base_offset(-1),
+                }
+            };
+            partition_responses.push(resp);
+        }
+        responses.push(TopicProduceResponse::default()
+            .with_name(topic_data.name.clone())
+            .with_partition_responses(partition_responses));
+    }
+    ProduceResponse::default()
+        .with_responses(responses)
+}
@@ -0,0 +1,35 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use tokio::sync::watch;
+
+use crate::log::TopicPartition;
+
+/// Parks Fetch requests until new data arrives. Each partition has a watch
+/// channel carrying its high watermark; appends publish the new value and
+/// every delayed fetch subscribed to that partition wakes up to re-read.
+#[derive(Debug, Default)]
+pub struct FetchPurgatory {
+    watermarks: Mutex<HashMap<TopicPartition, watch::Sender<i64>>>,
+}
+
+impl FetchPurgatory {
+    /// Subscribes before reading, so an append racing with the read is not missed.
+    pub fn subscribe(&self, topic_partition: &TopicPartition) -> watch::Receiver<i64> {
+        let mut watermarks = self.watermarks.lock().unwrap();
+        watermarks.entry(topic_partition.clone())
+            .or_insert_with(|| watch::channel(-1).0)
+            .subscribe()
+    }
+
+    pub fn update(&self, topic_partition: &TopicPartition, high_watermark: i64) {
+        let mut watermarks = self.watermarks.lock().unwrap();
+        if let Some(sender) = watermarks.get(topic_partition) {
+            if sender.receiver_count() == 0 {
+                watermarks.remove(topic_partition);
+            } else {
+                sender.send_replace(high_watermark);
+            }
+        }
+    }
+}
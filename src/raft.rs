@@ -0,0 +1,1325 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::{Bytes, BytesMut};
+use futures::future::join_all;
+use futures::stream::{FuturesUnordered, StreamExt};
+use kafka_protocol::error::ResponseError;
+use kafka_protocol::messages::describe_quorum_response::ReplicaState;
+use kafka_protocol::messages::fetch_request::{FetchPartition, FetchTopic};
+use kafka_protocol::messages::fetch_response::{EpochEndOffset, FetchableTopicResponse, LeaderIdAndEpoch, PartitionData, SnapshotId};
+use kafka_protocol::messages::{begin_quorum_epoch_request, begin_quorum_epoch_response, describe_quorum_response, end_quorum_epoch_request, end_quorum_epoch_response, fetch_snapshot_request, fetch_snapshot_response, vote_request, vote_response};
+use kafka_protocol::messages::{ApiKey, BeginQuorumEpochRequest, BeginQuorumEpochResponse, BrokerId, DescribeQuorumRequest, DescribeQuorumResponse, EndQuorumEpochRequest, EndQuorumEpochResponse, FetchRequest, FetchResponse, FetchSnapshotRequest, FetchSnapshotResponse, TopicName, VoteRequest, VoteResponse};
+use kafka_protocol::protocol::StrBytes;
+use tokio::fs;
+use tokio::sync::{watch, Mutex};
+use tokio::time::{timeout_at, Instant};
+use uuid::Uuid;
+
+use crate::acl::{Authorizer, ANONYMOUS_PRINCIPAL, OP_DESCRIBE, RESOURCE_TYPE_CLUSTER};
+use crate::broker::{now_ms, Broker, NODE_ID};
+use crate::client::send_request;
+use crate::config::BrokerConfig;
+use crate::describe_configs::CLUSTER_RESOURCE_NAME;
+use crate::fetch::FLEXIBLE_MIN_VERSION;
+use crate::log::METADATA_TOPIC;
+use crate::log_dir::random_uuid;
+use crate::metadata::{snapshot_file_name, MetadataCache};
+
+pub const PROCESS_ROLES_CONFIG: &str = "process.roles";
+pub const NODE_ID_CONFIG: &str = "node.id";
+pub const QUORUM_VOTERS_CONFIG: &str = "controller.quorum.voters";
+pub const QUORUM_ELECTION_TIMEOUT_MS_CONFIG: &str = "controller.quorum.election.timeout.ms";
+pub const DEFAULT_QUORUM_ELECTION_TIMEOUT_MS: i64 = 1000;
+pub const QUORUM_FETCH_TIMEOUT_MS_CONFIG: &str = "controller.quorum.fetch.timeout.ms";
+pub const DEFAULT_QUORUM_FETCH_TIMEOUT_MS: i64 = 2000;
+pub const QUORUM_ELECTION_BACKOFF_MAX_MS_CONFIG: &str = "controller.quorum.election.backoff.max.ms";
+pub const DEFAULT_QUORUM_ELECTION_BACKOFF_MAX_MS: i64 = 1000;
+pub const QUORUM_REQUEST_TIMEOUT_MS_CONFIG: &str = "controller.quorum.request.timeout.ms";
+pub const DEFAULT_QUORUM_REQUEST_TIMEOUT_MS: i64 = 2000;
+pub const QUORUM_RETRY_BACKOFF_MS_CONFIG: &str = "controller.quorum.retry.backoff.ms";
+pub const DEFAULT_QUORUM_RETRY_BACKOFF_MS: i64 = 20;
+
+// the topic id KRaft gives the metadata log
+pub const METADATA_TOPIC_ID: Uuid = Uuid::from_u128(1);
+pub const QUORUM_STATE_FILE: &str = "quorum-state";
+
+// how long a leader parks a fetch with nothing new, as KRaft's fetch max wait
+const FETCH_MAX_WAIT_MS: i32 = 500;
+const FETCH_MAX_BYTES: i32 = 8 * 1024 * 1024;
+// the versions this node sends
+const VOTE_VERSION: i16 = 0;
+const BEGIN_QUORUM_EPOCH_VERSION: i16 = 0;
+const END_QUORUM_EPOCH_VERSION: i16 = 0;
+const FETCH_VERSION: i16 = 13;
+const FETCH_SNAPSHOT_VERSION: i16 = 0;
+// FetchRequest.replica_state replaces replica_id
+const REPLICA_STATE_MIN_VERSION: i16 = 15;
+const TOPIC_ID_MIN_VERSION: i16 = 13;
+
+/// Parses `controller.quorum.voters`, "id@host:port,...", into voter id ->
+/// (host, port).
+pub fn parse_voters(voters: &str) -> BTreeMap<i32, (String, i32)> {
+    voters.split(',')
+        .filter_map(|voter| {
+            let (id, address) = voter.trim().split_once('@')?;
+            let (host, port) = address.rsplit_once(':')?;
+            Some((id.parse().ok()?, (host.to_string(), port.parse().ok()?)))
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuorumConfig {
+    pub node_id: i32,
+    // whether process.roles has controller, only controllers vote
+    pub controller: bool,
+    pub voters: BTreeMap<i32, (String, i32)>,
+    pub election_timeout_ms: i64,
+    pub fetch_timeout_ms: i64,
+    pub election_backoff_max_ms: i64,
+    pub request_timeout_ms: i64,
+    pub retry_backoff_ms: i64,
+}
+
+impl QuorumConfig {
+    /// The quorum settings, None for a node without `process.roles` or
+    /// `controller.quorum.voters`, which reads the metadata log as it is.
+    pub fn from_config(config: &BrokerConfig) -> Option<QuorumConfig> {
+        let roles: Vec<&str> = config.get(PROCESS_ROLES_CONFIG)?.split(',').map(|role| role.trim()).collect();
+        let voters = parse_voters(config.get(QUORUM_VOTERS_CONFIG)?);
+        if voters.is_empty() {
+            return None;
+        }
+        Some(QuorumConfig {
+            node_id: config.get_or(NODE_ID_CONFIG, NODE_ID),
+            controller: roles.contains(&"controller"),
+            voters,
+            election_timeout_ms: config.get_or(QUORUM_ELECTION_TIMEOUT_MS_CONFIG, DEFAULT_QUORUM_ELECTION_TIMEOUT_MS),
+            fetch_timeout_ms: config.get_or(QUORUM_FETCH_TIMEOUT_MS_CONFIG, DEFAULT_QUORUM_FETCH_TIMEOUT_MS),
+            election_backoff_max_ms: config.get_or(QUORUM_ELECTION_BACKOFF_MAX_MS_CONFIG, DEFAULT_QUORUM_ELECTION_BACKOFF_MAX_MS),
+            request_timeout_ms: config.get_or(QUORUM_REQUEST_TIMEOUT_MS_CONFIG, DEFAULT_QUORUM_REQUEST_TIMEOUT_MS),
+            retry_backoff_ms: config.get_or(QUORUM_RETRY_BACKOFF_MS_CONFIG, DEFAULT_QUORUM_RETRY_BACKOFF_MS),
+        })
+    }
+
+    pub fn is_voter(&self) -> bool {
+        self.controller && self.voters.contains_key(&self.node_id)
+    }
+
+    pub fn majority(&self) -> usize {
+        self.voters.len() / 2 + 1
+    }
+
+    pub fn address(&self, voter_id: i32) -> Option<String> {
+        self.voters.get(&voter_id).map(|(host, port)| format!("{}:{}", host, port))
+    }
+
+    // (id, address) of the voters besides this node
+    fn other_voters(&self) -> Vec<(i32, String)> {
+        self.voters.keys()
+            .filter(|id| **id != self.node_id)
+            .filter_map(|id| Some((*id, self.address(*id)?)))
+            .collect()
+    }
+
+    fn client_id(&self) -> String {
+        format!("raft-client-{}", self.node_id)
+    }
+}
+
+/// The election state kept in `quorum-state`, so a restarted voter neither
+/// votes twice in an epoch nor goes back to an older one.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ElectionState {
+    pub epoch: i32,
+    pub leader_id: Option<i32>,
+    pub voted_id: Option<i32>,
+}
+
+/// Kafka's `quorum-state` JSON, data version 0.
+pub fn format_quorum_state(election: &ElectionState, voters: &[i32]) -> String {
+    let voters: Vec<String> = voters.iter().map(|id| format!("{{\"voterId\":{}}}", id)).collect();
+    format!("{{\"clusterId\":\"\",\"leaderId\":{},\"leaderEpoch\":{},\"votedId\":{},\"appliedOffset\":0,\"currentVoters\":[{}],\"data_version\":0}}",
+        election.leader_id.unwrap_or(-1), election.epoch, election.voted_id.unwrap_or(-1), voters.join(","))
+}
+
+// an integer field of the flat JSON object
+fn json_int(text: &str, name: &str) -> Option<i64> {
+    let start = text.find(&format!("\"{}\":", name))? + name.len() + 3;
+    let value = text[start..].trim_start();
+    let end = value.find(|c: char| c != '-' && !c.is_ascii_digit()).unwrap_or(value.len());
+    value[..end].parse().ok()
+}
+
+pub fn parse_quorum_state(text: &str) -> io::Result<ElectionState> {
+    let field = |name: &str| json_int(text, name)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("quorum-state has no {}", name)));
+    let id = |value: i64| (value >= 0).then_some(value as i32);
+    Ok(ElectionState {
+        epoch: field("leaderEpoch")? as i32,
+        leader_id: id(field("leaderId")?),
+        voted_id: id(field("votedId")?),
+    })
+}
+
+/// The offset a majority of the voters have reached.
+pub fn majority_high_watermark(log_end_offsets: &[i64], majority: usize) -> i64 {
+    let mut offsets = log_end_offsets.to_vec();
+    offsets.sort_unstable_by(|a, b| b.cmp(a));
+    offsets.get(majority.saturating_sub(1)).copied().unwrap_or(0)
+}
+
+fn metadata_topic_name() -> TopicName {
+    TopicName(StrBytes::from_static_str(METADATA_TOPIC))
+}
+
+fn is_metadata_topic(topic_name: &TopicName) -> bool {
+    topic_name.0.as_str() == METADATA_TOPIC
+}
+
+fn random_ms(bound: i64) -> i64 {
+    if bound <= 0 {
+        return 0;
+    }
+    (random_uuid().as_u128() % bound as u128) as i64
+}
+
+async fn sleep_ms(ms: i64) {
+    tokio::time::sleep(Duration::from_millis(ms.max(0) as u64)).await;
+}
+
+/// How far a replica has fetched, as its leader saw it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Replica {
+    pub log_end_offset: i64,
+    pub last_fetch_timestamp: i64,
+    pub last_caught_up_timestamp: i64,
+}
+
+const UNKNOWN_REPLICA: Replica = Replica {
+    log_end_offset: -1,
+    last_fetch_timestamp: -1,
+    last_caught_up_timestamp: -1,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Role {
+    // no leader known and no vote cast in the epoch
+    Unattached,
+    // voted for `voted_id` in the epoch
+    Voted,
+    Candidate { granted: BTreeSet<i32> },
+    // the offset of the LeaderChange batch, which commits before anything
+    // else of the epoch does, and the replicas fetching from this leader
+    Leader { epoch_start_offset: i64, replicas: BTreeMap<i32, Replica> },
+    Follower,
+}
+
+impl Role {
+    fn name(&self) -> &'static str {
+        match self {
+            Role::Unattached => "unattached",
+            Role::Voted => "voted",
+            Role::Candidate { .. } => "candidate",
+            Role::Leader { .. } => "leader",
+            Role::Follower => "follower",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct RaftState {
+    election: ElectionState,
+    role: Role,
+    // when a voter starts an election, a leader checks it still has a
+    // majority, or a follower gives up on its leader
+    deadline: i64,
+}
+
+/// This node's part in the KRaft quorum replicating the metadata log:
+/// elections among the voters, and the leader's log copied by fetching
+/// followers and observers. Records count once the high watermark passes
+/// them.
+#[derive(Debug)]
+pub struct Raft {
+    pub config: QuorumConfig,
+    dir: PathBuf,
+    state: Mutex<RaftState>,
+    // (log end offset, high watermark), wakes parked fetches and appends
+    progress: watch::Sender<(i64, i64)>,
+}
+
+impl Raft {
+    pub fn new(config: QuorumConfig, dir: PathBuf) -> Raft {
+        Raft {
+            config,
+            dir,
+            state: Mutex::new(RaftState {
+                election: ElectionState::default(),
+                role: Role::Unattached,
+                deadline: 0,
+            }),
+            progress: watch::channel((0, 0)).0,
+        }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The leader of the current epoch, the active controller, if known.
+    pub async fn leader_id(&self) -> Option<i32> {
+        self.state.lock().await.election.leader_id
+    }
+
+    fn election_deadline(&self, now: i64) -> i64 {
+        now + self.config.election_timeout_ms + random_ms(self.config.election_timeout_ms)
+    }
+
+    /// Restores the election state. A voter that led before the restart keeps
+    /// its vote but has to win a new epoch; a sole voter does so right away.
+    pub async fn load(&self, broker: &Broker) -> io::Result<()> {
+        let election = match fs::read_to_string(self.dir.join(QUORUM_STATE_FILE)).await {
+            Ok(text) => parse_quorum_state(&text)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => ElectionState::default(),
+            Err(e) => return Err(e),
+        };
+        let now = now_ms();
+        let mut state = self.state.lock().await;
+        state.election = election;
+        (state.role, state.deadline) = match election.leader_id {
+            Some(leader_id) if leader_id == self.config.node_id => {
+                state.election = ElectionState { leader_id: None, voted_id: Some(leader_id), ..election };
+                (Role::Voted, self.election_deadline(now))
+            }
+            Some(_) => (Role::Follower, now + self.config.fetch_timeout_ms),
+            None if election.voted_id.is_some() => (Role::Voted, self.election_deadline(now)),
+            None => (Role::Unattached, self.election_deadline(now)),
+        };
+        // what is committed is known again once the leader says so, past
+        // what the snapshot holds
+        {
+            let mut metadata = broker.metadata.lock().await;
+            let committed = metadata.snapshot_id(&broker.segment_cache)?.map_or(0, |(end_offset, _)| end_offset);
+            metadata.set_high_watermark(committed);
+        }
+        if self.config.is_voter() && self.config.voters.len() == 1 {
+            self.become_candidate(broker, &mut state).await?;
+        }
+        Ok(())
+    }
+
+    // persists the election state before acting on it
+    async fn transition(&self, state: &mut RaftState, election: ElectionState, role: Role, deadline: i64) -> io::Result<()> {
+        if state.election != election {
+            let voters: Vec<i32> = self.config.voters.keys().copied().collect();
+            fs::create_dir_all(&self.dir).await?;
+            let path = self.dir.join(QUORUM_STATE_FILE);
+            let tmp = path.with_extension("tmp");
+            fs::write(&tmp, format_quorum_state(&election, &voters)).await?;
+            fs::rename(&tmp, &path).await?;
+        }
+        if state.election.epoch != election.epoch || state.role.name() != role.name() {
+            println!("Quorum epoch {}: {}, leader {:?}", election.epoch, role.name(), election.leader_id);
+        }
+        state.election = election;
+        state.role = role;
+        state.deadline = deadline;
+        Ok(())
+    }
+
+    // moves to a newer epoch seen in a request or response, following its
+    // leader when it names one
+    async fn observe_leader(&self, state: &mut RaftState, epoch: i32, leader_id: i32) -> io::Result<()> {
+        let new_leader = epoch == state.election.epoch && state.election.leader_id.is_none() && leader_id >= 0 && leader_id != self.config.node_id;
+        if epoch <= state.election.epoch && !new_leader {
+            return Ok(());
+        }
+        let now = now_ms();
+        let voted_id = if epoch == state.election.epoch { state.election.voted_id } else { None };
+        if leader_id >= 0 {
+            let election = ElectionState { epoch, leader_id: Some(leader_id), voted_id };
+            self.transition(state, election, Role::Follower, now + self.config.fetch_timeout_ms).await
+        } else {
+            let election = ElectionState { epoch, leader_id: None, voted_id };
+            let deadline = self.election_deadline(now);
+            self.transition(state, election, Role::Unattached, deadline).await
+        }
+    }
+
+    async fn become_candidate(&self, broker: &Broker, state: &mut RaftState) -> io::Result<()> {
+        let node_id = self.config.node_id;
+        let election = ElectionState { epoch: state.election.epoch + 1, leader_id: None, voted_id: Some(node_id) };
+        let deadline = self.election_deadline(now_ms());
+        self.transition(state, election, Role::Candidate { granted: BTreeSet::from([node_id]) }, deadline).await?;
+        self.maybe_become_leader(broker, state).await
+    }
+
+    async fn maybe_become_leader(&self, broker: &Broker, state: &mut RaftState) -> io::Result<()> {
+        let Role::Candidate { granted } = &state.role else { return Ok(()) };
+        if granted.len() < self.config.majority() {
+            return Ok(());
+        }
+        let granted: Vec<i32> = granted.iter().copied().collect();
+        let now = now_ms();
+        let election = ElectionState { leader_id: Some(self.config.node_id), ..state.election };
+        let role = Role::Leader { epoch_start_offset: i64::MAX, replicas: BTreeMap::new() };
+        self.transition(state, election, role, now + self.config.fetch_timeout_ms).await?;
+        let voters: Vec<i32> = self.config.voters.keys().copied().collect();
+        let mut metadata = broker.metadata.lock().await;
+        let epoch_start_offset = metadata.append_leader_change(&broker.segment_cache, election.epoch, self.config.node_id, &voters, &granted, now)?;
+        state.role = Role::Leader { epoch_start_offset, replicas: BTreeMap::new() };
+        self.update_high_watermark(broker, state, &mut metadata)
+    }
+
+    // the leader's high watermark: the offset a majority of the voters have,
+    // once that is past the start of its epoch
+    fn update_high_watermark(&self, broker: &Broker, state: &mut RaftState, metadata: &mut MetadataCache) -> io::Result<()> {
+        let log_end_offset = metadata.log_end_offset(&broker.segment_cache)?;
+        let Role::Leader { epoch_start_offset, replicas } = &mut state.role else { return Ok(()) };
+        let now = now_ms();
+        replicas.insert(self.config.node_id, Replica { log_end_offset, last_fetch_timestamp: now, last_caught_up_timestamp: now });
+        let offsets: Vec<i64> = self.config.voters.keys()
+            .map(|id| replicas.get(id).map(|replica| replica.log_end_offset).unwrap_or(0))
+            .collect();
+        let high_watermark = majority_high_watermark(&offsets, self.config.majority());
+        if high_watermark > *epoch_start_offset && high_watermark > metadata.high_watermark().unwrap_or(0) {
+            metadata.set_high_watermark(high_watermark);
+        }
+        self.publish(log_end_offset, metadata.high_watermark().unwrap_or(0));
+        Ok(())
+    }
+
+    fn publish(&self, log_end_offset: i64, high_watermark: i64) {
+        self.progress.send_if_modified(|progress| {
+            let modified = *progress != (log_end_offset, high_watermark);
+            *progress = (log_end_offset, high_watermark);
+            modified
+        });
+    }
+
+    /// Appends metadata records as the leader and waits until a majority of
+    /// the voters have them.
+    pub async fn append(&self, broker: &Broker, values: Vec<Bytes>) -> io::Result<()> {
+        let last_offset = {
+            let mut state = self.state.lock().await;
+            if !matches!(state.role, Role::Leader { .. }) {
+                return Err(io::Error::other(format!("node {} is not the active controller", self.config.node_id)));
+            }
+            let mut metadata = broker.metadata.lock().await;
+            let last_offset = metadata.append_in_epoch(&broker.segment_cache, values, state.election.epoch, now_ms())?;
+            self.update_high_watermark(broker, &mut state, &mut metadata)?;
+            last_offset
+        };
+        let mut progress = self.progress.subscribe();
+        let deadline = Instant::now() + Duration::from_millis(self.config.request_timeout_ms.max(0) as u64);
+        while progress.borrow_and_update().1 <= last_offset {
+            if timeout_at(deadline, progress.changed()).await.is_err() {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, format!("metadata records up to offset {} were not committed in time", last_offset)));
+            }
+        }
+        Ok(())
+    }
+
+    async fn poll(&self, broker: &Broker) -> io::Result<()> {
+        let (election, role, deadline) = {
+            let state = self.state.lock().await;
+            (state.election, state.role.clone(), state.deadline)
+        };
+        let now = now_ms();
+        match role {
+            Role::Leader { .. } => self.lead(election).await,
+            Role::Follower => self.fetch(broker, election, election.leader_id.unwrap_or(-1)).await,
+            _ if !self.config.is_voter() => {
+                // an observer finds the leader through any voter
+                let voters: Vec<i32> = self.config.voters.keys().copied().collect();
+                let voter_id = voters[random_ms(voters.len() as i64) as usize];
+                self.fetch(broker, election, voter_id).await
+            }
+            _ if now >= deadline => self.elect(broker).await,
+            _ => {
+                sleep_ms(deadline - now).await;
+                Ok(())
+            }
+        }
+    }
+
+    // stands for the next epoch and asks the other voters for their votes
+    async fn elect(&self, broker: &Broker) -> io::Result<()> {
+        let (election, request) = {
+            let mut state = self.state.lock().await;
+            if matches!(state.role, Role::Leader { .. } | Role::Follower) || now_ms() < state.deadline {
+                return Ok(());
+            }
+            self.become_candidate(broker, &mut state).await?;
+            if !matches!(state.role, Role::Candidate { .. }) {
+                return Ok(());
+            }
+            let segments = &broker.segment_cache;
+            let mut metadata = broker.metadata.lock().await;
+            let partition = vote_request::PartitionData::default()
+                .with_partition_index(0)
+                .with_candidate_epoch(state.election.epoch)
+                .with_candidate_id(BrokerId(self.config.node_id))
+                .with_last_offset_epoch(metadata.last_epoch(segments)?)
+                .with_last_offset(metadata.log_end_offset(segments)?);
+            let request = VoteRequest::default()
+                .with_topics(vec![vote_request::TopicData::default()
+                    .with_topic_name(metadata_topic_name())
+                    .with_partitions(vec![partition])]);
+            (state.election, request)
+        };
+        let client_id = self.config.client_id();
+        let mut pending: FuturesUnordered<_> = self.config.other_voters().into_iter()
+            .map(|(voter_id, address)| {
+                let (request, client_id) = (&request, &client_id);
+                async move {
+                    let result = send_request::<_, VoteResponse>(&address, client_id, ApiKey::Vote, VOTE_VERSION, request, self.config.request_timeout_ms).await;
+                    (voter_id, result)
+                }
+            })
+            .collect();
+        while let Some((voter_id, result)) = pending.next().await {
+            let partition = match result {
+                Ok(resp) => resp.topics.into_iter().flat_map(|topic| topic.partitions).next(),
+                Err(e) => {
+                    println!("Vote request to {} failed: {}", voter_id, e);
+                    None
+                }
+            };
+            let Some(partition) = partition else { continue };
+            let mut state = self.state.lock().await;
+            self.observe_leader(&mut state, partition.leader_epoch, partition.leader_id.0).await?;
+            if state.election != election {
+                return Ok(());
+            }
+            if let Role::Candidate { granted } = &mut state.role {
+                if partition.vote_granted {
+                    granted.insert(voter_id);
+                }
+            }
+            self.maybe_become_leader(broker, &mut state).await?;
+            if !matches!(state.role, Role::Candidate { .. }) {
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+
+    // tells voters that have not fetched yet about this leader, and steps
+    // down when a majority has not fetched within the fetch timeout
+    async fn lead(&self, election: ElectionState) -> io::Result<()> {
+        let now = now_ms();
+        let (unacknowledged, resign) = {
+            let mut state = self.state.lock().await;
+            if state.election != election {
+                return Ok(());
+            }
+            let Role::Leader { replicas, .. } = &state.role else { return Ok(()) };
+            let unacknowledged: Vec<(i32, String)> = self.config.other_voters().into_iter()
+                .filter(|(id, _)| !replicas.contains_key(id))
+                .collect();
+            let resign = if now >= state.deadline {
+                let fetched = self.config.voters.keys()
+                    .filter(|id| **id == self.config.node_id || replicas.get(id).is_some_and(|replica| replica.last_fetch_timestamp >= now - self.config.fetch_timeout_ms))
+                    .count();
+                state.deadline = now + self.config.fetch_timeout_ms;
+                fetched < self.config.majority()
+            } else {
+                false
+            };
+            (unacknowledged, resign)
+        };
+        if resign {
+            return self.resign(election).await;
+        }
+        if !unacknowledged.is_empty() {
+            let request = BeginQuorumEpochRequest::default()
+                .with_topics(vec![begin_quorum_epoch_request::TopicData::default()
+                    .with_topic_name(metadata_topic_name())
+                    .with_partitions(vec![begin_quorum_epoch_request::PartitionData::default()
+                        .with_partition_index(0)
+                        .with_leader_id(BrokerId(self.config.node_id))
+                        .with_leader_epoch(election.epoch)])]);
+            let client_id = self.config.client_id();
+            let results = join_all(unacknowledged.iter()
+                .map(|(_, address)| send_request::<_, BeginQuorumEpochResponse>(address, &client_id, ApiKey::BeginQuorumEpoch, BEGIN_QUORUM_EPOCH_VERSION, &request, self.config.request_timeout_ms)))
+                .await;
+            for resp in results.into_iter().flatten() {
+                if let Some(partition) = resp.topics.into_iter().flat_map(|topic| topic.partitions).next() {
+                    let mut state = self.state.lock().await;
+                    self.observe_leader(&mut state, partition.leader_epoch, partition.leader_id.0).await?;
+                }
+            }
+        }
+        sleep_ms(self.config.election_timeout_ms / 2).await;
+        Ok(())
+    }
+
+    // gives up leadership, naming the most caught up voters as successors
+    async fn resign(&self, election: ElectionState) -> io::Result<()> {
+        let successors: Vec<i32> = {
+            let mut state = self.state.lock().await;
+            if state.election != election {
+                return Ok(());
+            }
+            let Role::Leader { replicas, .. } = &state.role else { return Ok(()) };
+            let mut successors: Vec<(i64, i32)> = self.config.other_voters().into_iter()
+                .map(|(id, _)| (replicas.get(&id).map_or(-1, |replica| replica.log_end_offset), id))
+                .collect();
+            successors.sort_by(|a, b| b.cmp(a));
+            let resigned = ElectionState { leader_id: None, voted_id: Some(self.config.node_id), ..election };
+            let deadline = self.election_deadline(now_ms());
+            self.transition(&mut state, resigned, Role::Voted, deadline).await?;
+            successors.into_iter().map(|(_, id)| id).collect()
+        };
+        println!("Resigning as the leader of epoch {}, a majority of the voters stopped fetching", election.epoch);
+        let request = EndQuorumEpochRequest::default()
+            .with_topics(vec![end_quorum_epoch_request::TopicData::default()
+                .with_topic_name(metadata_topic_name())
+                .with_partitions(vec![end_quorum_epoch_request::PartitionData::default()
+                    .with_partition_index(0)
+                    .with_leader_id(BrokerId(self.config.node_id))
+                    .with_leader_epoch(election.epoch)
+                    .with_preferred_successors(successors)])]);
+        let client_id = self.config.client_id();
+        join_all(self.config.other_voters().iter()
+            .map(|(_, address)| send_request::<_, EndQuorumEpochResponse>(address, &client_id, ApiKey::EndQuorumEpoch, END_QUORUM_EPOCH_VERSION, &request, self.config.request_timeout_ms)))
+            .await;
+        Ok(())
+    }
+
+    // one fetch of the metadata log from the leader, or from any voter while
+    // an observer looks for the leader
+    async fn fetch(&self, broker: &Broker, election: ElectionState, target_id: i32) -> io::Result<()> {
+        let segments = &broker.segment_cache;
+        let request = {
+            let mut metadata = broker.metadata.lock().await;
+            let log_end_offset = metadata.log_end_offset(segments)?;
+            let last_fetched_epoch = if log_end_offset == 0 { -1 } else { metadata.last_epoch(segments)? };
+            FetchRequest::default()
+                .with_replica_id(BrokerId(self.config.node_id))
+                .with_max_wait_ms(FETCH_MAX_WAIT_MS)
+                .with_min_bytes(1)
+                .with_max_bytes(FETCH_MAX_BYTES)
+                .with_topics(vec![FetchTopic::default()
+                    .with_topic_id(METADATA_TOPIC_ID)
+                    .with_partitions(vec![FetchPartition::default()
+                        .with_partition(0)
+                        .with_current_leader_epoch(election.epoch)
+                        .with_fetch_offset(log_end_offset)
+                        .with_last_fetched_epoch(last_fetched_epoch)
+                        .with_partition_max_bytes(FETCH_MAX_BYTES)])])
+        };
+        let partition = match self.config.address(target_id) {
+            Some(address) => {
+                let timeout_ms = FETCH_MAX_WAIT_MS as i64 + self.config.request_timeout_ms;
+                match send_request::<_, FetchResponse>(&address, &self.config.client_id(), ApiKey::Fetch, FETCH_VERSION, &request, timeout_ms).await {
+                    Ok(resp) => resp.responses.into_iter().flat_map(|topic| topic.partitions).next(),
+                    Err(e) => {
+                        println!("Fetch of the metadata log from {} failed: {}", target_id, e);
+                        None
+                    }
+                }
+            }
+            None => None,
+        };
+        let mut state = self.state.lock().await;
+        if state.election != election {
+            return Ok(());
+        }
+        let now = now_ms();
+        let Some(partition) = partition else {
+            if matches!(state.role, Role::Follower) && now >= state.deadline {
+                // a voter stands for election, an observer looks for the leader
+                let election = ElectionState { leader_id: None, ..election };
+                let role = if election.voted_id.is_some() { Role::Voted } else { Role::Unattached };
+                self.transition(&mut state, election, role, now).await?;
+            }
+            drop(state);
+            sleep_ms(self.config.retry_backoff_ms).await;
+            return Ok(());
+        };
+        self.observe_leader(&mut state, partition.current_leader.leader_epoch, partition.current_leader.leader_id.0).await?;
+        if partition.error_code != 0 || state.election.leader_id != Some(target_id) {
+            drop(state);
+            sleep_ms(self.config.retry_backoff_ms).await;
+            return Ok(());
+        }
+        state.deadline = now + self.config.fetch_timeout_ms;
+        let mut metadata = broker.metadata.lock().await;
+        if partition.diverging_epoch.end_offset >= 0 {
+            let (_, end_offset) = metadata.end_offset_for_epoch(segments, partition.diverging_epoch.epoch)?;
+            let offset = end_offset.min(partition.diverging_epoch.end_offset);
+            println!("Truncating the metadata log to offset {}, where it diverged from the leader", offset);
+            return metadata.truncate(segments, offset);
+        }
+        if partition.snapshot_id.end_offset >= 0 {
+            drop(metadata);
+            drop(state);
+            let snapshot_id = (partition.snapshot_id.end_offset, partition.snapshot_id.epoch);
+            return self.fetch_snapshot(broker, election, target_id, snapshot_id).await;
+        }
+        let log_end_offset = match &partition.records {
+            Some(records) => metadata.append_batches(segments, records)?,
+            None => metadata.log_end_offset(segments)?,
+        };
+        let high_watermark = partition.high_watermark.min(log_end_offset);
+        if high_watermark > metadata.high_watermark().unwrap_or(0) {
+            metadata.set_high_watermark(high_watermark);
+        }
+        self.publish(log_end_offset, metadata.high_watermark().unwrap_or(0));
+        Ok(())
+    }
+
+    // copies the leader's snapshot when the log to fetch from is gone, and
+    // continues the log from its end
+    async fn fetch_snapshot(&self, broker: &Broker, election: ElectionState, leader_id: i32, snapshot_id: (i64, i32)) -> io::Result<()> {
+        let Some(address) = self.config.address(leader_id) else { return Ok(()) };
+        let (end_offset, epoch) = snapshot_id;
+        println!("Fetching snapshot {} of the metadata log from {}", snapshot_file_name(end_offset, epoch), leader_id);
+        let mut bytes = BytesMut::new();
+        loop {
+            let request = FetchSnapshotRequest::default()
+                .with_replica_id(BrokerId(self.config.node_id))
+                .with_max_bytes(FETCH_MAX_BYTES)
+                .with_topics(vec![fetch_snapshot_request::TopicSnapshot::default()
+                    .with_name(metadata_topic_name())
+                    .with_partitions(vec![fetch_snapshot_request::PartitionSnapshot::default()
+                        .with_partition(0)
+                        .with_current_leader_epoch(election.epoch)
+                        .with_snapshot_id(fetch_snapshot_request::SnapshotId::default()
+                            .with_end_offset(end_offset)
+                            .with_epoch(epoch))
+                        .with_position(bytes.len() as i64)])]);
+            let resp: FetchSnapshotResponse = send_request(&address, &self.config.client_id(), ApiKey::FetchSnapshot, FETCH_SNAPSHOT_VERSION, &request, self.config.request_timeout_ms).await?;
+            let Some(partition) = resp.topics.into_iter().flat_map(|topic| topic.partitions).next() else {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "FetchSnapshot response without the metadata partition"));
+            };
+            if partition.error_code != 0 {
+                return Err(io::Error::other(format!("fetching snapshot {} failed with error code {}", snapshot_file_name(end_offset, epoch), partition.error_code)));
+            }
+            if partition.unaligned_records.is_empty() && (bytes.len() as i64) < partition.size {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "snapshot chunk is empty"));
+            }
+            bytes.extend_from_slice(&partition.unaligned_records);
+            if bytes.len() as i64 >= partition.size {
+                break;
+            }
+        }
+        let state = self.state.lock().await;
+        if state.election != election {
+            return Ok(());
+        }
+        let mut metadata = broker.metadata.lock().await;
+        metadata.install_snapshot(&broker.segment_cache, end_offset, epoch, &bytes)?;
+        let log_end_offset = metadata.log_end_offset(&broker.segment_cache)?;
+        self.publish(log_end_offset, metadata.high_watermark().unwrap_or(0));
+        Ok(())
+    }
+
+    // the error for a request meant for the leader of `current_leader_epoch`
+    fn check_leader(&self, state: &RaftState, current_leader_epoch: i32) -> Option<ResponseError> {
+        if !matches!(state.role, Role::Leader { .. }) {
+            Some(ResponseError::NotLeaderOrFollower)
+        } else if current_leader_epoch > state.election.epoch {
+            Some(ResponseError::UnknownLeaderEpoch)
+        } else if current_leader_epoch >= 0 && current_leader_epoch < state.election.epoch {
+            Some(ResponseError::FencedLeaderEpoch)
+        } else {
+            None
+        }
+    }
+
+    // a replica's fetch as the leader sees it, and whether it can be answered
+    // without waiting for more records
+    async fn read(&self, broker: &Broker, replica_id: i32, partition: &FetchPartition, api_version: i16) -> io::Result<(PartitionData, bool)> {
+        let mut state = self.state.lock().await;
+        let election = state.election;
+        let mut data = PartitionData::default().with_partition_index(partition.partition);
+        if api_version >= FLEXIBLE_MIN_VERSION {
+            data = data.with_current_leader(LeaderIdAndEpoch::default()
+                .with_leader_id(BrokerId(election.leader_id.unwrap_or(-1)))
+                .with_leader_epoch(election.epoch));
+        }
+        let error = if partition.partition != 0 {
+            Some(ResponseError::UnknownTopicOrPartition)
+        } else {
+            self.check_leader(&state, partition.current_leader_epoch)
+        };
+        if let Some(error) = error {
+            return Ok((data.with_error_code(error.code()), true));
+        }
+        let segments = &broker.segment_cache;
+        let mut metadata = broker.metadata.lock().await;
+        let high_watermark = metadata.high_watermark().unwrap_or(0);
+        let log_start_offset = metadata.log_start_offset(segments)?;
+        // a replica behind the log start, or diverged before it, starts over
+        // from the snapshot
+        let mut behind = partition.fetch_offset < log_start_offset;
+        if !behind && api_version >= FLEXIBLE_MIN_VERSION && partition.fetch_offset > 0 && partition.last_fetched_epoch >= 0 {
+            let (epoch, end_offset) = metadata.end_offset_for_epoch(segments, partition.last_fetched_epoch)?;
+            if end_offset < log_start_offset {
+                behind = true;
+            } else if epoch != partition.last_fetched_epoch || end_offset < partition.fetch_offset {
+                let diverging_epoch = EpochEndOffset::default()
+                    .with_epoch(epoch)
+                    .with_end_offset(end_offset);
+                return Ok((data.with_high_watermark(high_watermark).with_diverging_epoch(diverging_epoch), true));
+            }
+        }
+        if behind && api_version >= FLEXIBLE_MIN_VERSION {
+            if let Some((end_offset, epoch)) = metadata.snapshot_id(segments)? {
+                let snapshot_id = SnapshotId::default()
+                    .with_end_offset(end_offset)
+                    .with_epoch(epoch);
+                return Ok((data.with_high_watermark(high_watermark).with_log_start_offset(log_start_offset).with_snapshot_id(snapshot_id), true));
+            }
+        }
+        let log_end_offset = metadata.log_end_offset(segments)?;
+        if partition.fetch_offset < log_start_offset || partition.fetch_offset > log_end_offset {
+            return Ok((data.with_error_code(ResponseError::OffsetOutOfRange.code()).with_high_watermark(high_watermark), true));
+        }
+        if replica_id >= 0 {
+            if let Role::Leader { replicas, .. } = &mut state.role {
+                let now = now_ms();
+                let previous = replicas.get(&replica_id).copied().unwrap_or(UNKNOWN_REPLICA);
+                replicas.insert(replica_id, Replica {
+                    log_end_offset: partition.fetch_offset,
+                    last_fetch_timestamp: now,
+                    last_caught_up_timestamp: if partition.fetch_offset >= log_end_offset { now } else { previous.last_caught_up_timestamp },
+                });
+            }
+            self.update_high_watermark(broker, &mut state, &mut metadata)?;
+        }
+        let high_watermark = metadata.high_watermark().unwrap_or(0);
+        let records = metadata.read(segments, partition.fetch_offset, partition.partition_max_bytes.max(0) as usize)?;
+        let complete = !records.is_empty();
+        let data = data
+            .with_high_watermark(high_watermark)
+            .with_last_stable_offset(high_watermark)
+            .with_log_start_offset(log_start_offset)
+            .with_records(Some(records));
+        Ok((data, complete))
+    }
+
+    async fn vote(&self, broker: &Broker, partition: &vote_request::PartitionData) -> io::Result<vote_response::PartitionData> {
+        let mut state = self.state.lock().await;
+        let (candidate_id, candidate_epoch) = (partition.candidate_id.0, partition.candidate_epoch);
+        let mut vote_granted = false;
+        let error = if !self.config.voters.contains_key(&candidate_id) {
+            Some(ResponseError::InconsistentVoterSet)
+        } else if candidate_epoch < state.election.epoch {
+            Some(ResponseError::FencedLeaderEpoch)
+        } else {
+            if candidate_epoch > state.election.epoch {
+                let election = ElectionState { epoch: candidate_epoch, leader_id: None, voted_id: None };
+                let deadline = self.election_deadline(now_ms());
+                self.transition(&mut state, election, Role::Unattached, deadline).await?;
+            }
+            // only a candidate whose log is at least as long as this one may win
+            let segments = &broker.segment_cache;
+            let last = {
+                let mut metadata = broker.metadata.lock().await;
+                (metadata.last_epoch(segments)?, metadata.log_end_offset(segments)?)
+            };
+            vote_granted = match state.role {
+                Role::Unattached => self.config.is_voter() && (partition.last_offset_epoch, partition.last_offset) >= last,
+                Role::Voted => state.election.voted_id == Some(candidate_id),
+                _ => false,
+            };
+            if vote_granted && state.role == Role::Unattached {
+                let election = ElectionState { voted_id: Some(candidate_id), ..state.election };
+                let deadline = self.election_deadline(now_ms());
+                self.transition(&mut state, election, Role::Voted, deadline).await?;
+            }
+            None
+        };
+        Ok(vote_response::PartitionData::default()
+            .with_partition_index(partition.partition_index)
+            .with_error_code(error.map(|e| e.code()).unwrap_or(0))
+            .with_leader_id(BrokerId(state.election.leader_id.unwrap_or(-1)))
+            .with_leader_epoch(state.election.epoch)
+            .with_vote_granted(vote_granted))
+    }
+
+    async fn begin_quorum_epoch(&self, partition: &begin_quorum_epoch_request::PartitionData) -> io::Result<begin_quorum_epoch_response::PartitionData> {
+        let mut state = self.state.lock().await;
+        let (leader_id, leader_epoch) = (partition.leader_id.0, partition.leader_epoch);
+        let error = if leader_epoch < state.election.epoch {
+            Some(ResponseError::FencedLeaderEpoch)
+        } else if !self.config.voters.contains_key(&leader_id) {
+            Some(ResponseError::InconsistentVoterSet)
+        } else {
+            self.observe_leader(&mut state, leader_epoch, leader_id).await?;
+            None
+        };
+        Ok(begin_quorum_epoch_response::PartitionData::default()
+            .with_partition_index(partition.partition_index)
+            .with_error_code(error.map(|e| e.code()).unwrap_or(0))
+            .with_leader_id(BrokerId(state.election.leader_id.unwrap_or(-1)))
+            .with_leader_epoch(state.election.epoch))
+    }
+
+    async fn end_quorum_epoch(&self, partition: &end_quorum_epoch_request::PartitionData) -> io::Result<end_quorum_epoch_response::PartitionData> {
+        let mut state = self.state.lock().await;
+        let (leader_id, leader_epoch) = (partition.leader_id.0, partition.leader_epoch);
+        let error = if leader_epoch < state.election.epoch {
+            Some(ResponseError::FencedLeaderEpoch)
+        } else {
+            let resigned = leader_epoch > state.election.epoch
+                || (state.election.leader_id == Some(leader_id) && leader_id != self.config.node_id);
+            if resigned {
+                // the preferred successors stand first, in order
+                let now = now_ms();
+                let successors = &partition.preferred_successors;
+                let deadline = match successors.iter().position(|id| *id == self.config.node_id) {
+                    Some(position) => now + self.config.election_backoff_max_ms * position as i64 / successors.len() as i64,
+                    None => self.election_deadline(now),
+                };
+                let voted_id = if leader_epoch == state.election.epoch { state.election.voted_id } else { None };
+                let election = ElectionState { epoch: leader_epoch, leader_id: None, voted_id };
+                let role = if voted_id.is_some() { Role::Voted } else { Role::Unattached };
+                self.transition(&mut state, election, role, deadline).await?;
+            }
+            None
+        };
+        Ok(end_quorum_epoch_response::PartitionData::default()
+            .with_partition_index(partition.partition_index)
+            .with_error_code(error.map(|e| e.code()).unwrap_or(0))
+            .with_leader_id(BrokerId(state.election.leader_id.unwrap_or(-1)))
+            .with_leader_epoch(state.election.epoch))
+    }
+
+    async fn describe(&self, api_version: i16) -> describe_quorum_response::PartitionData {
+        let state = self.state.lock().await;
+        let data = describe_quorum_response::PartitionData::default()
+            .with_partition_index(0)
+            .with_leader_id(BrokerId(state.election.leader_id.unwrap_or(-1)))
+            .with_leader_epoch(state.election.epoch);
+        let Role::Leader { replicas, .. } = &state.role else {
+            return data.with_error_code(ResponseError::NotLeaderOrFollower.code());
+        };
+        let now = now_ms();
+        let describe = |id: i32| {
+            let replica = match replicas.get(&id) {
+                Some(replica) if id == self.config.node_id => Replica { last_fetch_timestamp: now, last_caught_up_timestamp: now, ..*replica },
+                Some(replica) => *replica,
+                None => UNKNOWN_REPLICA,
+            };
+            let described = ReplicaState::default()
+                .with_replica_id(BrokerId(id))
+                .with_log_end_offset(replica.log_end_offset);
+            if api_version >= 1 {
+                described
+                    .with_last_fetch_timestamp(replica.last_fetch_timestamp)
+                    .with_last_caught_up_timestamp(replica.last_caught_up_timestamp)
+            } else {
+                described
+            }
+        };
+        let voters = self.config.voters.keys().map(|id| describe(*id)).collect();
+        let observers = replicas.keys()
+            .filter(|id| !self.config.voters.contains_key(id))
+            .map(|id| describe(*id))
+            .collect();
+        data.with_high_watermark(self.progress.borrow().1)
+            .with_current_voters(voters)
+            .with_observers(observers)
+    }
+
+    async fn read_snapshot(&self, partition: &fetch_snapshot_request::PartitionSnapshot, max_bytes: i32) -> fetch_snapshot_response::PartitionSnapshot {
+        let (election, error) = {
+            let state = self.state.lock().await;
+            (state.election, self.check_leader(&state, partition.current_leader_epoch))
+        };
+        let data = fetch_snapshot_response::PartitionSnapshot::default()
+            .with_index(partition.partition)
+            .with_snapshot_id(fetch_snapshot_response::SnapshotId::default()
+                .with_end_offset(partition.snapshot_id.end_offset)
+                .with_epoch(partition.snapshot_id.epoch))
+            .with_current_leader(fetch_snapshot_response::LeaderIdAndEpoch::default()
+                .with_leader_id(BrokerId(election.leader_id.unwrap_or(-1)))
+                .with_leader_epoch(election.epoch));
+        if let Some(error) = error {
+            return data.with_error_code(error.code());
+        }
+        let path = self.dir.join(snapshot_file_name(partition.snapshot_id.end_offset, partition.snapshot_id.epoch));
+        match read_range(&path, partition.position, max_bytes) {
+            Ok((size, bytes)) => data
+                .with_size(size)
+                .with_position(partition.position)
+                .with_unaligned_records(bytes),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => data.with_error_code(ResponseError::SnapshotNotFound.code()),
+            Err(e) if e.kind() == io::ErrorKind::InvalidInput => data.with_error_code(ResponseError::PositionOutOfRange.code()),
+            Err(e) => {
+                println!("Failed to read snapshot {}: {}", path.display(), e);
+                data.with_error_code(ResponseError::KafkaStorageError.code())
+            }
+        }
+    }
+}
+
+// the size of a file and up to `max_bytes` of it from `position`
+fn read_range(path: &Path, position: i64, max_bytes: i32) -> io::Result<(i64, Bytes)> {
+    let file = File::open(path)?;
+    let size = file.metadata()?.len() as i64;
+    if position < 0 || position > size {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("position {} is outside of the {} bytes", position, size)));
+    }
+    let mut buf = vec![0; (size - position).min(max_bytes.max(0) as i64) as usize];
+    file.read_exact_at(&mut buf, position as u64)?;
+    Ok((size, Bytes::from(buf)))
+}
+
+/// Runs the quorum: elections, the leader's checks on its voters, and the
+/// fetches of followers and observers.
+pub fn start(broker: &Arc<Broker>) {
+    if broker.raft.is_none() {
+        return;
+    }
+    let broker = broker.clone();
+    tokio::spawn(async move {
+        let Some(raft) = &broker.raft else { return };
+        loop {
+            if let Err(e) = raft.poll(&broker).await {
+                println!("Quorum error: {}", e);
+                sleep_ms(raft.config.retry_backoff_ms).await;
+            }
+        }
+    });
+}
+
+/// Whether a Fetch is for the metadata log, which the quorum serves rather
+/// than the partition logs.
+pub fn is_metadata_fetch(broker: &Broker, req: &FetchRequest) -> bool {
+    broker.raft.is_some() && req.topics.iter().any(|topic| topic.topic_id == METADATA_TOPIC_ID || is_metadata_topic(&topic.topic))
+}
+
+/// Serves a replica's fetch of the metadata log from the leader, parking it
+/// up to `max_wait_ms` while there is nothing new.
+pub async fn handle_fetch(broker: &Broker, req: FetchRequest, api_version: i16) -> FetchResponse {
+    let partition = req.topics.iter()
+        .find(|topic| topic.topic_id == METADATA_TOPIC_ID || is_metadata_topic(&topic.topic))
+        .and_then(|topic| topic.partitions.first());
+    let (Some(raft), Some(partition)) = (&broker.raft, partition) else {
+        return FetchResponse::default().with_error_code(ResponseError::InvalidRequest.code());
+    };
+    let replica_id = if api_version >= REPLICA_STATE_MIN_VERSION { req.replica_state.replica_id.0 } else { req.replica_id.0 };
+    let mut progress = raft.progress.subscribe();
+    let deadline = Instant::now() + Duration::from_millis(req.max_wait_ms.max(0) as u64);
+    let data = loop {
+        let (data, complete) = match raft.read(broker, replica_id, partition, api_version).await {
+            Ok(read) => read,
+            Err(e) => {
+                println!("Failed to read the metadata log: {}", e);
+                (PartitionData::default().with_error_code(ResponseError::KafkaStorageError.code()), true)
+            }
+        };
+        if complete || timeout_at(deadline, progress.changed()).await.is_err() {
+            break data;
+        }
+    };
+    let topic = FetchableTopicResponse::default().with_partitions(vec![data]);
+    let topic = if api_version >= TOPIC_ID_MIN_VERSION {
+        topic.with_topic_id(METADATA_TOPIC_ID)
+    } else {
+        topic.with_topic(metadata_topic_name())
+    };
+    FetchResponse::default().with_responses(vec![topic])
+}
+
+pub async fn handle_vote(broker: &Broker, req: VoteRequest) -> VoteResponse {
+    let partition = req.topics.iter()
+        .find(|topic| is_metadata_topic(&topic.topic_name))
+        .and_then(|topic| topic.partitions.first());
+    let (Some(raft), Some(partition)) = (&broker.raft, partition) else {
+        return VoteResponse::default().with_error_code(ResponseError::InvalidRequest.code());
+    };
+    let data = raft.vote(broker, partition).await.unwrap_or_else(|e| {
+        println!("Failed to handle the vote request of {}: {}", partition.candidate_id.0, e);
+        vote_response::PartitionData::default().with_error_code(ResponseError::UnknownServerError.code())
+    });
+    VoteResponse::default()
+        .with_topics(vec![vote_response::TopicData::default()
+            .with_topic_name(metadata_topic_name())
+            .with_partitions(vec![data])])
+}
+
+pub async fn handle_begin_quorum_epoch(broker: &Broker, req: BeginQuorumEpochRequest) -> BeginQuorumEpochResponse {
+    let partition = req.topics.iter()
+        .find(|topic| is_metadata_topic(&topic.topic_name))
+        .and_then(|topic| topic.partitions.first());
+    let (Some(raft), Some(partition)) = (&broker.raft, partition) else {
+        return BeginQuorumEpochResponse::default().with_error_code(ResponseError::InvalidRequest.code());
+    };
+    let data = raft.begin_quorum_epoch(partition).await.unwrap_or_else(|e| {
+        println!("Failed to handle the new epoch of {}: {}", partition.leader_id.0, e);
+        begin_quorum_epoch_response::PartitionData::default().with_error_code(ResponseError::UnknownServerError.code())
+    });
+    BeginQuorumEpochResponse::default()
+        .with_topics(vec![begin_quorum_epoch_response::TopicData::default()
+            .with_topic_name(metadata_topic_name())
+            .with_partitions(vec![data])])
+}
+
+pub async fn handle_end_quorum_epoch(broker: &Broker, req: EndQuorumEpochRequest) -> EndQuorumEpochResponse {
+    let partition = req.topics.iter()
+        .find(|topic| is_metadata_topic(&topic.topic_name))
+        .and_then(|topic| topic.partitions.first());
+    let (Some(raft), Some(partition)) = (&broker.raft, partition) else {
+        return EndQuorumEpochResponse::default().with_error_code(ResponseError::InvalidRequest.code());
+    };
+    let data = raft.end_quorum_epoch(partition).await.unwrap_or_else(|e| {
+        println!("Failed to handle the resignation of {}: {}", partition.leader_id.0, e);
+        end_quorum_epoch_response::PartitionData::default().with_error_code(ResponseError::UnknownServerError.code())
+    });
+    EndQuorumEpochResponse::default()
+        .with_topics(vec![end_quorum_epoch_response::TopicData::default()
+            .with_topic_name(metadata_topic_name())
+            .with_partitions(vec![data])])
+}
+
+/// Describes the voters and observers of the quorum as the leader sees them.
+pub async fn handle_describe_quorum(broker: &Broker, req: DescribeQuorumRequest, api_version: i16, authorizer: &Authorizer, client_host: &str) -> DescribeQuorumResponse {
+    if !authorizer.authorize(ANONYMOUS_PRINCIPAL, client_host, OP_DESCRIBE, RESOURCE_TYPE_CLUSTER, CLUSTER_RESOURCE_NAME) {
+        return DescribeQuorumResponse::default().with_error_code(ResponseError::ClusterAuthorizationFailed.code());
+    }
+    let Some(raft) = &broker.raft else {
+        return DescribeQuorumResponse::default().with_error_code(ResponseError::InvalidRequest.code());
+    };
+    let mut topics = Vec::new();
+    for topic in req.topics.iter() {
+        let mut partitions = Vec::new();
+        for partition in topic.partitions.iter() {
+            if is_metadata_topic(&topic.topic_name) && partition.partition_index == 0 {
+                partitions.push(raft.describe(api_version).await);
+            } else {
+                partitions.push(describe_quorum_response::PartitionData::default()
+                    .with_partition_index(partition.partition_index)
+                    .with_error_code(ResponseError::UnknownTopicOrPartition.code()));
+            }
+        }
+        topics.push(describe_quorum_response::TopicData::default()
+            .with_topic_name(topic.topic_name.clone())
+            .with_partitions(partitions));
+    }
+    DescribeQuorumResponse::default().with_topics(topics)
+}
+
+/// Serves a chunk of a metadata snapshot to a replica whose fetch offset
+/// is older than the leader's log.
+pub async fn handle_fetch_snapshot(broker: &Broker, req: FetchSnapshotRequest) -> FetchSnapshotResponse {
+    let Some(raft) = &broker.raft else {
+        return FetchSnapshotResponse::default().with_error_code(ResponseError::InvalidRequest.code());
+    };
+    let mut topics = Vec::new();
+    for topic in req.topics.iter() {
+        let mut partitions = Vec::new();
+        for partition in topic.partitions.iter() {
+            if is_metadata_topic(&topic.name) && partition.partition == 0 {
+                partitions.push(raft.read_snapshot(partition, req.max_bytes).await);
+            } else {
+                partitions.push(fetch_snapshot_response::PartitionSnapshot::default()
+                    .with_index(partition.partition)
+                    .with_error_code(ResponseError::UnknownTopicOrPartition.code()));
+            }
+        }
+        topics.push(fetch_snapshot_response::TopicSnapshot::default()
+            .with_name(topic.name.clone())
+            .with_partitions(partitions));
+    }
+    FetchSnapshotResponse::default().with_topics(topics)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+    use std::path::Path;
+
+    use kafka_protocol::error::ResponseError;
+    use kafka_protocol::messages::fetch_request::FetchPartition;
+    use kafka_protocol::messages::{begin_quorum_epoch_request, vote_request, BrokerId};
+
+    use crate::broker::{now_ms, Broker};
+    use crate::config::BrokerConfig;
+    use crate::record::{encode_producer_ids_record, ProducerIdsRecord};
+    use crate::test_util::temp_path;
+
+    use super::{format_quorum_state, majority_high_watermark, parse_quorum_state, ElectionState, QuorumConfig, Role, FETCH_VERSION};
+
+    fn quorum_broker(dir: &Path, voters: &str) -> Broker {
+        Broker::new(BrokerConfig::parse(&format!(
+            "process.roles=broker,controller\nnode.id=1\ncontroller.quorum.voters={}\nlog.dirs={}",
+            voters,
+            dir.display(),
+        )))
+    }
+
+    fn vote(candidate_id: i32, candidate_epoch: i32, last_offset_epoch: i32, last_offset: i64) -> vote_request::PartitionData {
+        vote_request::PartitionData::default()
+            .with_candidate_id(BrokerId(candidate_id))
+            .with_candidate_epoch(candidate_epoch)
+            .with_last_offset_epoch(last_offset_epoch)
+            .with_last_offset(last_offset)
+    }
+
+    // runs for the next epoch and wins with the vote of `voter_id`
+    async fn win(broker: &Broker, voter_id: i32) -> i32 {
+        let raft = broker.raft.as_ref().unwrap();
+        let mut state = raft.state.lock().await;
+        raft.become_candidate(broker, &mut state).await.unwrap();
+        if let Role::Candidate { granted } = &mut state.role {
+            granted.insert(voter_id);
+        }
+        raft.maybe_become_leader(broker, &mut state).await.unwrap();
+        state.election.epoch
+    }
+
+    fn fetch(fetch_offset: i64, last_fetched_epoch: i32, current_leader_epoch: i32) -> FetchPartition {
+        FetchPartition::default()
+            .with_fetch_offset(fetch_offset)
+            .with_last_fetched_epoch(last_fetched_epoch)
+            .with_current_leader_epoch(current_leader_epoch)
+            .with_partition_max_bytes(1024 * 1024)
+    }
+
+    #[test]
+    fn test_majority_high_watermark() {
+        assert_eq!(10, majority_high_watermark(&[10], 1));
+        assert_eq!(7, majority_high_watermark(&[3, 10, 7], 2));
+        assert_eq!(5, majority_high_watermark(&[3, 10, 0, 7, 5], 3));
+    }
+
+    #[test]
+    fn test_quorum_config() {
+        let config = BrokerConfig::parse("
+            process.roles=broker,controller
+            node.id=2
+            controller.quorum.voters=1@localhost:9093,2@localhost:9095,3@localhost:9097
+        ");
+        let quorum = QuorumConfig::from_config(&config).unwrap();
+        assert!(quorum.is_voter());
+        assert_eq!(2, quorum.majority());
+        assert_eq!(Some(&("localhost".to_string(), 9097)), quorum.voters.get(&3));
+        assert_eq!(vec![1, 3], quorum.other_voters().into_iter().map(|(id, _)| id).collect::<Vec<_>>());
+        assert!(!QuorumConfig::from_config(&BrokerConfig::parse("process.roles=broker\ncontroller.quorum.voters=1@localhost:9093")).unwrap().is_voter());
+        assert_eq!(None, QuorumConfig::from_config(&BrokerConfig::parse("node.id=1")));
+
+        let election = ElectionState { epoch: 5, leader_id: Some(1), voted_id: None };
+        let text = format_quorum_state(&election, &[1, 2, 3]);
+        assert!(text.contains("\"currentVoters\":[{\"voterId\":1},{\"voterId\":2},{\"voterId\":3}]"));
+        assert_eq!(election, parse_quorum_state(&text).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_sole_voter_election() {
+        let dir = temp_path("sole-voter");
+        let broker = quorum_broker(&dir, "1@localhost:9093");
+        let raft = broker.raft.as_ref().unwrap();
+        raft.load(&broker).await.unwrap();
+        assert_eq!(Some(1), raft.leader_id().await);
+        assert_eq!(1, raft.state.lock().await.election.epoch);
+        // its own LeaderChange is all the majority it needs
+        assert_eq!(Some(1), broker.metadata.lock().await.high_watermark());
+        let producer_ids = ProducerIdsRecord { broker_id: 1, broker_epoch: -1, next_producer_id: 1000 };
+        raft.append(&broker, vec![encode_producer_ids_record(&producer_ids)]).await.unwrap();
+        assert_eq!(Some(2), broker.metadata.lock().await.high_watermark());
+
+        // after a restart it has to win a new epoch
+        let broker = quorum_broker(&dir, "1@localhost:9093");
+        let raft = broker.raft.as_ref().unwrap();
+        raft.load(&broker).await.unwrap();
+        let election = raft.state.lock().await.election;
+        assert_eq!(ElectionState { epoch: 2, leader_id: Some(1), voted_id: Some(1) }, election);
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_election() {
+        let dir = temp_path("election");
+        let broker = quorum_broker(&dir, "1@localhost:9093,2@localhost:9095,3@localhost:9097");
+        let raft = broker.raft.as_ref().unwrap();
+        raft.load(&broker).await.unwrap();
+        assert_eq!(Role::Unattached, raft.state.lock().await.role);
+
+        // one vote per epoch, which a retried request gets again
+        assert!(raft.vote(&broker, &vote(2, 1, 0, 0)).await.unwrap().vote_granted);
+        assert!(!raft.vote(&broker, &vote(3, 1, 0, 0)).await.unwrap().vote_granted);
+        assert!(raft.vote(&broker, &vote(2, 1, 0, 0)).await.unwrap().vote_granted);
+        assert_eq!(ResponseError::InconsistentVoterSet.code(), raft.vote(&broker, &vote(4, 1, 0, 0)).await.unwrap().error_code);
+        assert_eq!(ResponseError::FencedLeaderEpoch.code(), raft.vote(&broker, &vote(3, 0, 0, 0)).await.unwrap().error_code);
+        assert_eq!(Some(2), raft.state.lock().await.election.voted_id);
+
+        // the winner announces itself
+        let begin = begin_quorum_epoch_request::PartitionData::default()
+            .with_leader_id(BrokerId(2))
+            .with_leader_epoch(1);
+        assert_eq!(0, raft.begin_quorum_epoch(&begin).await.unwrap().error_code);
+        assert_eq!(Some(2), raft.leader_id().await);
+        assert_eq!(Role::Follower, raft.state.lock().await.role);
+
+        // this node runs in the next epoch and wins with a second vote
+        {
+            let mut state = raft.state.lock().await;
+            raft.become_candidate(&broker, &mut state).await.unwrap();
+            assert_eq!(ElectionState { epoch: 2, leader_id: None, voted_id: Some(1) }, state.election);
+            let Role::Candidate { granted } = &mut state.role else { panic!("not a candidate: {:?}", state.role) };
+            assert_eq!(&BTreeSet::from([1]), granted);
+            granted.insert(3);
+            raft.maybe_become_leader(&broker, &mut state).await.unwrap();
+            assert!(matches!(state.role, Role::Leader { epoch_start_offset: 0, .. }));
+        }
+        assert_eq!(Some(1), raft.leader_id().await);
+
+        // a candidate of a later epoch with a shorter log is refused, and
+        // this node steps down to that epoch
+        let response = raft.vote(&broker, &vote(3, 3, 1, 5)).await.unwrap();
+        assert!(!response.vote_granted);
+        assert_eq!((3, -1), (response.leader_epoch, response.leader_id.0));
+        assert_eq!(Role::Unattached, raft.state.lock().await.role);
+        assert!(raft.vote(&broker, &vote(2, 3, 2, 1)).await.unwrap().vote_granted);
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_commit_advancement() {
+        let dir = temp_path("commit");
+        let broker = quorum_broker(&dir, "1@localhost:9093,2@localhost:9095,3@localhost:9097");
+        let raft = broker.raft.as_ref().unwrap();
+        raft.load(&broker).await.unwrap();
+        let producer_ids = |next_producer_id| encode_producer_ids_record(&ProducerIdsRecord { broker_id: 1, broker_epoch: -1, next_producer_id });
+
+        // epoch 1 starts at 0, records at 1 and 2 are appended but not replicated
+        assert_eq!(1, win(&broker, 2).await);
+        broker.metadata.lock().await.append_in_epoch(&broker.segment_cache, vec![producer_ids(1000), producer_ids(2000)], 1, now_ms()).unwrap();
+        assert_eq!(Some(0), broker.metadata.lock().await.high_watermark());
+
+        // epoch 2 starts at 3; a majority holding the earlier records does
+        // not commit them before the epoch's LeaderChange
+        assert_eq!(2, win(&broker, 3).await);
+        let (data, _) = raft.read(&broker, 2, &fetch(3, 1, 2), FETCH_VERSION).await.unwrap();
+        assert_eq!((0, 0), (data.error_code, data.high_watermark));
+        let (data, _) = raft.read(&broker, 2, &fetch(4, 2, 2), FETCH_VERSION).await.unwrap();
+        assert_eq!(4, data.high_watermark);
+        assert_eq!(Some(4), broker.metadata.lock().await.high_watermark());
+        // the other follower catching up does not move it further
+        let (data, _) = raft.read(&broker, 3, &fetch(2, 1, 2), FETCH_VERSION).await.unwrap();
+        assert_eq!(4, data.high_watermark);
+
+        // a follower with records of epoch 1 the leader never had diverges
+        // where the leader's epoch 1 ends
+        let (data, _) = raft.read(&broker, 3, &fetch(5, 1, 2), FETCH_VERSION).await.unwrap();
+        assert_eq!((1, 3), (data.diverging_epoch.epoch, data.diverging_epoch.end_offset));
+        // and a stale leader epoch is fenced
+        let (data, _) = raft.read(&broker, 3, &fetch(4, 2, 1), FETCH_VERSION).await.unwrap();
+        assert_eq!(ResponseError::FencedLeaderEpoch.code(), data.error_code);
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
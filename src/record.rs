@@ -1,6 +1,6 @@
 use std::collections::HashMap;
 
-use bytes::Bytes;
+use bytes::{BufMut, Bytes, BytesMut};
 use kafka_protocol::{protocol::buf::ByteBuf, records::{Record, RecordBatchDecoder, RecordSet}};
 use uuid::Uuid;
 
@@ -20,9 +20,49 @@ pub fn to_i16(bytes: &[u8]) -> i16 {
 }
 
 // type: 
+// 00: RegisterBrokerRecord
+// 01: UnregisterBrokerRecord
 // 02: TopicRecord
 // 03: PartitionRecord
+// 04: ConfigRecord
+// 05: PartitionChangeRecord
+// 07: FenceBrokerRecord
+// 08: UnfenceBrokerRecord
 // 12: Feature Level Record
+// 17: AccessControlEntryRecord
+// 18: RemoveAccessControlEntryRecord
+// 27: RegisterControllerRecord
+
+// tags of the PartitionChangeRecord fields that are read
+const PARTITION_CHANGE_ISR_TAG: u32 = 0;
+const PARTITION_CHANGE_LEADER_TAG: u32 = 1;
+const PARTITION_CHANGE_REPLICAS_TAG: u32 = 2;
+const PARTITION_CHANGE_DIRECTORIES_TAG: u32 = 8;
+// the leader of a PartitionChangeRecord that keeps the current one
+const NO_LEADER_CHANGE: i32 = -2;
+// control record types of a quorum leader's first batch and of the batches
+// that open and close a metadata snapshot
+const CONTROL_TYPE_LEADER_CHANGE: i16 = 2;
+const CONTROL_TYPE_SNAPSHOT_HEADER: i16 = 3;
+const CONTROL_TYPE_SNAPSHOT_FOOTER: i16 = 4;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BrokerEndpoint {
+    pub name: String,
+    pub host: String,
+    pub port: u16,
+    pub security_protocol: i16,
+}
+
+/// A broker or controller registration; the fields after the rack are not
+/// parsed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegisterBrokerRecord {
+    pub broker_id: i32,
+    pub broker_epoch: i64,
+    pub endpoints: Vec<BrokerEndpoint>,
+    pub rack: Option<String>,
+}
 
 #[derive(Debug)]
 pub struct TopicRecord {
@@ -40,13 +80,80 @@ pub struct FeatureLevelRecord {
 pub struct PartitionRecord {
     pub partition_id: i32,
     pub topic_id: Uuid,
+    pub replicas: Vec<i32>,
+    pub isr: Vec<i32>,
+    pub leader: i32,
+    pub leader_epoch: i32,
+    pub partition_epoch: i32,
+    // the log dir of each replica, empty before version 1
+    pub directories: Vec<Uuid>,
+}
+
+/// The fields of a PartitionChangeRecord that are read; None leaves them
+/// unchanged.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PartitionChangeRecord {
+    pub partition_id: i32,
+    pub topic_id: Uuid,
+    pub isr: Option<Vec<i32>>,
+    // -2 (NO_LEADER_CHANGE) is read as None
+    pub leader: Option<i32>,
+    pub replicas: Option<Vec<i32>>,
+    pub directories: Option<Vec<Uuid>>,
+}
+
+#[derive(Debug)]
+pub struct ConfigRecord {
+    pub resource_type: i8,
+    pub resource_name: String,
+    pub name: String,
+    // None deletes the config
+    pub value: Option<String>,
+}
+
+/// A block of producer ids reserved by a broker; ids below
+/// `next_producer_id` are never handed out again.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProducerIdsRecord {
+    pub broker_id: i32,
+    pub broker_epoch: i64,
+    pub next_producer_id: i64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccessControlEntryRecord {
+    pub id: Uuid,
+    pub resource_type: i8,
+    pub resource_name: String,
+    pub pattern_type: i8,
+    pub principal: String,
+    pub host: String,
+    pub operation: i8,
+    pub permission_type: i8,
+}
+
+#[derive(Debug)]
+pub struct RemoveAccessControlEntryRecord {
+    pub id: Uuid,
 }
 
 #[derive(Debug)]
 pub enum RecordValue {
+    RegisterBrokerRecord(RegisterBrokerRecord),
+    // broker id
+    UnregisterBrokerRecord(i32),
+    FenceBrokerRecord(i32),
+    UnfenceBrokerRecord(i32),
+    RegisterControllerRecord(RegisterBrokerRecord),
     TopicRecord(TopicRecord),
     FeatureLevelRecord(FeatureLevelRecord),
     PartitionRecord(PartitionRecord),
+    PartitionChangeRecord(PartitionChangeRecord),
+    ConfigRecord(ConfigRecord),
+    ProducerIdsRecord(ProducerIdsRecord),
+    AccessControlEntryRecord(AccessControlEntryRecord),
+    RemoveAccessControlEntryRecord(RemoveAccessControlEntryRecord),
+    Unknown(i8),
 }
 
 pub fn parse_string_by_length<B: ByteBuf>(buf: &mut B) -> String {
@@ -56,10 +163,211 @@ pub fn parse_string_by_length<B: ByteBuf>(buf: &mut B) -> String {
     String::from_utf8(str_buf).unwrap()
 }
 
+pub fn parse_unsigned_varint<B: ByteBuf>(buf: &mut B) -> u32 {
+    let mut value = 0;
+    for shift in (0..35).step_by(7) {
+        let byte = buf.get_u8();
+        value |= ((byte & 0x7f) as u32) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+    value
+}
+
+pub fn parse_compact_string<B: ByteBuf>(buf: &mut B) -> String {
+    let len = (parse_unsigned_varint(buf) as usize).saturating_sub(1);
+    let mut str_buf = vec![0; len];
+    str_buf.copy_from_slice(&buf.get_bytes(len));
+    String::from_utf8(str_buf).unwrap()
+}
+
+pub fn parse_compact_nullable_string<B: ByteBuf>(buf: &mut B) -> Option<String> {
+    let len = parse_unsigned_varint(buf) as usize;
+    if len == 0 {
+        return None;
+    }
+    let mut str_buf = vec![0; len - 1];
+    str_buf.copy_from_slice(&buf.get_bytes(len - 1));
+    Some(String::from_utf8(str_buf).unwrap())
+}
+
+fn skip_tagged_fields<B: ByteBuf>(buf: &mut B) {
+    for _ in 0..parse_unsigned_varint(buf) {
+        parse_unsigned_varint(buf); // tag
+        let size = parse_unsigned_varint(buf) as usize;
+        buf.advance(size);
+    }
+}
+
+fn parse_endpoints<B: ByteBuf>(buf: &mut B) -> Vec<BrokerEndpoint> {
+    let len = (parse_unsigned_varint(buf) as usize).saturating_sub(1);
+    (0..len).map(|_| {
+        let name = parse_compact_string(buf);
+        let host = parse_compact_string(buf);
+        let port = buf.get_u16();
+        let security_protocol = buf.get_i16();
+        skip_tagged_fields(buf);
+        BrokerEndpoint { name, host, port, security_protocol }
+    }).collect()
+}
+
+// [(name, min_version, max_version)]
+fn skip_features<B: ByteBuf>(buf: &mut B) {
+    let len = (parse_unsigned_varint(buf) as usize).saturating_sub(1);
+    for _ in 0..len {
+        parse_compact_string(buf);
+        buf.get_i16();
+        buf.get_i16();
+        skip_tagged_fields(buf);
+    }
+}
+
 pub fn parse_uuid<B: ByteBuf>(buf: &mut B) -> Uuid {
     let mut uuid_buf = [0; 16];
     buf.try_copy_to_slice(&mut uuid_buf).unwrap();
-    Uuid::from_bytes(uuid_buf).into()
+    Uuid::from_bytes(uuid_buf)
+}
+
+/// The value of a ProducerIdsRecord (version 0) reserving the producer ids
+/// up to `next_producer_id`.
+pub fn encode_producer_ids_record(record: &ProducerIdsRecord) -> Bytes {
+    let mut buf = BytesMut::new();
+    buf.put_i8(1); // frame version
+    buf.put_i8(0x0f); // type
+    buf.put_i8(0); // version
+    buf.put_i32(record.broker_id);
+    buf.put_i64(record.broker_epoch);
+    buf.put_i64(record.next_producer_id);
+    put_unsigned_varint(&mut buf, 0); // tagged fields
+    buf.freeze()
+}
+
+fn parse_compact_i32_array<B: ByteBuf>(buf: &mut B) -> Vec<i32> {
+    let len = (parse_unsigned_varint(buf) as usize).saturating_sub(1);
+    (0..len).map(|_| buf.get_i32()).collect()
+}
+
+fn parse_compact_uuid_array<B: ByteBuf>(buf: &mut B) -> Vec<Uuid> {
+    let len = (parse_unsigned_varint(buf) as usize).saturating_sub(1);
+    (0..len).map(|_| parse_uuid(buf)).collect()
+}
+
+fn put_unsigned_varint(buf: &mut BytesMut, mut value: u32) {
+    while value >= 0x80 {
+        buf.put_u8((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.put_u8(value as u8);
+}
+
+fn put_compact_nullable_string(buf: &mut BytesMut, value: Option<&str>) {
+    match value {
+        Some(value) => {
+            put_unsigned_varint(buf, value.len() as u32 + 1);
+            buf.put_slice(value.as_bytes());
+        }
+        None => put_unsigned_varint(buf, 0),
+    }
+}
+
+/// The value of a PartitionChangeRecord (version 1) moving the replicas to
+/// `directories`, the only field it changes.
+pub fn encode_partition_change_record(partition_id: i32, topic_id: Uuid, directories: &[Uuid]) -> Bytes {
+    let mut field = BytesMut::new();
+    put_unsigned_varint(&mut field, directories.len() as u32 + 1);
+    for directory in directories {
+        field.put_slice(directory.as_bytes());
+    }
+    let mut buf = BytesMut::new();
+    buf.put_i8(1); // frame version
+    buf.put_i8(0x05); // type
+    buf.put_i8(1); // version
+    buf.put_i32(partition_id);
+    buf.put_slice(topic_id.as_bytes());
+    put_unsigned_varint(&mut buf, 1); // tagged fields
+    put_unsigned_varint(&mut buf, PARTITION_CHANGE_DIRECTORIES_TAG);
+    put_unsigned_varint(&mut buf, field.len() as u32);
+    buf.put_slice(&field);
+    buf.freeze()
+}
+
+/// The value of a PartitionChangeRecord (version 0) setting the ISR of a
+/// partition, as the controller writes it for an AlterPartition request.
+pub fn encode_isr_change_record(partition_id: i32, topic_id: Uuid, isr: &[i32]) -> Bytes {
+    let mut field = BytesMut::new();
+    put_unsigned_varint(&mut field, isr.len() as u32 + 1);
+    for replica in isr {
+        field.put_i32(*replica);
+    }
+    let mut buf = BytesMut::new();
+    buf.put_i8(1); // frame version
+    buf.put_i8(0x05); // type
+    buf.put_i8(0); // version
+    buf.put_i32(partition_id);
+    buf.put_slice(topic_id.as_bytes());
+    put_unsigned_varint(&mut buf, 1); // tagged fields
+    put_unsigned_varint(&mut buf, PARTITION_CHANGE_ISR_TAG);
+    put_unsigned_varint(&mut buf, field.len() as u32);
+    buf.put_slice(&field);
+    buf.freeze()
+}
+
+fn encode_control_key(control_type: i16) -> Bytes {
+    let mut key = BytesMut::new();
+    key.put_i16(0); // version
+    key.put_i16(control_type);
+    key.freeze()
+}
+
+/// The key and value of a LeaderChange control record (version 0), written
+/// by a new quorum leader at the start of its epoch.
+pub fn encode_leader_change_message(leader_id: i32, voters: &[i32], granting_voters: &[i32]) -> (Bytes, Bytes) {
+    let mut buf = BytesMut::new();
+    buf.put_i16(0); // version
+    buf.put_i32(leader_id);
+    for ids in [voters, granting_voters] {
+        put_unsigned_varint(&mut buf, ids.len() as u32 + 1);
+        for id in ids {
+            buf.put_i32(*id);
+            put_unsigned_varint(&mut buf, 0); // tagged fields
+        }
+    }
+    put_unsigned_varint(&mut buf, 0); // tagged fields
+    (encode_control_key(CONTROL_TYPE_LEADER_CHANGE), buf.freeze())
+}
+
+/// The key and value of the SnapshotHeader control record (version 0) that
+/// opens a metadata snapshot.
+pub fn encode_snapshot_header(last_contained_log_timestamp: i64) -> (Bytes, Bytes) {
+    let mut buf = BytesMut::new();
+    buf.put_i16(0); // version
+    buf.put_i64(last_contained_log_timestamp);
+    put_unsigned_varint(&mut buf, 0); // tagged fields
+    (encode_control_key(CONTROL_TYPE_SNAPSHOT_HEADER), buf.freeze())
+}
+
+/// The key and value of the SnapshotFooter control record (version 0) that
+/// closes a metadata snapshot.
+pub fn encode_snapshot_footer() -> (Bytes, Bytes) {
+    let mut buf = BytesMut::new();
+    buf.put_i16(0); // version
+    put_unsigned_varint(&mut buf, 0); // tagged fields
+    (encode_control_key(CONTROL_TYPE_SNAPSHOT_FOOTER), buf.freeze())
+}
+
+/// The value of a ConfigRecord (version 0) as written to the metadata log.
+pub fn encode_config_record(config: &ConfigRecord) -> Bytes {
+    let mut buf = BytesMut::new();
+    buf.put_i8(1); // frame version
+    buf.put_i8(0x04); // type
+    buf.put_i8(0); // version
+    buf.put_i8(config.resource_type);
+    put_compact_nullable_string(&mut buf, Some(&config.resource_name));
+    put_compact_nullable_string(&mut buf, Some(&config.name));
+    put_compact_nullable_string(&mut buf, config.value.as_deref());
+    put_unsigned_varint(&mut buf, 0); // tagged fields
+    buf.freeze()
 }
 
 pub fn extract_record_value(record: &Record) -> RecordValue {
@@ -73,8 +381,30 @@ pub fn extract_record_value(record: &Record) -> RecordValue {
 pub fn parse_record_value<B: ByteBuf>(buf: &mut B) -> RecordValue {
     buf.get_i8(); // frame version
     let value_type = buf.get_i8(); // type
-    buf.get_i8(); // version
+    let version = buf.get_i8();
     match value_type {
+        0x00 => {
+            let broker_id = buf.get_i32();
+            if version >= 2 {
+                buf.get_u8(); // is_migrating_zk_broker
+            }
+            parse_uuid(buf); // incarnation id
+            let broker_epoch = buf.get_i64();
+            let endpoints = parse_endpoints(buf);
+            skip_features(buf);
+            let rack = parse_compact_nullable_string(buf);
+            RecordValue::RegisterBrokerRecord(RegisterBrokerRecord { broker_id, broker_epoch, endpoints, rack })
+        }
+        0x01 => RecordValue::UnregisterBrokerRecord(buf.get_i32()),
+        0x07 => RecordValue::FenceBrokerRecord(buf.get_i32()),
+        0x08 => RecordValue::UnfenceBrokerRecord(buf.get_i32()),
+        0x1b => {
+            let controller_id = buf.get_i32();
+            parse_uuid(buf); // incarnation id
+            buf.get_u8(); // zk_migration_ready
+            let endpoints = parse_endpoints(buf);
+            RecordValue::RegisterControllerRecord(RegisterBrokerRecord { broker_id: controller_id, broker_epoch: -1, endpoints, rack: None })
+        }
         0x0c => {
             let name = parse_string_by_length(buf);
             let metadata_version = buf.get_i16();
@@ -88,16 +418,70 @@ pub fn parse_record_value<B: ByteBuf>(buf: &mut B) -> RecordValue {
         0x03 => {
             let partition_id = buf.get_i32();
             let topic_id = parse_uuid(buf);
-            RecordValue::PartitionRecord(PartitionRecord { partition_id, topic_id })
+            let replicas = parse_compact_i32_array(buf);
+            let isr = parse_compact_i32_array(buf);
+            parse_compact_i32_array(buf); // removing replicas
+            parse_compact_i32_array(buf); // adding replicas
+            let leader = buf.get_i32();
+            let leader_epoch = buf.get_i32();
+            let partition_epoch = buf.get_i32();
+            let directories = if version >= 1 { parse_compact_uuid_array(buf) } else { Vec::new() };
+            RecordValue::PartitionRecord(PartitionRecord { partition_id, topic_id, replicas, isr, leader, leader_epoch, partition_epoch, directories })
         }
-        _ => {
-            panic!("Unsupported record value version");
+        0x05 => {
+            let partition_id = buf.get_i32();
+            let topic_id = parse_uuid(buf);
+            let (mut isr, mut leader, mut replicas, mut directories) = (None, None, None, None);
+            // everything else is a tagged field
+            for _ in 0..parse_unsigned_varint(buf) {
+                let tag = parse_unsigned_varint(buf);
+                let size = parse_unsigned_varint(buf) as usize;
+                match tag {
+                    PARTITION_CHANGE_ISR_TAG => isr = Some(parse_compact_i32_array(buf)),
+                    PARTITION_CHANGE_LEADER_TAG => leader = Some(buf.get_i32()).filter(|leader| *leader != NO_LEADER_CHANGE),
+                    PARTITION_CHANGE_REPLICAS_TAG => replicas = Some(parse_compact_i32_array(buf)),
+                    PARTITION_CHANGE_DIRECTORIES_TAG => directories = Some(parse_compact_uuid_array(buf)),
+                    _ => buf.advance(size),
+                }
+            }
+            RecordValue::PartitionChangeRecord(PartitionChangeRecord { partition_id, topic_id, isr, leader, replicas, directories })
+        }
+        0x04 => {
+            let resource_type = buf.get_i8();
+            let resource_name = parse_compact_string(buf);
+            let name = parse_compact_string(buf);
+            let value = parse_compact_nullable_string(buf);
+            RecordValue::ConfigRecord(ConfigRecord { resource_type, resource_name, name, value })
+        }
+        0x0f => {
+            let broker_id = buf.get_i32();
+            let broker_epoch = buf.get_i64();
+            let next_producer_id = buf.get_i64();
+            RecordValue::ProducerIdsRecord(ProducerIdsRecord { broker_id, broker_epoch, next_producer_id })
+        }
+        0x11 => {
+            let id = parse_uuid(buf);
+            let resource_type = buf.get_i8();
+            let resource_name = parse_compact_string(buf);
+            let pattern_type = buf.get_i8();
+            let principal = parse_compact_string(buf);
+            let host = parse_compact_string(buf);
+            let operation = buf.get_i8();
+            let permission_type = buf.get_i8();
+            RecordValue::AccessControlEntryRecord(AccessControlEntryRecord {
+                id, resource_type, resource_name, pattern_type, principal, host, operation, permission_type,
+            })
+        }
+        0x12 => {
+            let id = parse_uuid(buf);
+            RecordValue::RemoveAccessControlEntryRecord(RemoveAccessControlEntryRecord { id })
         }
+        _ => RecordValue::Unknown(value_type),
     }
 }
 
 // record_set to topic_id -> vec[partition_id]
-pub fn record_set_to_topic(record_sets: &Vec<RecordSet>) -> HashMap<String, (Uuid, Vec<i32>)> {
+pub fn record_set_to_topic(record_sets: &[RecordSet]) -> HashMap<String, (Uuid, Vec<i32>)> {
     let mut ret = HashMap::new();
     for record_set in record_sets.iter() {
         if record_set.records.len() > 1 {
@@ -134,7 +518,7 @@ pub fn parse_metadata_to_record_batch<B: ByteBuf>(buf: &mut B) -> HashMap<Uuid,
     let mut left_idx = 0;
     let mut right_idx = 0;
     let mut len = buf.remaining();
-    let buf_clone = Bytes::from(buf.peek_bytes(0..len));
+    let buf_clone = buf.peek_bytes(0..len);
     // buf_clone.copy_from_slice(&buf.peek_bytes(0..len));
 
     while buf.has_remaining() {
@@ -0,0 +1,345 @@
+use std::fmt::Debug;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use bytes::Bytes;
+use tokio::fs;
+use uuid::Uuid;
+
+use crate::broker::{now_ms, Broker};
+use crate::config::BrokerConfig;
+use crate::log::{batches, decode_batch, partition_dir, BatchHeader, TopicPartition, BATCH_HEADER_SIZE};
+use crate::log_dir::{uuid_from_base64, uuid_to_base64};
+use crate::offset_index::lookup_position;
+use crate::record::record_set_to_topic;
+use crate::txn_index::AbortedTxn;
+
+pub const REMOTE_LOG_STORAGE_SYSTEM_ENABLE_CONFIG: &str = "remote.log.storage.system.enable";
+pub const REMOTE_LOG_MANAGER_TASK_INTERVAL_MS_CONFIG: &str = "remote.log.manager.task.interval.ms";
+pub const DEFAULT_REMOTE_LOG_MANAGER_TASK_INTERVAL_MS: u64 = 30 * 1000;
+// settings of the storage manager are the broker settings under this prefix
+pub const REMOTE_LOG_STORAGE_MANAGER_IMPL_PREFIX_CONFIG: &str = "remote.log.storage.manager.impl.prefix";
+const DEFAULT_REMOTE_LOG_STORAGE_MANAGER_IMPL_PREFIX: &str = "rsm.config.";
+// the root of FileSystemRemoteStorageManager, under the prefix
+const REMOTE_STORAGE_DIR_CONFIG: &str = "dir";
+pub const REMOTE_LOG_DIR: &str = "/tmp/kraft-remote-logs";
+
+// the remote segments of a partition, kept in its partition directory
+pub const REMOTE_LOG_SEGMENT_METADATA_FILE: &str = "remote-log-segment-metadata";
+const REMOTE_LOG_METADATA_VERSION: i32 = 0;
+
+/// A segment copied to remote storage.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RemoteLogSegmentMetadata {
+    pub segment_id: Uuid,
+    pub start_offset: i64,
+    pub end_offset: i64,
+    pub max_timestamp: i64,
+    pub size: u64,
+}
+
+/// The local files of a closed segment, as handed to remote storage.
+#[derive(Debug, Clone)]
+pub struct LogSegmentData {
+    pub log: PathBuf,
+    pub offset_index: PathBuf,
+    pub txn_index: PathBuf,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IndexType {
+    Offset,
+    Transaction,
+}
+
+/// Where segments go once they are closed, as Kafka's RemoteStorageManager.
+/// The calls block, so the broker makes them from blocking tasks.
+pub trait RemoteStorageManager: Debug + Send + Sync {
+    fn copy_log_segment(&self, topic_partition: &TopicPartition, metadata: &RemoteLogSegmentMetadata, segment: &LogSegmentData) -> io::Result<()>;
+
+    /// Up to `length` bytes of a segment from `position` on.
+    fn fetch_log_segment(&self, topic_partition: &TopicPartition, metadata: &RemoteLogSegmentMetadata, position: u64, length: u64) -> io::Result<Vec<u8>>;
+
+    /// An index of a segment, empty when the segment has none.
+    fn fetch_index(&self, topic_partition: &TopicPartition, metadata: &RemoteLogSegmentMetadata, index_type: IndexType) -> io::Result<Vec<u8>>;
+
+    fn delete_log_segment(&self, topic_partition: &TopicPartition, metadata: &RemoteLogSegmentMetadata) -> io::Result<()>;
+}
+
+/// Keeps remote segments under a directory, typically on a larger and
+/// slower disk than the log dirs, as
+/// `<root>/<topic>-<partition>/<start offset>-<segment id>.log`.
+#[derive(Debug)]
+pub struct FileSystemRemoteStorageManager {
+    root: PathBuf,
+}
+
+impl FileSystemRemoteStorageManager {
+    pub fn new(root: PathBuf) -> FileSystemRemoteStorageManager {
+        FileSystemRemoteStorageManager { root }
+    }
+
+    fn path(&self, topic_partition: &TopicPartition, metadata: &RemoteLogSegmentMetadata, suffix: &str) -> PathBuf {
+        partition_dir(&self.root, &topic_partition.0, topic_partition.1)
+            .join(format!("{:020}-{}.{}", metadata.start_offset, uuid_to_base64(metadata.segment_id), suffix))
+    }
+}
+
+impl RemoteStorageManager for FileSystemRemoteStorageManager {
+    fn copy_log_segment(&self, topic_partition: &TopicPartition, metadata: &RemoteLogSegmentMetadata, segment: &LogSegmentData) -> io::Result<()> {
+        std::fs::create_dir_all(partition_dir(&self.root, &topic_partition.0, topic_partition.1))?;
+        // the log goes last, so a segment whose log is there is complete
+        for (src, suffix) in [(&segment.offset_index, "index"), (&segment.txn_index, "txnindex"), (&segment.log, "log")] {
+            match std::fs::copy(src, self.path(topic_partition, metadata, suffix)) {
+                Ok(_) => {}
+                // segments without aborted transactions have no txnindex
+                Err(e) if e.kind() == io::ErrorKind::NotFound && suffix == "txnindex" => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn fetch_log_segment(&self, topic_partition: &TopicPartition, metadata: &RemoteLogSegmentMetadata, position: u64, length: u64) -> io::Result<Vec<u8>> {
+        let file = std::fs::File::open(self.path(topic_partition, metadata, "log"))?;
+        let len = file.metadata()?.len();
+        let mut bytes = vec![0; length.min(len.saturating_sub(position)) as usize];
+        file.read_exact_at(&mut bytes, position)?;
+        Ok(bytes)
+    }
+
+    fn fetch_index(&self, topic_partition: &TopicPartition, metadata: &RemoteLogSegmentMetadata, index_type: IndexType) -> io::Result<Vec<u8>> {
+        let suffix = match index_type {
+            IndexType::Offset => "index",
+            IndexType::Transaction => "txnindex",
+        };
+        match std::fs::read(self.path(topic_partition, metadata, suffix)) {
+            Ok(bytes) => Ok(bytes),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn delete_log_segment(&self, topic_partition: &TopicPartition, metadata: &RemoteLogSegmentMetadata) -> io::Result<()> {
+        for suffix in ["log", "index", "txnindex"] {
+            match std::fs::remove_file(self.path(topic_partition, metadata, suffix)) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The storage manager when `remote.log.storage.system.enable` is set,
+/// rooted at the `dir` setting under the manager's prefix.
+pub fn remote_storage_manager(config: &BrokerConfig) -> Option<Arc<dyn RemoteStorageManager>> {
+    if !config.get_or(REMOTE_LOG_STORAGE_SYSTEM_ENABLE_CONFIG, false) {
+        return None;
+    }
+    let prefix = config.get(REMOTE_LOG_STORAGE_MANAGER_IMPL_PREFIX_CONFIG).unwrap_or(DEFAULT_REMOTE_LOG_STORAGE_MANAGER_IMPL_PREFIX);
+    let root = config.get(&format!("{}{}", prefix, REMOTE_STORAGE_DIR_CONFIG)).unwrap_or(REMOTE_LOG_DIR);
+    Some(Arc::new(FileSystemRemoteStorageManager::new(PathBuf::from(root))))
+}
+
+/// Parses the remote segments of a partition: a version line, a count and
+/// one `segment_id start_offset end_offset max_timestamp size` line each.
+pub fn parse_remote_log_metadata(text: &str) -> io::Result<Vec<RemoteLogSegmentMetadata>> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+    let mut lines = text.lines();
+    let version: i32 = lines.next()
+        .and_then(|line| line.trim().parse().ok())
+        .ok_or_else(|| invalid("missing remote log metadata version"))?;
+    if version != REMOTE_LOG_METADATA_VERSION {
+        return Err(invalid(&format!("unsupported remote log metadata version {}", version)));
+    }
+    let count: usize = lines.next()
+        .and_then(|line| line.trim().parse().ok())
+        .ok_or_else(|| invalid("missing remote segment count"))?;
+    let mut segments = Vec::with_capacity(count);
+    for line in lines.take(count) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [segment_id, start_offset, end_offset, max_timestamp, size] = fields[..] else {
+            return Err(invalid(&format!("malformed remote segment {:?}", line)));
+        };
+        segments.push(RemoteLogSegmentMetadata {
+            segment_id: uuid_from_base64(segment_id).ok_or_else(|| invalid(line))?,
+            start_offset: start_offset.parse().map_err(|_| invalid(line))?,
+            end_offset: end_offset.parse().map_err(|_| invalid(line))?,
+            max_timestamp: max_timestamp.parse().map_err(|_| invalid(line))?,
+            size: size.parse().map_err(|_| invalid(line))?,
+        });
+    }
+    if segments.len() != count {
+        return Err(invalid("remote segment count mismatch"));
+    }
+    Ok(segments)
+}
+
+pub fn format_remote_log_metadata(segments: &[RemoteLogSegmentMetadata]) -> String {
+    let mut text = format!("{}\n{}\n", REMOTE_LOG_METADATA_VERSION, segments.len());
+    for segment in segments {
+        text.push_str(&format!("{} {} {} {} {}\n", uuid_to_base64(segment.segment_id), segment.start_offset, segment.end_offset, segment.max_timestamp, segment.size));
+    }
+    text
+}
+
+/// Reads the remote segments of a partition directory, none when the file
+/// is missing.
+pub async fn read_remote_log_metadata(dir: &Path) -> io::Result<Vec<RemoteLogSegmentMetadata>> {
+    match fs::read_to_string(dir.join(REMOTE_LOG_SEGMENT_METADATA_FILE)).await {
+        Ok(text) => parse_remote_log_metadata(&text),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Replaces the remote segments of a partition directory through a
+/// temporary file.
+pub async fn write_remote_log_metadata(dir: &Path, segments: &[RemoteLogSegmentMetadata]) -> io::Result<()> {
+    let path = dir.join(REMOTE_LOG_SEGMENT_METADATA_FILE);
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, format_remote_log_metadata(segments)).await?;
+    fs::rename(&tmp, &path).await
+}
+
+/// Fetches the batches of a remote segment from the one holding
+/// `fetch_offset` on, up to `max_bytes` but always the whole first batch,
+/// along with the aborted transactions among them for read_committed.
+pub fn fetch_remote(remote_storage: &dyn RemoteStorageManager, topic_partition: &TopicPartition, metadata: &RemoteLogSegmentMetadata, fetch_offset: i64, max_bytes: i32, read_committed: bool) -> io::Result<(Bytes, Vec<AbortedTxn>)> {
+    let index = remote_storage.fetch_index(topic_partition, metadata, IndexType::Offset)?;
+    let mut position = lookup_position(&index, metadata.start_offset, fetch_offset);
+    let mut records = Bytes::new();
+    while position < metadata.size {
+        let header = remote_storage.fetch_log_segment(topic_partition, metadata, position, BATCH_HEADER_SIZE as u64)?;
+        let Some(header) = BatchHeader::parse(&header) else { break };
+        if header.last_offset() < fetch_offset {
+            position += header.size() as u64;
+            continue;
+        }
+        let length = (max_bytes.max(0) as u64).max(header.size() as u64);
+        let bytes = remote_storage.fetch_log_segment(topic_partition, metadata, position, length)?;
+        // only whole batches
+        let end = batches(&bytes).last().map(|(pos, header)| pos + header.size()).unwrap_or(0);
+        records = Bytes::from(bytes).slice(..end);
+        break;
+    }
+    let aborted_transactions = if read_committed {
+        let txn_index = remote_storage.fetch_index(topic_partition, metadata, IndexType::Transaction)?;
+        AbortedTxn::parse_all(&txn_index).into_iter()
+            .filter(|txn| txn.last_offset >= fetch_offset)
+            .collect()
+    } else {
+        Vec::new()
+    };
+    Ok((records, aborted_transactions))
+}
+
+/// Finds the first record of a remote segment at or above the log start
+/// offset with a timestamp at or after `timestamp`, as (offset, timestamp).
+pub fn remote_offset_for_timestamp(remote_storage: &dyn RemoteStorageManager, topic_partition: &TopicPartition, metadata: &RemoteLogSegmentMetadata, timestamp: i64, log_start_offset: i64) -> io::Result<Option<(i64, i64)>> {
+    let bytes = remote_storage.fetch_log_segment(topic_partition, metadata, 0, metadata.size)?;
+    for (pos, header) in batches(&bytes) {
+        if header.is_control() || header.max_timestamp < timestamp || header.last_offset() < log_start_offset {
+            continue;
+        }
+        let record_set = decode_batch(&bytes[pos..pos + header.size()])?;
+        let found = record_set.records.iter()
+            .find(|record| record.offset >= log_start_offset && record.timestamp >= timestamp)
+            .map(|record| (record.offset, record.timestamp));
+        if found.is_some() {
+            return Ok(found);
+        }
+    }
+    Ok(None)
+}
+
+/// The remote log manager's pass: copies the closed segments of the topics
+/// with `remote.storage.enable` to remote storage, then deletes the remote
+/// segments past retention.
+pub async fn manage_remote_logs(broker: &Broker) {
+    let record_sets = broker.cluster_metadata().await;
+    for (topic_name, (_, partition_ids)) in record_set_to_topic(&record_sets) {
+        if !broker.log_config(&topic_name).await.remote_storage_enable {
+            continue;
+        }
+        for partition_idx in partition_ids {
+            match broker.copy_to_remote(&topic_name, partition_idx).await {
+                Ok(0) => {}
+                Ok(copied) => println!("Copied {} segments of {}-{} to remote storage", copied, topic_name, partition_idx),
+                Err(e) => println!("Failed to copy {}-{} to remote storage: {}", topic_name, partition_idx, e),
+            }
+            match broker.expire_remote_segments(&topic_name, partition_idx, now_ms()).await {
+                Ok(0) => {}
+                Ok(deleted) => println!("Deleted {} remote segments of {}-{}", deleted, topic_name, partition_idx),
+                Err(e) => println!("Failed to apply retention to the remote segments of {}-{}: {}", topic_name, partition_idx, e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use bytes::BytesMut;
+
+    use crate::log::PartitionLog;
+    use crate::log_config::LogConfig;
+    use crate::test_util::{batch, temp_path};
+
+    use super::{fetch_remote, parse_remote_log_metadata, remote_offset_for_timestamp, FileSystemRemoteStorageManager, RemoteStorageManager};
+
+    #[tokio::test]
+    async fn test_tiered_storage() {
+        let dir = temp_path("tiered");
+        let remote_storage = FileSystemRemoteStorageManager::new(dir.join("remote"));
+        let topic_partition = ("foo".to_string(), 0);
+        // one batch per segment, kept locally only once copied
+        let config = LogConfig { segment_bytes: 1, retention_ms: -1, remote_storage_enable: true, local_retention_ms: 0, ..LogConfig::default() };
+        let mut log = PartitionLog::open(dir.join("foo-0"), config.clone(), Arc::default()).await.unwrap();
+        let batches: Vec<BytesMut> = [1000, 2000, 3000].into_iter().map(|timestamp| batch(None, Some("value"), timestamp)).collect();
+        for batch in batches.iter() {
+            log.append(batch).await.unwrap();
+        }
+        assert_eq!(0, log.delete_old_segments(5000).await.unwrap());
+
+        // the active segment stays local
+        let segments = log.segments_to_copy();
+        assert_eq!(2, segments.len());
+        for (metadata, data) in segments {
+            remote_storage.copy_log_segment(&topic_partition, &metadata, &data).unwrap();
+            assert!(log.add_remote_segment(metadata).await.unwrap());
+        }
+        assert!(log.segments_to_copy().is_empty());
+        assert_eq!(2, log.delete_old_segments(5000).await.unwrap());
+        assert_eq!((0, 2), (log.log_start_offset(), log.local_log_start_offset()));
+
+        let fetched = log.read(1, i32::MAX, false).await.unwrap();
+        assert!(fetched.records.is_empty());
+        let metadata = fetched.remote_segment.unwrap();
+        let (records, _) = fetch_remote(&remote_storage, &topic_partition, &metadata, 1, 1, false).unwrap();
+        // the same batch but for the base offset assigned on append
+        assert_eq!(&batches[1][8..], &records[8..]);
+        let remote_segment = log.remote_segment_for_timestamp(1500).unwrap();
+        assert_eq!(Some((1, 2000)), remote_offset_for_timestamp(&remote_storage, &topic_partition, remote_segment, 1500, 0).unwrap());
+
+        // reopened, the log still starts in remote storage
+        let mut log = PartitionLog::open(dir.join("foo-0"), config.clone(), Arc::default()).await.unwrap();
+        assert_eq!((0, 2), (log.log_start_offset(), log.local_log_start_offset()));
+        assert_eq!(2, parse_remote_log_metadata(&super::format_remote_log_metadata(log.remote_segments())).unwrap().len());
+
+        log.set_config(LogConfig { retention_ms: 2500, ..config });
+        let expired = log.expire_remote_segments(5000).await.unwrap();
+        assert_eq!(2, expired.len());
+        assert_eq!(2, log.log_start_offset());
+        for metadata in expired.iter() {
+            remote_storage.delete_log_segment(&topic_partition, metadata).unwrap();
+        }
+        assert!(remote_storage.fetch_log_segment(&topic_partition, &expired[0], 0, 1).is_err());
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
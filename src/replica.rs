@@ -0,0 +1,727 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::future::join_all;
+use kafka_protocol::error::ResponseError;
+use kafka_protocol::messages::fetch_request::{FetchPartition, FetchTopic};
+use kafka_protocol::messages::fetch_response::PartitionData;
+use kafka_protocol::messages::{alter_partition_request, alter_partition_response, AlterPartitionRequest, AlterPartitionResponse, ApiKey, BrokerId, FetchRequest, FetchResponse, TopicName};
+use kafka_protocol::protocol::StrBytes;
+use kafka_protocol::records::RecordSet;
+use uuid::Uuid;
+
+use crate::broker::{now_ms, Broker, NODE_ID};
+use crate::client::send_request;
+use crate::config::BrokerConfig;
+use crate::describe_cluster::{client_listener, ClusterImage};
+use crate::fetch_session::{FINAL_EPOCH, INVALID_SESSION_ID};
+use crate::leader_epoch::UNDEFINED_EPOCH;
+use crate::log::TopicPartition;
+use crate::raft::NODE_ID_CONFIG;
+use crate::record::{encode_isr_change_record, extract_record_value, RecordValue};
+
+pub const REPLICA_LAG_TIME_MAX_MS_CONFIG: &str = "replica.lag.time.max.ms";
+pub const DEFAULT_REPLICA_LAG_TIME_MAX_MS: i64 = 30 * 1000;
+pub const REPLICA_FETCH_WAIT_MAX_MS_CONFIG: &str = "replica.fetch.wait.max.ms";
+pub const DEFAULT_REPLICA_FETCH_WAIT_MAX_MS: i32 = 500;
+pub const REPLICA_FETCH_MIN_BYTES_CONFIG: &str = "replica.fetch.min.bytes";
+pub const DEFAULT_REPLICA_FETCH_MIN_BYTES: i32 = 1;
+pub const REPLICA_FETCH_MAX_BYTES_CONFIG: &str = "replica.fetch.max.bytes";
+pub const DEFAULT_REPLICA_FETCH_MAX_BYTES: i32 = 1024 * 1024;
+pub const REPLICA_FETCH_RESPONSE_MAX_BYTES_CONFIG: &str = "replica.fetch.response.max.bytes";
+pub const DEFAULT_REPLICA_FETCH_RESPONSE_MAX_BYTES: i32 = 10 * 1024 * 1024;
+pub const REPLICA_FETCH_BACKOFF_MS_CONFIG: &str = "replica.fetch.backoff.ms";
+pub const DEFAULT_REPLICA_FETCH_BACKOFF_MS: i64 = 1000;
+pub const REPLICA_SOCKET_TIMEOUT_MS_CONFIG: &str = "replica.socket.timeout.ms";
+pub const DEFAULT_REPLICA_SOCKET_TIMEOUT_MS: i64 = 30 * 1000;
+
+// the last version naming topics, whose ids a follower may not know yet
+const FETCH_VERSION: i16 = 12;
+// the last version with the ISR as plain broker ids
+const ALTER_PARTITION_VERSION: i16 = 1;
+const TOPIC_ID_MIN_VERSION: i16 = 2;
+
+/// This broker's id, `node.id` or NODE_ID.
+pub fn node_id(config: &BrokerConfig) -> i32 {
+    config.get_or(NODE_ID_CONFIG, NODE_ID)
+}
+
+/// A partition's replicas and leadership as the metadata log has them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PartitionState {
+    pub topic_id: Uuid,
+    pub replicas: Vec<i32>,
+    pub isr: Vec<i32>,
+    // -1 while no replica leads it
+    pub leader: i32,
+    pub leader_epoch: i32,
+    // bumped by every change, so the controller can refuse stale ISR updates
+    pub partition_epoch: i32,
+}
+
+/// The state of every partition from its PartitionRecord and the
+/// PartitionChangeRecords after it. A change of leader bumps the leader
+/// epoch, any change the partition epoch.
+pub fn partition_states(record_sets: &[RecordSet]) -> HashMap<TopicPartition, PartitionState> {
+    let mut topic_names = HashMap::new();
+    let mut states = HashMap::new();
+    for record in record_sets.iter().flat_map(|record_set| record_set.records.iter()) {
+        if record.value.is_none() {
+            continue;
+        }
+        match extract_record_value(record) {
+            RecordValue::TopicRecord(topic) => {
+                topic_names.insert(topic.topic_id, topic.name);
+            }
+            RecordValue::PartitionRecord(partition) => {
+                let Some(topic_name) = topic_names.get(&partition.topic_id) else { continue };
+                states.insert((topic_name.clone(), partition.partition_id), PartitionState {
+                    topic_id: partition.topic_id,
+                    replicas: partition.replicas,
+                    isr: partition.isr,
+                    leader: partition.leader,
+                    leader_epoch: partition.leader_epoch,
+                    partition_epoch: partition.partition_epoch,
+                });
+            }
+            RecordValue::PartitionChangeRecord(change) => {
+                let Some(topic_name) = topic_names.get(&change.topic_id) else { continue };
+                let Some(state) = states.get_mut(&(topic_name.clone(), change.partition_id)) else { continue };
+                if let Some(isr) = change.isr {
+                    state.isr = isr;
+                }
+                if let Some(replicas) = change.replicas {
+                    state.replicas = replicas;
+                }
+                if let Some(leader) = change.leader.filter(|leader| *leader != state.leader) {
+                    state.leader = leader;
+                    state.leader_epoch += 1;
+                }
+                state.partition_epoch += 1;
+            }
+            _ => {}
+        }
+    }
+    states
+}
+
+/// How far a follower has fetched, as its leader saw it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FollowerState {
+    pub log_end_offset: i64,
+    pub last_fetch_time: i64,
+    // the last time the follower had everything the leader had, which
+    // keeps it in the ISR for replica.lag.time.max.ms
+    pub last_caught_up_time: i64,
+    pub last_fetch_leader_log_end_offset: i64,
+}
+
+#[derive(Debug)]
+struct LeaderState {
+    topic_id: Uuid,
+    leader_epoch: i32,
+    partition_epoch: i32,
+    isr: Vec<i32>,
+    followers: BTreeMap<i32, FollowerState>,
+}
+
+/// An ISR update for the controller to accept.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IsrChange {
+    pub topic_partition: TopicPartition,
+    pub topic_id: Uuid,
+    pub leader_epoch: i32,
+    pub partition_epoch: i32,
+    pub isr: Vec<i32>,
+}
+
+/// The partitions this broker leads, with the progress of their followers,
+/// and those it follows, with their leader and leader epoch. Partitions
+/// without a state in the metadata log are in neither and behave as if
+/// this broker were their only replica.
+#[derive(Debug)]
+pub struct ReplicaManager {
+    node_id: i32,
+    leaders: HashMap<TopicPartition, LeaderState>,
+    followers: HashMap<TopicPartition, (i32, i32)>,
+}
+
+impl Default for ReplicaManager {
+    fn default() -> Self {
+        ReplicaManager::new(NODE_ID)
+    }
+}
+
+impl ReplicaManager {
+    pub fn new(node_id: i32) -> ReplicaManager {
+        ReplicaManager {
+            node_id,
+            leaders: HashMap::new(),
+            followers: HashMap::new(),
+        }
+    }
+
+    pub fn node_id(&self) -> i32 {
+        self.node_id
+    }
+
+    /// Takes on the leadership in `states`. A new leader epoch starts over
+    /// with the ISR members counted as caught up. Returns the partitions led
+    /// under a new leader epoch, with that epoch, and those followed from
+    /// now on or under a new leader epoch, which may have to be truncated
+    /// before fetching.
+    pub fn reconcile(&mut self, states: &HashMap<TopicPartition, PartitionState>, now: i64) -> (Vec<(TopicPartition, i32)>, Vec<TopicPartition>) {
+        let node_id = self.node_id;
+        let follows = |state: &PartitionState| state.leader >= 0 && state.leader != node_id && state.replicas.contains(&node_id);
+        self.leaders.retain(|topic_partition, _| states.get(topic_partition).is_some_and(|state| state.leader == node_id));
+        self.followers.retain(|topic_partition, _| states.get(topic_partition).is_some_and(follows));
+        let mut led = Vec::new();
+        let mut followed = Vec::new();
+        for (topic_partition, state) in states.iter() {
+            if state.leader == node_id {
+                if self.leaders.get(topic_partition).map_or(true, |leader| leader.leader_epoch != state.leader_epoch) {
+                    self.leaders.insert(topic_partition.clone(), LeaderState {
+                        topic_id: state.topic_id,
+                        leader_epoch: state.leader_epoch,
+                        partition_epoch: state.partition_epoch,
+                        isr: state.isr.clone(),
+                        followers: BTreeMap::new(),
+                    });
+                    led.push((topic_partition.clone(), state.leader_epoch));
+                }
+                let leader = self.leaders.get_mut(topic_partition).unwrap();
+                // ISR updates this leader applied already come back with the same epoch
+                if state.partition_epoch > leader.partition_epoch {
+                    leader.partition_epoch = state.partition_epoch;
+                    leader.isr = state.isr.clone();
+                }
+                leader.followers.retain(|replica, _| state.replicas.contains(replica));
+                for replica in state.replicas.iter().filter(|replica| **replica != node_id) {
+                    let caught_up = if state.isr.contains(replica) { now } else { -1 };
+                    leader.followers.entry(*replica).or_insert(FollowerState {
+                        log_end_offset: -1,
+                        last_fetch_time: -1,
+                        last_caught_up_time: caught_up,
+                        last_fetch_leader_log_end_offset: -1,
+                    });
+                }
+            } else if follows(state) {
+                let leader = (state.leader, state.leader_epoch);
+                if self.followers.insert(topic_partition.clone(), leader) != Some(leader) {
+                    followed.push(topic_partition.clone());
+                }
+            }
+        }
+        led.sort();
+        followed.sort();
+        (led, followed)
+    }
+
+    pub fn is_leader(&self, topic_partition: &TopicPartition) -> bool {
+        self.leaders.contains_key(topic_partition)
+    }
+
+    pub fn is_follower(&self, topic_partition: &TopicPartition) -> bool {
+        self.followers.contains_key(topic_partition)
+    }
+
+    /// The epoch of a partition this broker leads.
+    pub fn leader_epoch(&self, topic_partition: &TopicPartition) -> Option<i32> {
+        self.leaders.get(topic_partition).map(|leader| leader.leader_epoch)
+    }
+
+    /// The followed partitions as (partition, leader id, leader epoch).
+    pub fn followed(&self) -> Vec<(TopicPartition, i32, i32)> {
+        let mut followed: Vec<(TopicPartition, i32, i32)> = self.followers.iter()
+            .map(|(topic_partition, (leader_id, leader_epoch))| (topic_partition.clone(), *leader_id, *leader_epoch))
+            .collect();
+        followed.sort();
+        followed
+    }
+
+    /// Checks a fetch of a partition against its leadership: only the leader
+    /// serves it, to replicas of the partition alone, and the fetcher's
+    /// leader epoch has to be the current one. -1 skips the epoch check.
+    pub fn check_fetch(&self, topic_partition: &TopicPartition, replica_id: Option<i32>, current_leader_epoch: i32) -> Result<(), ResponseError> {
+        let Some(leader) = self.leaders.get(topic_partition) else {
+            if replica_id.is_some() || self.followers.contains_key(topic_partition) {
+                return Err(ResponseError::NotLeaderOrFollower);
+            }
+            return Ok(());
+        };
+        if current_leader_epoch >= 0 && current_leader_epoch < leader.leader_epoch {
+            return Err(ResponseError::FencedLeaderEpoch);
+        }
+        if current_leader_epoch > leader.leader_epoch {
+            return Err(ResponseError::UnknownLeaderEpoch);
+        }
+        if replica_id.is_some_and(|replica_id| !leader.followers.contains_key(&replica_id)) {
+            return Err(ResponseError::NotLeaderOrFollower);
+        }
+        Ok(())
+    }
+
+    /// Records a follower's fetch from `fetch_offset`, its log end offset.
+    /// It counts as caught up if it had everything this leader had, now or
+    /// at its previous fetch.
+    pub fn update_follower(&mut self, topic_partition: &TopicPartition, replica_id: i32, fetch_offset: i64, log_end_offset: i64, now: i64) {
+        let Some(follower) = self.leaders.get_mut(topic_partition)
+            .and_then(|leader| leader.followers.get_mut(&replica_id)) else { return };
+        if fetch_offset >= log_end_offset {
+            follower.last_caught_up_time = now;
+        } else if fetch_offset >= follower.last_fetch_leader_log_end_offset {
+            follower.last_caught_up_time = follower.last_caught_up_time.max(follower.last_fetch_time);
+        }
+        follower.log_end_offset = fetch_offset;
+        follower.last_fetch_time = now;
+        follower.last_fetch_leader_log_end_offset = log_end_offset;
+    }
+
+    /// The high watermark of a partition this broker leads: the smallest
+    /// log end offset across the ISR. None for a partition it does not lead.
+    pub fn high_watermark(&self, topic_partition: &TopicPartition, log_end_offset: i64) -> Option<i64> {
+        let leader = self.leaders.get(topic_partition)?;
+        let high_watermark = leader.isr.iter()
+            .map(|replica| match leader.followers.get(replica) {
+                Some(follower) => follower.log_end_offset,
+                None => log_end_offset,
+            })
+            .min()
+            .unwrap_or(log_end_offset);
+        Some(high_watermark)
+    }
+
+    /// The ISR changes due given the (log end offset, high watermark) of
+    /// the led partitions: followers short of the log end offset that have
+    /// not caught up for `lag_time_max_ms` leave the ISR, and caught up
+    /// ones that reached the high watermark join it.
+    pub fn isr_changes(&self, offsets: &HashMap<TopicPartition, (i64, i64)>, now: i64, lag_time_max_ms: i64) -> Vec<IsrChange> {
+        let mut changes = Vec::new();
+        for (topic_partition, leader) in self.leaders.iter() {
+            let Some((log_end_offset, high_watermark)) = offsets.get(topic_partition) else { continue };
+            let caught_up = |follower: &FollowerState| follower.log_end_offset == *log_end_offset
+                || now - follower.last_caught_up_time <= lag_time_max_ms;
+            let mut isr: Vec<i32> = leader.isr.iter()
+                .copied()
+                .filter(|replica| leader.followers.get(replica).map_or(true, caught_up))
+                .collect();
+            for (replica, follower) in leader.followers.iter() {
+                if !isr.contains(replica) && follower.log_end_offset >= *high_watermark.max(&0) && caught_up(follower) {
+                    isr.push(*replica);
+                }
+            }
+            if isr != leader.isr {
+                changes.push(IsrChange {
+                    topic_partition: topic_partition.clone(),
+                    topic_id: leader.topic_id,
+                    leader_epoch: leader.leader_epoch,
+                    partition_epoch: leader.partition_epoch,
+                    isr,
+                });
+            }
+        }
+        changes.sort_by(|a, b| a.topic_partition.cmp(&b.topic_partition));
+        changes
+    }
+
+    /// Applies an ISR change the controller accepted, unless the partition
+    /// moved on to another leader epoch meanwhile.
+    pub fn apply_isr(&mut self, topic_partition: &TopicPartition, isr: Vec<i32>, leader_epoch: i32, partition_epoch: i32) {
+        if let Some(leader) = self.leaders.get_mut(topic_partition) {
+            if leader.leader_epoch == leader_epoch && partition_epoch > leader.partition_epoch {
+                leader.isr = isr;
+                leader.partition_epoch = partition_epoch;
+            }
+        }
+    }
+}
+
+/// Runs the replica fetchers, one Fetch per leader at a time, along with
+/// the leader's ISR maintenance, backing off `replica.fetch.backoff.ms`
+/// while there is nothing to fetch or a fetch fails.
+pub fn start(broker: &Arc<Broker>) {
+    let broker = broker.clone();
+    tokio::spawn(async move {
+        let backoff_ms = broker.config.get_or(REPLICA_FETCH_BACKOFF_MS_CONFIG, DEFAULT_REPLICA_FETCH_BACKOFF_MS);
+        loop {
+            let fetched = match replicate(&broker).await {
+                Ok(fetched) => fetched,
+                Err(e) => {
+                    println!("Replication error: {}", e);
+                    false
+                }
+            };
+            if !fetched {
+                tokio::time::sleep(Duration::from_millis(backoff_ms.max(0) as u64)).await;
+            }
+        }
+    });
+}
+
+// one round of replication, returning whether any fetch went through
+async fn replicate(broker: &Broker) -> io::Result<bool> {
+    broker.reconcile_replicas().await?;
+    if let Err(e) = maintain_isr(broker).await {
+        println!("Failed to update the ISR: {}", e);
+    }
+    let followed = broker.replica_manager.lock().await.followed();
+    if followed.is_empty() {
+        return Ok(false);
+    }
+    let mut by_leader: BTreeMap<i32, Vec<(TopicPartition, i32)>> = BTreeMap::new();
+    for (topic_partition, leader_id, leader_epoch) in followed {
+        by_leader.entry(leader_id).or_default().push((topic_partition, leader_epoch));
+    }
+    let image = ClusterImage::from_record_sets(&broker.cluster_metadata().await);
+    let listener = client_listener(&broker.config);
+    let results = join_all(by_leader.into_iter()
+        .map(|(leader_id, partitions)| fetch_from_leader(broker, &image, &listener, leader_id, partitions)))
+        .await;
+    let mut fetched = false;
+    for result in results {
+        match result {
+            Ok(()) => fetched = true,
+            Err(e) => println!("Replica fetch failed: {}", e),
+        }
+    }
+    Ok(fetched)
+}
+
+// fetches the partitions followed from a leader from their log end offsets
+async fn fetch_from_leader(broker: &Broker, image: &ClusterImage, listener: &str, leader_id: i32, partitions: Vec<(TopicPartition, i32)>) -> io::Result<()> {
+    let registration = image.brokers.get(&leader_id)
+        .ok_or_else(|| io::Error::other(format!("leader {} is not registered", leader_id)))?;
+    let endpoint = registration.endpoints.iter()
+        .find(|endpoint| endpoint.name == listener)
+        .or_else(|| registration.endpoints.first())
+        .ok_or_else(|| io::Error::other(format!("leader {} has no endpoint", leader_id)))?;
+    let address = format!("{}:{}", endpoint.host, endpoint.port);
+
+    let config = &broker.config;
+    let partition_max_bytes = config.get_or(REPLICA_FETCH_MAX_BYTES_CONFIG, DEFAULT_REPLICA_FETCH_MAX_BYTES);
+    let mut topics: Vec<FetchTopic> = Vec::new();
+    for ((topic_name, partition_idx), leader_epoch) in partitions.iter() {
+        let (log_start_offset, log_end_offset, _) = broker.log_offsets(topic_name, *partition_idx).await?;
+        let last_fetched_epoch = broker.latest_epoch(topic_name, *partition_idx).await?;
+        let partition = FetchPartition::default()
+            .with_partition(*partition_idx)
+            .with_current_leader_epoch(*leader_epoch)
+            .with_fetch_offset(log_end_offset)
+            .with_last_fetched_epoch(last_fetched_epoch.unwrap_or(UNDEFINED_EPOCH))
+            .with_log_start_offset(log_start_offset)
+            .with_partition_max_bytes(partition_max_bytes);
+        match topics.last_mut() {
+            Some(topic) if topic.topic.0.as_str() == topic_name => topic.partitions.push(partition),
+            _ => topics.push(FetchTopic::default()
+                .with_topic(TopicName(StrBytes::from_string(topic_name.clone())))
+                .with_partitions(vec![partition])),
+        }
+    }
+    let node_id = broker.replica_manager.lock().await.node_id();
+    let max_wait_ms = config.get_or(REPLICA_FETCH_WAIT_MAX_MS_CONFIG, DEFAULT_REPLICA_FETCH_WAIT_MAX_MS);
+    let request = FetchRequest::default()
+        .with_replica_id(BrokerId(node_id))
+        .with_max_wait_ms(max_wait_ms)
+        .with_min_bytes(config.get_or(REPLICA_FETCH_MIN_BYTES_CONFIG, DEFAULT_REPLICA_FETCH_MIN_BYTES))
+        .with_max_bytes(config.get_or(REPLICA_FETCH_RESPONSE_MAX_BYTES_CONFIG, DEFAULT_REPLICA_FETCH_RESPONSE_MAX_BYTES))
+        .with_session_id(INVALID_SESSION_ID)
+        .with_session_epoch(FINAL_EPOCH)
+        .with_topics(topics);
+    let timeout_ms = config.get_or(REPLICA_SOCKET_TIMEOUT_MS_CONFIG, DEFAULT_REPLICA_SOCKET_TIMEOUT_MS);
+    let client_id = format!("replica-fetcher-{}", node_id);
+    let resp: FetchResponse = send_request(&address, &client_id, ApiKey::Fetch, FETCH_VERSION, &request, timeout_ms).await?;
+    if resp.error_code != 0 {
+        return Err(io::Error::other(format!("fetch from {} failed with error code {}", leader_id, resp.error_code)));
+    }
+    for topic in resp.responses.iter() {
+        let topic_name = topic.topic.0.as_str();
+        for partition in topic.partitions.iter() {
+            if let Err(e) = apply_fetched(broker, topic_name, partition).await {
+                println!("Failed to replicate {}-{} from {}: {}", topic_name, partition.partition_index, leader_id, e);
+            }
+        }
+    }
+    Ok(())
+}
+
+// appends a fetched partition, or truncates the log where it diverges from
+// the leader's or when the leader has no longer got the fetch offset
+async fn apply_fetched(broker: &Broker, topic_name: &str, partition: &PartitionData) -> io::Result<()> {
+    let partition_idx = partition.partition_index;
+    match partition.error_code {
+        0 if partition.diverging_epoch.epoch >= 0 => {
+            let diverging_epoch = &partition.diverging_epoch;
+            let offset = broker.truncate_diverged(topic_name, partition_idx, diverging_epoch.epoch, diverging_epoch.end_offset).await?;
+            println!("Truncated {}-{} to {}, where it diverges from the leader's epoch {}", topic_name, partition_idx, offset, diverging_epoch.epoch);
+            Ok(())
+        }
+        0 => {
+            let records = partition.records.as_deref().unwrap_or_default();
+            broker.append_as_follower(topic_name, partition_idx, records, partition.high_watermark, partition.log_start_offset).await
+        }
+        code if code == ResponseError::OffsetOutOfRange.code() => {
+            let (_, log_end_offset, _) = broker.log_offsets(topic_name, partition_idx).await?;
+            if log_end_offset < partition.log_start_offset {
+                println!("Restarting {}-{} at the leader's log start offset {}", topic_name, partition_idx, partition.log_start_offset);
+                broker.truncate_fully_and_start_at(topic_name, partition_idx, partition.log_start_offset).await
+            } else {
+                // beyond the leader's log, as after an unclean election
+                println!("Truncating {}-{} to the leader's high watermark {}", topic_name, partition_idx, partition.high_watermark);
+                broker.truncate_log(topic_name, partition_idx, partition.high_watermark).await
+            }
+        }
+        // the metadata log will say who leads now
+        code if code == ResponseError::NotLeaderOrFollower.code()
+            || code == ResponseError::FencedLeaderEpoch.code()
+            || code == ResponseError::UnknownLeaderEpoch.code() => Ok(()),
+        code => Err(io::Error::other(format!("error code {}", code))),
+    }
+}
+
+// asks the controller for the ISR changes due on the led partitions
+async fn maintain_isr(broker: &Broker) -> io::Result<()> {
+    let lag_time_max_ms = broker.config.get_or(REPLICA_LAG_TIME_MAX_MS_CONFIG, DEFAULT_REPLICA_LAG_TIME_MAX_MS);
+    let offsets: HashMap<TopicPartition, (i64, i64)> = broker.logs.lock().await.iter()
+        .map(|(topic_partition, log)| (topic_partition.clone(), (log.log_end_offset(), log.high_watermark())))
+        .collect();
+    let changes = broker.replica_manager.lock().await.isr_changes(&offsets, now_ms(), lag_time_max_ms);
+    if changes.is_empty() {
+        return Ok(());
+    }
+    alter_isr(broker, changes).await
+}
+
+// sends ISR changes to the active controller, this node itself without a
+// quorum, and applies those it accepts
+async fn alter_isr(broker: &Broker, changes: Vec<IsrChange>) -> io::Result<()> {
+    let node_id = broker.replica_manager.lock().await.node_id();
+    let mut topics: Vec<alter_partition_request::TopicData> = Vec::new();
+    for change in changes.iter() {
+        let (topic_name, partition_idx) = &change.topic_partition;
+        let partition = alter_partition_request::PartitionData::default()
+            .with_partition_index(*partition_idx)
+            .with_leader_epoch(change.leader_epoch)
+            .with_new_isr(change.isr.iter().map(|replica| BrokerId(*replica)).collect())
+            .with_partition_epoch(change.partition_epoch);
+        match topics.last_mut() {
+            Some(topic) if topic.topic_name.0.as_str() == topic_name => topic.partitions.push(partition),
+            _ => topics.push(alter_partition_request::TopicData::default()
+                .with_topic_name(TopicName(StrBytes::from_string(topic_name.clone())))
+                .with_partitions(vec![partition])),
+        }
+    }
+    let request = AlterPartitionRequest::default()
+        .with_broker_id(BrokerId(node_id))
+        .with_topics(topics);
+    let resp = match &broker.raft {
+        Some(raft) => match raft.leader_id().await {
+            Some(leader_id) if leader_id == raft.config.node_id => handle_alter_partition(broker, request, ALTER_PARTITION_VERSION).await,
+            Some(leader_id) => {
+                let address = raft.config.address(leader_id)
+                    .ok_or_else(|| io::Error::other(format!("controller {} is not a voter", leader_id)))?;
+                let timeout_ms = broker.config.get_or(REPLICA_SOCKET_TIMEOUT_MS_CONFIG, DEFAULT_REPLICA_SOCKET_TIMEOUT_MS);
+                let client_id = format!("alter-partition-{}", node_id);
+                send_request(&address, &client_id, ApiKey::AlterPartition, ALTER_PARTITION_VERSION, &request, timeout_ms).await?
+            }
+            None => return Err(io::Error::other("no active controller")),
+        },
+        None => handle_alter_partition(broker, request, ALTER_PARTITION_VERSION).await,
+    };
+    if resp.error_code != 0 {
+        return Err(io::Error::other(format!("AlterPartition failed with error code {}", resp.error_code)));
+    }
+    for topic in resp.topics.iter() {
+        for partition in topic.partitions.iter() {
+            let topic_partition = (topic.topic_name.0.to_string(), partition.partition_index);
+            if partition.error_code != 0 {
+                println!("ISR change of {}-{} refused with error code {}", topic_partition.0, topic_partition.1, partition.error_code);
+                continue;
+            }
+            let isr = partition.isr.iter().map(|replica| replica.0).collect();
+            println!("ISR of {}-{} is now {:?}", topic_partition.0, topic_partition.1, isr);
+            broker.replica_manager.lock().await.apply_isr(&topic_partition, isr, partition.leader_epoch, partition.partition_epoch);
+            // a smaller ISR may let the high watermark move
+            broker.update_high_watermark(&topic_partition).await;
+        }
+    }
+    Ok(())
+}
+
+/// Serves AlterPartition as the active controller: a leader's ISR change
+/// is accepted if it is based on the partition's current leader and
+/// partition epochs, and written to the metadata log.
+pub async fn handle_alter_partition(broker: &Broker, req: AlterPartitionRequest, api_version: i16) -> AlterPartitionResponse {
+    if let Some(raft) = &broker.raft {
+        if raft.leader_id().await != Some(raft.config.node_id) {
+            return AlterPartitionResponse::default().with_error_code(ResponseError::NotController.code());
+        }
+    }
+    if api_version >= TOPIC_ID_MIN_VERSION {
+        return AlterPartitionResponse::default().with_error_code(ResponseError::UnsupportedVersion.code());
+    }
+    let states = partition_states(&broker.cluster_metadata().await);
+    let mut values = Vec::new();
+    let mut topics = Vec::new();
+    for topic in req.topics.iter() {
+        let topic_name = topic.topic_name.0.to_string();
+        let mut partitions = Vec::new();
+        for partition in topic.partitions.iter() {
+            let resp = alter_partition_response::PartitionData::default()
+                .with_partition_index(partition.partition_index);
+            let new_isr: Vec<i32> = partition.new_isr.iter().map(|replica| replica.0).collect();
+            let Some(state) = states.get(&(topic_name.clone(), partition.partition_index)) else {
+                partitions.push(resp.with_error_code(ResponseError::UnknownTopicOrPartition.code()));
+                continue;
+            };
+            let error = if req.broker_id.0 != state.leader {
+                Some(ResponseError::NotLeaderOrFollower)
+            } else if partition.leader_epoch != state.leader_epoch {
+                Some(ResponseError::FencedLeaderEpoch)
+            } else if partition.partition_epoch != state.partition_epoch {
+                Some(ResponseError::InvalidUpdateVersion)
+            } else if !new_isr.contains(&state.leader) || new_isr.iter().any(|replica| !state.replicas.contains(replica)) {
+                Some(ResponseError::InvalidRequest)
+            } else {
+                None
+            };
+            if let Some(error) = error {
+                partitions.push(resp.with_error_code(error.code()));
+                continue;
+            }
+            values.push(encode_isr_change_record(partition.partition_index, state.topic_id, &new_isr));
+            partitions.push(resp
+                .with_leader_id(BrokerId(state.leader))
+                .with_leader_epoch(state.leader_epoch)
+                .with_isr(partition.new_isr.clone())
+                .with_partition_epoch(state.partition_epoch + 1));
+        }
+        topics.push(alter_partition_response::TopicData::default()
+            .with_topic_name(topic.topic_name.clone())
+            .with_partitions(partitions));
+    }
+    if !values.is_empty() {
+        if let Err(e) = broker.append_metadata(values).await {
+            println!("Failed to record ISR changes: {}", e);
+            return AlterPartitionResponse::default().with_error_code(ResponseError::UnknownServerError.code());
+        }
+    }
+    AlterPartitionResponse::default().with_topics(topics)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use kafka_protocol::error::ResponseError;
+    use uuid::Uuid;
+
+    use super::{PartitionState, ReplicaManager};
+
+    fn state(leader: i32, leader_epoch: i32, isr: Vec<i32>) -> PartitionState {
+        PartitionState {
+            topic_id: Uuid::from_u128(7),
+            replicas: vec![1, 2, 3],
+            isr,
+            leader,
+            leader_epoch,
+            partition_epoch: 0,
+        }
+    }
+
+    #[test]
+    fn test_isr_maintenance() {
+        let tp = ("foo".to_string(), 0);
+        let mut replicas = ReplicaManager::new(1);
+        let states = HashMap::from([(tp.clone(), state(1, 0, vec![1, 2, 3]))]);
+        assert_eq!((vec![(tp.clone(), 0)], vec![]), replicas.reconcile(&states, 0));
+        assert!(replicas.is_leader(&tp));
+        assert_eq!(Some(0), replicas.leader_epoch(&tp));
+        assert_eq!(Some(-1), replicas.high_watermark(&tp, 10));
+
+        replicas.update_follower(&tp, 2, 10, 10, 1000);
+        replicas.update_follower(&tp, 3, 4, 10, 1000);
+        assert_eq!(Some(4), replicas.high_watermark(&tp, 10));
+        assert!(replicas.check_fetch(&tp, Some(2), 0).is_ok());
+        assert_eq!(Err(ResponseError::NotLeaderOrFollower.code()), replicas.check_fetch(&tp, Some(4), 0).map_err(|e| e.code()));
+        assert_eq!(Err(ResponseError::UnknownLeaderEpoch.code()), replicas.check_fetch(&tp, Some(2), 1).map_err(|e| e.code()));
+
+        // 3 has not caught up for longer than the lag time
+        let offsets = HashMap::from([(tp.clone(), (10, 4))]);
+        let changes = replicas.isr_changes(&offsets, 40_000, 30_000);
+        assert_eq!(1, changes.len());
+        assert_eq!(vec![1, 2], changes[0].isr);
+        replicas.apply_isr(&tp, changes[0].isr.clone(), 0, 1);
+        assert_eq!(Some(10), replicas.high_watermark(&tp, 10));
+
+        // and rejoins once it reaches the high watermark
+        replicas.update_follower(&tp, 3, 10, 10, 41_000);
+        let offsets = HashMap::from([(tp.clone(), (10, 10))]);
+        assert_eq!(vec![1, 2, 3], replicas.isr_changes(&offsets, 41_000, 30_000)[0].isr);
+
+        // another broker takes over
+        let states = HashMap::from([(tp.clone(), state(2, 1, vec![1, 2, 3]))]);
+        assert_eq!((vec![], vec![tp.clone()]), replicas.reconcile(&states, 42_000));
+        assert!(replicas.is_follower(&tp));
+        assert_eq!(None, replicas.leader_epoch(&tp));
+        assert_eq!((vec![], vec![]), replicas.reconcile(&states, 43_000));
+        assert_eq!(Err(ResponseError::NotLeaderOrFollower.code()), replicas.check_fetch(&tp, None, -1).map_err(|e| e.code()));
+    }
+
+    #[test]
+    fn test_isr_shrink() {
+        let tp = ("foo".to_string(), 0);
+        let mut replicas = ReplicaManager::new(1);
+        replicas.reconcile(&HashMap::from([(tp.clone(), state(1, 0, vec![1, 2, 3]))]), 0);
+
+        // 2 reaches the log end offset of its previous fetch, which counts
+        // as caught up as of that fetch
+        replicas.update_follower(&tp, 2, 5, 10, 1000);
+        replicas.update_follower(&tp, 2, 10, 12, 20_000);
+        replicas.update_follower(&tp, 3, 12, 12, 20_000);
+        let offsets = HashMap::from([(tp.clone(), (12, 10))]);
+        assert!(replicas.isr_changes(&offsets, 31_000, 30_000).is_empty());
+        let changes = replicas.isr_changes(&offsets, 31_001, 30_000);
+        assert_eq!(vec![1, 3], changes[0].isr);
+        assert_eq!((0, 0), (changes[0].leader_epoch, changes[0].partition_epoch));
+        // a follower at the log end offset stays however long ago it fetched
+        assert_eq!(vec![1, 3], replicas.isr_changes(&offsets, 100_000, 30_000)[0].isr);
+
+        // an answer for another leader epoch or an older partition epoch is stale
+        replicas.apply_isr(&tp, vec![1, 3], 1, 1);
+        replicas.apply_isr(&tp, vec![1, 3], 0, 0);
+        assert_eq!(Some(10), replicas.high_watermark(&tp, 12));
+        replicas.apply_isr(&tp, vec![1, 3], 0, 1);
+        assert_eq!(Some(12), replicas.high_watermark(&tp, 12));
+        // 2 is below the high watermark, so it does not rejoin yet
+        let offsets = HashMap::from([(tp.clone(), (12, 12))]);
+        assert!(replicas.isr_changes(&offsets, 31_001, 30_000).is_empty());
+    }
+
+    #[test]
+    fn test_isr_expand() {
+        let tp = ("foo".to_string(), 0);
+        let mut replicas = ReplicaManager::new(1);
+        replicas.reconcile(&HashMap::from([(tp.clone(), state(1, 0, vec![1, 2]))]), 0);
+        replicas.update_follower(&tp, 2, 10, 10, 1000);
+        replicas.update_follower(&tp, 3, 4, 10, 1000);
+        assert_eq!(Some(10), replicas.high_watermark(&tp, 10));
+        let offsets = HashMap::from([(tp.clone(), (10, 10))]);
+        assert!(replicas.isr_changes(&offsets, 1000, 30_000).is_empty());
+
+        // 3 reaches the high watermark while the leader appends more
+        replicas.update_follower(&tp, 3, 10, 12, 2000);
+        let offsets = HashMap::from([(tp.clone(), (12, 10))]);
+        assert_eq!(vec![1, 2, 3], replicas.isr_changes(&offsets, 2000, 30_000)[0].isr);
+
+        // the controller's ISR arrives through the metadata log, an older
+        // partition epoch of it does not undo that
+        let accepted = PartitionState { partition_epoch: 1, ..state(1, 0, vec![1, 2, 3]) };
+        assert_eq!((vec![], vec![]), replicas.reconcile(&HashMap::from([(tp.clone(), accepted)]), 2000));
+        replicas.reconcile(&HashMap::from([(tp.clone(), state(1, 0, vec![1, 2]))]), 2000);
+        assert!(replicas.isr_changes(&offsets, 2000, 30_000).is_empty());
+        replicas.update_follower(&tp, 2, 12, 12, 3000);
+        assert_eq!(Some(10), replicas.high_watermark(&tp, 12));
+    }
+}
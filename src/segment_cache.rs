@@ -0,0 +1,108 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+pub const MAX_OPEN_FILES_CONFIG: &str = "log.segment.cache.max.open.files";
+pub const DEFAULT_MAX_OPEN_FILES: usize = 256;
+
+#[derive(Debug, Default)]
+struct Files {
+    // bumped on every access, orders the entries for eviction
+    clock: u64,
+    // path -> (handle, last access)
+    open: HashMap<PathBuf, (Arc<File>, u64)>,
+}
+
+/// Read handles to segment files shared by every partition log, so reads
+/// skip opening the file each time. At most `max_open_files` are kept, the
+/// least recently used going first; an evicted handle stays open until the
+/// fetches holding it are written out.
+#[derive(Debug)]
+pub struct SegmentCache {
+    max_open_files: usize,
+    files: Mutex<Files>,
+}
+
+impl Default for SegmentCache {
+    fn default() -> Self {
+        SegmentCache::new(DEFAULT_MAX_OPEN_FILES)
+    }
+}
+
+impl SegmentCache {
+    pub fn new(max_open_files: usize) -> SegmentCache {
+        SegmentCache {
+            max_open_files: max_open_files.max(1),
+            files: Mutex::new(Files::default()),
+        }
+    }
+
+    pub fn open(&self, path: &Path) -> io::Result<Arc<File>> {
+        let mut files = self.files.lock().unwrap();
+        files.clock += 1;
+        let clock = files.clock;
+        if let Some((file, last_used)) = files.open.get_mut(path) {
+            *last_used = clock;
+            return Ok(file.clone());
+        }
+        let file = Arc::new(File::open(path)?);
+        if files.open.len() >= self.max_open_files {
+            let oldest = files.open.iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(path, _)| path.clone());
+            if let Some(oldest) = oldest {
+                files.open.remove(&oldest);
+            }
+        }
+        files.open.insert(path.to_path_buf(), (file.clone(), clock));
+        Ok(file)
+    }
+
+    /// Drops the handle to a segment file that was deleted or replaced.
+    pub fn evict(&self, path: &Path) {
+        self.files.lock().unwrap().open.remove(path);
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.lock().unwrap().open.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::test_util::temp_path;
+
+    use super::SegmentCache;
+
+    #[test]
+    fn test_eviction() {
+        let dir = temp_path("segment-cache");
+        std::fs::create_dir_all(&dir).unwrap();
+        let paths: Vec<_> = (0..3).map(|idx| dir.join(format!("{}.log", idx))).collect();
+        for path in paths.iter() {
+            std::fs::write(path, b"records").unwrap();
+        }
+
+        let cache = SegmentCache::new(2);
+        let first = cache.open(&paths[0]).unwrap();
+        cache.open(&paths[1]).unwrap();
+        assert!(Arc::ptr_eq(&first, &cache.open(&paths[0]).unwrap()));
+        // 1 is the least recently used
+        cache.open(&paths[2]).unwrap();
+        assert_eq!(2, cache.len());
+        assert!(Arc::ptr_eq(&first, &cache.open(&paths[0]).unwrap()));
+
+        cache.evict(&paths[0]);
+        assert!(!Arc::ptr_eq(&first, &cache.open(&paths[0]).unwrap()));
+        assert!(cache.open(&dir.join("missing.log")).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,316 @@
+use std::io;
+
+use std::ops::Range;
+
+use bytes::buf::UninitSlice;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use kafka_protocol::protocol::buf::ByteBufMut;
+use tokio::io::AsyncWriteExt;
+use tokio::net::tcp::OwnedWriteHalf;
+
+use crate::log::{FileRecords, FileRegion};
+
+// leads every placeholder, followed by the index of its FileRecords
+const PLACEHOLDER_MAGIC: &[u8; 8] = b"\xfeZCOPY\xfe\x00";
+const PLACEHOLDER_SIZE: usize = 16;
+
+/// Stands in for the records of a partition in an encoded Fetch response
+/// until they are spliced in from the segment files.
+pub fn placeholder(idx: usize) -> Bytes {
+    let mut buf = BytesMut::with_capacity(PLACEHOLDER_SIZE);
+    buf.put_slice(PLACEHOLDER_MAGIC);
+    buf.put_u64(idx as u64);
+    buf.freeze()
+}
+
+// records are compact nullable bytes in flexible versions, int32-prefixed before
+fn put_length(buf: &mut BytesMut, len: usize, flexible: bool) {
+    if !flexible {
+        buf.put_i32(len as i32);
+        return;
+    }
+    let mut value = len as u64 + 1;
+    while value >= 0x80 {
+        buf.put_u8((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    buf.put_u8(value as u8);
+}
+
+/// The buffer a response is encoded into, noting where each placeholder is
+/// written. Only a single write of exactly a placeholder's bytes counts,
+/// never a match inside other data.
+#[derive(Debug, Default)]
+pub struct ResponseBuf {
+    buf: BytesMut,
+    // (placeholder index, position) in the order they were written
+    placeholders: Vec<(usize, usize)>,
+}
+
+impl ResponseBuf {
+    pub fn into_inner(self) -> BytesMut {
+        self.buf
+    }
+}
+
+unsafe impl BufMut for ResponseBuf {
+    fn remaining_mut(&self) -> usize {
+        self.buf.remaining_mut()
+    }
+
+    unsafe fn advance_mut(&mut self, cnt: usize) {
+        self.buf.advance_mut(cnt)
+    }
+
+    fn chunk_mut(&mut self) -> &mut UninitSlice {
+        self.buf.chunk_mut()
+    }
+
+    fn put_slice(&mut self, src: &[u8]) {
+        if src.len() == PLACEHOLDER_SIZE && src.starts_with(PLACEHOLDER_MAGIC) {
+            let idx = u64::from_be_bytes(src[PLACEHOLDER_MAGIC.len()..].try_into().unwrap());
+            self.placeholders.push((idx as usize, self.buf.len()));
+        }
+        self.buf.put_slice(src)
+    }
+}
+
+impl ByteBufMut for ResponseBuf {
+    fn offset(&self) -> usize {
+        self.buf.offset()
+    }
+
+    fn seek(&mut self, offset: usize) {
+        self.buf.seek(offset)
+    }
+
+    fn range(&mut self, r: Range<usize>) -> &mut [u8] {
+        self.buf.range(r)
+    }
+}
+
+#[derive(Debug)]
+enum Part {
+    Bytes(Bytes),
+    File(FileRegion),
+}
+
+/// An encoded response, size prefix included, whose record data is still in
+/// the segment files. Written out, the file regions go from the page cache
+/// to the socket with sendfile.
+#[derive(Debug, Default)]
+pub struct ResponseSend {
+    parts: Vec<Part>,
+}
+
+impl From<BytesMut> for ResponseSend {
+    fn from(response: BytesMut) -> Self {
+        ResponseSend {
+            parts: vec![Part::Bytes(response.freeze())],
+        }
+    }
+}
+
+impl ResponseSend {
+    /// Splits an encoded response at the placeholder of each FileRecords,
+    /// rewriting the placeholder's length prefix and the response size for the
+    /// records that are sent in its place.
+    pub fn with_file_records(response: ResponseBuf, flexible: bool, file_records: Vec<FileRecords>) -> io::Result<ResponseSend> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+        let mut placeholder_prefix = BytesMut::new();
+        put_length(&mut placeholder_prefix, PLACEHOLDER_SIZE, flexible);
+        if response.placeholders.len() != file_records.len() {
+            return Err(invalid("missing records placeholder"));
+        }
+
+        let mut file_records: Vec<Option<FileRecords>> = file_records.into_iter().map(Some).collect();
+        let mut response_buf = response.buf.freeze();
+        // where the rest of the response starts in the encoded one
+        let mut consumed = 0;
+        let mut parts = Vec::new();
+        for (idx, pos) in response.placeholders {
+            let records = file_records.get_mut(idx)
+                .and_then(Option::take)
+                .ok_or_else(|| invalid("unexpected records placeholder"))?;
+            let prefix_start = pos.checked_sub(placeholder_prefix.len())
+                .filter(|start| *start >= consumed && response_buf[*start - consumed..pos - consumed] == placeholder_prefix[..])
+                .ok_or_else(|| invalid("unexpected records placeholder length"))?;
+            parts.push(Part::Bytes(response_buf.split_to(prefix_start - consumed)));
+            response_buf.advance(placeholder_prefix.len() + PLACEHOLDER_SIZE);
+            consumed = pos + PLACEHOLDER_SIZE;
+
+            let mut prefix = BytesMut::new();
+            put_length(&mut prefix, records.len() as usize, flexible);
+            parts.push(Part::Bytes(prefix.freeze()));
+            if !records.memory.is_empty() {
+                parts.push(Part::Bytes(records.memory));
+            }
+            parts.extend(records.regions.into_iter().map(Part::File));
+        }
+        parts.push(Part::Bytes(response_buf));
+
+        let mut send = ResponseSend { parts };
+        let size = send.len() - 4;
+        // the size prefix leads the first part
+        if let Some(Part::Bytes(first)) = send.parts.first_mut() {
+            let mut head = BytesMut::from(&first[..]);
+            (&mut head[..4]).put_i32(size as i32);
+            *first = head.freeze();
+        }
+        Ok(send)
+    }
+
+    pub fn len(&self) -> usize {
+        self.parts.iter()
+            .map(|part| match part {
+                Part::Bytes(bytes) => bytes.len(),
+                Part::File(region) => region.length as usize,
+            })
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub async fn write_to(&self, wr: &mut OwnedWriteHalf) -> io::Result<()> {
+        for part in self.parts.iter() {
+            match part {
+                Part::Bytes(bytes) => wr.write_all(bytes).await?,
+                Part::File(region) => send_file(wr, region).await?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Copies the whole response into memory.
+    pub async fn to_bytes(&self) -> io::Result<BytesMut> {
+        let mut buf = BytesMut::with_capacity(self.len());
+        for part in self.parts.iter() {
+            match part {
+                Part::Bytes(bytes) => buf.extend_from_slice(bytes),
+                Part::File(region) => buf.extend_from_slice(&region.read().await?),
+            }
+        }
+        Ok(buf)
+    }
+}
+
+#[cfg(target_os = "linux")]
+async fn send_file(wr: &mut OwnedWriteHalf, region: &FileRegion) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    use tokio::io::Interest;
+
+    let socket = wr.as_ref();
+    let mut offset = region.position as libc::off_t;
+    let end = offset + region.length as libc::off_t;
+    while offset < end {
+        socket.writable().await?;
+        let sent = socket.try_io(Interest::WRITABLE, || {
+            let count = (end - offset) as usize;
+            // SAFETY: both descriptors stay open for the duration of the call
+            let sent = unsafe { libc::sendfile(socket.as_raw_fd(), region.file.as_raw_fd(), &mut offset, count) };
+            if sent < 0 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(sent)
+            }
+        });
+        match sent {
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "segment file shorter than the fetched region")),
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn send_file(wr: &mut OwnedWriteHalf, region: &FileRegion) -> io::Result<()> {
+    wr.write_all(&region.read().await?).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::sync::Arc;
+
+    use bytes::{BufMut, Bytes, BytesMut};
+    use kafka_protocol::messages::fetch_response::{FetchableTopicResponse, PartitionData};
+    use kafka_protocol::messages::{FetchResponse, TopicName};
+    use kafka_protocol::protocol::{Encodable, StrBytes};
+
+    use crate::log::{FileRecords, FileRegion};
+    use crate::test_util::temp_path;
+
+    use super::{placeholder, ResponseBuf, ResponseSend};
+
+    // a response with some records between two other fields, as encoded
+    fn response(records: &Bytes, flexible: bool) -> ResponseBuf {
+        let mut body = BytesMut::new();
+        body.put_i32(7);
+        super::put_length(&mut body, records.len(), flexible);
+        let mut buf = ResponseBuf::default();
+        buf.put_i32((body.len() + records.len() + 2) as i32);
+        buf.put_slice(&body);
+        buf.put_slice(records);
+        buf.put_i16(0);
+        buf
+    }
+
+    #[tokio::test]
+    async fn test_with_file_records() {
+        let path = temp_path("send");
+        let records: Vec<u8> = (0..200).map(|i| i as u8).collect();
+        std::fs::File::create(&path).unwrap().write_all(&records).unwrap();
+        let file = Arc::new(std::fs::File::open(&path).unwrap());
+        let file_records = FileRecords {
+            memory: Bytes::new(),
+            regions: vec![FileRegion { file, position: 10, length: 150 }],
+        };
+
+        for flexible in [false, true] {
+            let expected = response(&Bytes::copy_from_slice(&records[10..160]), flexible).into_inner();
+            let send = ResponseSend::with_file_records(response(&placeholder(0), flexible), flexible, vec![file_records.clone()]).unwrap();
+            assert_eq!(expected.len(), send.len());
+            assert_eq!(expected, send.to_bytes().await.unwrap());
+        }
+        assert!(ResponseSend::with_file_records(response(&placeholder(1), false), false, vec![file_records.clone()]).is_err());
+
+        // placeholder bytes inside other data are left alone
+        let mut embedded = BytesMut::from(&b"xy"[..]);
+        embedded.put_slice(&placeholder(0));
+        let send = ResponseSend::with_file_records(response(&embedded.freeze(), false), false, vec![file_records]);
+        assert!(send.is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    fn fetch_response(records: Bytes) -> FetchResponse {
+        FetchResponse::default()
+            .with_responses(vec![FetchableTopicResponse::default()
+                .with_topic(TopicName(StrBytes::from_static_str("foo")))
+                .with_partitions(vec![PartitionData::default().with_records(Some(records))])])
+    }
+
+    #[tokio::test]
+    async fn test_encoded_fetch_response() {
+        let records = Bytes::from_static(b"some records");
+        let file_records = FileRecords { memory: records.clone(), regions: Vec::new() };
+        for (version, flexible) in [(4, false), (12, true)] {
+            let mut expected = BytesMut::new();
+            expected.put_i32(0);
+            fetch_response(records.clone()).encode(&mut expected, version).unwrap();
+            let size = (expected.len() - 4) as i32;
+            (&mut expected[..4]).put_i32(size);
+
+            let mut buf = ResponseBuf::default();
+            buf.put_i32(0);
+            fetch_response(placeholder(0)).encode(&mut buf, version).unwrap();
+            assert_eq!(1, buf.placeholders.len());
+            let send = ResponseSend::with_file_records(buf, flexible, vec![file_records.clone()]).unwrap();
+            assert_eq!(expected, send.to_bytes().await.unwrap());
+        }
+    }
+}
@@ -0,0 +1,55 @@
+use std::path::PathBuf;
+
+use bytes::{Bytes, BytesMut};
+use kafka_protocol::records::{Compression, Record, RecordBatchEncoder, RecordEncodeOptions, TimestampType, NO_SEQUENCE};
+
+/// A record outside any transaction, without a producer sequence.
+pub fn record(offset: i64, timestamp: i64, key: Option<Bytes>, value: Option<Bytes>) -> Record {
+    Record {
+        transactional: false,
+        control: false,
+        partition_leader_epoch: 0,
+        producer_id: -1,
+        producer_epoch: -1,
+        timestamp_type: TimestampType::Creation,
+        offset,
+        sequence: NO_SEQUENCE,
+        timestamp,
+        key,
+        value,
+        headers: Default::default(),
+    }
+}
+
+/// Encodes `records` as one batch with no base sequence. The encoder starts
+/// a new batch wherever offset - sequence changes, so the sequences are
+/// counted up from the base sequence of -1 here.
+pub fn encode(records: &[Record], compression: Compression) -> BytesMut {
+    let base_offset = records[0].offset;
+    let records: Vec<Record> = records.iter()
+        .map(|record| Record { sequence: NO_SEQUENCE + (record.offset - base_offset) as i32, ..record.clone() })
+        .collect();
+    let mut buf = BytesMut::new();
+    let options = RecordEncodeOptions {
+        version: 2,
+        compression,
+    };
+    RecordBatchEncoder::encode(&mut buf, &records, &options).unwrap();
+    buf
+}
+
+/// An uncompressed batch of one record.
+pub fn batch(key: Option<&'static str>, value: Option<&'static str>, timestamp: i64) -> BytesMut {
+    let key = key.map(|key| Bytes::from_static(key.as_bytes()));
+    let value = value.map(|value| Bytes::from_static(value.as_bytes()));
+    encode(&[record(0, timestamp, key, value)], Compression::None)
+}
+
+/// A path in the temp dir for this test run, cleared of whatever an earlier
+/// run left there.
+pub fn temp_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&path);
+    let _ = std::fs::remove_file(&path);
+    path
+}
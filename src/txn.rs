@@ -0,0 +1,873 @@
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use kafka_protocol::error::ResponseError;
+use kafka_protocol::messages::add_partitions_to_txn_request::AddPartitionsToTxnTopic;
+use kafka_protocol::messages::add_partitions_to_txn_response::{AddPartitionsToTxnPartitionResult, AddPartitionsToTxnResult, AddPartitionsToTxnTopicResult};
+use kafka_protocol::messages::txn_offset_commit_response::{TxnOffsetCommitResponsePartition, TxnOffsetCommitResponseTopic};
+use kafka_protocol::messages::{AddOffsetsToTxnRequest, AddOffsetsToTxnResponse, AddPartitionsToTxnRequest, AddPartitionsToTxnResponse, EndTxnRequest, EndTxnResponse, InitProducerIdRequest, InitProducerIdResponse, ProducerId, TxnOffsetCommitRequest, TxnOffsetCommitResponse};
+use kafka_protocol::records::{Compression, Record, RecordBatchDecoder, RecordBatchEncoder, RecordEncodeOptions, RecordSet, TimestampType};
+
+use crate::broker::{now_ms, Broker};
+use crate::group::{txn_offset_commit_batch, GroupTopicPartition, OffsetAndMetadata, CONSUMER_OFFSETS_PARTITION, CONSUMER_OFFSETS_TOPIC};
+use crate::log::{TopicPartition, CONTROL_TYPE_ABORT, CONTROL_TYPE_COMMIT};
+use crate::record::{encode_producer_ids_record, parse_record_value, ProducerIdsRecord, RecordValue};
+use crate::replica::node_id;
+
+// Kafka spreads transactional ids over 50 partitions; a single broker only needs one.
+pub const TRANSACTION_STATE_TOPIC: &str = "__transaction_state";
+const TRANSACTION_STATE_PARTITION: i32 = 0;
+const MAX_TRANSACTION_TIMEOUT_MS: i32 = 900_000;
+const COORDINATOR_EPOCH: i32 = 0;
+// producer ids are reserved in the metadata log this many at a time
+const PRODUCER_ID_BLOCK_SIZE: i64 = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransactionState {
+    Empty,
+    Ongoing,
+    PrepareCommit,
+    PrepareAbort,
+    CompleteCommit,
+    CompleteAbort,
+    Dead,
+    PrepareEpochFence,
+}
+
+impl TransactionState {
+    pub fn id(self) -> i8 {
+        match self {
+            TransactionState::Empty => 0,
+            TransactionState::Ongoing => 1,
+            TransactionState::PrepareCommit => 2,
+            TransactionState::PrepareAbort => 3,
+            TransactionState::CompleteCommit => 4,
+            TransactionState::CompleteAbort => 5,
+            TransactionState::Dead => 6,
+            TransactionState::PrepareEpochFence => 7,
+        }
+    }
+
+    pub fn from_id(id: i8) -> Option<TransactionState> {
+        match id {
+            0 => Some(TransactionState::Empty),
+            1 => Some(TransactionState::Ongoing),
+            2 => Some(TransactionState::PrepareCommit),
+            3 => Some(TransactionState::PrepareAbort),
+            4 => Some(TransactionState::CompleteCommit),
+            5 => Some(TransactionState::CompleteAbort),
+            6 => Some(TransactionState::Dead),
+            7 => Some(TransactionState::PrepareEpochFence),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransactionMetadata {
+    pub transactional_id: String,
+    pub producer_id: i64,
+    pub producer_epoch: i16,
+    pub timeout_ms: i32,
+    pub state: TransactionState,
+    pub partitions: BTreeSet<TopicPartition>,
+    // groups whose offsets were added with AddOffsetsToTxn, kept in memory only
+    pub groups: BTreeSet<String>,
+    pub last_update_timestamp_ms: i64,
+    pub start_timestamp_ms: i64,
+}
+
+impl TransactionMetadata {
+    fn check_producer(&self, producer_id: i64, producer_epoch: i16) -> Result<(), ResponseError> {
+        if self.producer_id != producer_id {
+            Err(ResponseError::InvalidProducerIdMapping)
+        } else if self.producer_epoch != producer_epoch {
+            Err(ResponseError::ProducerFenced)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn begin(&mut self, now: i64) -> Result<(), ResponseError> {
+        match self.state {
+            TransactionState::PrepareCommit | TransactionState::PrepareAbort | TransactionState::PrepareEpochFence => {
+                Err(ResponseError::ConcurrentTransactions)
+            }
+            TransactionState::Dead => Err(ResponseError::InvalidTxnState),
+            TransactionState::Ongoing => {
+                self.last_update_timestamp_ms = now;
+                Ok(())
+            }
+            _ => {
+                self.state = TransactionState::Ongoing;
+                self.start_timestamp_ms = now;
+                self.last_update_timestamp_ms = now;
+                Ok(())
+            }
+        }
+    }
+}
+
+// __transaction_state key: version(i16) transactional_id(string)
+pub fn encode_txn_log_key(transactional_id: &str) -> Bytes {
+    let mut buf = BytesMut::new();
+    buf.put_i16(0);
+    buf.put_i16(transactional_id.len() as i16);
+    buf.put_slice(transactional_id.as_bytes());
+    buf.freeze()
+}
+
+// __transaction_state value v0: version(i16) producer_id(i64) producer_epoch(i16)
+// timeout_ms(i32) state(i8) [topic(string) [partition(i32)]] last_update_ms(i64) start_ms(i64)
+pub fn encode_txn_log_value(metadata: &TransactionMetadata) -> Bytes {
+    let mut topics: Vec<(&str, Vec<i32>)> = Vec::new();
+    for (topic, partition) in metadata.partitions.iter() {
+        match topics.last_mut() {
+            Some((name, partitions)) if *name == topic.as_str() => partitions.push(*partition),
+            _ => topics.push((topic.as_str(), vec![*partition])),
+        }
+    }
+
+    let mut buf = BytesMut::new();
+    buf.put_i16(0);
+    buf.put_i64(metadata.producer_id);
+    buf.put_i16(metadata.producer_epoch);
+    buf.put_i32(metadata.timeout_ms);
+    buf.put_i8(metadata.state.id());
+    buf.put_i32(topics.len() as i32);
+    for (topic, partitions) in topics {
+        buf.put_i16(topic.len() as i16);
+        buf.put_slice(topic.as_bytes());
+        buf.put_i32(partitions.len() as i32);
+        for partition in partitions {
+            buf.put_i32(partition);
+        }
+    }
+    buf.put_i64(metadata.last_update_timestamp_ms);
+    buf.put_i64(metadata.start_timestamp_ms);
+    buf.freeze()
+}
+
+pub fn get_string<B: Buf>(buf: &mut B) -> Option<String> {
+    let len = buf.try_get_i16().ok()?;
+    if len < 0 || buf.remaining() < len as usize {
+        return None;
+    }
+    let mut str_buf = vec![0; len as usize];
+    buf.copy_to_slice(&mut str_buf);
+    String::from_utf8(str_buf).ok()
+}
+
+pub fn decode_txn_log_key<B: Buf>(buf: &mut B) -> Option<String> {
+    buf.try_get_i16().ok()?; // version
+    get_string(buf)
+}
+
+/// Decodes a transaction log value, None when it is cut short.
+pub fn decode_txn_log_value<B: Buf>(transactional_id: &str, buf: &mut B) -> Option<TransactionMetadata> {
+    buf.try_get_i16().ok()?; // version
+    let producer_id = buf.try_get_i64().ok()?;
+    let producer_epoch = buf.try_get_i16().ok()?;
+    let timeout_ms = buf.try_get_i32().ok()?;
+    let state = TransactionState::from_id(buf.try_get_i8().ok()?)?;
+    let mut partitions = BTreeSet::new();
+    let topic_count = buf.try_get_i32().ok()?;
+    for _ in 0..topic_count.max(0) {
+        let topic = get_string(buf)?;
+        let partition_count = buf.try_get_i32().ok()?;
+        for _ in 0..partition_count.max(0) {
+            partitions.insert((topic.clone(), buf.try_get_i32().ok()?));
+        }
+    }
+    let last_update_timestamp_ms = buf.try_get_i64().ok()?;
+    let start_timestamp_ms = buf.try_get_i64().ok()?;
+    Some(TransactionMetadata {
+        transactional_id: transactional_id.to_string(),
+        producer_id,
+        producer_epoch,
+        timeout_ms,
+        state,
+        partitions,
+        groups: BTreeSet::new(),
+        last_update_timestamp_ms,
+        start_timestamp_ms,
+    })
+}
+
+/// A single-record control batch marking the end of a producer's transaction.
+pub fn control_batch(producer_id: i64, producer_epoch: i16, commit: bool, timestamp: i64) -> BytesMut {
+    let mut key = BytesMut::new();
+    key.put_i16(0);
+    key.put_i16(if commit { CONTROL_TYPE_COMMIT } else { CONTROL_TYPE_ABORT });
+    let mut value = BytesMut::new();
+    value.put_i16(0);
+    value.put_i32(COORDINATOR_EPOCH);
+
+    let records = vec![Record {
+        transactional: true,
+        control: true,
+        partition_leader_epoch: 0,
+        producer_id,
+        producer_epoch,
+        timestamp_type: TimestampType::Creation,
+        offset: 0,
+        sequence: -1,
+        timestamp,
+        key: Some(key.freeze()),
+        value: Some(value.freeze()),
+        headers: Default::default(),
+    }];
+    let mut buf = BytesMut::new();
+    let options = RecordEncodeOptions {
+        version: 2,
+        compression: Compression::None,
+    };
+    RecordBatchEncoder::encode(&mut buf, &records, &options).unwrap();
+    buf
+}
+
+fn txn_log_batch(metadata: &TransactionMetadata) -> BytesMut {
+    let records = vec![Record {
+        transactional: false,
+        control: false,
+        partition_leader_epoch: 0,
+        producer_id: -1,
+        producer_epoch: -1,
+        timestamp_type: TimestampType::Creation,
+        offset: 0,
+        sequence: -1,
+        timestamp: metadata.last_update_timestamp_ms,
+        key: Some(encode_txn_log_key(&metadata.transactional_id)),
+        value: Some(encode_txn_log_value(metadata)),
+        headers: Default::default(),
+    }];
+    let mut buf = BytesMut::new();
+    let options = RecordEncodeOptions {
+        version: 2,
+        compression: Compression::None,
+    };
+    RecordBatchEncoder::encode(&mut buf, &records, &options).unwrap();
+    buf
+}
+
+#[derive(Debug, Default)]
+pub struct TransactionCoordinator {
+    next_producer_id: i64,
+    // the end of the block reserved by the last ProducerIdsRecord
+    producer_id_block_end: i64,
+    transactions: HashMap<String, TransactionMetadata>,
+}
+
+impl TransactionCoordinator {
+    pub fn get(&self, transactional_id: &str) -> Option<&TransactionMetadata> {
+        self.transactions.get(transactional_id)
+    }
+
+    /// Replays one __transaction_state entry; later entries overwrite earlier ones.
+    pub fn load(&mut self, metadata: TransactionMetadata) {
+        self.next_producer_id = self.next_producer_id.max(metadata.producer_id + 1);
+        self.transactions.insert(metadata.transactional_id.clone(), metadata);
+    }
+
+    /// Replays a ProducerIdsRecord; the block it reserved may have been
+    /// handed out before a restart, so ids start again at its end.
+    pub fn load_producer_ids(&mut self, next_producer_id: i64) {
+        self.next_producer_id = self.next_producer_id.max(next_producer_id);
+        self.producer_id_block_end = self.producer_id_block_end.max(next_producer_id);
+    }
+
+    /// The end of the next block to reserve once the current one runs out,
+    /// or None while it still has ids left.
+    pub fn producer_id_block_needed(&self) -> Option<i64> {
+        (self.next_producer_id >= self.producer_id_block_end).then(|| self.next_producer_id + PRODUCER_ID_BLOCK_SIZE)
+    }
+
+    /// Hands out ids up to `block_end` once its ProducerIdsRecord is written.
+    pub fn reserve_producer_ids(&mut self, block_end: i64) {
+        self.producer_id_block_end = self.producer_id_block_end.max(block_end);
+    }
+
+    /// The next id of the reserved block, None once it is used up.
+    pub fn next_producer_id(&mut self) -> Option<i64> {
+        if self.next_producer_id >= self.producer_id_block_end {
+            return None;
+        }
+        let producer_id = self.next_producer_id;
+        self.next_producer_id += 1;
+        Some(producer_id)
+    }
+
+    /// Transactions stuck in a prepare state, e.g. after a restart between the two phases.
+    pub fn prepared(&self) -> Vec<TransactionMetadata> {
+        self.transactions.values()
+            .filter(|m| matches!(m.state, TransactionState::PrepareCommit | TransactionState::PrepareAbort))
+            .cloned()
+            .collect()
+    }
+
+    /// Registers or re-initializes a transactional producer, bumping its epoch.
+    /// An ongoing transaction has to be aborted first, see `fence`.
+    pub fn init_producer_id(&mut self, transactional_id: &str, timeout_ms: i32, now: i64) -> Result<TransactionMetadata, ResponseError> {
+        if timeout_ms <= 0 || timeout_ms > MAX_TRANSACTION_TIMEOUT_MS {
+            return Err(ResponseError::InvalidTransactionTimeout);
+        }
+        if !self.transactions.contains_key(transactional_id) {
+            let producer_id = self.next_producer_id().ok_or(ResponseError::CoordinatorNotAvailable)?;
+            let metadata = TransactionMetadata {
+                transactional_id: transactional_id.to_string(),
+                producer_id,
+                producer_epoch: 0,
+                timeout_ms,
+                state: TransactionState::Empty,
+                partitions: BTreeSet::new(),
+                groups: BTreeSet::new(),
+                last_update_timestamp_ms: now,
+                start_timestamp_ms: -1,
+            };
+            self.transactions.insert(transactional_id.to_string(), metadata.clone());
+            return Ok(metadata);
+        }
+
+        let exhausted = self.transactions[transactional_id].producer_epoch >= i16::MAX - 1;
+        let new_producer_id = if exhausted {
+            Some(self.next_producer_id().ok_or(ResponseError::CoordinatorNotAvailable)?)
+        } else {
+            None
+        };
+        let metadata = self.transactions.get_mut(transactional_id).unwrap();
+        match metadata.state {
+            TransactionState::Ongoing
+            | TransactionState::PrepareCommit
+            | TransactionState::PrepareAbort
+            | TransactionState::PrepareEpochFence => Err(ResponseError::ConcurrentTransactions),
+            _ => {
+                match new_producer_id {
+                    Some(producer_id) => {
+                        metadata.producer_id = producer_id;
+                        metadata.producer_epoch = 0;
+                    }
+                    None => metadata.producer_epoch += 1,
+                }
+                metadata.timeout_ms = timeout_ms;
+                metadata.state = TransactionState::Empty;
+                metadata.partitions.clear();
+                metadata.groups.clear();
+                metadata.last_update_timestamp_ms = now;
+                metadata.start_timestamp_ms = -1;
+                Ok(metadata.clone())
+            }
+        }
+    }
+
+    pub fn add_partitions(&mut self, transactional_id: &str, producer_id: i64, producer_epoch: i16, partitions: &[TopicPartition], now: i64) -> Result<TransactionMetadata, ResponseError> {
+        let metadata = self.transactions.get_mut(transactional_id)
+            .ok_or(ResponseError::InvalidProducerIdMapping)?;
+        metadata.check_producer(producer_id, producer_epoch)?;
+        metadata.begin(now)?;
+        metadata.partitions.extend(partitions.iter().cloned());
+        Ok(metadata.clone())
+    }
+
+    pub fn verify_partitions(&self, transactional_id: &str, producer_id: i64, producer_epoch: i16, partitions: &[TopicPartition]) -> Result<(), ResponseError> {
+        let metadata = self.transactions.get(transactional_id)
+            .ok_or(ResponseError::InvalidProducerIdMapping)?;
+        metadata.check_producer(producer_id, producer_epoch)?;
+        if metadata.state == TransactionState::Ongoing && partitions.iter().all(|tp| metadata.partitions.contains(tp)) {
+            Ok(())
+        } else {
+            Err(ResponseError::InvalidTxnState)
+        }
+    }
+
+    pub fn add_offsets(&mut self, transactional_id: &str, producer_id: i64, producer_epoch: i16, group_id: &str, now: i64) -> Result<TransactionMetadata, ResponseError> {
+        let metadata = self.transactions.get_mut(transactional_id)
+            .ok_or(ResponseError::InvalidProducerIdMapping)?;
+        metadata.check_producer(producer_id, producer_epoch)?;
+        metadata.begin(now)?;
+        metadata.groups.insert(group_id.to_string());
+        // the offsets partition takes a marker like any other participant
+        metadata.partitions.insert((CONSUMER_OFFSETS_TOPIC.to_string(), CONSUMER_OFFSETS_PARTITION));
+        Ok(metadata.clone())
+    }
+
+    pub fn check_offset_commit(&self, transactional_id: &str, producer_id: i64, producer_epoch: i16, group_id: &str) -> Result<(), ResponseError> {
+        let metadata = self.transactions.get(transactional_id)
+            .ok_or(ResponseError::InvalidProducerIdMapping)?;
+        metadata.check_producer(producer_id, producer_epoch)?;
+        if metadata.state == TransactionState::Ongoing && metadata.groups.contains(group_id) {
+            Ok(())
+        } else {
+            Err(ResponseError::InvalidTxnState)
+        }
+    }
+
+    /// First phase of EndTxn. Returns the prepared metadata, or `None` when the
+    /// request is a retry of an already completed transaction.
+    pub fn prepare_end(&mut self, transactional_id: &str, producer_id: i64, producer_epoch: i16, commit: bool, now: i64) -> Result<Option<TransactionMetadata>, ResponseError> {
+        let metadata = self.transactions.get_mut(transactional_id)
+            .ok_or(ResponseError::InvalidProducerIdMapping)?;
+        metadata.check_producer(producer_id, producer_epoch)?;
+        match (metadata.state, commit) {
+            (TransactionState::Ongoing, _) => {
+                metadata.state = if commit { TransactionState::PrepareCommit } else { TransactionState::PrepareAbort };
+                metadata.last_update_timestamp_ms = now;
+                Ok(Some(metadata.clone()))
+            }
+            (TransactionState::CompleteCommit, true) | (TransactionState::CompleteAbort, false) => Ok(None),
+            (TransactionState::PrepareCommit, true) | (TransactionState::PrepareAbort, false) => Err(ResponseError::ConcurrentTransactions),
+            _ => Err(ResponseError::InvalidTxnState),
+        }
+    }
+
+    /// Bumps the epoch of an ongoing transaction and prepares it for abort,
+    /// fencing the current producer. Used on timeout and re-initialization.
+    pub fn fence(&mut self, transactional_id: &str, now: i64) -> Option<TransactionMetadata> {
+        let metadata = self.transactions.get_mut(transactional_id)?;
+        if metadata.state != TransactionState::Ongoing {
+            return None;
+        }
+        metadata.producer_epoch += 1;
+        metadata.state = TransactionState::PrepareAbort;
+        metadata.last_update_timestamp_ms = now;
+        Some(metadata.clone())
+    }
+
+    /// Second phase of EndTxn, after the markers are written.
+    pub fn complete(&mut self, transactional_id: &str, now: i64) -> Option<TransactionMetadata> {
+        let metadata = self.transactions.get_mut(transactional_id)?;
+        metadata.state = match metadata.state {
+            TransactionState::PrepareCommit => TransactionState::CompleteCommit,
+            TransactionState::PrepareAbort => TransactionState::CompleteAbort,
+            _ => return None,
+        };
+        metadata.partitions.clear();
+        metadata.groups.clear();
+        metadata.last_update_timestamp_ms = now;
+        Some(metadata.clone())
+    }
+
+    pub fn expired(&self, now: i64) -> Vec<String> {
+        self.transactions.values()
+            .filter(|m| m.state == TransactionState::Ongoing && m.start_timestamp_ms + m.timeout_ms as i64 <= now)
+            .map(|m| m.transactional_id.clone())
+            .collect()
+    }
+}
+
+async fn persist(broker: &Broker, metadata: &TransactionMetadata) -> io::Result<()> {
+    let batch = txn_log_batch(metadata);
+    broker.append(TRANSACTION_STATE_TOPIC, TRANSACTION_STATE_PARTITION, &batch).await?;
+    Ok(())
+}
+
+/// The end of the last producer id block reserved in the metadata log.
+fn last_producer_id_block(record_sets: &[RecordSet]) -> Option<i64> {
+    record_sets.iter()
+        .flat_map(|record_set| record_set.records.iter())
+        .filter_map(|record| match parse_record_value(&mut record.value.clone()?) {
+            RecordValue::ProducerIdsRecord(record) => Some(record.next_producer_id),
+            _ => None,
+        })
+        .last()
+}
+
+/// Reserves the next block of producer ids in the metadata log once the
+/// current one is used up. The coordinator stays locked until the record is
+/// written, so two requests never reserve the same block.
+async fn reserve_producer_ids(broker: &Broker, coordinator: &mut TransactionCoordinator) -> io::Result<()> {
+    if coordinator.producer_id_block_needed().is_none() {
+        return Ok(());
+    }
+    // another broker may have reserved a block since
+    if let Some(next_producer_id) = last_producer_id_block(&broker.cluster_metadata().await) {
+        coordinator.load_producer_ids(next_producer_id);
+    }
+    let Some(block_end) = coordinator.producer_id_block_needed() else { return Ok(()) };
+    let record = ProducerIdsRecord {
+        broker_id: node_id(&broker.config),
+        broker_epoch: -1,
+        next_producer_id: block_end,
+    };
+    broker.append_metadata(vec![encode_producer_ids_record(&record)]).await?;
+    coordinator.reserve_producer_ids(block_end);
+    Ok(())
+}
+
+/// Writes the COMMIT/ABORT marker into every participating partition, settles
+/// the transactional offsets and records the completed state.
+async fn complete(broker: &Broker, prepared: TransactionMetadata) -> io::Result<()> {
+    let commit = prepared.state == TransactionState::PrepareCommit;
+    let now = now_ms();
+    let marker = control_batch(prepared.producer_id, prepared.producer_epoch, commit, now);
+    for (topic, partition) in prepared.partitions.iter() {
+        broker.append(topic, *partition, &marker).await?;
+    }
+    broker.group_coordinator.lock().await.complete_txn(prepared.producer_id, commit);
+    let completed = broker.txn_coordinator.lock().await.complete(&prepared.transactional_id, now);
+    if let Some(completed) = completed {
+        persist(broker, &completed).await?;
+    }
+    Ok(())
+}
+
+async fn end(broker: &Broker, prepared: TransactionMetadata) -> io::Result<()> {
+    persist(broker, &prepared).await?;
+    complete(broker, prepared).await
+}
+
+/// Rebuilds the coordinator from __transaction_state and finishes any
+/// transaction that was left between the two commit phases.
+pub async fn load(broker: &Broker) -> io::Result<()> {
+    let mut buf = broker.read_all(TRANSACTION_STATE_TOPIC, TRANSACTION_STATE_PARTITION).await?;
+    let record_sets = RecordBatchDecoder::decode_all(&mut buf)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+    let next_producer_id = last_producer_id_block(&broker.cluster_metadata().await);
+    let prepared = {
+        let mut coordinator = broker.txn_coordinator.lock().await;
+        if let Some(next_producer_id) = next_producer_id {
+            coordinator.load_producer_ids(next_producer_id);
+        }
+        for record_set in record_sets.iter() {
+            for record in record_set.records.iter() {
+                let Some(mut key) = record.key.clone() else { continue };
+                let Some(transactional_id) = decode_txn_log_key(&mut key) else { continue };
+                match record.value.clone() {
+                    Some(mut value) => {
+                        if let Some(metadata) = decode_txn_log_value(&transactional_id, &mut value) {
+                            coordinator.load(metadata);
+                        }
+                    }
+                    None => {
+                        coordinator.transactions.remove(&transactional_id);
+                    }
+                }
+            }
+        }
+        coordinator.prepared()
+    };
+    for metadata in prepared {
+        complete(broker, metadata).await?;
+    }
+    Ok(())
+}
+
+/// Aborts transactions that have been ongoing for longer than their timeout.
+pub async fn abort_expired(broker: &Broker) {
+    let now = now_ms();
+    let fenced: Vec<TransactionMetadata> = {
+        let mut coordinator = broker.txn_coordinator.lock().await;
+        coordinator.expired(now)
+            .iter()
+            .filter_map(|transactional_id| coordinator.fence(transactional_id, now))
+            .collect()
+    };
+    for metadata in fenced {
+        println!("Aborting timed out transaction {}", metadata.transactional_id);
+        if let Err(e) = end(broker, metadata).await {
+            println!("Failed to abort transaction: {}", e);
+        }
+    }
+}
+
+pub async fn handle_init_producer_id(broker: &Broker, req: InitProducerIdRequest) -> InitProducerIdResponse {
+    let error_response = |error: ResponseError| InitProducerIdResponse::default()
+        .with_error_code(error.code())
+        .with_producer_id(ProducerId(-1))
+        .with_producer_epoch(-1);
+
+    let mut coordinator = broker.txn_coordinator.lock().await;
+    if let Err(e) = reserve_producer_ids(broker, &mut coordinator).await {
+        println!("Failed to reserve producer ids: {}", e);
+        return error_response(ResponseError::CoordinatorNotAvailable);
+    }
+    let Some(transactional_id) = req.transactional_id else {
+        return match coordinator.next_producer_id() {
+            Some(producer_id) => InitProducerIdResponse::default()
+                .with_producer_id(ProducerId(producer_id))
+                .with_producer_epoch(0),
+            None => error_response(ResponseError::CoordinatorNotAvailable),
+        };
+    };
+    let transactional_id = transactional_id.0.to_string();
+    let now = now_ms();
+    let result = coordinator.init_producer_id(&transactional_id, req.transaction_timeout_ms, now);
+    drop(coordinator);
+    match result {
+        Ok(metadata) => {
+            if let Err(e) = persist(broker, &metadata).await {
+                println!("Failed to write transaction state: {}", e);
+                return error_response(ResponseError::CoordinatorNotAvailable);
+            }
+            InitProducerIdResponse::default()
+                .with_producer_id(ProducerId(metadata.producer_id))
+                .with_producer_epoch(metadata.producer_epoch)
+        }
+        Err(ResponseError::ConcurrentTransactions) => {
+            // abort the previous producer's transaction; the client retries once it is done
+            let fenced = broker.txn_coordinator.lock().await.fence(&transactional_id, now);
+            if let Some(fenced) = fenced {
+                if let Err(e) = end(broker, fenced).await {
+                    println!("Failed to abort transaction: {}", e);
+                }
+            }
+            error_response(ResponseError::ConcurrentTransactions)
+        }
+        Err(error) => error_response(error),
+    }
+}
+
+async fn add_partitions(broker: &Broker, transactional_id: &str, producer_id: i64, producer_epoch: i16, verify_only: bool, topics: &[AddPartitionsToTxnTopic]) -> Vec<AddPartitionsToTxnTopicResult> {
+    let partitions: Vec<TopicPartition> = topics.iter()
+        .flat_map(|t| t.partitions.iter().map(|p| (t.name.0.to_string(), *p)))
+        .collect();
+    let result = {
+        let mut coordinator = broker.txn_coordinator.lock().await;
+        if verify_only {
+            coordinator.verify_partitions(transactional_id, producer_id, producer_epoch, &partitions).map(|_| None)
+        } else {
+            coordinator.add_partitions(transactional_id, producer_id, producer_epoch, &partitions, now_ms()).map(Some)
+        }
+    };
+    let error_code = match result {
+        Ok(Some(metadata)) => match persist(broker, &metadata).await {
+            Ok(_) => 0,
+            Err(_) => ResponseError::CoordinatorNotAvailable.code(),
+        },
+        Ok(None) => 0,
+        Err(error) => error.code(),
+    };
+    topics.iter()
+        .map(|t| AddPartitionsToTxnTopicResult::default()
+            .with_name(t.name.clone())
+            .with_results_by_partition(t.partitions.iter()
+                .map(|p| AddPartitionsToTxnPartitionResult::default()
+                    .with_partition_index(*p)
+                    .with_partition_error_code(error_code))
+                .collect()))
+        .collect()
+}
+
+pub async fn handle_add_partitions_to_txn(broker: &Broker, req: AddPartitionsToTxnRequest, api_version: i16) -> AddPartitionsToTxnResponse {
+    if api_version <= 3 {
+        let results = add_partitions(
+            broker,
+            req.v3_and_below_transactional_id.0.as_str(),
+            req.v3_and_below_producer_id.0,
+            req.v3_and_below_producer_epoch,
+            false,
+            &req.v3_and_below_topics,
+        ).await;
+        return AddPartitionsToTxnResponse::default()
+            .with_results_by_topic_v3_and_below(results);
+    }
+    let mut results = Vec::new();
+    for transaction in req.transactions.iter() {
+        let topic_results = add_partitions(
+            broker,
+            transaction.transactional_id.0.as_str(),
+            transaction.producer_id.0,
+            transaction.producer_epoch,
+            transaction.verify_only,
+            &transaction.topics,
+        ).await;
+        results.push(AddPartitionsToTxnResult::default()
+            .with_transactional_id(transaction.transactional_id.clone())
+            .with_topic_results(topic_results));
+    }
+    AddPartitionsToTxnResponse::default()
+        .with_results_by_transaction(results)
+}
+
+pub async fn handle_add_offsets_to_txn(broker: &Broker, req: AddOffsetsToTxnRequest) -> AddOffsetsToTxnResponse {
+    let result = broker.txn_coordinator.lock().await.add_offsets(
+        req.transactional_id.0.as_str(),
+        req.producer_id.0,
+        req.producer_epoch,
+        req.group_id.0.as_str(),
+        now_ms(),
+    );
+    let error_code = match result {
+        Ok(metadata) => match persist(broker, &metadata).await {
+            Ok(_) => 0,
+            Err(_) => ResponseError::CoordinatorNotAvailable.code(),
+        },
+        Err(error) => error.code(),
+    };
+    AddOffsetsToTxnResponse::default()
+        .with_error_code(error_code)
+}
+
+pub async fn handle_end_txn(broker: &Broker, req: EndTxnRequest) -> EndTxnResponse {
+    let result = broker.txn_coordinator.lock().await.prepare_end(
+        req.transactional_id.0.as_str(),
+        req.producer_id.0,
+        req.producer_epoch,
+        req.committed,
+        now_ms(),
+    );
+    let error_code = match result {
+        Ok(Some(prepared)) => match end(broker, prepared).await {
+            Ok(_) => 0,
+            Err(e) => {
+                println!("Failed to complete transaction: {}", e);
+                ResponseError::CoordinatorNotAvailable.code()
+            }
+        },
+        Ok(None) => 0,
+        Err(error) => error.code(),
+    };
+    EndTxnResponse::default()
+        .with_error_code(error_code)
+}
+
+pub async fn handle_txn_offset_commit(broker: &Broker, req: TxnOffsetCommitRequest) -> TxnOffsetCommitResponse {
+    let group_id = req.group_id.0.to_string();
+    let result = broker.txn_coordinator.lock().await.check_offset_commit(
+        req.transactional_id.0.as_str(),
+        req.producer_id.0,
+        req.producer_epoch,
+        &group_id,
+    );
+    let offsets: Vec<(GroupTopicPartition, OffsetAndMetadata)> = req.topics.iter()
+        .flat_map(|topic| topic.partitions.iter().map(|partition| {
+            let key = (group_id.clone(), topic.name.0.to_string(), partition.partition_index);
+            let offset = OffsetAndMetadata {
+                offset: partition.committed_offset,
+                leader_epoch: partition.committed_leader_epoch,
+                metadata: partition.committed_metadata.as_ref().map(|m| m.to_string()),
+            };
+            (key, offset)
+        }))
+        .collect();
+    let error_code = match result {
+        Ok(_) if offsets.is_empty() => 0,
+        Ok(_) => {
+            let batch = txn_offset_commit_batch(req.producer_id.0, req.producer_epoch, &offsets, now_ms());
+            match broker.append(CONSUMER_OFFSETS_TOPIC, CONSUMER_OFFSETS_PARTITION, &batch).await {
+                Ok(_) => {
+                    let mut group_coordinator = broker.group_coordinator.lock().await;
+                    for (key, offset) in offsets {
+                        group_coordinator.store_txn_offset(req.producer_id.0, key, offset);
+                    }
+                    0
+                }
+                Err(e) => {
+                    println!("Failed to write transactional offsets: {}", e);
+                    ResponseError::CoordinatorNotAvailable.code()
+                }
+            }
+        }
+        Err(error) => error.code(),
+    };
+    let topics = req.topics.iter()
+        .map(|topic| TxnOffsetCommitResponseTopic::default()
+            .with_name(topic.name.clone())
+            .with_partitions(topic.partitions.iter()
+                .map(|p| TxnOffsetCommitResponsePartition::default()
+                    .with_partition_index(p.partition_index)
+                    .with_error_code(error_code))
+                .collect()))
+        .collect();
+    TxnOffsetCommitResponse::default()
+        .with_topics(topics)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use kafka_protocol::error::ResponseError;
+
+    use super::{decode_txn_log_key, decode_txn_log_value, encode_txn_log_key, encode_txn_log_value, TransactionCoordinator, TransactionMetadata, TransactionState};
+
+    #[test]
+    fn test_txn_log_round_trip() {
+        let metadata = TransactionMetadata {
+            transactional_id: "txn-1".to_string(),
+            producer_id: 42,
+            producer_epoch: 3,
+            timeout_ms: 60_000,
+            state: TransactionState::Ongoing,
+            partitions: BTreeSet::from([("foo".to_string(), 0), ("foo".to_string(), 2), ("bar".to_string(), 1)]),
+            groups: BTreeSet::new(),
+            last_update_timestamp_ms: 1_000,
+            start_timestamp_ms: 900,
+        };
+        let mut key = encode_txn_log_key(&metadata.transactional_id);
+        let mut value = encode_txn_log_value(&metadata);
+        let transactional_id = decode_txn_log_key(&mut key).unwrap();
+        assert_eq!("txn-1", transactional_id);
+        let encoded = value.clone();
+        assert_eq!(Some(metadata), decode_txn_log_value(&transactional_id, &mut value));
+        // a value cut short anywhere is rejected rather than read past its end
+        for len in 0..encoded.len() {
+            assert_eq!(None, decode_txn_log_value(&transactional_id, &mut encoded.slice(..len)));
+        }
+    }
+
+    #[test]
+    fn test_two_phase_commit() {
+        let mut coordinator = TransactionCoordinator::default();
+        coordinator.reserve_producer_ids(1_000);
+        let metadata = coordinator.init_producer_id("txn", 1_000, 0).unwrap();
+        let (producer_id, epoch) = (metadata.producer_id, metadata.producer_epoch);
+
+        let partitions = vec![("foo".to_string(), 0)];
+        let error = coordinator.add_partitions("txn", producer_id, epoch + 1, &partitions, 1).unwrap_err();
+        assert_eq!(ResponseError::ProducerFenced.code(), error.code());
+        let metadata = coordinator.add_partitions("txn", producer_id, epoch, &partitions, 1).unwrap();
+        assert_eq!(TransactionState::Ongoing, metadata.state);
+
+        let prepared = coordinator.prepare_end("txn", producer_id, epoch, true, 2).unwrap().unwrap();
+        assert_eq!(TransactionState::PrepareCommit, prepared.state);
+        let error = coordinator.add_partitions("txn", producer_id, epoch, &partitions, 3).unwrap_err();
+        assert_eq!(ResponseError::ConcurrentTransactions.code(), error.code());
+
+        let completed = coordinator.complete("txn", 3).unwrap();
+        assert_eq!(TransactionState::CompleteCommit, completed.state);
+        assert!(completed.partitions.is_empty());
+        assert!(coordinator.prepare_end("txn", producer_id, epoch, true, 4).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_producer_id_blocks() {
+        let mut coordinator = TransactionCoordinator::default();
+        // nothing is handed out before a block is reserved
+        assert_eq!(Some(1_000), coordinator.producer_id_block_needed());
+        assert_eq!(None, coordinator.next_producer_id());
+        let error = coordinator.init_producer_id("txn", 1_000, 0).unwrap_err();
+        assert_eq!(ResponseError::CoordinatorNotAvailable.code(), error.code());
+
+        coordinator.reserve_producer_ids(1_000);
+        assert_eq!(None, coordinator.producer_id_block_needed());
+        assert_eq!(Some(0), coordinator.next_producer_id());
+        assert_eq!(1, coordinator.init_producer_id("txn", 1_000, 0).unwrap().producer_id);
+        for _ in 2..1_000 {
+            coordinator.next_producer_id().unwrap();
+        }
+        assert_eq!(None, coordinator.next_producer_id());
+        assert_eq!(Some(2_000), coordinator.producer_id_block_needed());
+
+        // after a restart the ids of the last reserved block are skipped
+        let mut restarted = TransactionCoordinator::default();
+        restarted.load_producer_ids(1_000);
+        assert_eq!(None, restarted.next_producer_id());
+        restarted.reserve_producer_ids(2_000);
+        assert_eq!(Some(1_000), restarted.next_producer_id());
+    }
+
+    #[test]
+    fn test_timeout_fences_producer() {
+        let mut coordinator = TransactionCoordinator::default();
+        coordinator.reserve_producer_ids(1_000);
+        let metadata = coordinator.init_producer_id("txn", 1_000, 0).unwrap();
+        coordinator.add_partitions("txn", metadata.producer_id, 0, &[("foo".to_string(), 0)], 10).unwrap();
+        assert!(coordinator.expired(500).is_empty());
+        assert_eq!(vec!["txn".to_string()], coordinator.expired(1_010));
+
+        let fenced = coordinator.fence("txn", 1_010).unwrap();
+        assert_eq!(1, fenced.producer_epoch);
+        assert_eq!(TransactionState::PrepareAbort, fenced.state);
+    }
+}
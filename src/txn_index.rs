@@ -0,0 +1,84 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use bytes::{Buf, BufMut, BytesMut};
+use tokio::fs::{self, OpenOptions};
+use tokio::io::AsyncWriteExt;
+
+use crate::log::segment_file_name;
+
+// version(2) + producerId(8) + firstOffset(8) + lastOffset(8) + lastStableOffset(8)
+pub const ABORTED_TXN_SIZE: usize = 34;
+const ABORTED_TXN_VERSION: i16 = 0;
+
+/// An entry of a segment's `.txnindex`: the offset range of an aborted transaction.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AbortedTxn {
+    pub producer_id: i64,
+    pub first_offset: i64,
+    pub last_offset: i64,
+    pub last_stable_offset: i64,
+}
+
+impl AbortedTxn {
+    pub fn encode(&self, buf: &mut BytesMut) {
+        buf.put_i16(ABORTED_TXN_VERSION);
+        buf.put_i64(self.producer_id);
+        buf.put_i64(self.first_offset);
+        buf.put_i64(self.last_offset);
+        buf.put_i64(self.last_stable_offset);
+    }
+
+    pub fn parse_all(mut buf: &[u8]) -> Vec<AbortedTxn> {
+        let mut ret = Vec::new();
+        while buf.len() >= ABORTED_TXN_SIZE {
+            buf.get_i16(); // version
+            ret.push(AbortedTxn {
+                producer_id: buf.get_i64(),
+                first_offset: buf.get_i64(),
+                last_offset: buf.get_i64(),
+                last_stable_offset: buf.get_i64(),
+            });
+        }
+        ret
+    }
+}
+
+pub fn txn_index_path(dir: &Path, base_offset: i64) -> PathBuf {
+    dir.join(segment_file_name(base_offset, "txnindex"))
+}
+
+pub async fn read_txn_index(path: &Path) -> io::Result<Vec<AbortedTxn>> {
+    match fs::read(path).await {
+        Ok(bytes) => Ok(AbortedTxn::parse_all(&bytes)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+pub async fn append_txn_index(path: &Path, entries: &[AbortedTxn]) -> io::Result<()> {
+    if entries.is_empty() {
+        return Ok(());
+    }
+    let mut buf = BytesMut::with_capacity(entries.len() * ABORTED_TXN_SIZE);
+    for entry in entries {
+        entry.encode(&mut buf);
+    }
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    file.write_all(&buf).await?;
+    file.flush().await
+}
+
+/// Replaces a segment's `.txnindex`, as when recovery rebuilds it.
+pub async fn write_txn_index(path: &Path, entries: &[AbortedTxn]) -> io::Result<()> {
+    match fs::remove_file(path).await {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    append_txn_index(path, entries).await
+}